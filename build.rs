@@ -7,8 +7,14 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also passes on what the firmware says about which build it is (see the console's `version`
+//! command): the git commit, the date, and the features enabled.
+//!
+//! Finally, it turns the keymap file, if there is one (see `keymaps/example.toml`), into layers for
+//! `src/keymap.rs` to use in place of its own, so that a mistake in the file is reported by line.
 
-use std::{env, fs::File, io::Write, path::PathBuf};
+use std::{collections::HashMap, env, fs, fs::File, io::Write, path::PathBuf, process::Command, time::SystemTime};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -22,4 +28,293 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    // defmt needs its own linker script to place log strings.
+    if env::var_os("CARGO_FEATURE_DEBUG_LOG").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+
+    println!("cargo:rustc-env=BUILD_GIT_HASH={}", git_hash().unwrap_or_else(|| "unknown".into()));
+    println!("cargo:rustc-env=BUILD_DATE={}", build_date());
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features());
+    // so that the commit is kept up to date, including whether there are changes on top of it
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let keymap_file = env::var("KEYMAP").unwrap_or_else(|_| DEFAULT_KEYMAP_FILE.into());
+    let layers = match fs::read_to_string(&keymap_file) {
+        Ok(text) => keymap_file_layers(&text).unwrap_or_else(|(line, err)| panic!("{}:{}: {}", keymap_file, line, err)),
+        Err(_) if env::var_os("KEYMAP").is_none() => HashMap::new(),
+        Err(err) => panic!("can't read {}: {}", keymap_file, err),
+    };
+    File::create(out.join("keymap_file.rs")).unwrap().write_all(keymap_file_source(&layers).as_bytes()).unwrap();
+    println!("cargo:rerun-if-changed=keymaps");
+    println!("cargo:rerun-if-env-changed=KEYMAP");
+}
+
+/// Short hash of the commit being built, marked `-dirty` if there are changes on top of it.
+fn git_hash() -> Option<String> {
+    let output = Command::new("git").args(["rev-parse", "--short", "HEAD"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let hash = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    let dirty = Command::new("git").args(["status", "--porcelain", "--untracked-files=no"]).output()
+        .is_ok_and(|status| !status.stdout.is_empty());
+    Some(if dirty { hash + "-dirty" } else { hash })
+}
+
+/// Today's date (UTC) as YYYY-MM-DD, or that of `SOURCE_DATE_EPOCH` for reproducible builds.
+fn build_date() -> String {
+    let secs = env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| epoch.parse().ok()).unwrap_or_else(||
+        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("after 1970").as_secs()
+    );
+    // days since 1970 to a civil date, as in http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Cargo features enabled, comma-separated, or "none".
+fn features() -> String {
+    let mut features: Vec<String> = env::vars().filter_map(|(name, _)|
+        Some(name.strip_prefix("CARGO_FEATURE_")?.to_lowercase().replace('_', "-"))
+    ).collect();
+    features.sort();
+    if features.is_empty() { "none".into() } else { features.join(",") }
+}
+
+/// Keymap file used if `KEYMAP` doesn't name another, and only if it exists
+const DEFAULT_KEYMAP_FILE: &str = "keymaps/keymap.toml";
+
+/// Layers which the keymap file may give, by the name of their section, each as a `FILE_` constant
+/// for `src/keymap.rs`. The steno layer isn't one of them, having keys the file can't name.
+const FILE_LAYERS: [&str; 9] = [
+    "normal", "dvorak_emu", "colemak_dh_emu", "workman_emu",
+    "symbols", "dvorak_emu_symbols", "navigation", "function", "right_symbols",
+];
+
+/// Things which may be named in the keymap file as they are, taking nothing else
+const FILE_THINGS: [&str; 20] = [
+    "LeftSymbolKey", "RightSymbolKey", "NavKey", "FunctionKey", "LayoutCycle", "StenoToggle",
+    "PaperTapeToggle", "StenoProtocolCycle", "MidiToggle", "Bootloader", "BacklightBrightness",
+    "LedBrightness", "BuzzerVolume", "JigglerToggle", "OutputToggle", "LatencyTestToggle",
+    "DebugMarker", "OsCycle", "KeyboardLock", "MicMute",
+];
+
+/// Modifier `KeyCode`s, which may be joined onto another key with `+`
+const MODIFIERS: [&str; 8] = ["LCtrl", "LShift", "LAlt", "LGui", "RCtrl", "RShift", "RAlt", "RGui"];
+
+/// Rows of each hand, and keys in each row, that a layer in the keymap file has
+const HAND_ROWS: usize = 4;
+const ROW_KEYS: usize = 6;
+
+/// Piece of the keymap file, with the line it's on
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Equals,
+    Comma,
+    Name(String),
+    Quoted(String),
+}
+
+type FileError = (usize, String);
+
+/// Split the keymap file (a small part of TOML: sections, and fields holding arrays of strings)
+/// into [Token]s, leaving out comments.
+fn tokens(text: &str) -> Result<Vec<(usize, Token)>, FileError> {
+    let mut tokens = Vec::new();
+    for (line_idx, line) in text.lines().enumerate() {
+        let line_num = line_idx + 1;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '#' => break,
+                '[' => Token::Open,
+                ']' => Token::Close,
+                '=' => Token::Equals,
+                ',' => Token::Comma,
+                '"' => {
+                    let mut quoted = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some(c) => quoted.push(c),
+                            None => return Err((line_num, "string not closed by the end of the line".into())),
+                        }
+                    }
+                    Token::Quoted(quoted)
+                },
+                c if c.is_whitespace() => continue,
+                c if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                    let mut name = c.to_string();
+                    while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_' || **c == '.') {
+                        name.push(c);
+                        chars.next();
+                    }
+                    Token::Name(name)
+                },
+                c => return Err((line_num, format!("unexpected `{}`", c))),
+            };
+            tokens.push((line_num, token));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Read the layers given by the keymap file, by section name, as the Rust for each key in the full
+/// keymap's order (left rows flipped round by `rev`, as in `src/keymap.rs`).
+fn keymap_file_layers(text: &str) -> Result<HashMap<String, Vec<String>>, FileError> {
+    let key_codes = enum_variants(include_str!("src/rmk/keycode.rs"), "KeyCode");
+    let consumer_keys = enum_variants(include_str!("src/rmk/keycode.rs"), "ConsumerKey");
+    let mut tokens = tokens(text)?.into_iter().peekable();
+    let mut layers: HashMap<String, Vec<String>> = HashMap::new();
+    let mut section: Option<String> = None;
+    let expect = |tokens: &mut std::iter::Peekable<std::vec::IntoIter<(usize, Token)>>, wanted: Token, line_num: usize| {
+        match tokens.next() {
+            Some((_, token)) if token == wanted => Ok(()),
+            Some((line_num, token)) => Err((line_num, format!("expected {:?}, found {:?}", wanted, token))),
+            None => Err((line_num, format!("expected {:?} before the end of the file", wanted))),
+        }
+    };
+
+    while let Some((line_num, token)) = tokens.next() {
+        match token {
+            Token::Open => {
+                let Some((_, Token::Name(name))) = tokens.next() else {
+                    return Err((line_num, "expected the name of a layer".into()));
+                };
+                expect(&mut tokens, Token::Close, line_num)?;
+                if !FILE_LAYERS.contains(&name.as_str()) {
+                    return Err((line_num, format!("no layer called `{}` (expected one of {})", name, FILE_LAYERS.join(", "))));
+                }
+                if layers.contains_key(&name) {
+                    return Err((line_num, format!("layer `{}` given twice", name)));
+                }
+                layers.insert(name.clone(), Vec::new());
+                section = Some(name);
+            },
+            Token::Name(hand) => {
+                let Some(layer) = section.as_ref().and_then(|name| layers.get_mut(name)) else {
+                    return Err((line_num, "expected a layer's [name] before its keys".into()));
+                };
+                let first_row = match (hand.as_str(), layer.len()) {
+                    ("left", 0) => 0,
+                    ("right", n) if n == HAND_ROWS * ROW_KEYS => HAND_ROWS,
+                    ("left" | "right", _) => return Err((line_num, "expected `left` and then `right`, once each".into())),
+                    _ => return Err((line_num, format!("expected `left` or `right`, found `{}`", hand))),
+                };
+                expect(&mut tokens, Token::Equals, line_num)?;
+                expect(&mut tokens, Token::Open, line_num)?;
+                let mut rows = Vec::new();
+                loop {
+                    match tokens.next() {
+                        Some((_, Token::Close)) => break,
+                        Some((_, Token::Comma)) => continue,
+                        Some((line_num, Token::Quoted(row))) => rows.push((line_num, row)),
+                        Some((line_num, token)) => return Err((line_num, format!("expected a row of keys in quotes, found {:?}", token))),
+                        None => return Err((line_num, "`]` missing from the end of the rows".into())),
+                    }
+                }
+                if rows.len() != HAND_ROWS {
+                    return Err((line_num, format!("expected {} rows for the {} hand, found {}", HAND_ROWS, hand, rows.len())));
+                }
+                for (row_idx, (line_num, row)) in rows.iter().enumerate() {
+                    let mut things = row.split_whitespace()
+                        .map(|key| key_thing(key, &key_codes, &consumer_keys).map_err(|err| (*line_num, err)))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if things.len() != ROW_KEYS {
+                        return Err((*line_num, format!("expected {} keys in row {}, found {}", ROW_KEYS, first_row + row_idx + 1, things.len())));
+                    }
+                    if hand == "left" {
+                        things.reverse();
+                    }
+                    layer.extend(things);
+                }
+            },
+            token => return Err((line_num, format!("unexpected {:?}", token))),
+        }
+    }
+
+    if let Some((name, _)) = layers.iter().find(|(_, things)| things.len() != 2 * HAND_ROWS * ROW_KEYS) {
+        return Err((text.lines().count(), format!("layer `{}` needs both `left` and `right` keys", name)));
+    }
+    Ok(layers)
+}
+
+/// The Rust for the `Thing` a key in the keymap file does:
+/// - `_` for nothing,
+/// - the name of a `KeyCode`, such as `A`, `Kc1` or `LShift`,
+/// - the same with modifiers held too, such as `LShift+Kc1` or `LCtrl+LAlt+T`,
+/// - `Consumer.` and the name of a `ConsumerKey`, such as `Consumer.Mute`,
+/// - or one of [FILE_THINGS].
+fn key_thing(key: &str, key_codes: &HashMap<String, u16>, consumer_keys: &HashMap<String, u16>) -> Result<String, String> {
+    if key == "_" {
+        return Ok("DFA".into());
+    }
+    if FILE_THINGS.contains(&key) {
+        return Ok(format!("Thing::{}", key));
+    }
+    if let Some(name) = key.strip_prefix("Consumer.") {
+        return match consumer_keys.get(name) {
+            Some(_) => Ok(format!("c(ConsumerKey::{})", name)),
+            None => Err(format!("no consumer key called `{}`", name)),
+        };
+    }
+
+    let mut parts: Vec<&str> = key.split('+').collect();
+    let name = parts.pop().expect("split gives at least one part");
+    match key_codes.get(name) {
+        Some(_) if MODIFIERS.contains(&name) => {},
+        Some(&code) if code > 0 && code <= 0xff => {},
+        Some(_) => return Err(format!("`{}` isn't a key which can be typed", name)),
+        None => return Err(format!("no key called `{}`", name)),
+    }
+    let mut thing = format!("k({})", name);
+    for modifier in parts.iter().rev() {
+        if !MODIFIERS.contains(modifier) {
+            return Err(format!("`{}` isn't a modifier (expected one of {})", modifier, MODIFIERS.join(", ")));
+        }
+        thing = format!("with({}, {})", modifier, thing);
+    }
+    Ok(thing)
+}
+
+/// Names and values of the variants of `pub enum $name` in `source`, which must each be written
+/// `Name = value,` on their own line
+fn enum_variants(source: &str, name: &str) -> HashMap<String, u16> {
+    source.lines()
+        .skip_while(|line| line.trim() != format!("pub enum {} {{", name))
+        .skip(1)
+        .take_while(|line| line.trim() != "}")
+        .filter_map(|line| {
+            let (variant, value) = line.trim().trim_end_matches(',').split_once(" = ")?;
+            Some((variant.to_owned(), u16::from_str_radix(value.strip_prefix("0x")?, 16).ok()?))
+        })
+        .collect()
+}
+
+/// Rust for a `FILE_` constant for each of [FILE_LAYERS], `Some` of the full keymap's rows if the
+/// keymap file gives the layer
+fn keymap_file_source(layers: &HashMap<String, Vec<String>>) -> String {
+    let mut source = String::new();
+    for name in FILE_LAYERS {
+        let layer = match layers.get(name) {
+            Some(things) => format!("Some([{}])", things.chunks(ROW_KEYS).map(|row| format!("[{}]", row.join(", "))).collect::<Vec<_>>().join(", ")),
+            None => "None".into(),
+        };
+        source += &format!("const FILE_{}: Option<KeymapLayer> = {};\n", name.to_uppercase(), layer);
+    }
+    source
 }
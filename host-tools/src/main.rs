@@ -0,0 +1,289 @@
+//! Talks to the keyboard from the computer it's plugged into: runs commands on its console (such as
+//! switching modes), shows which keys are pressed and the strokes written, and reads or remaps its
+//! keymap over the raw HID interface used by Vial.
+//!
+//! Shares the board, protocol and steno stroke definitions with the firmware, by including its
+//! source files.
+//! Linux only, as it finds the keyboard's devices through `/dev` and `/sys`.
+
+#[path = "../../src/boards/mod.rs"]
+#[macro_use]
+#[allow(dead_code, unused_macros)]
+mod boards;
+#[path = "../../src/vial/protocol.rs"]
+#[allow(dead_code)]
+mod protocol;
+#[path = "../../src/steno/protocol.rs"]
+#[allow(dead_code)]
+mod steno;
+mod strokes;
+
+use protocol::*;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::process::{Command, ExitCode};
+
+const USAGE: &str = "usage: host-tools console <command>...
+       host-tools keys
+       host-tools strokes <gemini|txbolt|console>
+       host-tools keymap dump
+       host-tools keymap get <layer> <row> <column>
+       host-tools keymap set <layer> <row> <column> <keycode>";
+
+/// Suffix of the steno port's name in `/dev/serial/by-id`, numbering its interface after the HID
+/// interface
+const STENO_INTERFACE: &str = "-if01";
+/// Suffix of the console's name in `/dev/serial/by-id`, numbering its interface after the HID
+/// interface and the steno port's two
+const CONSOLE_INTERFACE: &str = "-if03";
+/// Baud rates to open the steno port at, for the keyboard to send strokes in Gemini PR or TX Bolt
+const GEMINI_BAUD_RATE: u32 = 9600;
+const TX_BOLT_BAUD_RATE: u32 = 19200;
+/// Suffix of the raw HID interface's physical path, as given in `/sys/class/hidraw`
+const RAW_HID_INTERFACE: &str = "/input5";
+
+/// Keycodes read or written by each keymap buffer message, after its 4 header bytes
+const KEYCODES_PER_MESSAGE: usize = (RAW_HID_REPORT_SIZE - 4) / 2;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["console", command @ ..] if !command.is_empty() => run_console_command(&command.join(" ")),
+        ["keys"] => show_keys(),
+        ["strokes", "gemini"] => show_gemini_strokes(),
+        ["strokes", "txbolt"] => show_tx_bolt_strokes(),
+        ["strokes", "console"] => show_mirrored_strokes(),
+        ["keymap", "dump"] => dump_keymap(),
+        ["keymap", "get", layer, row, column] => get_keycode(layer, row, column),
+        ["keymap", "set", layer, row, column, keycode] => set_keycode(layer, row, column, keycode),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        },
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+/// Open the keyboard's serial port with the interface `interface`, at `baud_rate`, set up so that
+/// reads give up after `timeout_tenths` tenths of a second without anything arriving (or never, if
+/// 0).
+fn open_serial(interface: &str, baud_rate: u32, timeout_tenths: u8) -> io::Result<File> {
+    let name = boards::PRODUCT.replace(' ', "_");
+    let path = fs::read_dir("/dev/serial/by-id")?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .find(|path| path.to_string_lossy().contains(&name) && path.to_string_lossy().ends_with(interface))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no serial port {} found for {}", interface, boards::PRODUCT)))?;
+
+    // without this, the port would echo back everything the keyboard writes
+    let (min, time) = if timeout_tenths == 0 { ("1", "0".to_string()) } else { ("0", timeout_tenths.to_string()) };
+    let status = Command::new("stty").arg("-F").arg(&path).arg(baud_rate.to_string())
+        .args(["raw", "-echo", "min", min, "time", &time]).status()?;
+    if !status.success() {
+        return Err(io::Error::other("stty failed"));
+    }
+    File::options().read(true).write(true).open(&path)
+}
+
+/// Open the keyboard's console port, as [open_serial] does.
+fn open_console(timeout_tenths: u8) -> io::Result<File> {
+    open_serial(CONSOLE_INTERFACE, GEMINI_BAUD_RATE, timeout_tenths)
+}
+
+/// Type `command` into the console, and print the response.
+fn run_console_command(command: &str) -> io::Result<()> {
+    let mut console = open_console(5)?;
+    console.write_all(format!("{}\r", command).as_bytes())?;
+    let mut output = String::new();
+    let mut buf = [0; 64];
+    loop {
+        match console.read(&mut buf)? {
+            0 => break,
+            len => output.push_str(&String::from_utf8_lossy(&buf[..len])),
+        }
+    }
+    // skip the echo of the command itself
+    let response = output.split_once("\r\n").map_or("", |(_, response)| response);
+    print!("{}", response.replace("\r\n", "\n"));
+    Ok(())
+}
+
+/// Switch on the key tester, then print what each key does as it's pressed, until interrupted.
+/// The key tester stays on afterwards, until switched off with `console keytest off` (or the
+/// chord).
+fn show_keys() -> io::Result<()> {
+    run_console_command("keytest on")?;
+    let mut console = open_console(0)?;
+    let mut buf = [0; 64];
+    loop {
+        let len = console.read(&mut buf)?;
+        print!("{}", String::from_utf8_lossy(&buf[..len]).replace("\r\n", "\n"));
+        io::stdout().flush()?;
+    }
+}
+
+/// Print `packet` in steno notation, unless it has no keys (as the packet standing for the keys'
+/// release after each Gemini PR stroke).
+fn print_stroke(packet: &steno::GeminiPacket) -> io::Result<()> {
+    if !packet.is_empty() {
+        println!("{}", steno::to_notation(packet).trim_end());
+        io::stdout().flush()?;
+    }
+    Ok(())
+}
+
+/// Open the steno port at the baud rate for Gemini PR, then print each stroke read from it until
+/// interrupted.
+fn show_gemini_strokes() -> io::Result<()> {
+    let mut port = open_serial(STENO_INTERFACE, GEMINI_BAUD_RATE, 0)?;
+    let mut bytes = Vec::with_capacity(steno::PACKET_LEN);
+    let mut byte = [0];
+    loop {
+        port.read_exact(&mut byte)?;
+        // a lead byte starts a packet, dropping anything left over from the last
+        if byte[0] & steno::LEAD_BYTE_FLAG != 0 {
+            bytes.clear();
+        }
+        bytes.push(byte[0]);
+        if let Ok(packet) = <[u8; steno::PACKET_LEN]>::try_from(bytes.as_slice()) {
+            bytes.clear();
+            match strokes::read_gemini(packet) {
+                Some(packet) => print_stroke(&packet)?,
+                None => eprintln!("bad packet: {:02x?}", packet),
+            }
+        }
+    }
+}
+
+/// Open the steno port at the baud rate for TX Bolt, then print each stroke read from it until
+/// interrupted.
+fn show_tx_bolt_strokes() -> io::Result<()> {
+    let mut port = open_serial(STENO_INTERFACE, TX_BOLT_BAUD_RATE, 0)?;
+    let mut sets = Vec::new();
+    let mut byte = [0];
+    loop {
+        port.read_exact(&mut byte)?;
+        if byte[0] != 0 {
+            sets.push(byte[0]);
+            continue;
+        }
+        match strokes::read_tx_bolt(&sets) {
+            Some(packet) => print_stroke(&packet)?,
+            None => eprintln!("bad stroke: {:02x?}", sets),
+        }
+        sets.clear();
+    }
+}
+
+/// Switch on the console's stroke mirror, then print each stroke it writes, with the time since
+/// the keyboard booted, until interrupted. Like the key tester, the mirror stays on afterwards.
+fn show_mirrored_strokes() -> io::Result<()> {
+    run_console_command("strokemirror on")?;
+    let mut console = open_console(0)?;
+    let mut output = String::new();
+    let mut buf = [0; 64];
+    loop {
+        let len = console.read(&mut buf)?;
+        output.push_str(&String::from_utf8_lossy(&buf[..len]));
+        while let Some((line, rest)) = output.split_once("\r\n") {
+            if let Some((millis, packet)) = strokes::read_mirror_line(line) {
+                println!("{}.{:03}s {}", millis / 1000, millis % 1000, steno::to_notation(&packet).trim_end());
+                io::stdout().flush()?;
+            }
+            output = rest.to_string();
+        }
+    }
+}
+
+/// Open the keyboard's raw HID interface.
+fn open_raw_hid() -> io::Result<File> {
+    for entry in fs::read_dir("/sys/class/hidraw")? {
+        let entry = entry?;
+        let uevent = fs::read_to_string(entry.path().join("device/uevent"))?;
+        let is_keyboard = uevent.lines().any(|line| line.strip_prefix("HID_NAME=").is_some_and(|name| name.ends_with(boards::PRODUCT)));
+        let is_raw_hid = uevent.lines().any(|line| line.starts_with("HID_PHYS=") && line.ends_with(RAW_HID_INTERFACE));
+        if is_keyboard && is_raw_hid {
+            return File::options().read(true).write(true).open(std::path::Path::new("/dev").join(entry.file_name()));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, format!("no raw HID interface found for {}", boards::PRODUCT)))
+}
+
+/// Send one message over raw HID, returning the keyboard's response.
+fn exchange(raw_hid: &mut File, msg: [u8; RAW_HID_REPORT_SIZE]) -> io::Result<[u8; RAW_HID_REPORT_SIZE]> {
+    let mut report = [0; RAW_HID_REPORT_SIZE + 1];  // led by report ID 0, as there are none
+    report[1..].copy_from_slice(&msg);
+    raw_hid.write_all(&report)?;
+    let mut response = [0; RAW_HID_REPORT_SIZE];
+    raw_hid.read_exact(&mut response)?;
+    if response[0] == VIA_UNHANDLED {
+        return Err(io::Error::other(format!("keyboard didn't understand message {:#04x}", msg[0])));
+    }
+    Ok(response)
+}
+
+fn parse<T: TryFrom<u64>>(arg: &str, what: &str) -> io::Result<T> {
+    let number = match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    number.ok().and_then(|number| T::try_from(number).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("bad {}: {}", what, arg)))
+}
+
+/// Print every layer's keycodes, as QMK keycodes in hex.
+fn dump_keymap() -> io::Result<()> {
+    let mut raw_hid = open_raw_hid()?;
+    let mut msg = [0; RAW_HID_REPORT_SIZE];
+    msg[0] = VIA_GET_LAYER_COUNT;
+    let layers = exchange(&mut raw_hid, msg)?[1] as usize;
+
+    let total = layers * boards::ROWS * boards::COLUMNS;
+    let mut keycodes = Vec::with_capacity(total);
+    while keycodes.len() < total {
+        let count = KEYCODES_PER_MESSAGE.min(total - keycodes.len());
+        let mut msg = [0; RAW_HID_REPORT_SIZE];
+        msg[0] = VIA_GET_KEYMAP_BUFFER;
+        msg[1..3].copy_from_slice(&(keycodes.len() as u16 * 2).to_be_bytes());
+        msg[3] = count as u8 * 2;
+        let response = exchange(&mut raw_hid, msg)?;
+        keycodes.extend(response[4..4 + count * 2].chunks(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])));
+    }
+
+    for (layer_idx, layer) in keycodes.chunks(boards::ROWS * boards::COLUMNS).enumerate() {
+        println!("layer {}:", layer_idx);
+        for (row_idx, row) in layer.chunks(boards::COLUMNS).enumerate() {
+            let row: Vec<String> = row.iter().map(|keycode| format!("{:#06x}", keycode)).collect();
+            println!("  row {}: {}", row_idx, row.join(" "));
+        }
+    }
+    Ok(())
+}
+
+fn get_keycode(layer: &str, row: &str, column: &str) -> io::Result<()> {
+    let mut msg = [0; RAW_HID_REPORT_SIZE];
+    msg[0] = VIA_GET_KEYCODE;
+    msg[1] = parse(layer, "layer")?;
+    msg[2] = parse(row, "row")?;
+    msg[3] = parse(column, "column")?;
+    let response = exchange(&mut open_raw_hid()?, msg)?;
+    println!("{:#06x}", u16::from_be_bytes([response[4], response[5]]));
+    Ok(())
+}
+
+fn set_keycode(layer: &str, row: &str, column: &str, keycode: &str) -> io::Result<()> {
+    let mut msg = [0; RAW_HID_REPORT_SIZE];
+    msg[0] = VIA_SET_KEYCODE;
+    msg[1] = parse(layer, "layer")?;
+    msg[2] = parse(row, "row")?;
+    msg[3] = parse(column, "column")?;
+    msg[4..6].copy_from_slice(&parse::<u16>(keycode, "keycode")?.to_be_bytes());
+    exchange(&mut open_raw_hid()?, msg)?;
+    Ok(())
+}
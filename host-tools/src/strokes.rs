@@ -0,0 +1,184 @@
+//! Reads strokes back from what the keyboard writes: Gemini PR packets and TX Bolt bytes on its
+//! steno port, and the steno notation its console mirrors strokes in. Where a protocol has one bit
+//! for keys the keyboard has several of, such as S and star, the stroke has the first of them.
+
+use crate::steno::{GeminiPacket, KeyCode, LEAD_BYTE_FLAG, NOTATION_LEFT, NOTATION_MIDDLE, NOTATION_RIGHT, PACKET_LEN};
+
+/// Start of each line the console's stroke mirror (`strokemirror on`) writes, before the time
+const MIRROR_LINE_START: &str = "stroke at ";
+
+/// The stroke in a Gemini PR packet, or `None` if the lead byte flag isn't on its first byte only,
+/// or it has a flag which isn't a key's.
+pub fn read_gemini(bytes: [u8; PACKET_LEN]) -> Option<GeminiPacket> {
+    if bytes[0] & LEAD_BYTE_FLAG == 0 || bytes[1..].iter().any(|byte| byte & LEAD_BYTE_FLAG != 0) {
+        return None;
+    }
+    let mut flags = bytes;
+    flags[0] &= !LEAD_BYTE_FLAG;
+    let mut packet = GeminiPacket::default();
+    for code in KeyCode::ALL {
+        let (byte_position, flag) = code.to_packet_code();
+        if flags[byte_position as usize] & flag != 0 {
+            flags[byte_position as usize] &= !flag;
+            packet.press(code);
+        }
+    }
+    (flags == [0; PACKET_LEN]).then_some(packet)
+}
+
+/// The stroke in the TX Bolt key sets `bytes`, without the null byte ending it, or `None` if the
+/// sets are out of order (as they would be in more than one stroke), or it has a flag which isn't
+/// a key's.
+pub fn read_tx_bolt(bytes: &[u8]) -> Option<GeminiPacket> {
+    let mut packet = GeminiPacket::default();
+    let mut last_set = None;
+    for &byte in bytes {
+        let set = byte >> 6;
+        if last_set >= Some(set) {
+            return None;
+        }
+        last_set = Some(set);
+        for flag in (0..6).map(|bit| 1 << bit).filter(|flag| byte & flag != 0) {
+            packet.press(*KeyCode::ALL.iter().find(|code| code.to_tx_bolt_code() == (set, flag))?);
+        }
+    }
+    Some(packet)
+}
+
+/// The stroke written in steno notation, without a line ending, or `None` if it has anything but
+/// steno letters in steno order.
+pub fn read_notation(notation: &str) -> Option<GeminiPacket> {
+    let order: Vec<(char, KeyCode)> = NOTATION_LEFT.iter().chain(&NOTATION_MIDDLE).chain(&NOTATION_RIGHT)
+        .map(|&(letter, codes)| (letter, codes[0]))
+        .collect();
+    let right_bank = NOTATION_LEFT.len() + NOTATION_MIDDLE.len();
+
+    let mut packet = GeminiPacket::default();
+    let mut next = 0;
+    for letter in notation.chars() {
+        // a hyphen, where there are no vowels, skips to the right hand
+        if letter == '-' && next <= NOTATION_LEFT.len() {
+            next = right_bank;
+            continue;
+        }
+        let idx = next + order[next..].iter().position(|&(key_letter, _)| key_letter == letter)?;
+        packet.press(order[idx].1);
+        next = idx + 1;
+    }
+    Some(packet)
+}
+
+/// The time since boot, in milliseconds, and stroke of a line written by the console's stroke
+/// mirror, or `None` for any other line.
+pub fn read_mirror_line(line: &str) -> Option<(u64, GeminiPacket)> {
+    let (time, notation) = line.strip_prefix(MIRROR_LINE_START)?.split_once("s: ")?;
+    let (secs, millis) = time.split_once('.')?;
+    let millis = secs.parse::<u64>().ok()? * 1000 + millis.parse::<u64>().ok()?;
+    Some((millis, read_notation(notation.trim_end())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::steno::{to_notation, to_tx_bolt};
+
+    /// The stroke of the keys of [KeyCode::ALL] whose bits are set in `chord`
+    fn stroke(chord: u32) -> GeminiPacket {
+        let mut packet = GeminiPacket::default();
+        for (idx, &code) in KeyCode::ALL.iter().enumerate() {
+            if chord & 1 << idx != 0 {
+                packet.press(code);
+            }
+        }
+        packet
+    }
+
+    /// Every chord of up to two keys, then a spread of larger ones through all of them
+    fn chords() -> impl Iterator<Item = u32> {
+        let keys = KeyCode::ALL.len();
+        let pairs = (0..keys).flat_map(move |first| (first..keys).map(move |second| 1 << first | 1 << second));
+        std::iter::once(0).chain(pairs).chain((0..1 << keys).step_by(9973))
+    }
+
+    /// `packet` with each S and star key as the first of them, as read back from a protocol with
+    /// one bit for each
+    fn with_one_s_and_star(packet: GeminiPacket) -> GeminiPacket {
+        let mut one = GeminiPacket::default();
+        for code in KeyCode::ALL.into_iter().filter(|&code| packet.contains(code)) {
+            one.press(match code {
+                KeyCode::S2 => KeyCode::S1,
+                KeyCode::ST2 | KeyCode::ST3 | KeyCode::ST4 => KeyCode::ST1,
+                code => code,
+            });
+        }
+        one
+    }
+
+    #[test]
+    fn gemini_pr_round_trips() {
+        for chord in chords() {
+            let packet = stroke(chord);
+            assert!(read_gemini(packet.to_bytes()) == Some(packet), "chord {:#x}", chord);
+        }
+    }
+
+    #[test]
+    fn gemini_pr_without_the_lead_byte_flag_first_only_is_refused() {
+        let bytes = stroke(0x1234).to_bytes();
+        let mut no_lead = bytes;
+        no_lead[0] &= !LEAD_BYTE_FLAG;
+        assert!(read_gemini(no_lead).is_none());
+        let mut two_leads = bytes;
+        two_leads[3] |= LEAD_BYTE_FLAG;
+        assert!(read_gemini(two_leads).is_none());
+        // the function key, which the keyboard hasn't got
+        assert!(read_gemini([0xc0, 0, 0, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn tx_bolt_round_trips() {
+        for chord in chords() {
+            let packet = stroke(chord);
+            let bytes = to_tx_bolt(&packet);
+            let (&end, sets) = bytes.split_last().expect("stroke is never empty");
+            assert_eq!(end, 0, "chord {:#x} ends with a null byte", chord);
+            assert!(read_tx_bolt(sets) == Some(with_one_s_and_star(packet)), "chord {:#x}", chord);
+        }
+    }
+
+    #[test]
+    fn tx_bolt_sets_out_of_order_are_refused() {
+        assert!(read_tx_bolt(&[0x41, 0x01]).is_none());
+        assert!(read_tx_bolt(&[0x41, 0x42]).is_none());
+        // the one bit of the last set which isn't a key
+        assert!(read_tx_bolt(&[0xe0]).is_none());
+    }
+
+    #[test]
+    fn notation_round_trips() {
+        for chord in chords() {
+            let packet = stroke(chord);
+            let notation = to_notation(&packet);
+            assert!(read_notation(notation.trim_end()) == Some(with_one_s_and_star(packet)), "chord {:#x} as {:?}", chord, notation);
+        }
+    }
+
+    #[test]
+    fn notation_out_of_steno_order_is_refused() {
+        assert!(read_notation("UA").is_none());
+        assert!(read_notation("A-F").is_none());
+        assert!(read_notation("S--F").is_none());
+        assert!(read_notation("STKPWx").is_none());
+    }
+
+    #[test]
+    fn mirror_lines_give_the_time_and_stroke() {
+        let (millis, packet) = read_mirror_line("stroke at 12.034s: -RZ\r\n").expect("a stroke");
+        assert_eq!(millis, 12034);
+        let mut expected = GeminiPacket::default();
+        expected.press(KeyCode::RR);
+        expected.press(KeyCode::ZR);
+        assert!(packet == expected);
+        assert!(read_mirror_line("marker 3 at 12.034s\r\n").is_none());
+    }
+}
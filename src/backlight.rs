@@ -0,0 +1,90 @@
+//! Drives per-key LEDs through a chain of 74HC595 shift registers on SPI, lighting only the keys
+//! which do something on the current layer. Brightness is set by PWM on the registers' shared
+//! output-enable pin.
+//!
+//! Only on boards with the `backlight` feature.
+
+use crate::keymap::{Layer, Thing, COLUMNS, ROWS};
+use crate::{power, RawMutex};
+use core::cell::Cell;
+use embassy_rp::{
+    gpio::Output,
+    pwm::{Pwm, SetDutyCycle},
+    spi::{Blocking, Spi},
+    peripherals::SPI0,
+};
+use embassy_sync::blocking_mutex::Mutex;
+
+/// One bit per key, numbered `row * COLUMNS + column`, with bit 0 of byte 0 driving the first
+/// output of the register nearest the controller.
+type Frame = [u8; (ROWS * COLUMNS).div_ceil(8)];
+
+/// PWM duties for each brightness level, cycled through by [Thing::BacklightBrightness]
+const BRIGHTNESS_LEVELS: [u16; 4] = [0, 2000, 10000, 65535];
+
+/// Index into [BRIGHTNESS_LEVELS] of the brightness to show, changed by [crate::scan].
+static BRIGHTNESS_LEVEL: Mutex<RawMutex, Cell<usize>> = Mutex::new(Cell::new(1));
+
+/// Step on to the next of the [BRIGHTNESS_LEVELS], wrapping around to off.
+pub fn next_brightness() {
+    BRIGHTNESS_LEVEL.lock(|level| level.set((level.get() + 1) % BRIGHTNESS_LEVELS.len()));
+}
+
+pub struct Backlight<'a> {
+    spi: Spi<'a, SPI0, Blocking>,
+    /// Copies the shifted-in bits to the register outputs on its rising edge (RCLK)
+    latch: Output<'a>,
+    /// Output-enable (OE), configured with inverted output as the pin is active-low
+    enable: Pwm<'a>,
+    /// Duty currently shown
+    duty: Option<u16>,
+    /// Layer currently shown, to avoid re-sending an identical frame
+    shown_layer: Option<&'static Layer>,
+}
+
+impl<'a> Backlight<'a> {
+    pub fn new(spi: Spi<'a, SPI0, Blocking>, latch: Output<'a>, enable: Pwm<'a>) -> Self {
+        Backlight { spi, latch, enable, duty: None, shown_layer: None }
+    }
+
+    /// Light the keys which do something on `layer`, at the current brightness.
+    pub fn show_layer(&mut self, layer: &'static Layer) {
+        self.apply_brightness();
+        if self.shown_layer.is_some_and(|shown| core::ptr::eq(shown, layer)) {
+            return;
+        }
+        let mut frame: Frame = Default::default();
+        for (row_idx, row) in layer.iter().enumerate() {
+            for (column_idx, thing) in row.iter().enumerate() {
+                if !matches!(thing, Thing::Inactive) {
+                    let key_idx = row_idx * COLUMNS + column_idx;
+                    frame[key_idx / 8] |= 1 << (key_idx % 8);
+                }
+            }
+        }
+        self.write_frame(&frame);
+        self.shown_layer = Some(layer);
+    }
+
+    fn apply_brightness(&mut self) {
+        // the first level is off, as it should be while the host sleeps
+        let level = if power::is_host_suspended() { 0 } else { BRIGHTNESS_LEVEL.lock(|level| level.get()) };
+        // dimmed while the host hasn't granted all the current the board asked for
+        let duty = (BRIGHTNESS_LEVELS[level] as u32 * power::led_scale() / 256) as u16;
+        let duty = duty.min(self.enable.max_duty_cycle());
+        if self.duty == Some(duty) {
+            return;
+        }
+        self.enable.set_duty_cycle(duty).expect("pwm");
+        self.duty = Some(duty);
+    }
+
+    fn write_frame(&mut self, frame: &Frame) {
+        // The first byte shifted in ends up in the register furthest down the chain.
+        let mut bytes = *frame;
+        bytes.reverse();
+        self.spi.blocking_write(&bytes).expect("spi");
+        self.latch.set_high();
+        self.latch.set_low();
+    }
+}
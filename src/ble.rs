@@ -0,0 +1,397 @@
+//! Bluetooth LE HID on a Pico W, through its CYW43439 radio, so that the keyboard can type into a
+//! second host as well as the one it's plugged into. Reports go to whichever [Output] is chosen
+//! (by [crate::keymap::Thing::OutputToggle], or from the [crate::console]), and the other is left
+//! with everything released.
+//!
+//! The keyboard is a HID-over-GATT peripheral, with the same [hid::REPORT_DESCRIPTOR] as over USB.
+//! The host last paired with is bonded, its key kept in [crate::settings], so that it reconnects
+//! without pairing again. The Pico W's LED (on the radio) is lit while a host is connected.
+
+use crate::settings::{self, Bond};
+use crate::{boards, health, hid, usb, RawMutex};
+use bt_hci::controller::ExternalController;
+use bt_hci::uuid::{appearance, characteristic, descriptors, service};
+use core::cell::Cell;
+use cyw43_pio::PioSpi;
+use embassy_futures::{join::join, select::{select, Either}};
+use embassy_rp::{
+    bind_interrupts,
+    clocks::RoscRng,
+    gpio,
+    peripherals::{DMA_CH0, PIO0},
+    pio,
+};
+use embassy_sync::{blocking_mutex::Mutex, signal::Signal};
+use rand_core::{CryptoRng, RngCore};
+use static_cell::StaticCell;
+use trouble_host::prelude::*;
+use trouble_host::{BondInformation, LongTermKey};
+
+bind_interrupts!(pub struct Irqs {
+    PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
+});
+
+/// The radio's SPI bus, run by a PIO state machine on the Pico W's GP24 (data) and GP29 (clock)
+pub type RadioSpi = PioSpi<'static, PIO0, 0, DMA_CH0>;
+type Controller = ExternalController<cyw43::bluetooth::BtDriver<'static>, 10>;
+
+/// Where keys are sent
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "debug-log", derive(defmt::Format))]
+pub enum Output {
+    Usb,
+    Ble,
+}
+
+/// Which [Output] reports go to, USB until changed
+static OUTPUT: Mutex<RawMutex, Cell<Output>> = Mutex::new(Cell::new(Output::Usb));
+/// Raised for [next_report] of each [Output] (in order) when it's chosen, and when it's left
+static CHOSEN: [Signal<RawMutex, ()>; 2] = [Signal::new(), Signal::new()];
+static LEFT: [Signal<RawMutex, ()>; 2] = [Signal::new(), Signal::new()];
+
+/// Connections to keep at once, and L2CAP channels and MTU for them, as in [trouble_host]'s examples
+const CONNECTIONS: usize = 1;
+const L2CAP_CHANNELS: usize = 2;
+const L2CAP_MTU: usize = 251;
+
+/// Attributes in the table: the GAP service's, then 35 of the HID service's (see
+/// [attribute_server]) and 3 of the device information service's, with a few to spare
+const ATTRIBUTES: usize = GAP_SERVICE_ATTRIBUTE_COUNT + 35 + 3 + 4;
+/// Characteristics a host can subscribe to: one for each kind of input report
+const SUBSCRIBABLE: usize = hid::REPORT_KINDS;
+
+type Server = AttributeServer<'static, RawMutex, ATTRIBUTES, SUBSCRIBABLE, CONNECTIONS>;
+
+/// Value of an input report's characteristic: the report without its ID, which is told by the
+/// characteristic's report reference instead
+type InputReport = heapless::Vec<u8, { hid::MAX_INPUT_REPORT_SIZE }>;
+type HealthReport = [u8; hid::HEALTH_REPORT_SIZE - 1];
+
+/// HID information: HID 1.11, no country, normally connectable
+static HID_INFORMATION: [u8; 4] = [0x11, 0x01, 0x00, 0x02];
+static REPORT_MAP: &[u8] = hid::REPORT_DESCRIPTOR;
+
+/// Report types, as given in each report reference
+const INPUT_REPORT: u8 = 1;
+const OUTPUT_REPORT: u8 = 2;
+const FEATURE_REPORT: u8 = 3;
+/// Report reference of each input report's characteristic, in order of [hid::OutgoingReport::kind_index]
+static INPUT_REFERENCES: [[u8; 2]; hid::REPORT_KINDS] = {
+    let mut references = [[0, INPUT_REPORT]; hid::REPORT_KINDS];
+    let mut idx = 0;
+    while idx < hid::REPORT_KINDS {
+        references[idx][0] = hid::RELEASED_REPORTS[idx].id();
+        idx += 1;
+    }
+    references
+};
+static LEDS_REFERENCE: [u8; 2] = [hid::KEYBOARD_REPORT_ID, OUTPUT_REPORT];
+static HEALTH_REFERENCE: [u8; 2] = [hid::HEALTH_REPORT_ID, FEATURE_REPORT];
+
+/// PnP ID: the USB vendor and product IDs (as opposed to Bluetooth SIG ones), and version 1.0
+static PNP_ID: [u8; 7] = {
+    let [vendor_low, vendor_high] = boards::USB_VENDOR_ID.to_le_bytes();
+    let [product_low, product_high] = boards::USB_PRODUCT_ID.to_le_bytes();
+    [0x02, vendor_low, vendor_high, product_low, product_high, 0x00, 0x01]
+};
+
+/// AD type of the appearance, which [AdStructure] has no variant for
+const AD_APPEARANCE: u8 = 0x19;
+
+/// Where the HID service's characteristics ended up in the attribute table
+struct HidCharacteristics {
+    /// Each kind of input report, in order of [hid::OutgoingReport::kind_index]
+    inputs: [Characteristic<InputReport>; hid::REPORT_KINDS],
+    /// The keyboard LEDs output report
+    leds: Characteristic<[u8; 1]>,
+    /// The [crate::health] feature report, filled in as it's read
+    health: Characteristic<HealthReport>,
+}
+
+/// The RP2040's ring oscillator, the best source of randomness it has, vouched for as good enough
+/// for the keys made on pairing, which is all [trouble_host] needs it for
+struct Rng(RoscRng);
+
+impl RngCore for Rng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
+
+impl CryptoRng for Rng {}
+
+/// Send reports to `output` from now on, with everything released on the other.
+pub fn set_output(output: Output) {
+    let left = OUTPUT.lock(|current| current.replace(output));
+    if left == output {
+        return;
+    }
+    info!("Sending keys over {}", output);
+    LEFT[left as usize].signal(());
+    CHOSEN[output as usize].signal(());
+    if left == Output::Usb {
+        usb::RESEND_RELEASED_REPORTS.signal(());
+    }
+}
+
+/// Switch between sending reports over USB and over Bluetooth, for
+/// [crate::keymap::Thing::OutputToggle].
+pub fn toggle_output() {
+    set_output(match output() {
+        Output::Usb => Output::Ble,
+        Output::Ble => Output::Usb,
+    });
+}
+
+pub fn output() -> Output {
+    OUTPUT.lock(|output| output.get())
+}
+
+/// Wait for the next report to send over `output`, which is only ever taken while it's chosen.
+/// When it's left, returns a released keyboard report instead, for the caller to release whatever
+/// the host still has held.
+pub async fn next_report(output: Output) -> hid::OutgoingReport {
+    let (chosen, left) = (&CHOSEN[output as usize], &LEFT[output as usize]);
+    loop {
+        if left.try_take().is_some() {
+            return hid::RELEASED_REPORTS[0];
+        }
+        if self::output() != output {
+            chosen.wait().await;
+            continue;
+        }
+        match select(usb::any_report(), left.wait()).await {
+            Either::First(report) => return report,
+            Either::Second(()) => return hid::RELEASED_REPORTS[0],
+        }
+    }
+}
+
+/// Address to advertise from: a random static address, made from the flash chip's unique ID so
+/// that it's the same every time and a bonded host recognises it.
+pub fn address(unique_id: [u8; 8]) -> [u8; 6] {
+    let mut address = [0; 6];
+    address.copy_from_slice(&unique_id[..6]);
+    address[5] |= 0xc0;  // the two top bits mark a random static address
+    address
+}
+
+#[embassy_executor::task]
+pub async fn run(power: gpio::Output<'static>, spi: RadioSpi, address: [u8; 6]) {
+    static STATE: StaticCell<cyw43::State> = StaticCell::new();
+    let (_net, bt, mut control, radio) = cyw43::new_with_bluetooth(
+        STATE.init(cyw43::State::new()),
+        power,
+        spi,
+        cyw43_firmware::CYW43_43439A0,
+        cyw43_firmware::CYW43_43439A0_BTFW,
+    ).await;
+
+    let ble_fut = async {
+        control.init(cyw43_firmware::CYW43_43439A0_CLM).await;
+
+        static RESOURCES: StaticCell<HostResources<CONNECTIONS, L2CAP_CHANNELS, L2CAP_MTU>> = StaticCell::new();
+        static STACK: StaticCell<Stack<'static, Controller>> = StaticCell::new();
+        let stack = STACK.init(
+            trouble_host::new(Controller::new(bt), RESOURCES.init(HostResources::new()))
+                .set_random_address(Address::random(address))
+                .set_random_generator_seed(&mut Rng(RoscRng)),
+        );
+        if let Some(bond) = settings::get().bond {
+            let bond = BondInformation::new(BdAddr::new(bond.address), LongTermKey::from_le_bytes(bond.ltk));
+            if let Err(e) = stack.add_bond_information(bond) {
+                warn!("Failed to restore Bluetooth bond: {:?}", e);
+            }
+        }
+        let Host { mut peripheral, mut runner, .. } = stack.build();
+        let (server, hid) = attribute_server();
+
+        let host_fut = async {
+            if let Err(e) = runner.run().await {
+                warn!("Bluetooth host stopped: {:?}", e);
+            }
+        };
+        let peripheral_fut = async {
+            let (mut adv_data, mut scan_data) = ([0; 31], [0; 31]);
+            let adv_len = AdStructure::encode_slice(&[
+                AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+                AdStructure::ServiceUuids16(&[service::HUMAN_INTERFACE_DEVICE.to_le_bytes()]),
+                AdStructure::Unknown { ty: AD_APPEARANCE, data: &appearance::human_interface_device::KEYBOARD.to_le_bytes() },
+            ], &mut adv_data).expect("advertising data fits");
+            let scan_len = AdStructure::encode_slice(&[
+                AdStructure::CompleteLocalName(boards::BLE_NAME.as_bytes()),
+            ], &mut scan_data).expect("name fits");
+
+            loop {
+                let advertisement = Advertisement::ConnectableScannableUndirected { adv_data: &adv_data[..adv_len], scan_data: &scan_data[..scan_len] };
+                let advertiser = match peripheral.advertise(&Default::default(), advertisement).await {
+                    Ok(advertiser) => advertiser,
+                    Err(e) => {
+                        warn!("Failed to advertise: {:?}", e);
+                        continue;
+                    },
+                };
+                // reports for Bluetooth while there's no host to send them to are dropped, rather
+                // than holding up whoever's sending them
+                let connection = match select(advertiser.accept(), discard_reports()).await {
+                    Either::First(Ok(connection)) => connection,
+                    Either::First(Err(e)) => {
+                        warn!("Failed to accept Bluetooth connection: {:?}", e);
+                        continue;
+                    },
+                };
+                let connection = match connection.with_attribute_server(server) {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        warn!("Failed to serve Bluetooth connection: {:?}", e);
+                        continue;
+                    },
+                };
+                info!("Bluetooth host connected");
+                control.gpio_set(0, true).await;
+                select(serve(server, &hid, &connection), send_reports(&hid, &connection)).await;
+                control.gpio_set(0, false).await;
+            }
+        };
+        join(host_fut, peripheral_fut).await;
+    };
+    select(radio.run(), ble_fut).await;
+}
+
+/// Build the GAP, HID and device information services.
+fn attribute_server() -> (&'static Server, HidCharacteristics) {
+    static CONTROL_POINT: StaticCell<[u8; 1]> = StaticCell::new();
+    static INPUTS: StaticCell<[[u8; hid::MAX_INPUT_REPORT_SIZE]; hid::REPORT_KINDS]> = StaticCell::new();
+    static LEDS: StaticCell<[u8; 1]> = StaticCell::new();
+    static HEALTH: StaticCell<HealthReport> = StaticCell::new();
+    static SERVER: StaticCell<Server> = StaticCell::new();
+
+    let mut table = AttributeTable::new();
+    GapConfig::Peripheral(PeripheralConfig { name: boards::BLE_NAME, appearance: &appearance::human_interface_device::KEYBOARD })
+        .build(&mut table).expect("BLE_NAME fits");
+
+    let hid = {
+        let mut service = table.add_service(Service::new(service::HUMAN_INTERFACE_DEVICE));
+        service.add_characteristic_ro(characteristic::HID_INFORMATION, &HID_INFORMATION);
+        service.add_characteristic_ro(characteristic::REPORT_MAP, &REPORT_MAP);
+        service.add_characteristic(characteristic::HID_CONTROL_POINT, &[CharacteristicProp::WriteWithoutResponse], [0u8; 1], CONTROL_POINT.init([0; 1]));
+
+        let mut stores = INPUTS.init([[0; hid::MAX_INPUT_REPORT_SIZE]; hid::REPORT_KINDS]).iter_mut();
+        let inputs = core::array::from_fn(|idx| {
+            let mut buf = [0; hid::MAX_INPUT_REPORT_SIZE];
+            let released = InputReport::from_slice(&hid::RELEASED_REPORTS[idx].serialize(&mut buf)[1..]).expect("report fits");
+            let store = stores.next().expect("a store for each kind of report");
+            let mut input = service.add_characteristic(characteristic::REPORT, &[CharacteristicProp::Read, CharacteristicProp::Notify], released, store);
+            input.add_descriptor_ro::<[u8; 2], _>(descriptors::REPORT_REFERENCE, &INPUT_REFERENCES[idx]);
+            input.build()
+        });
+        let leds = {
+            let props = [CharacteristicProp::Read, CharacteristicProp::Write, CharacteristicProp::WriteWithoutResponse];
+            let mut leds = service.add_characteristic(characteristic::REPORT, &props, [0u8; 1], LEDS.init([0; 1]));
+            leds.add_descriptor_ro::<[u8; 2], _>(descriptors::REPORT_REFERENCE, &LEDS_REFERENCE);
+            leds.build()
+        };
+        let health = {
+            let mut health = service.add_characteristic(characteristic::REPORT, &[CharacteristicProp::Read], [0; hid::HEALTH_REPORT_SIZE - 1], HEALTH.init([0; hid::HEALTH_REPORT_SIZE - 1]));
+            health.add_descriptor_ro::<[u8; 2], _>(descriptors::REPORT_REFERENCE, &HEALTH_REFERENCE);
+            health.build()
+        };
+        HidCharacteristics { inputs, leds, health }
+    };
+
+    table.add_service(Service::new(service::DEVICE_INFORMATION))
+        .add_characteristic_ro(characteristic::PNP_ID, &PNP_ID);
+
+    (SERVER.init(AttributeServer::new(table)), hid)
+}
+
+/// Answer the host's requests until it disconnects, keeping hold of the bond made if it pairs.
+async fn serve(server: &Server, hid: &HidCharacteristics, connection: &GattConnection<'_, '_>) {
+    loop {
+        let event = match connection.next().await {
+            GattConnectionEvent::Disconnected { reason } => {
+                info!("Bluetooth host disconnected: {:?}", reason);
+                return;
+            },
+            GattConnectionEvent::Bonded { bond_info } => {
+                info!("Bonded with Bluetooth host");
+                let bond = Bond { address: bond_info.address.into_inner(), ltk: bond_info.ltk.to_le_bytes() };
+                settings::update(|settings| settings.bond = Some(bond));
+                continue;
+            },
+            GattConnectionEvent::Gatt { event: Ok(event) } => event,
+            GattConnectionEvent::Gatt { event: Err(e) } => {
+                warn!("Bluetooth request failed: {:?}", e);
+                continue;
+            },
+            _ => continue,
+        };
+        match &event {
+            GattEvent::Read(read) if read.handle() == hid.health.handle => {
+                let mut report = [0; hid::HEALTH_REPORT_SIZE];
+                hid::health_report(hid::HEALTH_REPORT_ID, &health::counters(), &mut report);
+                let counters: HealthReport = report[1..].try_into().expect("health report after its ID");
+                hid.health.set(server, &counters).ok();
+            },
+            GattEvent::Write(write) if write.handle() == hid.leds.handle => {
+                if let [leds] = *write.data() {
+                    usb::take_output_report(&[hid::KEYBOARD_REPORT_ID, leds]);
+                }
+            },
+            _ => {},
+        }
+        match event.accept() {
+            Ok(reply) => reply.send().await,
+            Err(e) => warn!("Failed to answer Bluetooth request: {:?}", e),
+        }
+    }
+}
+
+/// Notify the host of each report for Bluetooth, or of everything released once it's no longer
+/// chosen. Keyboard reports have their modifiers sent ahead as over USB (see
+/// [usb::MODIFIERS_AHEAD]).
+async fn send_reports(hid: &HidCharacteristics, connection: &GattConnection<'_, '_>) {
+    let mut last_keyboard = usbd_hid::descriptor::KeyboardReport::default();
+    loop {
+        let report = next_report(Output::Ble).await;
+        let reports = if output() == Output::Ble { core::slice::from_ref(&report) } else { &hid::RELEASED_REPORTS[..] };
+        for &report in reports {
+            if let hid::OutgoingReport::Keyboard(next) = report {
+                if usb::MODIFIERS_AHEAD.lock(|enabled| enabled.get()) {
+                    for between in hid::modifiers_ahead(&last_keyboard, &next) {
+                        notify(hid, connection, hid::OutgoingReport::Keyboard(between)).await;
+                    }
+                }
+                last_keyboard = next;
+            }
+            notify(hid, connection, report).await;
+        }
+    }
+}
+
+async fn notify(hid: &HidCharacteristics, connection: &GattConnection<'_, '_>, report: hid::OutgoingReport) {
+    let mut buf = [0; hid::MAX_INPUT_REPORT_SIZE];
+    let value = InputReport::from_slice(&report.serialize(&mut buf)[1..]).expect("report fits");
+    if let Err(e) = hid.inputs[report.kind_index()].notify(connection, &value).await {
+        warn!("Failed to send report over Bluetooth: {:?}", e);
+    }
+}
+
+/// Take reports for Bluetooth and drop them, while no host is connected.
+async fn discard_reports() -> ! {
+    loop {
+        next_report(Output::Ble).await;
+    }
+}
@@ -0,0 +1,70 @@
+//! Profiles for each PCB the firmware can be built for, chosen by cargo feature (the full keyboard
+//! if none is given): how it names itself over USB, how big its matrix is, which pins it is wired
+//! to, and which keys of the full keymap in [crate::keymap] it has.
+//!
+//! Revisions of a PCB which only differ in how the matrix is wired are told apart at boot instead,
+//! by strap pins (see [revision]).
+
+#[cfg(not(feature = "macropad"))]
+#[macro_use]
+mod orthocurvular;
+#[cfg(not(feature = "macropad"))]
+pub use orthocurvular::*;
+
+#[cfg(feature = "macropad")]
+#[macro_use]
+mod macropad;
+#[cfg(feature = "macropad")]
+pub use macropad::*;
+
+/// Which way a matrix's diodes point, and so which of its lines are strobed one at a time and which
+/// are read
+#[derive(Clone, Copy, PartialEq)]
+pub enum DiodeDirection {
+    /// Rows are strobed and columns read ("COL2ROW")
+    ColumnToRow,
+    /// Columns are strobed and rows read ("ROW2COL")
+    RowToColumn,
+}
+
+/// Level a strobed line is driven to, and so which a closed switch reads as. Lines not strobed are
+/// left floating, and lines read are pulled the other way.
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]  // only one is chosen for each board
+pub enum ActiveLevel {
+    Low,
+    High,
+}
+
+const _: () = assert!(USB_MAX_POWER_MA >= 100 && USB_MAX_POWER_MA <= 500, "USB 2.0 allows from 100mA to 500mA");
+
+/// How many lines of the matrix are strobed, and how many are read
+pub const STROBES: usize = match DIODE_DIRECTION {
+    DiodeDirection::ColumnToRow => ROWS,
+    DiodeDirection::RowToColumn => COLUMNS,
+};
+pub const SENSES: usize = ROWS + COLUMNS - STROBES;
+
+/// Row and column of the switch between strobed line `strobe` and read line `sense`
+pub const fn matrix_position(strobe: usize, sense: usize) -> (usize, usize) {
+    match DIODE_DIRECTION {
+        DiodeDirection::ColumnToRow => (strobe, sense),
+        DiodeDirection::RowToColumn => (sense, strobe),
+    }
+}
+
+/// How one revision of the PCB has its matrix wired, compared with the pins [matrix_pins] gives
+pub struct Revision {
+    pub name: &'static str,
+    /// Which of the row pins each row is wired to
+    pub row_order: [usize; ROWS],
+    /// Whether the columns are wired the other way round
+    pub columns_reversed: bool,
+}
+
+/// The revision of the PCB whose strap pins read `grounded` (each `true` if tied to ground), taken
+/// as a binary number, first pin lowest. Unstrapped boards are the first revision.
+pub fn revision(grounded: [bool; STRAPS]) -> &'static Revision {
+    let idx = grounded.iter().rev().fold(0, |idx, &bit| idx << 1 | bit as usize);
+    REVISIONS.get(idx).unwrap_or(&REVISIONS[0])
+}
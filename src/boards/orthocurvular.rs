@@ -0,0 +1,61 @@
+//! The full split keyboard, with four rows per hand.
+
+/// USB identity, told apart from the other boards' so that several can be plugged in at once
+pub const USB_VENDOR_ID: u16 = 0xfeed;
+pub const USB_PRODUCT_ID: u16 = 0x3061;
+pub const MANUFACTURER: &str = "Tom's";
+pub const PRODUCT: &str = "Mini Orthocurvular Keyboard";
+/// Name to go by over Bluetooth (see [crate::ble]), which has room for no more than 22 bytes
+#[allow(dead_code)]  // only used with the `ble` feature
+pub const BLE_NAME: &str = "Orthocurvular Keyboard";
+
+/// Current (in mA) to ask the host for, up to 500. Boards whose LEDs draw more than 100mA should
+/// ask for what they need, and are dimmed whenever they aren't granted it (see [crate::power]).
+pub const USB_MAX_POWER_MA: u16 = 100;
+
+/// How many physical rows there are
+pub const ROWS: usize = 8;
+/// How many physical columns there are
+pub const COLUMNS: usize = 6;
+
+/// How the matrix is driven
+pub const DIODE_DIRECTION: super::DiodeDirection = super::DiodeDirection::ColumnToRow;
+pub const ACTIVE_LEVEL: super::ActiveLevel = super::ActiveLevel::Low;
+
+/// Row of the full keymap whose keys each physical row has
+pub const KEYMAP_ROWS: [usize; ROWS] = [0, 1, 2, 3, 4, 5, 6, 7];
+/// Column of the full keymap whose keys each physical column has
+pub const KEYMAP_COLUMNS: [usize; COLUMNS] = [0, 1, 2, 3, 4, 5];
+
+/// Identifies the board to Vial, which keeps its saved layouts by this
+pub const VIAL_KEYBOARD_ID: [u8; 8] = [0x4f, 0x43, 0x4b, 0x42, 0x8e, 0x21, 0x5d, 0x07];
+/// Describes the board's matrix and layout to Vial: `vial/orthocurvular.json` compressed by `xz`
+pub const VIAL_DEFINITION: &[u8] = include_bytes!("../../vial/orthocurvular.json.xz");
+
+/// How many strap pins tell revisions of the PCB apart
+pub const STRAPS: usize = 2;
+
+/// Each revision of the PCB, numbered by its strap pins (see [super::revision])
+pub const REVISIONS: [super::Revision; 2] = [
+    super::Revision { name: "rev 1", row_order: [0, 1, 2, 3, 4, 5, 6, 7], columns_reversed: false },
+    // the right hand's rows are wired bottom to top
+    super::Revision { name: "rev 2", row_order: [0, 1, 2, 3, 7, 6, 5, 4], columns_reversed: false },
+];
+
+/// Borrow the strap pins out of the peripherals `$p`, to read the revision: GP27 and GP28, each
+/// tied to ground or left floating. As a strap may ground them, nothing may drive them, so this
+/// board can't have the `rgb-led` or `buzzer` features, which do.
+macro_rules! strap_pins {
+    ($p:ident) => {[
+        Input::new(&mut $p.PIN_27, Pull::Up),
+        Input::new(&mut $p.PIN_28, Pull::Up),
+    ]}
+}
+
+/// Set up the row and column pins of the matrix, out of the peripherals `$p`
+macro_rules! matrix_pins {
+    ($p:ident) => {(
+        row_pins!($p; PIN_10, PIN_11, PIN_12, PIN_13, PIN_21, PIN_20, PIN_19, PIN_18),
+        column_pins!($p; PIN_17, PIN_8, PIN_16, PIN_15, PIN_14, PIN_9),
+    )}
+}
@@ -0,0 +1,83 @@
+//! Sounds short tunes on a piezo buzzer when a mode changes: steno on or off, a layout emulated or
+//! not, and Caps Lock on or off. Played by its own task, so that [crate::scan] is never held up by
+//! one, and quietened or muted by [crate::keymap::Thing::BuzzerVolume].
+//!
+//! Only on boards with the `buzzer` feature.
+
+use crate::RawMutex;
+use core::cell::Cell;
+use embassy_rp::pwm::{self, Pwm};
+use embassy_sync::{blocking_mutex::Mutex, channel::Channel};
+use embassy_time::{Duration, Timer};
+
+/// Frequency (Hz) and length of a note
+type Note = (u32, Duration);
+
+/// A few notes, played one after another
+pub type Tune = &'static [Note];
+
+/// Rising, for a mode being turned on
+pub const MODE_ON: Tune = &[(1500, Duration::from_millis(60)), (2000, Duration::from_millis(60))];
+/// Falling, for a mode being turned off
+pub const MODE_OFF: Tune = &[(2000, Duration::from_millis(60)), (1500, Duration::from_millis(60))];
+/// A single high blip
+pub const CAPS_LOCK_ON: Tune = &[(2500, Duration::from_millis(40))];
+/// A single low blip
+pub const CAPS_LOCK_OFF: Tune = &[(1200, Duration::from_millis(40))];
+
+/// Clock divider for the PWM slice, slowing its counter to 125MHz / 64 so that the whole range of
+/// audible notes fits in its 16 bits
+const DIVIDER: u8 = 64;
+const COUNTER_HZ: u32 = 125_000_000 / DIVIDER as u32;
+
+/// Volumes to cycle through by [crate::keymap::Thing::BuzzerVolume], as duty (out of 256) of each
+/// cycle of a note: half is loudest, and the last is muted.
+const VOLUME_LEVELS: [u32; 4] = [128, 24, 4, 0];
+
+/// Index into [VOLUME_LEVELS] of the volume to play at
+static VOLUME_LEVEL: Mutex<RawMutex, Cell<usize>> = Mutex::new(Cell::new(1));
+
+/// Whether the host last had Caps Lock on, to only sound changes to it
+static CAPS_LOCK: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+static TUNES: Channel<RawMutex, Tune, 4> = Channel::new();
+
+/// Step on to the next of the [VOLUME_LEVELS], wrapping around to the loudest, and play a note at
+/// it to show how loud it is.
+pub fn next_volume() {
+    VOLUME_LEVEL.lock(|level| level.set((level.get() + 1) % VOLUME_LEVELS.len()));
+    play(CAPS_LOCK_ON);
+}
+
+/// Have the buzzer task play `tune`, unless too many are already waiting.
+pub fn play(tune: Tune) {
+    if TUNES.try_send(tune).is_err() {
+        warn!("Tune dropped");
+    }
+}
+
+/// Note whether the host has Caps Lock on, sounding it if that's a change.
+pub fn caps_lock(on: bool) {
+    if CAPS_LOCK.lock(|caps_lock| caps_lock.replace(on)) != on {
+        play(if on { CAPS_LOCK_ON } else { CAPS_LOCK_OFF });
+    }
+}
+
+/// Play each tune as it comes, on the buzzer driven by `pwm`.
+#[embassy_executor::task]
+pub async fn run(mut pwm: Pwm<'static>) {
+    let mut config = pwm::Config::default();
+    config.divider = DIVIDER.into();
+    loop {
+        let tune = TUNES.receive().await;
+        let volume = VOLUME_LEVELS[VOLUME_LEVEL.lock(|level| level.get())];
+        for &(hz, length) in tune {
+            config.top = (COUNTER_HZ / hz - 1) as u16;
+            config.compare_a = (config.top as u32 * volume / 256) as u16;
+            pwm.set_config(&config);
+            Timer::after(length).await;
+        }
+        config.compare_a = 0;
+        pwm.set_config(&config);
+    }
+}
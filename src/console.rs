@@ -0,0 +1,581 @@
+//! A second serial port, apart from the steno one, for typing commands into from a terminal and
+//! reading diagnostics from.
+
+use crate::scan::{HeldKeysFull, PedalMode};
+use crate::steno::{self, NumberKey};
+use crate::keymap::{Hand, HidKey, Layer, Thing, COLUMNS, ROWS, ROW_HANDS};
+use crate::led::{self, Indication, Indicator, IndicatorStyle};
+#[cfg(feature = "ble")]
+use crate::ble;
+use crate::{boards, drill, health, keymap, scan, settings, stats, usb, vial, voltage, RawMutex};
+use core::cell::Cell;
+use core::fmt::Write;
+use embassy_sync::{blocking_mutex::Mutex, channel::Channel};
+use embassy_time::{Duration, Instant};
+use heapless::String;
+
+/// One line of output, including its line ending
+pub type ConsoleLine = String<128>;
+/// Response to a command, which may run to a few lines
+pub type Response = String<640>;
+/// A command as typed, without its line ending
+pub type CommandLine = String<64>;
+
+/// Lines waiting to be written to the console by [crate::usb]
+pub static OUTPUT: Channel<RawMutex, ConsoleLine, 8> = Channel::new();
+
+/// Write `line` to the console, or drop it if too much is already waiting (as when nothing has the
+/// port open).
+pub fn print(line: ConsoleLine) {
+    if OUTPUT.try_send(line).is_err() {
+        health::count(&health::CONSOLE_LINES_DROPPED);
+    }
+}
+
+/// How many markers [mark] has written since boot
+static MARKERS: Mutex<RawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Write a numbered marker line, with the time since boot as of `now`, for
+/// [crate::keymap::Thing::DebugMarker].
+pub fn mark(now: Instant) {
+    let number = MARKERS.lock(|markers| {
+        markers.set(markers.get() + 1);
+        markers.get()
+    });
+    let millis = now.as_millis();
+    let mut line = ConsoleLine::new();
+    write!(line, "marker {} at {}.{:03}s\r\n", number, millis / 1000, millis % 1000).ok();
+    info!("Marker {}", number);
+    print(line);
+}
+
+/// Whether to write every steno stroke to the console too, for something on the host to log while
+/// Plover has the steno port open. Switched from the console.
+pub static STROKE_MIRROR: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Write `packet` to the console in steno notation, with the time since boot as of `now`, if
+/// [STROKE_MIRROR] is on.
+pub fn mirror_stroke(packet: &steno::GeminiPacket, now: Instant) {
+    if !STROKE_MIRROR.lock(|enabled| enabled.get()) {
+        return;
+    }
+    let millis = now.as_millis();
+    let mut line = ConsoleLine::new();
+    // the notation brings its own line ending
+    write!(line, "stroke at {}.{:03}s: {}", millis / 1000, millis % 1000, steno::to_notation(packet)).ok();
+    print(line);
+}
+
+/// Short name of a [Thing], fitting in a column of [print_layer]'s grid
+pub type Label = String<{ LABEL_WIDTH }>;
+const LABEL_WIDTH: usize = 6;
+const _: () = assert!(2 * COLUMNS * (LABEL_WIDTH + 1) + 4 <= 128, "a row of each hand must fit on a console line");
+
+/// Names of the keyboard usages from 0x04 (A) to 0x52 (Up), in order
+const KEY_NAMES: [&str; 0x52 - 0x04 + 1] = [
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T",
+    "U", "V", "W", "X", "Y", "Z", "1", "2", "3", "4", "5", "6", "7", "8", "9", "0",
+    "Enter", "Esc", "Bspc", "Tab", "Space", "-", "=", "[", "]", "\\", "#", ";", "'", "`", ",", ".", "/", "Caps",
+    "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+    "PrtSc", "ScrLk", "Pause", "Ins", "Home", "PgUp", "Del", "End", "PgDn", "Right", "Left", "Down", "Up",
+];
+
+/// What the keys from 0x1e (1) to 0x38 (/) type with Shift held, on a US layout, or "" for those
+/// which aren't symbols
+const SHIFTED_NAMES: [&str; 0x38 - 0x1e + 1] = [
+    "!", "@", "#", "$", "%", "^", "&", "*", "(", ")",
+    "", "", "", "", "", "_", "+", "{", "}", "|", "", ":", "\"", "~", "<", ">", "?",
+];
+
+/// Short name of a key with modifiers, e.g. `C-A-T` for Ctrl+Alt+T, or `*` for Shift+8.
+pub fn key_label(label: &mut Label, (keycode, mods): HidKey) {
+    const LEFT_SHIFT: u8 = 0x02;
+    let shifted = (0x1e..=0x38).contains(&keycode).then(|| SHIFTED_NAMES[keycode as usize - 0x1e]).filter(|name| !name.is_empty());
+    if let (Some(name), LEFT_SHIFT) = (shifted, mods) {
+        label.push_str(name).ok();
+        return;
+    }
+    // each modifier, whichever side, by its bit on the left
+    let modifier_names = [(0x11, "Ctrl", "C-"), (0x22, "Shift", "S-"), (0x44, "Alt", "A-"), (0x88, "Gui", "G-")];
+    if keycode == 0 {
+        if let Some(&(_, name, _)) = modifier_names.iter().find(|&&(bits, _, _)| mods & bits != 0) {
+            label.push_str(name).ok();
+        }
+        return;
+    }
+    for (bits, _, prefix) in modifier_names {
+        if mods & bits != 0 {
+            label.push_str(prefix).ok();
+        }
+    }
+    match keycode {
+        0x04..=0x52 => { label.push_str(KEY_NAMES[keycode as usize - 0x04]).ok(); },
+        0x68..=0x73 => { write!(label, "F{}", keycode - 0x68 + 13).ok(); },
+        _ => { write!(label, "{:02x}", keycode).ok(); },
+    }
+}
+
+/// Short name of what `thing` does, for [print_layer]
+fn label(thing: Thing) -> Label {
+    let mut label = Label::new();
+    let name = match thing {
+        Thing::RealKey(key) => {
+            key_label(&mut label, key);
+            return label;
+        },
+        Thing::StenoKey(code) => {
+            write!(label, "{:?}", code).ok();
+            return label;
+        },
+        Thing::ConsumerKey(0xe2) => "Mute",
+        Thing::ConsumerKey(0xe9) => "Vol+",
+        Thing::ConsumerKey(0xea) => "Vol-",
+        Thing::ConsumerKey(_) => "Media",
+        Thing::LeftSymbolKey | Thing::RightSymbolKey => "Sym",
+        Thing::NavKey => "Nav",
+        Thing::FunctionKey => "Fn",
+        Thing::ModifiedLayer(..) => "Layer",
+        Thing::LayerTap(_, key) => {
+            key_label(&mut label, key);
+            return label;
+        },
+        Thing::LayoutCycle | Thing::MomentaryLayout(_) => "Layout",
+        Thing::StenoToggle => "Steno",
+        Thing::PaperTapeToggle => "Tape",
+        Thing::StenoProtocolCycle => "StProt",
+        Thing::MidiToggle => "MIDI",
+        Thing::Bootloader => "Boot",
+        Thing::BacklightBrightness => "BkLt",
+        Thing::LedBrightness => "LED",
+        Thing::BuzzerVolume => "Buzz",
+        Thing::JigglerToggle => "Jiggle",
+        Thing::OutputToggle => "BT",
+        Thing::LatencyTestToggle => "Latncy",
+        Thing::DebugMarker => "Marker",
+        Thing::OsCycle => "OS",
+        Thing::KeyboardLock => "Lock",
+        Thing::MicMute => "Mic",
+        Thing::Sequence(_) => "Macro",
+        Thing::Turbo(key, _) => {
+            key_label(&mut label, key);
+            return label;
+        },
+        Thing::TapHold(tap_hold) => return self::label(tap_hold.tap),
+        Thing::Inactive => ".",
+    };
+    label.push_str(name).ok();
+    label
+}
+
+/// Write out what each key does on `layer` (as remapped), as a grid laid out like the keyboard:
+/// each row of the left hand beside the same row of the right.
+pub fn print_layer(layer: &Layer) {
+    let mut header = ConsoleLine::new();
+    write!(header, "layer {}:\r\n", keymap::layer_index(layer)).ok();
+    print(header);
+
+    let rows_of = |hand| (0..ROWS).filter(|&row| ROW_HANDS[row] == hand).collect::<heapless::Vec<usize, ROWS>>();
+    let (left_rows, right_rows) = (rows_of(Hand::Left), rows_of(Hand::Right));
+    for idx in 0..left_rows.len().max(right_rows.len()) {
+        let mut line = ConsoleLine::new();
+        // the left hand's columns are numbered from the middle outwards (see [keymap]), so backwards
+        for column in (0..COLUMNS).rev() {
+            let thing = left_rows.get(idx).map_or(Thing::Inactive, |&row| vial::thing_at(layer, row, column));
+            write!(line, "{:>width$} ", label(thing), width = LABEL_WIDTH).ok();
+        }
+        if let Some(&row) = right_rows.get(idx) {
+            line.push_str("   ").ok();
+            for column in 0..COLUMNS {
+                write!(line, " {:<width$}", label(vial::thing_at(layer, row, column)), width = LABEL_WIDTH).ok();
+            }
+        }
+        line.push_str("\r\n").ok();
+        print(line);
+    }
+}
+
+/// Collects typed characters into a [CommandLine].
+#[derive(Default)]
+pub struct LineEditor {
+    line: CommandLine,
+}
+
+impl LineEditor {
+    /// Add a typed character, returning the whole line once it has been ended.
+    pub fn push(&mut self, byte: u8) -> Option<CommandLine> {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = core::mem::take(&mut self.line);
+                (!line.is_empty()).then_some(line)
+            },
+            0x08 | 0x7f => {
+                self.line.pop();
+                None
+            },
+            _ => {
+                if byte.is_ascii() && self.line.push(byte as char).is_err() {
+                    warn!("Console line too long");
+                }
+                None
+            },
+        }
+    }
+}
+
+type Switch = Mutex<RawMutex, Cell<bool>>;
+
+/// Turn a boolean setting on or off as told by `arg`, or just say what it is.
+fn switch(line: &mut Response, name: &str, setting: &Switch, arg: Option<&str>) {
+    match arg {
+        Some("on") => setting.lock(|enabled| enabled.set(true)),
+        Some("off") => setting.lock(|enabled| enabled.set(false)),
+        None => {},
+        Some(_) => {
+            line.push_str("expected on or off\r\n").ok();
+            return;
+        },
+    }
+    let enabled = setting.lock(|enabled| enabled.get());
+    write!(line, "{}: {}\r\n", name, if enabled { "on" } else { "off" }).ok();
+}
+
+/// Set [scan::MODE_IDLE_TIMEOUT] to `arg` minutes (or "off"), or just say what it is.
+fn idle_timeout(line: &mut Response, arg: Option<&str>) {
+    match arg.map(|arg| (arg, arg.parse::<u32>())) {
+        None => {},
+        Some(("off", _)) => scan::MODE_IDLE_TIMEOUT.lock(|timeout| timeout.set(None)),
+        Some((_, Ok(minutes))) if minutes > 0 => {
+            let timeout = Duration::from_secs(minutes as u64 * 60);
+            scan::MODE_IDLE_TIMEOUT.lock(|setting| setting.set(Some(timeout)));
+        },
+        Some(_) => {
+            line.push_str("expected minutes or off\r\n").ok();
+            return;
+        },
+    }
+    match scan::MODE_IDLE_TIMEOUT.lock(|timeout| timeout.get()) {
+        Some(timeout) => write!(line, "idle timeout: {} minutes\r\n", timeout.as_secs() / 60).ok(),
+        None => write!(line, "idle timeout: off\r\n").ok(),
+    };
+}
+
+/// Set [steno::STROKE_SPACING] to `arg` milliseconds (or "off"), or just say what it is.
+fn stroke_spacing(line: &mut Response, arg: Option<&str>) {
+    match arg.map(|arg| (arg, arg.parse::<u64>())) {
+        None => {},
+        Some(("off", _)) => steno::STROKE_SPACING.lock(|spacing| spacing.set(Duration::from_ticks(0))),
+        Some((_, Ok(millis))) => steno::STROKE_SPACING.lock(|spacing| spacing.set(Duration::from_millis(millis))),
+        Some(_) => {
+            line.push_str("expected milliseconds or off\r\n").ok();
+            return;
+        },
+    }
+    let spacing = steno::STROKE_SPACING.lock(|spacing| spacing.get());
+    write!(line, "stroke spacing: {}ms\r\n", spacing.as_millis()).ok();
+}
+
+/// Show the [stats] of steno strokes, or reset them, or set how often they pulse the LED.
+fn steno_stats(line: &mut Response, arg: Option<&str>, pulse_arg: Option<&str>) {
+    match (arg, pulse_arg.map(|arg| (arg, arg.parse::<u32>()))) {
+        (None, _) => {},
+        (Some("reset"), _) => stats::reset(),
+        (Some("pulse"), Some(("off", _))) => stats::PULSE_EVERY.lock(|every| every.set(None)),
+        (Some("pulse"), Some((_, Ok(strokes)))) if strokes > 0 => stats::PULSE_EVERY.lock(|every| every.set(Some(strokes))),
+        (Some("pulse"), _) => {
+            line.push_str("expected strokes or off\r\n").ok();
+            return;
+        },
+        (Some(_), _) => {
+            line.push_str("expected reset or pulse\r\n").ok();
+            return;
+        },
+    }
+    line.push_str(&stats::describe(Instant::now())).ok();
+}
+
+/// Set [steno::NUMBER_KEY] as told by `arg`, or just say what it is.
+fn number_key(line: &mut Response, arg: Option<&str>) {
+    match arg {
+        Some("momentary") => steno::NUMBER_KEY.lock(|number_key| number_key.set(NumberKey::Momentary)),
+        Some("latched") => steno::NUMBER_KEY.lock(|number_key| number_key.set(NumberKey::Latched)),
+        None => {},
+        Some(_) => {
+            line.push_str("expected momentary or latched\r\n").ok();
+            return;
+        },
+    }
+    let number_key = steno::NUMBER_KEY.lock(|number_key| number_key.get());
+    write!(line, "number key: {}\r\n", match number_key {
+        NumberKey::Momentary => "momentary",
+        NumberKey::Latched => "latched",
+    }).ok();
+}
+
+/// Choose where keys are sent as told by `arg`, or forget the bonded Bluetooth host, or just say
+/// where they're sent and whether there is one.
+#[cfg(feature = "ble")]
+fn bluetooth(line: &mut Response, arg: Option<&str>) {
+    match arg {
+        Some("usb") => ble::set_output(ble::Output::Usb),
+        Some("ble") => ble::set_output(ble::Output::Ble),
+        Some("forget") => {
+            settings::update(|settings| settings.bond = None);
+            line.push_str("bond forgotten, once restarted\r\n").ok();
+        },
+        None => {},
+        Some(_) => {
+            line.push_str("expected usb, ble or forget\r\n").ok();
+            return;
+        },
+    }
+    write!(line, "keys sent over: {}, bonded: {}\r\n", match ble::output() {
+        ble::Output::Usb => "usb",
+        ble::Output::Ble => "ble",
+    }, if settings::get().bond.is_some() { "yes" } else { "no" }).ok();
+}
+
+/// Show or set how the pedal numbered `which` (from 1) acts.
+fn pedal_mode(line: &mut Response, which: Option<&str>, arg: Option<&str>) {
+    let Some(pedal_idx) = which.and_then(|which| which.parse::<usize>().ok()).filter(|&n| (1..=keymap::PEDALS.len()).contains(&n)).map(|n| n - 1) else {
+        write!(line, "expected a pedal from 1 to {}\r\n", keymap::PEDALS.len()).ok();
+        return;
+    };
+    let mode = match arg {
+        Some("momentary") => Some(PedalMode::Momentary),
+        Some("toggle") => Some(PedalMode::Toggle),
+        None => None,
+        Some(_) => {
+            line.push_str("expected momentary or toggle\r\n").ok();
+            return;
+        },
+    };
+    let modes = scan::PEDAL_MODES.lock(|modes| {
+        if let Some(mode) = mode {
+            let mut changed = modes.get();
+            changed[pedal_idx] = mode;
+            modes.set(changed);
+        }
+        modes.get()
+    });
+    write!(line, "pedal {}: {}\r\n", pedal_idx + 1, match modes[pedal_idx] {
+        PedalMode::Momentary => "momentary",
+        PedalMode::Toggle => "toggle",
+    }).ok();
+}
+
+/// Start or stop the [drill] of steno chords, or say how it's going.
+fn chord_drill(line: &mut Response, arg: Option<&str>) {
+    let summary = match arg {
+        Some("on") => Some(drill::start()),
+        Some("off") => drill::stop(),
+        None => drill::describe(),
+        Some(_) => {
+            line.push_str("expected on or off\r\n").ok();
+            return;
+        },
+    };
+    line.push_str(summary.as_deref().unwrap_or("drill: off\r\n")).ok();
+}
+
+/// Close the switch at row `row` and column `column` (the pedals being the row after the last) as
+/// if it were pressed: until it's released, or if `tap`, for `arg` milliseconds (50 by default).
+fn simulate_press(line: &mut Response, row: Option<&str>, column: Option<&str>, arg: Option<&str>, tap: bool) {
+    let Some(code) = parse_switch(row, column) else {
+        line.push_str("expected a row and column\r\n").ok();
+        return;
+    };
+    let for_time = match (tap, arg.map(str::parse::<u64>)) {
+        (false, None) => None,
+        (true, None) => Some(Duration::from_millis(50)),
+        (true, Some(Ok(millis))) if millis > 0 => Some(Duration::from_millis(millis)),
+        _ => {
+            line.push_str("expected milliseconds\r\n").ok();
+            return;
+        },
+    };
+    if !scan::simulate_press(code, for_time) {
+        line.push_str("too many switches closed\r\n").ok();
+    }
+}
+
+/// Open the switch at row `row` and column `column` again, or every one closed by [simulate_press].
+fn simulate_release(line: &mut Response, row: Option<&str>, column: Option<&str>) {
+    if row.is_none() {
+        scan::simulate_release(None);
+        return;
+    }
+    match parse_switch(row, column) {
+        Some(code) => scan::simulate_release(Some(code)),
+        None => {
+            line.push_str("expected a row and column\r\n").ok();
+        },
+    }
+}
+
+/// The switch at `row` and `column`, if there is one.
+fn parse_switch(row: Option<&str>, column: Option<&str>) -> Option<(u8, u8)> {
+    let code = (row?.parse().ok()?, column?.parse().ok()?);
+    scan::is_switch(code).then_some(code)
+}
+
+/// Show or set what happens to a key pressed while too many are held (see [scan::HELD_KEYS_FULL]),
+/// and how many times it has happened, or reset the count.
+fn held_keys(line: &mut Response, arg: Option<&str>) {
+    match arg {
+        Some("reject") => scan::HELD_KEYS_FULL.lock(|full| full.set(HeldKeysFull::RejectNew)),
+        Some("evict") => scan::HELD_KEYS_FULL.lock(|full| full.set(HeldKeysFull::EvictOldest)),
+        Some("reset") => scan::HELD_KEYS_OVERFLOWS.lock(|overflows| overflows.set(0)),
+        None => {},
+        Some(_) => {
+            line.push_str("expected reject, evict or reset\r\n").ok();
+            return;
+        },
+    }
+    let full = scan::HELD_KEYS_FULL.lock(|full| full.get());
+    let overflows = scan::HELD_KEYS_OVERFLOWS.lock(|overflows| overflows.get());
+    write!(line, "held keys when full: {}, {} overflows\r\n", match full {
+        HeldKeysFull::RejectNew => "reject new",
+        HeldKeysFull::EvictOldest => "evict oldest",
+    }, overflows).ok();
+}
+
+/// Show what the status LED shows for each [Indication], or change one, as told by `args`: its name,
+/// then `default`, or a style, a duty and (for an RGB LED) red, green and blue duties.
+fn status_indicator<'a>(line: &mut Response, mut args: impl Iterator<Item = &'a str>) {
+    let Some(name) = args.next() else {
+        for indication in Indication::ALL {
+            describe_indicator(line, indication);
+        }
+        return;
+    };
+    let Some(indication) = Indication::from_name(name) else {
+        line.push_str("expected function, navigation, symbols, pedal, jiggler, steno or layout\r\n").ok();
+        return;
+    };
+    let indicator = match args.next() {
+        None => {
+            describe_indicator(line, indication);
+            return;
+        },
+        Some("default") => led::DEFAULT_INDICATORS[indication as usize],
+        Some(style) => {
+            let Some(style) = IndicatorStyle::from_name(style) else {
+                line.push_str("expected default, steady, blink or breathe\r\n").ok();
+                return;
+            };
+            let mut duties = args.map(str::parse::<u16>);
+            let Some(Ok(duty)) = duties.next() else {
+                line.push_str("expected a duty (0-65535)\r\n").ok();
+                return;
+            };
+            let mut colour = led::indicator(indication).colour;
+            for channel in &mut colour {
+                match duties.next() {
+                    Some(Ok(channel_duty)) => *channel = channel_duty,
+                    None => break,
+                    Some(Err(_)) => {
+                        line.push_str("expected red, green and blue duties (0-65535)\r\n").ok();
+                        return;
+                    },
+                }
+            }
+            Indicator { style, duty, colour }
+        },
+    };
+    settings::update(|settings| settings.indicators[indication as usize] = indicator);
+    describe_indicator(line, indication);
+}
+
+/// Say what the status LED shows for `indication`.
+fn describe_indicator(line: &mut Response, indication: Indication) {
+    let indicator = led::indicator(indication);
+    let [red, green, blue] = indicator.colour;
+    write!(line, "{}: {} {} ({} {} {})\r\n", indication.name(), indicator.style.name(), indicator.duty, red, green, blue).ok();
+}
+
+/// Show the supply voltage (see [voltage]), switch dimming the LEDs while it sags, or reset the
+/// lowest measured.
+fn supply_voltage(line: &mut Response, arg: Option<&str>, setting: Option<&str>) {
+    match arg {
+        Some("autodim") => {
+            switch(line, "auto-dim", &voltage::AUTO_DIM, setting);
+            return;
+        },
+        Some("reset") => voltage::reset_lowest(),
+        None => {},
+        Some(_) => {
+            line.push_str("expected autodim or reset\r\n").ok();
+            return;
+        },
+    }
+    let Some(readings) = voltage::readings() else {
+        line.push_str("VSYS: not measured yet\r\n").ok();
+        return;
+    };
+    line.push_str("VSYS: ").ok();
+    voltage::describe_mv(line, readings.latest).ok();
+    line.push_str(" (lowest ").ok();
+    voltage::describe_mv(line, readings.lowest).ok();
+    let auto_dim = voltage::AUTO_DIM.lock(|enabled| enabled.get());
+    write!(line, "){}, auto-dim: {}\r\n", if readings.sagging { ", weak" } else { "" }, if auto_dim { "on" } else { "off" }).ok();
+}
+
+/// Show how many HID reports have failed to send (see [usb::REPORT_ERRORS]), or reset the counts.
+fn report_errors(line: &mut Response, arg: Option<&str>) {
+    match arg {
+        None => {},
+        Some("reset") => usb::REPORT_ERRORS.lock(|errors| errors.set(usb::ReportErrors::NONE)),
+        Some(_) => {
+            line.push_str("expected reset\r\n").ok();
+            return;
+        },
+    }
+    let errors = usb::REPORT_ERRORS.lock(|errors| errors.get());
+    write!(line, "reports: {} failed to send, {} given up on\r\n", errors.failed, errors.dropped).ok();
+}
+
+/// Say which build of the firmware this is, to tell experimental builds apart.
+fn version(line: &mut Response) {
+    write!(line, "{} {} ({}, built {})\r\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), env!("BUILD_GIT_HASH"), env!("BUILD_DATE")).ok();
+    write!(line, "board: {}, keymap: {:08x}\r\n", boards::PRODUCT, keymap::keymap_checksum()).ok();
+    write!(line, "features: {}\r\n", env!("BUILD_FEATURES")).ok();
+}
+
+/// Carry out a command typed into the console, returning the response to write back.
+pub fn run_command(command: &str) -> Response {
+    let mut response = Response::new();
+    let mut words = command.split_ascii_whitespace();
+    match words.next() {
+        Some("help") => {
+            response.push_str("commands: help, version, keytest [on|off], modsahead [on|off], typematic [on|off],\r\n  idletimeout [minutes|off], layerpreview [on|off], stats [reset|pulse strokes|pulse off],\r\n  numberkey [momentary|latched], pedal <n> [momentary|toggle], usberrors [reset],\r\n  heldkeys [reject|evict|reset], drill [on|off], press <row> <col>, tap <row> <col> [ms],\r\n  release [<row> <col>], geminirelease [on|off], strokespacing [ms|off],\r\n  strokemirror [on|off], voltage [autodim on|off|reset],\r\n  indicator [<name> ...]\r\n").ok();
+            #[cfg(feature = "ble")]
+            response.push_str("  ble [usb|ble|forget]\r\n").ok();
+        },
+        Some("version") => version(&mut response),
+        Some("keytest") => switch(&mut response, "key tester", &scan::KEY_TEST, words.next()),
+        Some("idletimeout") => idle_timeout(&mut response, words.next()),
+        Some("layerpreview") => switch(&mut response, "layer preview", &scan::LAYER_PREVIEW, words.next()),
+        Some("numberkey") => number_key(&mut response, words.next()),
+        Some("press") => simulate_press(&mut response, words.next(), words.next(), None, false),
+        Some("tap") => simulate_press(&mut response, words.next(), words.next(), words.next(), true),
+        Some("release") => simulate_release(&mut response, words.next(), words.next()),
+        Some("geminirelease") => switch(&mut response, "Gemini PR release packet", &steno::GEMINI_RELEASE_PACKET, words.next()),
+        Some("strokespacing") => stroke_spacing(&mut response, words.next()),
+        Some("indicator") => status_indicator(&mut response, words),
+        Some("voltage") => supply_voltage(&mut response, words.next(), words.next()),
+        Some("strokemirror") => switch(&mut response, "stroke mirror", &STROKE_MIRROR, words.next()),
+        Some("drill") => chord_drill(&mut response, words.next()),
+        Some("heldkeys") => held_keys(&mut response, words.next()),
+        Some("pedal") => pedal_mode(&mut response, words.next(), words.next()),
+        Some("stats") => steno_stats(&mut response, words.next(), words.next()),
+        Some("usberrors") => report_errors(&mut response, words.next()),
+        Some("typematic") => switch(&mut response, "typematic repeat", &scan::TYPEMATIC, words.next()),
+        Some("modsahead") => switch(&mut response, "modifiers ahead", &usb::MODIFIERS_AHEAD, words.next()),
+        #[cfg(feature = "ble")]
+        Some("ble") => bluetooth(&mut response, words.next()),
+        _ => {
+            write!(response, "unknown command: {}\r\n", command).ok();
+        },
+    }
+    response
+}
@@ -0,0 +1,183 @@
+//! Shows what the keyboard is doing on an SSD1306 OLED (128x64, on I2C): the layer, the layout being
+//! emulated, whether steno is on, typing speed, and Caps Lock. Redrawn by its own task whenever
+//! [crate::scan] or the host's keyboard LEDs change what it should show.
+//!
+//! Only on boards with the `display` feature.
+
+use crate::keymap::{Layout, LAYERS};
+use crate::RawMutex;
+use core::fmt::Write;
+use embassy_futures::select::{select, Either};
+use embassy_rp::{
+    bind_interrupts,
+    i2c::{Async, I2c, InterruptHandler},
+    peripherals::I2C0,
+};
+use embassy_sync::signal::Signal;
+use heapless::String;
+
+bind_interrupts!(pub struct Irqs {
+    I2C0_IRQ => InterruptHandler<I2C0>;
+});
+
+const ADDRESS: u16 = 0x3C;
+const WIDTH: usize = 128;
+/// Rows of 8 pixels, each a byte per column, with the top pixel in bit 0
+const PAGES: usize = 8;
+
+/// Leads a write of commands
+const CONTROL_COMMANDS: u8 = 0x00;
+/// Leads a write of pixels, into wherever the last one left off
+const CONTROL_DATA: u8 = 0x40;
+
+#[rustfmt::skip]
+const INIT_COMMANDS: &[u8] = &[
+    CONTROL_COMMANDS,
+    0xAE,                   // display off
+    0xD5, 0x80,             // clock divide ratio and oscillator frequency
+    0xA8, 0x3F,             // multiplex ratio (64 lines)
+    0xD3, 0x00,             // display offset
+    0x40,                   // start line 0
+    0x8D, 0x14,             // charge pump on
+    0x20, 0x00,             // horizontal addressing, wrapping from page to page
+    0xA1,                   // column 127 at the left (segment remap)
+    0xC8,                   // scan from the bottom up (so the top is on top)
+    0xDA, 0x12,             // COM pins for 128x64
+    0x81, 0xCF,             // contrast
+    0xD9, 0xF1,             // pre-charge period
+    0xDB, 0x40,             // VCOMH deselect level
+    0xA4,                   // show the RAM contents
+    0xA6,                   // not inverted
+    0xAF,                   // display on
+];
+
+/// Sent before each frame, so that it fills the whole screen from the top left
+#[rustfmt::skip]
+const FRAME_COMMANDS: &[u8] = &[
+    CONTROL_COMMANDS,
+    0x21, 0x00, WIDTH as u8 - 1,    // columns
+    0x22, 0x00, PAGES as u8 - 1,    // pages
+];
+
+/// Name shown for each of [LAYERS], the letter layers all being "base" (with the layout shown apart)
+const LAYER_NAMES: [&str; LAYERS.len()] = ["BASE", "BASE", "BASE", "BASE", "SYMBOLS", "SYMBOLS", "NAVIGATION", "FUNCTION", "STENO", "SYMBOLS"];
+
+/// What the display shows from [crate::scan]
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Status {
+    /// Position in [LAYERS] of the layer in use
+    pub layer: usize,
+    pub layout: Layout,
+    pub stenotype: bool,
+    /// Words (or steno strokes) per minute over the last little while
+    pub words_per_minute: u16,
+}
+
+/// Raised by [crate::scan] whenever the [Status] changes
+pub static STATUS: Signal<RawMutex, Status> = Signal::new();
+/// Raised by [crate::usb] whenever the host turns Caps Lock on or off
+pub static CAPS_LOCK: Signal<RawMutex, bool> = Signal::new();
+
+/// The whole screen, led by [CONTROL_DATA] so that it can be written out in one go
+struct Frame([u8; 1 + WIDTH * PAGES]);
+
+impl Frame {
+    fn new() -> Self {
+        let mut frame = Frame([0; 1 + WIDTH * PAGES]);
+        frame.0[0] = CONTROL_DATA;
+        frame
+    }
+
+    /// Write `text` on line `page`, from the left, cutting it off at the edge.
+    fn text(&mut self, page: usize, text: &str) {
+        let line = &mut self.0[1 + page * WIDTH..][..WIDTH];
+        for (cell, c) in line.chunks_exact_mut(GLYPH_WIDTH + 1).zip(text.chars()) {
+            cell[..GLYPH_WIDTH].copy_from_slice(glyph(c));
+        }
+    }
+}
+
+/// Draw `status` and `caps_lock` onto a new frame.
+fn draw(status: &Status, caps_lock: bool) -> Frame {
+    let mut frame = Frame::new();
+    let layout = match status.layout {
+        Layout::Normal => "QWERTY",
+        Layout::DvorakEmu => "DVORAK",
+        Layout::ColemakDhEmu => "COLEMAK-DH",
+        Layout::WorkmanEmu => "WORKMAN",
+    };
+    let mut line = String::<32>::new();
+    write!(line, "LAYER: {}", LAYER_NAMES[status.layer]).ok();
+    frame.text(0, &line);
+    line.clear();
+    write!(line, "LAYOUT: {}", layout).ok();
+    frame.text(2, &line);
+    frame.text(3, if status.stenotype { "STENO: ON" } else { "STENO: OFF" });
+    line.clear();
+    write!(line, "WPM: {}", status.words_per_minute).ok();
+    frame.text(5, &line);
+    if caps_lock {
+        frame.text(7, "CAPS LOCK");
+    }
+    frame
+}
+
+#[embassy_executor::task]
+pub async fn run(mut i2c: I2c<'static, I2C0, Async>) {
+    if i2c.write_async(ADDRESS, INIT_COMMANDS.iter().copied()).await.is_err() {
+        warn!("No display found");
+        return;
+    }
+    let mut status = Status::default();
+    let mut caps_lock = false;
+    loop {
+        let frame = draw(&status, caps_lock);
+        let written = async {
+            i2c.write_async(ADDRESS, FRAME_COMMANDS.iter().copied()).await?;
+            i2c.write_async(ADDRESS, frame.0.iter().copied()).await
+        };
+        if written.await.is_err() {
+            warn!("Display write failed");
+        }
+        match select(STATUS.wait(), CAPS_LOCK.wait()).await {
+            Either::First(new_status) => status = new_status,
+            Either::Second(new_caps_lock) => caps_lock = new_caps_lock,
+        }
+    }
+}
+
+const GLYPH_WIDTH: usize = 5;
+
+/// Columns of a 5x7 font, from `' '` to `'Z'` (lowercase letters being drawn as uppercase)
+#[rustfmt::skip]
+const FONT: [[u8; GLYPH_WIDTH]; 59] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], [0x00, 0x00, 0x5F, 0x00, 0x00], [0x00, 0x07, 0x00, 0x07, 0x00], // space ! "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], [0x24, 0x2A, 0x7F, 0x2A, 0x12], [0x23, 0x13, 0x08, 0x64, 0x62], // # $ %
+    [0x36, 0x49, 0x56, 0x20, 0x50], [0x00, 0x05, 0x03, 0x00, 0x00], [0x00, 0x1C, 0x22, 0x41, 0x00], // & ' (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], [0x14, 0x08, 0x3E, 0x08, 0x14], [0x08, 0x08, 0x3E, 0x08, 0x08], // ) * +
+    [0x00, 0x50, 0x30, 0x00, 0x00], [0x08, 0x08, 0x08, 0x08, 0x08], [0x00, 0x60, 0x60, 0x00, 0x00], // , - .
+    [0x20, 0x10, 0x08, 0x04, 0x02], [0x3E, 0x51, 0x49, 0x45, 0x3E], [0x00, 0x42, 0x7F, 0x40, 0x00], // / 0 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], [0x21, 0x41, 0x45, 0x4B, 0x31], [0x18, 0x14, 0x12, 0x7F, 0x10], // 2 3 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], [0x3C, 0x4A, 0x49, 0x49, 0x30], [0x01, 0x71, 0x09, 0x05, 0x03], // 5 6 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], [0x06, 0x49, 0x49, 0x29, 0x1E], [0x00, 0x36, 0x36, 0x00, 0x00], // 8 9 :
+    [0x00, 0x56, 0x36, 0x00, 0x00], [0x08, 0x14, 0x22, 0x41, 0x00], [0x14, 0x14, 0x14, 0x14, 0x14], // ; < =
+    [0x00, 0x41, 0x22, 0x14, 0x08], [0x02, 0x01, 0x51, 0x09, 0x06], [0x32, 0x49, 0x79, 0x41, 0x3E], // > ? @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], [0x7F, 0x49, 0x49, 0x49, 0x36], [0x3E, 0x41, 0x41, 0x41, 0x22], // A B C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], [0x7F, 0x49, 0x49, 0x49, 0x41], [0x7F, 0x09, 0x09, 0x09, 0x01], // D E F
+    [0x3E, 0x41, 0x49, 0x49, 0x7A], [0x7F, 0x08, 0x08, 0x08, 0x7F], [0x00, 0x41, 0x7F, 0x41, 0x00], // G H I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], [0x7F, 0x08, 0x14, 0x22, 0x41], [0x7F, 0x40, 0x40, 0x40, 0x40], // J K L
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], [0x7F, 0x04, 0x08, 0x10, 0x7F], [0x3E, 0x41, 0x41, 0x41, 0x3E], // M N O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], [0x3E, 0x41, 0x51, 0x21, 0x5E], [0x7F, 0x09, 0x19, 0x29, 0x46], // P Q R
+    [0x46, 0x49, 0x49, 0x49, 0x31], [0x01, 0x01, 0x7F, 0x01, 0x01], [0x3F, 0x40, 0x40, 0x40, 0x3F], // S T U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], [0x3F, 0x40, 0x38, 0x40, 0x3F], [0x63, 0x14, 0x08, 0x14, 0x63], // V W X
+    [0x07, 0x08, 0x70, 0x08, 0x07], [0x61, 0x51, 0x49, 0x45, 0x43],                                 // Y Z
+];
+
+/// Columns to draw `c` with, or `'?'` if it isn't in the [FONT].
+fn glyph(c: char) -> &'static [u8; GLYPH_WIDTH] {
+    let c = c.to_ascii_uppercase();
+    match c {
+        ' '..='Z' => &FONT[c as usize - ' ' as usize],
+        _ => &FONT['?' as usize - ' ' as usize],
+    }
+}
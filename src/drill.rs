@@ -0,0 +1,149 @@
+//! A practice mode for steno chords: shows a chord to write on the [crate::console], then says which
+//! of its keys the stroke written got and which it missed, going on to the next chord once one is
+//! written right. Strokes go only to the drill while it's running, so they don't type anything.
+//!
+//! The checking itself is kept apart from the hardware, in [Drill], like [crate::stats].
+
+use crate::console::{self, ConsoleLine};
+use crate::steno::{self, GeminiPacket, KeyCode};
+use crate::RawMutex;
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::mem::take;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// Chords to practice, in turn: the briefs for the commonest English words, in Plover's theory
+const CHORDS: [(&str, &[KeyCode]); 16] = [
+    ("the", &[KeyCode::TR]),
+    ("of", &[KeyCode::FR]),
+    ("and", &[KeyCode::S1, KeyCode::KL, KeyCode::PL]),
+    ("to", &[KeyCode::TL, KeyCode::O]),
+    ("in", &[KeyCode::TL, KeyCode::PL, KeyCode::HL]),
+    ("is", &[KeyCode::S1]),
+    ("that", &[KeyCode::TL, KeyCode::HL, KeyCode::A]),
+    ("it", &[KeyCode::TL]),
+    ("for", &[KeyCode::TL, KeyCode::PL]),
+    ("was", &[KeyCode::WL, KeyCode::A, KeyCode::SR]),
+    ("with", &[KeyCode::WL]),
+    ("you", &[KeyCode::U]),
+    ("he", &[KeyCode::E]),
+    ("on", &[KeyCode::O, KeyCode::PR, KeyCode::BR]),
+    ("are", &[KeyCode::RL]),
+    ("be", &[KeyCode::BR]),
+];
+
+/// The drill, while it's running
+static DRILL: Mutex<RawMutex, RefCell<Option<Drill>>> = Mutex::new(RefCell::new(None));
+
+/// Progress through [CHORDS]
+pub struct Drill {
+    /// Position in [CHORDS] of the chord to write next
+    next: usize,
+    /// Chords written right so far
+    written: u32,
+    /// How many of [Self::written] were right first time
+    first_time: u32,
+    /// Whether the chord to write next has already been got wrong
+    missed: bool,
+}
+
+impl Drill {
+    pub const fn new() -> Self {
+        Drill { next: 0, written: 0, first_time: 0, missed: false }
+    }
+
+    /// The chord to write next, and the word it's for
+    fn target(&self) -> (GeminiPacket, &'static str) {
+        let (word, codes) = CHORDS[self.next];
+        let mut target = GeminiPacket::default();
+        for &code in codes {
+            target.press(code);
+        }
+        (target, word)
+    }
+
+    /// Say which chord to write next, followed by CRLF.
+    pub fn prompt(&self) -> ConsoleLine {
+        let (target, word) = self.target();
+        let mut line = ConsoleLine::new();
+        write!(line, "write {} ({})\r\n", notation(target), word).ok();
+        line
+    }
+
+    /// Check `stroke` against the chord to write, moving on to the next if it's right, and say how
+    /// it went, followed by CRLF.
+    pub fn check(&mut self, stroke: GeminiPacket) -> ConsoleLine {
+        let (target, word) = self.target();
+        let mut line = ConsoleLine::new();
+        if stroke == target {
+            self.written += 1;
+            if !take(&mut self.missed) {
+                self.first_time += 1;
+            }
+            self.next = (self.next + 1) % CHORDS.len();
+            write!(line, "right ({} of {} first time)\r\n", self.first_time, self.written).ok();
+            return line;
+        }
+        self.missed = true;
+        write!(line, "wrote {}", notation(stroke)).ok();
+        let (missed, extra) = (target.without(stroke), stroke.without(target));
+        if !missed.is_empty() {
+            write!(line, ", missed {}", notation(missed)).ok();
+        }
+        if !extra.is_empty() {
+            write!(line, ", extra {}", notation(extra)).ok();
+        }
+        write!(line, "; try {} ({}) again\r\n", notation(target), word).ok();
+        line
+    }
+
+    /// Sum up how the drill went, followed by CRLF.
+    pub fn describe(&self) -> ConsoleLine {
+        let mut line = ConsoleLine::new();
+        write!(line, "drill: {} chords written, {} first time\r\n", self.written, self.first_time).ok();
+        line
+    }
+}
+
+/// `packet` in steno notation, without the line ending
+fn notation(packet: GeminiPacket) -> steno::NotationLine {
+    let mut line = steno::to_notation(&packet);
+    line.truncate(line.trim_end().len());
+    line
+}
+
+/// Start the drill from the first chord, saying which to write.
+pub fn start() -> ConsoleLine {
+    let drill = Drill::new();
+    let prompt = drill.prompt();
+    DRILL.lock(|running| *running.borrow_mut() = Some(drill));
+    prompt
+}
+
+/// Stop the drill, summing up how it went, if it was running.
+pub fn stop() -> Option<ConsoleLine> {
+    DRILL.lock(|running| running.borrow_mut().take()).map(|drill| drill.describe())
+}
+
+/// Say how the drill is going, if it's running.
+pub fn describe() -> Option<ConsoleLine> {
+    DRILL.lock(|running| running.borrow().as_ref().map(Drill::describe))
+}
+
+/// Check `stroke` against the drill and write out how it went, returning whether the drill is
+/// running and so has taken the stroke, for it not to be sent to the host.
+pub fn take_stroke(stroke: GeminiPacket) -> bool {
+    let Some((result, prompt)) = DRILL.lock(|running| {
+        running.borrow_mut().as_mut().map(|drill| {
+            let result = drill.check(stroke);
+            (result, (!drill.missed).then(|| drill.prompt()))
+        })
+    }) else {
+        return false;
+    };
+    console::print(result);
+    if let Some(prompt) = prompt {
+        console::print(prompt);
+    }
+    true
+}
@@ -0,0 +1,49 @@
+//! Counts of things which went wrong inside the firmware without stopping it: reports and strokes
+//! the host didn't get, lines dropped from the [crate::console], scans which ran late. They're read
+//! by the host as a HID feature report (see [crate::hid::HEALTH_REPORT_SIZE]), so they can be
+//! watched without opening either serial port.
+
+use crate::console::OUTPUT;
+use crate::{hid, scan, usb, RawMutex, STROKES_CHANNEL};
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// Lines dropped from the console because too many were waiting, as when nothing has the port open
+pub static CONSOLE_LINES_DROPPED: Mutex<RawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+/// Steno strokes which couldn't be written to the serial port, and so were lost
+pub static SERIAL_WRITE_FAILURES: Mutex<RawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+/// Scans which took longer than [scan::SCAN_INTERVAL], putting the next one late
+pub static SCAN_OVERRUNS: Mutex<RawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+/// Times scanning waited for room for a steno stroke, with [crate::run_matrix]'s backlog full
+pub static SCANS_HELD_UP: Mutex<RawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+/// Most steno strokes ever backed up in [crate::run_matrix] at once
+pub static STROKE_BACKLOG_PEAK: Mutex<RawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Add one to `counter`, stopping at its maximum rather than wrapping round.
+pub fn count(counter: &Mutex<RawMutex, Cell<u32>>) {
+    counter.lock(|count| count.set(count.get().saturating_add(1)));
+}
+
+/// Raise `peak` to `value`, if it's below.
+pub fn note_peak(peak: &Mutex<RawMutex, Cell<u32>>, value: usize) {
+    peak.lock(|peak| peak.set(peak.get().max(value as u32)));
+}
+
+/// Everything counted, in the order [hid::health_report] lays them out, followed by how many steno
+/// strokes and console lines are waiting right now
+pub fn counters() -> [u32; hid::HEALTH_COUNTERS] {
+    let get = |counter: &Mutex<RawMutex, Cell<u32>>| counter.lock(|count| count.get());
+    let report_errors = usb::REPORT_ERRORS.lock(|errors| errors.get());
+    [
+        report_errors.failed,
+        report_errors.dropped,
+        get(&SERIAL_WRITE_FAILURES),
+        get(&CONSOLE_LINES_DROPPED),
+        get(&SCAN_OVERRUNS),
+        get(&SCANS_HELD_UP),
+        get(&STROKE_BACKLOG_PEAK),
+        get(&scan::HELD_KEYS_OVERFLOWS),
+        STROKES_CHANNEL.len() as u32,
+        OUTPUT.len() as u32,
+    ]
+}
@@ -0,0 +1,453 @@
+//! Defines a single composite HID report descriptor, covering keyboard, consumer control, system
+//! control, mouse and N-key rollover keyboard reports distinguished by report IDs, so that they can all share one interface
+//! and one pair of endpoints in [crate::usb]. A vendor-defined feature report alongside them carries
+//! the [crate::health] counters.
+
+use usbd_hid::descriptor::{KeyboardReport, MediaKeyboardReport, MouseReport, SystemControlReport};
+
+pub const KEYBOARD_REPORT_ID: u8 = 1;
+const CONSUMER_REPORT_ID: u8 = 2;
+const SYSTEM_REPORT_ID: u8 = 3;
+const MOUSE_REPORT_ID: u8 = 4;
+const NKRO_REPORT_ID: u8 = 5;
+pub const HEALTH_REPORT_ID: u8 = 6;
+
+/// How many different kinds of input report there are (numbered from 1).
+pub const REPORT_KINDS: usize = 5;
+
+/// Keyboard usages covered by [NkroReport], one bit each: enough for letters, digits and
+/// punctuation.
+const NKRO_USAGES: usize = 64;
+
+/// Longest input report, including its report ID byte.
+pub const MAX_INPUT_REPORT_SIZE: usize = 9;
+const _: () = assert!(NKRO_USAGES / 8 < MAX_INPUT_REPORT_SIZE, "NKRO report must fit with its ID");
+/// Longest output report (keyboard LEDs), including its report ID byte.
+pub const MAX_OUTPUT_REPORT_SIZE: usize = 2;
+
+/// How many counters the health feature report carries, each as a little-endian `u32`: reports
+/// failed and dropped, serial writes failed, console lines dropped, scan overruns, scans held up,
+/// most strokes backed up, held-key overflows, strokes waiting, console lines waiting.
+pub const HEALTH_COUNTERS: usize = 10;
+/// Length of the health feature report, including its report ID byte.
+pub const HEALTH_REPORT_SIZE: usize = 1 + 4 * HEALTH_COUNTERS;
+const _: () = assert!(HEALTH_REPORT_SIZE - 1 <= u8::MAX as usize, "health report count must fit in a byte");
+
+#[rustfmt::skip]
+pub const REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,             // Usage Page (Generic Desktop)
+    0x09, 0x06,             // Usage (Keyboard)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, KEYBOARD_REPORT_ID,
+    0x05, 0x07,             //   Usage Page (Keyboard)
+    0x19, 0xE0, 0x29, 0xE7, //   Usage Minimum/Maximum (LCtrl..RGui)
+    0x15, 0x00, 0x25, 0x01, //   Logical Minimum/Maximum (0..1)
+    0x75, 0x01, 0x95, 0x08, //   Report Size 1, Count 8
+    0x81, 0x02,             //   Input (Data, Variable, Absolute) -- modifier
+    0x75, 0x08, 0x95, 0x01, //   Report Size 8, Count 1
+    0x81, 0x01,             //   Input (Constant) -- reserved
+    0x05, 0x08,             //   Usage Page (LEDs)
+    0x19, 0x01, 0x29, 0x05, //   Usage Minimum/Maximum (Num Lock..Kana)
+    0x75, 0x01, 0x95, 0x05, //   Report Size 1, Count 5
+    0x91, 0x02,             //   Output (Data, Variable, Absolute) -- leds
+    0x75, 0x03, 0x95, 0x01, //   Report Size 3, Count 1
+    0x91, 0x01,             //   Output (Constant) -- padding
+    0x05, 0x07,             //   Usage Page (Keyboard)
+    0x19, 0x00, 0x2A, 0xFF, 0x00, // Usage Minimum/Maximum (0..255)
+    0x15, 0x00, 0x26, 0xFF, 0x00, // Logical Minimum/Maximum (0..255)
+    0x75, 0x08, 0x95, 0x06, //   Report Size 8, Count 6
+    0x81, 0x00,             //   Input (Data, Array, Absolute) -- keycodes
+    0xC0,                   // End Collection
+
+    0x05, 0x0C,             // Usage Page (Consumer)
+    0x09, 0x01,             // Usage (Consumer Control)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, CONSUMER_REPORT_ID,
+    0x19, 0x00, 0x2A, 0xFF, 0x03, // Usage Minimum/Maximum (0..0x3FF)
+    0x15, 0x00, 0x26, 0xFF, 0x03, // Logical Minimum/Maximum (0..0x3FF)
+    0x75, 0x10, 0x95, 0x01, //   Report Size 16, Count 1
+    0x81, 0x00,             //   Input (Data, Array, Absolute) -- usage_id
+    0xC0,                   // End Collection
+
+    0x05, 0x01,             // Usage Page (Generic Desktop)
+    0x09, 0x80,             // Usage (System Control)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, SYSTEM_REPORT_ID,
+    0x19, 0x81, 0x29, 0xB7, //   Usage Minimum/Maximum (Power Down..System Display LCD Autoscale)
+    0x16, 0x81, 0x00, 0x26, 0xB7, 0x00, // Logical Minimum/Maximum (0x81..0xB7), so 0 is none
+    0x75, 0x08, 0x95, 0x01, //   Report Size 8, Count 1
+    0x81, 0x00,             //   Input (Data, Array, Absolute) -- usage_id
+    0xC0,                   // End Collection
+
+    0x05, 0x01,             // Usage Page (Generic Desktop)
+    0x09, 0x02,             // Usage (Mouse)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, MOUSE_REPORT_ID,
+    0x09, 0x01,             //   Usage (Pointer)
+    0xA1, 0x00,             //   Collection (Physical)
+    0x05, 0x09,             //     Usage Page (Button)
+    0x19, 0x01, 0x29, 0x08, //     Usage Minimum/Maximum (1..8)
+    0x15, 0x00, 0x25, 0x01, //     Logical Minimum/Maximum (0..1)
+    0x75, 0x01, 0x95, 0x08, //     Report Size 1, Count 8
+    0x81, 0x02,             //     Input (Data, Variable, Absolute) -- buttons
+    0x05, 0x01,             //     Usage Page (Generic Desktop)
+    0x09, 0x30, 0x09, 0x31, 0x09, 0x38, // Usage (X), Usage (Y), Usage (Wheel)
+    0x15, 0x81, 0x25, 0x7F, //     Logical Minimum/Maximum (-127..127)
+    0x75, 0x08, 0x95, 0x03, //     Report Size 8, Count 3
+    0x81, 0x06,             //     Input (Data, Variable, Relative) -- x, y, wheel
+    0x05, 0x0C,             //     Usage Page (Consumer)
+    0x0A, 0x38, 0x02,       //     Usage (AC Pan)
+    0x95, 0x01,             //     Report Count 1
+    0x81, 0x06,             //     Input (Data, Variable, Relative) -- pan
+    0xC0,                   //   End Collection
+    0xC0,                   // End Collection
+
+    0x05, 0x01,             // Usage Page (Generic Desktop)
+    0x09, 0x06,             // Usage (Keyboard)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, NKRO_REPORT_ID,
+    0x05, 0x07,             //   Usage Page (Keyboard)
+    0x19, 0x00, 0x29, NKRO_USAGES as u8 - 1, // Usage Minimum/Maximum
+    0x15, 0x00, 0x25, 0x01, //   Logical Minimum/Maximum (0..1)
+    0x75, 0x01, 0x95, NKRO_USAGES as u8, // Report Size 1, Count (one per usage)
+    0x81, 0x02,             //   Input (Data, Variable, Absolute) -- key bitmap
+    0xC0,                   // End Collection
+
+    0x06, 0x00, 0xFF,       // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,             // Usage (Vendor Usage 1)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, HEALTH_REPORT_ID,
+    0x09, 0x02,             //   Usage (Vendor Usage 2)
+    0x15, 0x00, 0x26, 0xFF, 0x00, // Logical Minimum/Maximum (0..255)
+    0x75, 0x08, 0x95, HEALTH_REPORT_SIZE as u8 - 1, // Report Size 8, Count (counter bytes)
+    0xB1, 0x02,             //   Feature (Data, Variable, Absolute) -- health counters
+    0xC0,                   // End Collection
+];
+
+/// Write the health feature report with `counters` into `buf`, if `id` is its report ID, returning
+/// its length.
+pub fn health_report(id: u8, counters: &[u32; HEALTH_COUNTERS], buf: &mut [u8]) -> Option<usize> {
+    if id != HEALTH_REPORT_ID || buf.len() < HEALTH_REPORT_SIZE {
+        return None;
+    }
+    buf[0] = HEALTH_REPORT_ID;
+    for (bytes, counter) in buf[1..HEALTH_REPORT_SIZE].chunks_exact_mut(4).zip(counters) {
+        bytes.copy_from_slice(&counter.to_le_bytes());
+    }
+    Some(HEALTH_REPORT_SIZE)
+}
+
+/// Keyboard report with a bit for each key, so that any number can be held at once.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NkroReport {
+    keys: [u8; NKRO_USAGES / 8],
+}
+
+impl NkroReport {
+    pub const fn released() -> Self {
+        NkroReport { keys: [0; NKRO_USAGES / 8] }
+    }
+
+    /// Hold down the key with keyboard `usage`, if it's one covered by this report.
+    pub fn press(&mut self, usage: u8) {
+        if (usage as usize) < NKRO_USAGES {
+            self.keys[usage as usize / 8] |= 1 << (usage % 8);
+        } else {
+            warn!("Key {} not in NKRO report", usage);
+        }
+    }
+}
+
+/// Caps Lock's bit in the keyboard LEDs output report
+const LED_CAPS_LOCK: u8 = 0x02;
+
+/// Whether the host has Caps Lock on, if `data` is a keyboard LEDs output report (led by its ID).
+pub fn caps_lock(data: &[u8]) -> Option<bool> {
+    match data {
+        [KEYBOARD_REPORT_ID, leds] => Some(leds & LED_CAPS_LOCK != 0),
+        _ => None,
+    }
+}
+
+/// The reports to send between keyboard reports `last` and `next`, in order, when both their
+/// modifiers and their keys differ, so that the modifier change doesn't arrive in the same report as
+/// any key.
+///
+/// Some hosts see a key pressed alongside Shift as unshifted (or released alongside Shift as still
+/// shifted), so modifiers go down before keys and come up after them. Keys being released go up
+/// first, with the old modifiers still held, then the modifiers change with only the keys common to
+/// both held, and only then (in `next`) are keys pressed. When one key is let go of as another is
+/// pressed, that's both reports.
+pub fn modifiers_ahead(last: &KeyboardReport, next: &KeyboardReport) -> heapless::Vec<KeyboardReport, 2> {
+    let mut between = heapless::Vec::new();
+    if last.modifier == next.modifier || last.keycodes == next.keycodes {
+        return between;
+    }
+    let is_held = |report: &KeyboardReport, keycode: u8| keycode != 0 && report.keycodes.contains(&keycode);
+    let releasing = last.keycodes.iter().any(|&keycode| is_held(last, keycode) && !is_held(next, keycode));
+    let pressing = next.keycodes.iter().any(|&keycode| is_held(next, keycode) && !is_held(last, keycode));
+
+    let mut keycodes = [0; 6];
+    for (slot, &keycode) in keycodes.iter_mut().zip(&last.keycodes) {
+        if is_held(next, keycode) {
+            *slot = keycode;
+        }
+    }
+    if releasing {
+        between.push(KeyboardReport { modifier: last.modifier, reserved: 0, leds: 0, keycodes }).ok();
+    }
+    if pressing {
+        between.push(KeyboardReport { modifier: next.modifier, reserved: 0, leds: 0, keycodes }).ok();
+    }
+    between
+}
+
+/// Whether keyboard report `next` releases a modifier held on its own in `last`, with no keys, as
+/// when a bare modifier is tapped: if `last` is never sent, the host never sees the tap at all.
+pub fn releases_modifier_tap(last: &KeyboardReport, next: &KeyboardReport) -> bool {
+    last.keycodes == [0; 6] && next.keycodes == [0; 6] && last.modifier & !next.modifier != 0
+}
+
+/// Whether keyboard report `next` is to be handed to usb after `last`, while usb has yet to take
+/// `last` if it's `pending`. Only changes are, as usb repeats reports itself when the host wants
+/// them, and a bare modifier tap's release waits for usb to take its press, rather than replacing
+/// it unsent.
+pub fn is_handed_over(last: &KeyboardReport, next: &KeyboardReport, pending: bool) -> bool {
+    next != last && !(pending && releases_modifier_tap(last, next))
+}
+
+/// Rearrange the keys held in keyboard report `next` so that those also held in `last` stay in the
+/// same slots, and the others take whichever slots are free, as some hosts take a key moving to
+/// another slot for it being pressed again.
+pub fn keep_slots(last: &KeyboardReport, next: &mut KeyboardReport) {
+    let mut keycodes = [0; 6];
+    for (slot, &keycode) in keycodes.iter_mut().zip(&last.keycodes) {
+        if keycode != 0 && next.keycodes.contains(&keycode) {
+            *slot = keycode;
+        }
+    }
+    for &keycode in &next.keycodes {
+        if keycode == 0 || keycodes.contains(&keycode) {
+            continue;
+        }
+        if let Some(slot) = keycodes.iter_mut().find(|slot| **slot == 0) {
+            *slot = keycode;
+        }
+    }
+    next.keycodes = keycodes;
+}
+
+/// Any of the input reports described by [REPORT_DESCRIPTOR].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(dead_code)]  // not every kind of report is produced by something yet
+pub enum OutgoingReport {
+    Keyboard(KeyboardReport),
+    Consumer(MediaKeyboardReport),
+    System(SystemControlReport),
+    Mouse(MouseReport),
+    Nkro(NkroReport),
+}
+
+/// One of each kind of report, with nothing pressed or moving, in order of [OutgoingReport::kind_index].
+pub const RELEASED_REPORTS: [OutgoingReport; REPORT_KINDS] = [
+    OutgoingReport::Keyboard(KeyboardReport::default()),
+    OutgoingReport::Consumer(MediaKeyboardReport { usage_id: 0 }),
+    OutgoingReport::System(SystemControlReport { usage_id: 0 }),
+    OutgoingReport::Mouse(MouseReport { buttons: 0, x: 0, y: 0, wheel: 0, pan: 0 }),
+    OutgoingReport::Nkro(NkroReport::released()),
+];
+
+impl OutgoingReport {
+    /// Index of this kind of report among [REPORT_KINDS].
+    pub const fn kind_index(&self) -> usize {
+        (self.id() - 1) as usize
+    }
+
+    /// Index among [REPORT_KINDS] of the input report with the given report ID, if there is one.
+    pub const fn kind_index_for_id(id: u8) -> Option<usize> {
+        match id {
+            KEYBOARD_REPORT_ID | CONSUMER_REPORT_ID | SYSTEM_REPORT_ID | MOUSE_REPORT_ID | NKRO_REPORT_ID => Some((id - 1) as usize),
+            _ => None,
+        }
+    }
+
+    /// The report ID this kind of report is sent with.
+    pub const fn id(&self) -> u8 {
+        match self {
+            OutgoingReport::Keyboard(_) => KEYBOARD_REPORT_ID,
+            OutgoingReport::Consumer(_) => CONSUMER_REPORT_ID,
+            OutgoingReport::System(_) => SYSTEM_REPORT_ID,
+            OutgoingReport::Mouse(_) => MOUSE_REPORT_ID,
+            OutgoingReport::Nkro(_) => NKRO_REPORT_ID,
+        }
+    }
+
+    /// Whether this report describes movement, so must be sent even if identical to the last one.
+    pub fn is_relative(&self) -> bool {
+        matches!(self, OutgoingReport::Mouse(mouse) if mouse.x != 0 || mouse.y != 0 || mouse.wheel != 0 || mouse.pan != 0)
+    }
+
+    /// Whether this report is to be written after `last`, the last of its kind written: only if it
+    /// changes something, describes movement, or is a `repeat` of `last` for the host.
+    pub fn is_to_be_written(&self, last: &OutgoingReport, repeat: bool) -> bool {
+        self != last || self.is_relative() || repeat
+    }
+
+    /// Write the report, prefixed by its report ID, into `buf`, returning the written part.
+    pub fn serialize<'b>(&self, buf: &'b mut [u8; MAX_INPUT_REPORT_SIZE]) -> &'b [u8] {
+        buf[0] = self.id();
+        let len = match self {
+            OutgoingReport::Keyboard(keyboard) => {
+                buf[1] = keyboard.modifier;
+                buf[2] = 0;
+                buf[3..9].copy_from_slice(&keyboard.keycodes);
+                9
+            },
+            OutgoingReport::Consumer(consumer) => {
+                buf[1..3].copy_from_slice(&consumer.usage_id.to_le_bytes());
+                3
+            },
+            OutgoingReport::System(system) => {
+                buf[1] = system.usage_id;
+                2
+            },
+            OutgoingReport::Mouse(mouse) => {
+                buf[1] = mouse.buttons;
+                buf[2] = mouse.x as u8;
+                buf[3] = mouse.y as u8;
+                buf[4] = mouse.wheel as u8;
+                buf[5] = mouse.pan as u8;
+                6
+            },
+            OutgoingReport::Nkro(nkro) => {
+                buf[1..1 + nkro.keys.len()].copy_from_slice(&nkro.keys);
+                1 + nkro.keys.len()
+            },
+        };
+        &buf[..len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEFT_CTRL: u8 = 0x01;
+    const LEFT_SHIFT: u8 = 0x02;
+    const A: u8 = 0x04;
+    const B: u8 = 0x05;
+    const C: u8 = 0x06;
+
+    /// A keyboard report holding `modifier` and `keys`, in the first slots
+    fn report(modifier: u8, keys: &[u8]) -> KeyboardReport {
+        let mut keycodes = [0; 6];
+        keycodes[..keys.len()].copy_from_slice(keys);
+        KeyboardReport { modifier, reserved: 0, leds: 0, keycodes }
+    }
+
+    #[test]
+    fn modifier_goes_down_before_the_key_pressed_with_it() {
+        let between = modifiers_ahead(&report(0, &[]), &report(LEFT_SHIFT, &[A]));
+        assert_eq!(between.as_slice(), [report(LEFT_SHIFT, &[])]);
+    }
+
+    #[test]
+    fn modifier_comes_up_after_the_key_released_with_it() {
+        let between = modifiers_ahead(&report(LEFT_SHIFT, &[A]), &report(0, &[]));
+        assert_eq!(between.as_slice(), [report(LEFT_SHIFT, &[])]);
+    }
+
+    #[test]
+    fn released_key_goes_up_before_modifier_changes_for_the_key_pressed() {
+        // Shift+A then B alone: A mustn't go up without Shift, nor B go down with it
+        let between = modifiers_ahead(&report(LEFT_SHIFT, &[A]), &report(0, &[B]));
+        assert_eq!(between.as_slice(), [report(LEFT_SHIFT, &[]), report(0, &[])]);
+
+        let between = modifiers_ahead(&report(0, &[A]), &report(LEFT_SHIFT, &[B]));
+        assert_eq!(between.as_slice(), [report(0, &[]), report(LEFT_SHIFT, &[])]);
+    }
+
+    #[test]
+    fn keys_held_throughout_stay_in_their_slots() {
+        let between = modifiers_ahead(&report(0, &[A, B]), &report(LEFT_SHIFT, &[B, C]));
+        assert_eq!(between.as_slice(), [report(0, &[0, B]), report(LEFT_SHIFT, &[0, B])]);
+    }
+
+    #[test]
+    fn nothing_between_unless_both_modifiers_and_keys_change() {
+        assert!(modifiers_ahead(&report(0, &[A]), &report(0, &[B])).is_empty());
+        assert!(modifiers_ahead(&report(0, &[A]), &report(LEFT_SHIFT, &[A])).is_empty());
+        // the same keys, only moved to other slots
+        assert!(modifiers_ahead(&report(0, &[A, B]), &report(LEFT_SHIFT, &[B, A])).is_empty());
+    }
+
+    /// [keep_slots] of `next` after `last`
+    fn kept(last: &KeyboardReport, mut next: KeyboardReport) -> KeyboardReport {
+        keep_slots(last, &mut next);
+        next
+    }
+
+    #[test]
+    fn keys_stay_in_their_slots_when_one_before_them_is_released() {
+        assert_eq!(kept(&report(0, &[A, B, C]), report(0, &[B, C])), report(0, &[0, B, C]));
+        assert_eq!(kept(&report(0, &[0, B, C]), report(0, &[C])), report(0, &[0, 0, C]));
+    }
+
+    #[test]
+    fn new_key_takes_the_first_free_slot() {
+        assert_eq!(kept(&report(0, &[0, B, C]), report(0, &[B, C, A])), report(0, &[A, B, C]));
+        assert_eq!(kept(&report(0, &[A, 0, C]), report(LEFT_SHIFT, &[A, C, B])), report(LEFT_SHIFT, &[A, B, C]));
+    }
+
+    #[test]
+    fn keys_composed_in_another_order_keep_their_slots() {
+        assert_eq!(kept(&report(0, &[A, B]), report(0, &[B, A])), report(0, &[A, B]));
+    }
+
+    #[test]
+    fn bare_modifier_tap_releases_the_modifier() {
+        assert!(releases_modifier_tap(&report(LEFT_SHIFT, &[]), &report(0, &[])));
+        assert!(releases_modifier_tap(&report(LEFT_SHIFT | LEFT_CTRL, &[]), &report(LEFT_CTRL, &[])));
+    }
+
+    #[test]
+    fn modifier_released_with_keys_or_modifier_pressed_is_no_tap() {
+        assert!(!releases_modifier_tap(&report(LEFT_SHIFT, &[A]), &report(0, &[A])));
+        assert!(!releases_modifier_tap(&report(LEFT_SHIFT, &[]), &report(0, &[A])));
+        assert!(!releases_modifier_tap(&report(0, &[]), &report(LEFT_SHIFT, &[])));
+        assert!(!releases_modifier_tap(&report(LEFT_SHIFT, &[]), &report(LEFT_SHIFT, &[])));
+    }
+
+    #[test]
+    fn only_changed_keyboard_reports_are_handed_over() {
+        assert!(!is_handed_over(&report(LEFT_SHIFT, &[A]), &report(LEFT_SHIFT, &[A]), false));
+        assert!(is_handed_over(&report(0, &[]), &report(LEFT_SHIFT, &[]), true));
+        assert!(is_handed_over(&report(LEFT_SHIFT, &[A]), &report(LEFT_SHIFT, &[]), true));
+    }
+
+    #[test]
+    fn bare_modifier_tap_is_released_only_once_its_press_is_taken() {
+        let (pressed, released) = (report(LEFT_SHIFT, &[]), report(0, &[]));
+        // handed over while nothing's pending, then waiting on usb to take it
+        assert!(is_handed_over(&released, &pressed, false));
+        assert!(!is_handed_over(&pressed, &released, true));
+        assert!(is_handed_over(&pressed, &released, false));
+    }
+
+    #[test]
+    fn bare_modifier_tap_is_written_pressed_then_released() {
+        let (pressed, released) = (OutgoingReport::Keyboard(report(LEFT_SHIFT, &[])), OutgoingReport::Keyboard(report(0, &[])));
+        assert!(pressed.is_to_be_written(&released, false));
+        assert!(released.is_to_be_written(&pressed, false));
+    }
+
+    #[test]
+    fn unchanged_report_is_written_only_as_a_repeat() {
+        let held = OutgoingReport::Keyboard(report(LEFT_SHIFT, &[]));
+        assert!(!held.is_to_be_written(&held, false));
+        assert!(held.is_to_be_written(&held, true));
+        // whereas the same movement again is more movement
+        let moved = OutgoingReport::Mouse(MouseReport { buttons: 0, x: 1, y: 0, wheel: 0, pan: 0 });
+        assert!(moved.is_to_be_written(&moved, false));
+    }
+}
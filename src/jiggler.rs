@@ -0,0 +1,30 @@
+//! Keeps the host awake by nudging the mouse pointer back and forth now and then, while enabled
+//! (from the function layer).
+
+use crate::{hid::OutgoingReport, RawMutex, REPORTS_CHANNEL};
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Ticker};
+use usbd_hid::descriptor::MouseReport;
+
+/// How often to move the pointer; should be well under the host's idle timeout.
+const JIGGLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether jiggling is switched on, toggled by [crate::scan].
+pub static ENABLED: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+const fn nudge(x: i8) -> OutgoingReport {
+    OutgoingReport::Mouse(MouseReport { buttons: 0, x, y: 0, wheel: 0, pan: 0 })
+}
+
+#[embassy_executor::task]
+pub async fn run() {
+    let mut ticker = Ticker::every(JIGGLE_INTERVAL);
+    loop {
+        ticker.next().await;
+        if ENABLED.lock(|enabled| enabled.get()) {
+            REPORTS_CHANNEL.send(nudge(1)).await;
+            REPORTS_CHANNEL.send(nudge(-1)).await;
+        }
+    }
+}
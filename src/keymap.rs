@@ -4,39 +4,192 @@
 //! Intimately related to [crate::scan], which uses these definitions to actually scan for and
 //! interpret physical key presses.
 
-use crate::rmk::keycode::KeyCode;
+pub use crate::boards::{COLUMNS, ROWS};
+use crate::rmk::keycode::{ConsumerKey, KeyCode};
 use crate::rmk::keycode::KeyCode::*;
-use crate::steno::{KeyCode as StenoKeyCode, PacketCode as StenoPacketCode};
+use crate::steno::KeyCode as StenoKeyCode;
 use core::marker::Copy;
+use embassy_time::Duration;
 
 pub type HidKeyCode = u8;
 pub type HidModifiers = u8;
-type HidKey = (HidKeyCode, HidModifiers);
+pub type HidKey = (HidKeyCode, HidModifiers);
+pub type HidConsumerUsage = u16;
 
 /// A Thing which a keypress should Do
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum Thing {
     RealKey(HidKey),
-    StenoKey(StenoPacketCode),
+    ConsumerKey(HidConsumerUsage),
+    StenoKey(StenoKeyCode),
     LeftSymbolKey,
     RightSymbolKey,
     NavKey,
     FunctionKey,
-    DvorakToggle,
+    /// Selects a layer while held, like the other layer keys, while also holding down modifiers,
+    /// e.g. to type the shifted keys of a layer without needing another layer for them
+    ModifiedLayer(LayerRef, HidModifiers),
+    /// Selects a layer while held, like the other layer keys, but types a key instead when tapped
+    /// on its own, e.g. for a thumb key to be both Space and a layer key
+    LayerTap(LayerRef, HidKey),
+    LayoutCycle,
+    /// Types in this layout while held, whichever is chosen, e.g. for someone else to type plain
+    /// QWERTY without Dvorak emulation having to be cycled off and back on
+    MomentaryLayout(Layout),
     StenoToggle,
+    PaperTapeToggle,
+    StenoProtocolCycle,
+    /// Switches steno keys between making strokes and playing [crate::midi] notes
+    MidiToggle,
+    Bootloader,
+    BacklightBrightness,
+    LedBrightness,
+    /// Steps through the volumes of the [crate::buzzer], down to muted
+    BuzzerVolume,
+    JigglerToggle,
+    /// Switches between sending keys over USB and over Bluetooth, with the [crate::ble] feature
+    OutputToggle,
+    LatencyTestToggle,
+    /// Writes a numbered line with the time since boot to the [crate::console], to mark a moment
+    /// (such as typing feeling laggy) to find in a log later
+    DebugMarker,
+    OsCycle,
+    /// Ignores every key, sending nothing at all, until [UNLOCK_CHORD] is held for [UNLOCK_HOLD], e.g.
+    /// while cleaning the keyboard or to keep a cat from typing
+    KeyboardLock,
+    /// Whichever key toggles the microphone's mute on the current [crate::os::Os]
+    MicMute,
+    /// Plays a [crate::macros] bytecode macro, once per press: keys typed in turn, held and
+    /// released, pauses and repeats
+    #[allow(dead_code)]  // not in the built-in keymap
+    Sequence(&'static [u8]),
+    /// Presses and releases the key over and over while held, once every so long (at least two
+    /// scans), e.g. for games or scrolling
+    #[allow(dead_code)]  // not in the built-in keymap
+    Turbo(HidKey, Duration),
+    TapHold(&'static TapHold),
     #[default]
     Inactive,
 }
 
-/// How many physical rows there are
-pub const ROWS: usize = 8;
-/// How many physical columns there are
-pub const COLUMNS: usize = 6;
+/// Something which does one [Thing] when tapped, or another when held down for long enough
+#[derive(Debug, PartialEq)]
+pub struct TapHold {
+    pub tap: Thing,
+    pub hold: Thing,
+    /// How long the key must be held before it counts as held rather than tapped
+    pub hold_after: Duration,
+    /// If `Some`, the key is always tapped when pressed within this long of the key before it, as
+    /// then it's most likely being typed in the middle of a word ("flow tap"). Suits home-row
+    /// modifiers, for which 150ms or so works well.
+    pub flow_tap_within: Option<Duration>,
+    pub flavour: TapHoldFlavour,
+}
+
+/// How a [TapHold] is decided when another key is pressed before its time is up
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(dead_code)]  // only some are chosen by the keymap
+pub enum TapHoldFlavour {
+    /// Held if the other key is pressed by the opposite hand, or tapped if by the same one (see
+    /// [crate::scan]'s chordal hold), suiting home-row modifiers
+    Chordal,
+    /// Held, whichever key it is, suiting thumb keys which pick a layer
+    HoldPreferred,
+    /// Not decided by other keys at all, only by time, suiting keys pressed in the middle of fast
+    /// typing (such as a pinky shift) which mustn't get held by mistake
+    TapPreferred,
+}
+
+impl Thing {
+    /// Whether this Thing selects a layer while held, and so must take effect before other keys
+    /// pressed at the same time are looked up.
+    pub const fn is_layer_key(&self) -> bool {
+        matches!(self, Thing::LeftSymbolKey | Thing::RightSymbolKey | Thing::NavKey | Thing::FunctionKey | Thing::ModifiedLayer(..) | Thing::LayerTap(..) | Thing::MomentaryLayout(_))
+    }
+
+    /// Whether this Thing is a bare modifier (as made by [k]), or is one when held.
+    pub const fn is_modifier(&self) -> bool {
+        match self {
+            Thing::RealKey((0, mods)) => *mods != 0,
+            Thing::TapHold(tap_hold) => tap_hold.hold.is_modifier(),
+            _ => false,
+        }
+    }
+
+    /// How long this Thing must be held down before it does anything, as one of the
+    /// [DELIBERATE_HOLDS], or else no time at all.
+    pub fn deliberate_hold(&self) -> Duration {
+        DELIBERATE_HOLDS.iter().find(|(thing, _)| thing == self).map_or(Duration::from_ticks(0), |&(_, hold)| hold)
+    }
+}
+
+/// One of the [LAYERS], as chosen by a [Thing::ModifiedLayer] or [Thing::LayerTap]. Compared by
+/// address, as a layer may well contain keys which refer back to itself.
+#[derive(Clone, Copy)]
+pub struct LayerRef(pub &'static Layer);
+
+impl PartialEq for LayerRef {
+    fn eq(&self, other: &Self) -> bool {
+        core::ptr::eq(self.0, other.0)
+    }
+}
+
+impl core::fmt::Debug for LayerRef {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "LayerRef({})", layer_index(self.0))
+    }
+}
+
+/// How many rows the full keymap has, out of which each board picks the ones it has (see
+/// [crate::boards])
+const KEYMAP_ROWS: usize = 8;
+/// How many columns the full keymap has
+const KEYMAP_COLUMNS: usize = 6;
+
+/// Which hand a key is pressed by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hand {
+    Left,
+    Right,
+}
+
+/// Which hand presses the keys in each physical row, e.g. for deciding [TapHold]s by
+/// "chordal hold" (whether the next key is pressed by the opposite hand)
+pub const ROW_HANDS: [Hand; ROWS] = {
+    const KEYMAP_ROW_HANDS: [Hand; KEYMAP_ROWS] = [
+        Hand::Left, Hand::Left, Hand::Left, Hand::Left,
+        Hand::Right, Hand::Right, Hand::Right, Hand::Right,
+    ];
+    let mut hands = [Hand::Left; ROWS];
+    let mut row = 0;
+    while row < ROWS {
+        hands[row] = KEYMAP_ROW_HANDS[crate::boards::KEYMAP_ROWS[row]];
+        row += 1;
+    }
+    hands
+};
 
 /// Array of [Thing]s that a row of keys do
 pub type Row = [Thing; COLUMNS];
 /// 2D Array of [Thing]s that the whole set of keys do
 pub type Layer = [Row; ROWS];
+/// [Layer] of the full keymap, before picking out the keys a board has
+type KeymapLayer = [[Thing; KEYMAP_COLUMNS]; KEYMAP_ROWS];
+
+/// Pick out the keys which this board has from a layer of the full keymap.
+const fn for_board(full: KeymapLayer) -> Layer {
+    let mut layer = [[Thing::Inactive; COLUMNS]; ROWS];
+    let mut row = 0;
+    while row < ROWS {
+        let mut column = 0;
+        while column < COLUMNS {
+            layer[row][column] = full[crate::boards::KEYMAP_ROWS[row]][crate::boards::KEYMAP_COLUMNS[column]];
+            column += 1;
+        }
+        row += 1;
+    }
+    layer
+}
 
 /// Maps a modifier [KeyCode] to the equivalent flag bit for the USB HID modifier byte, or returns
 /// 0 for any non-modifier [KeyCode].
@@ -58,7 +211,7 @@ const fn modifier_key_bit_repr(code: KeyCode) -> u8 {
 /// opposite direction from the right rows.
 ///
 /// Conventionally I'm using column 0 to mean "near the controller", and 5 "near the sides"
-const fn rev<A: Copy>(r: [A; COLUMNS]) -> [A; COLUMNS] {
+const fn rev<A: Copy>(r: [A; KEYMAP_COLUMNS]) -> [A; KEYMAP_COLUMNS] {
     [r[5], r[4], r[3], r[2], r[1], r[0]]
 }
 
@@ -80,10 +233,44 @@ const fn shift(kc: KeyCode) -> Thing {
     Thing::RealKey((code, mods | modifier_key_bit_repr(LShift)))
 }
 
+/// Add a modifier to a [Thing] made by [k] (or by [shift], or this), e.g. for shortcuts
+const fn with(modifier: KeyCode, thing: Thing) -> Thing {
+    let Thing::RealKey((code, mods)) = thing else { panic!("with() with abnormal thing") };
+    Thing::RealKey((code, mods | modifier_key_bit_repr(modifier)))
+}
+
+/// Take the [HidKey] out of a [Thing] made by [k] or [shift], to go in a [Thing::Sequence]'s [Step]
+const fn key(thing: Thing) -> HidKey {
+    let Thing::RealKey(key) = thing else { panic!("key() with abnormal thing") };
+    key
+}
+
+/// Add every left-hand modifier to a [Thing] made by [k], for a "Hyper" shortcut which nothing on
+/// the host is likely to use already
+const fn hyper(thing: Thing) -> Thing {
+    with(LCtrl, with(LShift, with(LAlt, with(LGui, thing))))
+}
+
+/// Translate a [ConsumerKey] into a valid [Thing], sent as a consumer control report
+const fn c(k: ConsumerKey) -> Thing {
+    Thing::ConsumerKey(k as u16)
+}
+
 const DFA: Thing = Thing::Inactive;
 
+// `FILE_` layers from the keymap file, if one is given (see `keymaps/example.toml`)
+include!(concat!(env!("OUT_DIR"), "/keymap_file.rs"));
+
+/// The layer given by the keymap file, if there is one, or else the one built in
+const fn from_file_or(file: Option<KeymapLayer>, built_in: KeymapLayer) -> KeymapLayer {
+    match file {
+        Some(layer) => layer,
+        None => built_in,
+    }
+}
+
 /// Regular layer for typing words
-pub const LAYER_NORMAL: Layer = [
+pub static LAYER_NORMAL: Layer = for_board(from_file_or(FILE_NORMAL, [
     rev([k(Tab), k(Q), k(W), k(E), k(R), k(T)]),
     rev([k(Backspace), k(A), k(S), k(D), k(F), k(G)]),
     rev([k(Escape), k(Z), k(X), k(C), k(V), k(B)]),
@@ -92,10 +279,10 @@ pub const LAYER_NORMAL: Layer = [
         [k(H), k(J), k(K), k(L), k(Semicolon), k(Quote)],
         [k(N), k(M), k(Comma), k(Dot), k(Slash), Thing::NavKey],
         [Thing::RightSymbolKey, k(Space), k(LGui), k(RCtrl), k(RAlt), k(RShift)],
-];
+]));
 
 /// Emulates dvorak layout on other people's computers configured for qwerty
-pub const LAYER_DVORAK_EMU: Layer = [
+pub static LAYER_DVORAK_EMU: Layer = for_board(from_file_or(FILE_DVORAK_EMU, [
     rev([k(Tab), k(Quote), k(Comma), k(Dot), k(P), k(Y)]),
     rev([k(Backspace), k(A), k(O), k(E), k(U), k(I)]),
     rev([k(Escape), k(Semicolon), k(Q), k(J), k(K), k(X)]),
@@ -104,10 +291,37 @@ pub const LAYER_DVORAK_EMU: Layer = [
         [k(D), k(H), k(T), k(N), k(S), k(Minus)],
         [k(B), k(M), k(W), k(V), k(Z), Thing::NavKey],
         [Thing::RightSymbolKey, k(Space), k(LGui), k(RCtrl), k(RAlt), k(RShift)],
-];
+]));
+
+/// Emulates Colemak-DH layout on computers configured for qwerty
+pub static LAYER_COLEMAK_DH_EMU: Layer = for_board(from_file_or(FILE_COLEMAK_DH_EMU, [
+    rev([k(Tab), k(Q), k(W), k(F), k(P), k(B)]),
+    rev([k(Backspace), k(A), k(R), k(S), k(T), k(G)]),
+    rev([k(Escape), k(Z), k(X), k(C), k(D), k(V)]),
+    rev([k(LShift), Thing::FunctionKey, k(RGui), k(LAlt), k(LCtrl), Thing::LeftSymbolKey]),
+        [k(J), k(L), k(U), k(Y), k(Semicolon), k(LeftBracket)],
+        [k(M), k(N), k(E), k(I), k(O), k(Quote)],
+        [k(K), k(H), k(Comma), k(Dot), k(Slash), Thing::NavKey],
+        [Thing::RightSymbolKey, k(Space), k(LGui), k(RCtrl), k(RAlt), k(RShift)],
+]));
+
+/// Emulates Workman layout on computers configured for qwerty
+pub static LAYER_WORKMAN_EMU: Layer = for_board(from_file_or(FILE_WORKMAN_EMU, [
+    rev([k(Tab), k(Q), k(D), k(R), k(W), k(B)]),
+    rev([k(Backspace), k(A), k(S), k(H), k(T), k(G)]),
+    rev([k(Escape), k(Z), k(X), k(M), k(C), k(V)]),
+    rev([k(LShift), Thing::FunctionKey, k(RGui), k(LAlt), k(LCtrl), Thing::LeftSymbolKey]),
+        [k(J), k(F), k(U), k(P), k(Semicolon), k(LeftBracket)],
+        [k(Y), k(N), k(E), k(O), k(I), k(Quote)],
+        [k(K), k(L), k(Comma), k(Dot), k(Slash), Thing::NavKey],
+        [Thing::RightSymbolKey, k(Space), k(LGui), k(RCtrl), k(RAlt), k(RShift)],
+]));
 
 /// Layer for typing numbers and symbols
-pub const LAYER_SYMBOLS: Layer = [
+pub static LAYER_SYMBOLS: Layer = for_board(SYMBOLS);
+
+/// [LAYER_SYMBOLS] as the keymap file gives it, if it does, for [LAYER_RIGHT_SYMBOLS] to start from
+const SYMBOLS: KeymapLayer = from_file_or(FILE_SYMBOLS, [
     rev([k(Grave), shift(Kc8), k(Kc9), k(Kc8), k(Kc7), shift(RightBracket)]),
     rev([k(Backspace), k(Backslash), k(Kc6), k(Kc5), k(Kc4), shift(Kc5)]),
     rev([shift(Kc2), k(Kc0), k(Kc3), k(Kc2), k(Kc1), k(Quote)]),
@@ -116,10 +330,10 @@ pub const LAYER_SYMBOLS: Layer = [
         [k(RightBracket), shift(Kc9), shift(Kc0), shift(Kc3), k(LeftBracket), k(Enter)],
         [DFA, shift(Minus), shift(Equal), shift(Grave), shift(Backslash), Thing::NavKey],
         [Thing::RightSymbolKey, k(Space), k(LGui), k(RCtrl), k(RAlt), k(RShift)],
-];
+]);
 
 /// Same, but with a couple of changes for dvorak emulation
-pub const LAYER_DVORAK_EMU_SYMBOLS: Layer = [
+pub static LAYER_DVORAK_EMU_SYMBOLS: Layer = for_board(from_file_or(FILE_DVORAK_EMU_SYMBOLS, [
     rev([k(Grave), shift(Kc8), k(Kc9), k(Kc8), k(Kc7), shift(Equal)]),
     rev([k(Backspace), k(Backslash), k(Kc6), k(Kc5), k(Kc4), shift(Kc5)]),
     rev([shift(Kc2), k(Kc0), k(Kc3), k(Kc2), k(Kc1), k(Minus)]),
@@ -128,10 +342,67 @@ pub const LAYER_DVORAK_EMU_SYMBOLS: Layer = [
         [k(Equal), shift(Kc9), shift(Kc0), shift(Kc3), k(Slash), k(Enter)],
         [DFA, shift(LeftBracket), shift(RightBracket), shift(Grave), shift(Backslash), Thing::NavKey],
         [Thing::RightSymbolKey, k(Space), k(LGui), k(RCtrl), k(RAlt), k(RShift)],
-];
+]));
+
+/// Layer held by the right symbol key, which is [LAYER_SYMBOLS] unless the keymap file gives the
+/// right hand a layer of its own (as `keymaps/example.toml` does, with a number pad)
+pub static LAYER_RIGHT_SYMBOLS: Layer = for_board(from_file_or(FILE_RIGHT_SYMBOLS, SYMBOLS));
+
+/// Letter layouts which can be typed in, cycled through by [Thing::LayoutCycle]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "debug-log", derive(defmt::Format))]
+pub enum Layout {
+    #[default]
+    Normal,
+    DvorakEmu,
+    ColemakDhEmu,
+    WorkmanEmu,
+}
+
+/// Which of [Thing::LeftSymbolKey] and [Thing::RightSymbolKey] are held
+#[derive(Clone, Copy, PartialEq)]
+pub enum SymbolKeys {
+    Left,
+    Right,
+    Both,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 4] = [Layout::Normal, Layout::DvorakEmu, Layout::ColemakDhEmu, Layout::WorkmanEmu];
+
+    pub const fn letters(self) -> &'static Layer {
+        match self {
+            Layout::Normal => &LAYER_NORMAL,
+            Layout::DvorakEmu => &LAYER_DVORAK_EMU,
+            Layout::ColemakDhEmu => &LAYER_COLEMAK_DH_EMU,
+            Layout::WorkmanEmu => &LAYER_WORKMAN_EMU,
+        }
+    }
+
+    /// Layer selected by holding the symbol keys `held`, to go with the letters: the navigation
+    /// layer for both, or for either, the symbols layer (as Dvorak moves some punctuation that
+    /// others don't), the right one's own if the keymap file gives it one.
+    pub const fn symbols(self, held: SymbolKeys) -> &'static Layer {
+        match (self, held) {
+            (_, SymbolKeys::Both) => &LAYER_NAVIGATION,
+            (Layout::DvorakEmu, SymbolKeys::Left | SymbolKeys::Right) => &LAYER_DVORAK_EMU_SYMBOLS,
+            (_, SymbolKeys::Left) => &LAYER_SYMBOLS,
+            (_, SymbolKeys::Right) => &LAYER_RIGHT_SYMBOLS,
+        }
+    }
+
+    /// Position in [Self::ALL], which is also how many times the LED blinks to show it
+    pub const fn index(self) -> usize {
+        self as usize
+    }
+
+    pub const fn next(self) -> Self {
+        Self::ALL[(self.index() + 1) % Self::ALL.len()]
+    }
+}
 
 /// Layer for F-keys, arrows and other "navigation" keys
-pub const LAYER_NAVIGATION: Layer = [
+pub static LAYER_NAVIGATION: Layer = for_board(from_file_or(FILE_NAVIGATION, [
     rev([k(F15), k(F12), k(F9), k(F8), k(F7), DFA]),
     rev([k(F14), k(F11), k(F6), k(F5), k(F4), DFA]),
     rev([k(F13), k(F10), k(F3), k(F2), k(F1), DFA]),
@@ -140,27 +411,259 @@ pub const LAYER_NAVIGATION: Layer = [
         [DFA, k(Left), k(Down), k(UP), k(Right), k(Enter)],
         [DFA, k(Home), k(PageDown), k(PageUp), k(End), Thing::NavKey],
         [Thing::RightSymbolKey, k(Space), k(LGui), k(RCtrl), k(RAlt), k(RShift)],
-];
+]));
 
 /// Layer for changing modes, and special keys like volume
-pub const LAYER_FUNCTION: Layer = [
-    rev([DFA, DFA, DFA, DFA, DFA, DFA]),
-    rev([DFA, DFA, DFA, DFA, DFA, DFA]),
-    rev([DFA, DFA, DFA, DFA, DFA, DFA]),
+pub static LAYER_FUNCTION: Layer = for_board(from_file_or(FILE_FUNCTION, [
+    rev([DFA, DFA, DFA, Thing::DebugMarker, Thing::OsCycle, Thing::LatencyTestToggle]),
+    rev([DFA, DFA, DFA, Thing::BuzzerVolume, Thing::LedBrightness, Thing::BacklightBrightness]),
+    rev([DFA, DFA, DFA, DFA, DFA, Thing::Bootloader]),
     rev([DFA, Thing::FunctionKey, DFA, DFA, DFA, Thing::LeftSymbolKey]),
-        [DFA, DFA, DFA, DFA, DFA, DFA],
-        [Thing::DvorakToggle, k(KbMute), k(KbVolumeDown), k(KbVolumeUp), Thing::StenoToggle, DFA],
-        [DFA, DFA, DFA, DFA, DFA, Thing::NavKey],
+        [Thing::MomentaryLayout(Layout::Normal), DFA, Thing::MidiToggle, Thing::StenoProtocolCycle, Thing::PaperTapeToggle, Thing::OutputToggle],
+        [Thing::LayoutCycle, c(ConsumerKey::Mute), c(ConsumerKey::VolumeDecrement), c(ConsumerKey::VolumeIncrement), Thing::StenoToggle, DFA],
+        [DFA, Thing::JigglerToggle, DFA, DFA, Thing::KeyboardLock, Thing::NavKey],
         [Thing::RightSymbolKey, DFA, DFA, DFA, DFA, DFA],
+]));
+
+/// What each footswitch does, like an extra row of keys
+pub type PedalRow = [Thing; 2];
+
+/// What each footswitch does, on most layers:
+/// 1. mic-mute when tapped, or toggles steno mode when held
+/// 2. navigation layer while held, like push-to-talk
+pub const PEDALS: PedalRow = [
+    Thing::TapHold(&TapHold {
+        tap: Thing::MicMute,
+        hold: Thing::StenoToggle,
+        hold_after: Duration::from_millis(500),
+        flow_tap_within: None,  // feet don't get caught up in typing
+        flavour: TapHoldFlavour::TapPreferred,  // keys are typed freely while a foot is down
+    }),
+    Thing::NavKey,
+];
+
+/// The footswitches on the navigation layer: the first pages down, e.g. for reading hands-free
+const PEDALS_NAVIGATION: PedalRow = [k(PageDown), Thing::NavKey];
+
+/// The footswitches in steno mode: the first is the number bar
+const PEDALS_STENO: PedalRow = [Thing::StenoKey(StenoKeyCode::Number), Thing::NavKey];
+
+/// Keys which, all held at once, release every key and reset all modes, in case any is stuck
+pub const CLEAR_CHORD: [Thing; 3] = [k(LShift), k(RShift), k(Escape)];
+
+/// Things which must be held down for so long before they do anything, so that a mode isn't
+/// changed (or the board reset) by brushing against the key
+pub const DELIBERATE_HOLDS: &[(Thing, Duration)] = &[
+    (Thing::StenoToggle, Duration::from_millis(400)),
+    (Thing::StenoProtocolCycle, Duration::from_millis(400)),
+    (Thing::KeyboardLock, Duration::from_millis(400)),
+    (Thing::Bootloader, Duration::from_secs(1)),
+];
+
+/// Keys which the keyboard itself repeats while held, when [crate::scan::TYPEMATIC] is on, for
+/// hosts (or KVMs) which don't repeat them well
+pub const REPEATING_KEYS: [Thing; 5] = [k(Left), k(Right), k(UP), k(Down), k(Backspace)];
+
+/// Layer keys which, all held at once for [STENO_CHORD_HOLD], switch steno mode on or off from
+/// any layer, for when the function layer's toggle is out of reach (on the full keyboard only, as
+/// the macropad has no right hand)
+pub const STENO_CHORD: [Thing; 3] = [Thing::LeftSymbolKey, Thing::RightSymbolKey, Thing::NavKey];
+pub const STENO_CHORD_HOLD: Duration = Duration::from_secs(1);
+
+/// Keys (on [LAYER_NORMAL]) which, all held at once for [UNLOCK_HOLD], undo [Thing::KeyboardLock]:
+/// the top corners, which nothing is likely to hold down together by accident
+pub const UNLOCK_CHORD: [Thing; 2] = [k(Tab), k(LeftBracket)];
+pub const UNLOCK_HOLD: Duration = Duration::from_secs(2);
+
+/// Keys (on [LAYER_NORMAL]) which, all held at once, switch the key tester on or off
+pub const KEY_TEST_CHORD: [Thing; 3] = [k(LShift), k(RShift), k(Tab)];
+
+/// Keys (on [LAYER_NORMAL]) which, all held at once, start remapping a key without any software
+/// on the host: the next key pressed is changed to do what the key pressed after it does
+pub const REMAP_CHORD: [Thing; 3] = [k(LShift), k(RShift), k(Backspace)];
+
+/// Keys (on [LAYER_NORMAL]) which, pressed together, do something else instead of typing, e.g. a
+/// shortcut for an application launcher
+pub struct Combo {
+    /// Two or more keys, none of which is typed if the combo happens
+    pub keys: &'static [Thing],
+    /// What the combo does, held until the first of its keys to be pressed is released
+    pub thing: Thing,
+    /// How long every key must be held together before the combo happens, so that a fast roll
+    /// over the same keys still types them
+    pub overlap: Duration,
+}
+
+/// How long after the first key of a [Combo] is pressed the rest may still be, before it's taken
+/// to be typing after all. Every key in any combo is held back for up to this long.
+pub const COMBO_TERM: Duration = Duration::from_millis(50);
+
+pub const COMBOS: &[Combo] = &[
+    // open a terminal
+    Combo { keys: &[k(A), k(S), k(D)], thing: with(LCtrl, with(LAlt, k(T))), overlap: Duration::from_millis(30) },
+];
+
+const _: () = {
+    let mut combo_idx = 0;
+    while combo_idx < COMBOS.len() {
+        assert!(COMBOS[combo_idx].keys.len() >= 2, "a combo needs more than one key");
+        combo_idx += 1;
+    }
+};
+
+/// A shortcut which the host gets as another instead, changed on the keyboard, e.g. for a machine
+/// on which nothing can be remapped. Both are as the host gets them, so after layout emulation,
+/// and each through the current [crate::os::Os] as usual.
+pub struct ChordRemap {
+    /// A key held with exactly these modifiers (on either side of the keyboard)
+    pub from: HidKey,
+    /// What's sent instead, with its modifiers replacing all those held
+    pub to: HidKey,
+}
+
+pub const CHORD_REMAPS: &[ChordRemap] = &[
+    // Vim-style arrows
+    ChordRemap { from: key(hyper(k(H))), to: key(k(Left)) },
+    ChordRemap { from: key(hyper(k(J))), to: key(k(Down)) },
+    ChordRemap { from: key(hyper(k(K))), to: key(k(UP)) },
+    ChordRemap { from: key(hyper(k(L))), to: key(k(Right)) },
 ];
 
+const _: () = {
+    let mut remap_idx = 0;
+    while remap_idx < CHORD_REMAPS.len() {
+        assert!(CHORD_REMAPS[remap_idx].from.0 != 0, "a chord remap needs a key, not just modifiers");
+        assert!(CHORD_REMAPS[remap_idx].from.1 != 0, "a chord remap needs modifiers, or it would change the key itself");
+        remap_idx += 1;
+    }
+};
+
+/// Keys typed (each pressed and released in turn) the first time the host configures the
+/// keyboard after power-up, e.g. to identify the machine. Empty to type nothing.
+pub const STARTUP_MACRO: &[Thing] = &[];
+
+/// Every layer, numbered by position (as they are to [crate::vial])
+pub static LAYERS: [&Layer; 10] = [
+    &LAYER_NORMAL, &LAYER_DVORAK_EMU, &LAYER_COLEMAK_DH_EMU, &LAYER_WORKMAN_EMU,
+    &LAYER_SYMBOLS, &LAYER_DVORAK_EMU_SYMBOLS, &LAYER_NAVIGATION, &LAYER_FUNCTION, &LAYER_STENO,
+    &LAYER_RIGHT_SYMBOLS,
+];
+
+/// What the footswitches do on each of the [LAYERS], in the same order, as if they were an extra row
+/// of each
+pub static LAYER_PEDALS: [PedalRow; LAYERS.len()] = [
+    PEDALS, PEDALS, PEDALS, PEDALS,
+    PEDALS, PEDALS, PEDALS_NAVIGATION, PEDALS, PEDALS_STENO,
+    PEDALS,
+];
+
+/// Layers whose keys count as pressed as soon as their switches are first seen closed, skipping
+/// debouncing on press (though not on release), for the lowest latency. Suits the steno layer, as a
+/// stroke already takes each key once however much it bounces.
+pub static IMMEDIATE_PRESS_LAYERS: [&Layer; 1] = [&LAYER_STENO];
+
+/// Position of `layer` in [LAYERS]. Layers are statics, so can be told apart by address.
+pub fn layer_index(layer: &Layer) -> usize {
+    LAYERS.iter().position(|&other| core::ptr::eq(other, layer)).expect("every layer is listed")
+}
+
+/// FNV-1a hash of everything written to it, for [keymap_checksum]
+struct Fnv1a(u32);
+
+impl core::fmt::Write for Fnv1a {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.0 = (self.0 ^ byte as u32).wrapping_mul(0x0100_0193);
+        }
+        Ok(())
+    }
+}
+
+/// Checksum of the keymap as built (not as remapped), to tell builds with different keymaps apart.
+pub fn keymap_checksum() -> u32 {
+    use core::fmt::Write;
+    let mut hash = Fnv1a(0x811C_9DC5);
+    for layer in LAYERS {
+        for thing in layer.iter().flatten() {
+            write!(hash, "{:?};", thing).ok();
+        }
+    }
+    write!(hash, "{:?}", LAYER_PEDALS).ok();
+    hash.0
+}
+
+/// Keys which must be somewhere in the keymap, or there would be no typing some things at all
+const REQUIRED_KEYS: [Thing; 5] = [k(Enter), k(Escape), k(Backspace), k(Tab), k(Space)];
+
+/// Whether `thing` types the same key (with or without modifiers) or steno key as `wanted`.
+const fn types_same(thing: Thing, wanted: Thing) -> bool {
+    match (thing, wanted) {
+        (Thing::RealKey((code, _)), Thing::RealKey((wanted_code, _))) => code == wanted_code,
+        (Thing::StenoKey(code), Thing::StenoKey(wanted_code)) => code as u8 == wanted_code as u8,
+        _ => false,
+    }
+}
+
+/// Whether `layer` has a key which [types_same] as `wanted`.
+const fn layer_has(layer: &Layer, wanted: Thing) -> bool {
+    let mut row = 0;
+    while row < ROWS {
+        let mut column = 0;
+        while column < COLUMNS {
+            if types_same(layer[row][column], wanted) {
+                return true;
+            }
+            column += 1;
+        }
+        row += 1;
+    }
+    false
+}
+
+// Catch mistakes in editing the layers before they're flashed: keys which do nothing on any layer,
+// and keys which can't be typed at all. Boards with only part of the keymap are let off the latter,
+// as they may well leave some keys out.
+const _: () = {
+    let mut row = 0;
+    while row < ROWS {
+        let mut column = 0;
+        while column < COLUMNS {
+            let mut layer_idx = 0;
+            while layer_idx < LAYERS.len() && matches!(LAYERS[layer_idx][row][column], Thing::Inactive) {
+                layer_idx += 1;
+            }
+            assert!(layer_idx < LAYERS.len(), "a key does nothing on any layer");
+            column += 1;
+        }
+        row += 1;
+    }
+
+    if ROWS == KEYMAP_ROWS && COLUMNS == KEYMAP_COLUMNS {
+        let mut key_idx = 0;
+        while key_idx < REQUIRED_KEYS.len() {
+            let mut layer_idx = 0;
+            while layer_idx < LAYERS.len() && !layer_has(LAYERS[layer_idx], REQUIRED_KEYS[key_idx]) {
+                layer_idx += 1;
+            }
+            assert!(layer_idx < LAYERS.len(), "a required key is on no layer");
+            key_idx += 1;
+        }
+
+        let mut code_idx = 0;
+        while code_idx < StenoKeyCode::ALL.len() {
+            assert!(layer_has(&LAYER_STENO, Thing::StenoKey(StenoKeyCode::ALL[code_idx])), "a steno key is missing from the steno layer");
+            code_idx += 1;
+        }
+    }
+};
+
 /// Translate a [StenoKeyCode] into a valid [Thing]
 macro_rules! st {
-    ($i:ident) => { Thing::StenoKey(StenoKeyCode::$i.to_packet_code()) }
+    ($i:ident) => { Thing::StenoKey(StenoKeyCode::$i) }
 }
 
 /// Layer for sending serial codes like a stenotype machine (Gemini PR protocol)
-pub const LAYER_STENO: Layer = [
+pub static LAYER_STENO: Layer = for_board([
     rev([DFA, st!(S1), st!(TL), st!(PL), st!(HL), st!(ST1)]),
     rev([DFA, st!(S2), st!(KL), st!(WL), st!(RL), st!(ST2)]),
     rev([DFA, DFA, DFA, DFA, DFA, DFA]),
@@ -169,4 +672,4 @@ pub const LAYER_STENO: Layer = [
         [st!(ST4), st!(RR), st!(BR), st!(GR), st!(SR), st!(ZR)],
         [DFA, DFA, DFA, DFA, DFA, Thing::NavKey],
         [Thing::RightSymbolKey, st!(E), st!(U), DFA, DFA, st!(Number)],
-];
+]);
@@ -0,0 +1,76 @@
+//! Diagnostic mode for measuring how long key presses take to reach the host, switched on from the
+//! function layer.
+//!
+//! [crate::scan] toggles a probe pin as soon as it reads a newly closed switch, so that it can be
+//! compared against the switch itself on a scope, and notes the time. [crate::usb] then writes to
+//! the [crate::console] how long it took from then until the report was handed to the HID writer
+//! (scanning and debouncing), and until the host took it (waiting for the host to poll).
+//!
+//! Steno strokes are timed the same way over the serial port, from the scan which finished the
+//! stroke, to tell whether the first stroke after a pause is any slower than the rest.
+
+use crate::console::ConsoleLine;
+use crate::RawMutex;
+use core::cell::Cell;
+use core::fmt::Write;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+
+/// Whether latency is being measured, toggled by [crate::scan].
+pub static ENABLED: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// When a switch was last seen newly closed, if no report has been sent for it yet
+static CLOSED_AT: Mutex<RawMutex, Cell<Option<Instant>>> = Mutex::new(Cell::new(None));
+
+/// When the steno stroke about to be written was finished, if it was noted
+static STROKE_TAKEN_AT: Mutex<RawMutex, Cell<Option<Instant>>> = Mutex::new(Cell::new(None));
+
+pub fn is_enabled() -> bool {
+    ENABLED.lock(|enabled| enabled.get())
+}
+
+/// Note that a switch was seen newly closed at `now`.
+pub fn key_closed(now: Instant) {
+    CLOSED_AT.lock(|closed_at| closed_at.set(Some(now)));
+}
+
+/// When the switch which the report about to be sent is for was closed, if it was noted.
+pub fn take_closed_at() -> Option<Instant> {
+    CLOSED_AT.lock(|closed_at| closed_at.take())
+}
+
+/// Describe the time from a switch being `closed_at` until its report was `handed_at` the HID
+/// writer, and then `sent_at`, followed by CRLF.
+pub fn describe(closed_at: Instant, handed_at: Instant, sent_at: Instant) -> ConsoleLine {
+    let mut line = ConsoleLine::new();
+    write!(
+        line,
+        "scan {}us, usb {}us\r\n",
+        (handed_at - closed_at).as_micros(),
+        (sent_at - handed_at).as_micros(),
+    ).expect("line fits");
+    line
+}
+
+/// Note that a steno stroke was finished at `now`.
+pub fn stroke_taken(now: Instant) {
+    STROKE_TAKEN_AT.lock(|taken_at| taken_at.set(Some(now)));
+}
+
+/// When the steno stroke about to be written was finished, if it was noted.
+pub fn take_stroke_taken_at() -> Option<Instant> {
+    STROKE_TAKEN_AT.lock(|taken_at| taken_at.take())
+}
+
+/// Describe the time from a steno stroke being `taken_at` until it was `handed_at` the serial port,
+/// and then `written_at`, followed by CRLF.
+pub fn describe_stroke(taken_at: Instant, handed_at: Instant, written_at: Instant) -> ConsoleLine {
+    let mut line = ConsoleLine::new();
+    write!(
+        line,
+        "stroke: queue {}us, cdc {}us\r\n",
+        (handed_at - taken_at).as_micros(),
+        (written_at - handed_at).as_micros(),
+    ).expect("line fits");
+    line
+}
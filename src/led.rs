@@ -0,0 +1,335 @@
+//! Drives the LEDs from their own task, so that they can blink and fade smoothly however quickly
+//! the matrix is being scanned. [crate::scan] sends [LedCommand]s whenever what they should show
+//! changes.
+//!
+//! With the `rgb-led` feature, the front (status) LED is a common-anode RGB one, showing each layer
+//! and mode in its own colour rather than at its own brightness.
+
+use crate::{power, settings, RawMutex};
+use core::cell::Cell;
+use embassy_rp::pwm::{Pwm, SetDutyCycle};
+use embassy_sync::{blocking_mutex::Mutex, channel::Channel};
+use embassy_time::{Duration, Instant, Ticker};
+
+/// Brightnesses (out of 256) which every LED duty is scaled by, cycled through by
+/// [crate::keymap::Thing::LedBrightness]. The last is "stealth", with the LEDs off altogether.
+pub const LED_BRIGHTNESS_LEVELS: [u32; 4] = [256, 64, 16, 0];
+
+/// How often the LEDs are updated
+const FRAME_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Scan LED duty while nothing has been typed for a while, just to show the firmware is running
+const SCAN_LED_IDLE: u16 = 400;
+/// Scan LED duty while typing as fast as [crate::scan] counts as fully active
+const SCAN_LED_ACTIVE: u16 = 30000;
+
+/// How the scan LED's brightness follows typing [ACTIVITY]
+#[allow(dead_code)]  // only one is chosen at a time
+enum ActivityCurve {
+    Linear,
+    /// Stays dim until typing gets going, then brightens quickly
+    Quadratic,
+    /// Brightens quickly with a little typing, then levels off
+    SquareRoot,
+}
+
+const ACTIVITY_CURVE: ActivityCurve = ActivityCurve::SquareRoot;
+
+/// How busy typing has been lately, from 0 (not at all) to `u16::MAX` (as fast as it's counted),
+/// kept up to date by [crate::scan] and shown as the scan LED's brightness
+pub static ACTIVITY: Mutex<RawMutex, Cell<u16>> = Mutex::new(Cell::new(0));
+
+/// How long the status LED lights up fully for a [LedCommand::Pulse]
+const PULSE_TIME: Duration = Duration::from_millis(80);
+
+/// How long each blink (and each gap between) lasts in a [Pattern::BlinkCount]
+const COUNT_BLINK_MS: u64 = 200;
+/// How often the blinks of a [Pattern::BlinkCount] repeat
+const COUNT_BLINK_PERIOD_MS: u64 = 2000;
+/// Most blinks a [Pattern::BlinkCount] can show while leaving a pause before they repeat
+pub const MAX_BLINK_COUNT: usize = (COUNT_BLINK_PERIOD_MS / COUNT_BLINK_MS / 2 - 1) as usize;
+
+/// How long each breath of a [Pattern::Breathe] takes, in and out
+const BREATHE_PERIOD_MS: u64 = 4000;
+
+/// How many separately driven colours the status LED has: red, green and blue, or just the one
+#[cfg(feature = "rgb-led")]
+pub const STATUS_CHANNELS: usize = 3;
+#[cfg(not(feature = "rgb-led"))]
+pub const STATUS_CHANNELS: usize = 1;
+
+/// Duty of each of the status LED's [STATUS_CHANNELS]
+#[derive(Clone, Copy, PartialEq)]
+pub struct Light([u16; STATUS_CHANNELS]);
+
+impl Light {
+    pub const OFF: Light = Light([0; STATUS_CHANNELS]);
+    /// As bright as it goes (white, on an RGB LED), to get attention
+    pub const FULL: Light = Light([u16::MAX; STATUS_CHANNELS]);
+
+    /// Lit at `duty`, or with the `rgb-led` feature, at the duties of `[red, green, blue]` instead.
+    pub const fn new(duty: u16, colour: [u16; 3]) -> Light {
+        #[cfg(feature = "rgb-led")]
+        { let _ = duty; Light(colour) }
+        #[cfg(not(feature = "rgb-led"))]
+        { let _ = colour; Light([duty]) }
+    }
+
+    /// Dimmed to `level` out of `u16::MAX`, channel by channel.
+    fn dimmed(self, level: u16) -> Light {
+        Light(self.0.map(|duty| (duty as u32 * level as u32 / u16::MAX as u32) as u16))
+    }
+
+    /// Eased halfway from `self` towards `target`, channel by channel.
+    fn ease_towards(self, target: Light) -> Light {
+        let mut eased = self;
+        for (duty, target) in eased.0.iter_mut().zip(target.0) {
+            *duty = if target > *duty { target - (target - *duty) / 2 } else { target + (*duty - target) / 2 };
+        }
+        eased
+    }
+}
+
+/// Something for the status LED to show, in a given [Light]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Pattern {
+    Off,
+    Steady(Light),
+    /// On, but dropping out briefly now and then
+    Flicker(Light),
+    /// On and off evenly, once a second
+    Blink(Light),
+    /// Blinks this many times, then pauses
+    BlinkCount(Light, u8),
+    /// Fades up and down, slowly
+    Breathe(Light),
+}
+
+/// A layer or mode shown on the status LED by [crate::scan], in order of precedence, each with an
+/// [Indicator] which may be changed from the console
+#[derive(Clone, Copy, PartialEq)]
+pub enum Indication {
+    Function,
+    Navigation,
+    Symbols,
+    PedalToggled,
+    Jiggler,
+    Steno,
+    /// A [crate::keymap::Layout] other than the normal one, blinking its number
+    Layout,
+}
+
+impl Indication {
+    pub const ALL: [Indication; 7] = [
+        Indication::Function, Indication::Navigation, Indication::Symbols, Indication::PedalToggled,
+        Indication::Jiggler, Indication::Steno, Indication::Layout,
+    ];
+
+    /// Name to pick it by on the console
+    pub const fn name(self) -> &'static str {
+        match self {
+            Indication::Function => "function",
+            Indication::Navigation => "navigation",
+            Indication::Symbols => "symbols",
+            Indication::PedalToggled => "pedal",
+            Indication::Jiggler => "jiggler",
+            Indication::Steno => "steno",
+            Indication::Layout => "layout",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|indication| indication.name() == name)
+    }
+}
+
+/// How an [Indicator] lights the status LED
+#[derive(Clone, Copy, PartialEq)]
+pub enum IndicatorStyle {
+    /// On, or flickering while the layer is locked
+    Steady,
+    /// Blinking, or counting out a number when there's one to show
+    Blink,
+    Breathe,
+}
+
+impl IndicatorStyle {
+    pub const ALL: [IndicatorStyle; 3] = [IndicatorStyle::Steady, IndicatorStyle::Blink, IndicatorStyle::Breathe];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            IndicatorStyle::Steady => "steady",
+            IndicatorStyle::Blink => "blink",
+            IndicatorStyle::Breathe => "breathe",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|style| style.name() == name)
+    }
+}
+
+/// What the status LED shows for an [Indication]: its brightness, or on an RGB LED, its colour
+#[derive(Clone, Copy, PartialEq)]
+pub struct Indicator {
+    pub style: IndicatorStyle,
+    pub duty: u16,
+    /// Duties of red, green and blue, with the `rgb-led` feature
+    pub colour: [u16; 3],
+}
+
+/// Length of an [Indicator] as saved in [crate::settings]: the style, then the duty and colour as
+/// little-endian `u16`s
+pub const INDICATOR_BYTES: usize = 1 + 2 * 4;
+
+impl Indicator {
+    const fn new(style: IndicatorStyle, duty: u16, colour: [u16; 3]) -> Self {
+        Indicator { style, duty, colour }
+    }
+
+    /// The pattern to show, blinking out `count` if it's blinking and there's a count to show, and
+    /// flickering if a steady layer is `locked`.
+    pub fn pattern(self, count: u8, locked: bool) -> Pattern {
+        let light = Light::new(self.duty, self.colour);
+        match self.style {
+            IndicatorStyle::Steady if locked => Pattern::Flicker(light),
+            IndicatorStyle::Steady => Pattern::Steady(light),
+            IndicatorStyle::Blink if count > 0 => Pattern::BlinkCount(light, count),
+            IndicatorStyle::Blink => Pattern::Blink(light),
+            IndicatorStyle::Breathe => Pattern::Breathe(light),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; INDICATOR_BYTES] {
+        let mut bytes = [0; INDICATOR_BYTES];
+        bytes[0] = self.style as u8;
+        for (chunk, duty) in bytes[1..].chunks_exact_mut(2).zip([self.duty, self.colour[0], self.colour[1], self.colour[2]]) {
+            chunk.copy_from_slice(&duty.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// The Indicator saved as `bytes` by [Self::to_bytes], or `None` if it isn't one.
+    pub fn from_bytes(bytes: &[u8; INDICATOR_BYTES]) -> Option<Self> {
+        let style = *IndicatorStyle::ALL.get(bytes[0] as usize)?;
+        let duty = |idx: usize| u16::from_le_bytes([bytes[1 + 2 * idx], bytes[2 + 2 * idx]]);
+        Some(Indicator { style, duty: duty(0), colour: [duty(1), duty(2), duty(3)] })
+    }
+}
+
+/// The [Indicator] of each of [Indication::ALL], in the same order, unless changed
+pub const DEFAULT_INDICATORS: [Indicator; Indication::ALL.len()] = [
+    Indicator::new(IndicatorStyle::Steady, 3400, [6000, 0, 0]),  // red
+    Indicator::new(IndicatorStyle::Steady, 1400, [0, 4000, 0]),  // green
+    Indicator::new(IndicatorStyle::Steady, 300, [0, 0, 6000]),  // blue
+    Indicator::new(IndicatorStyle::Steady, 2200, [4000, 4000, 0]),  // yellow
+    Indicator::new(IndicatorStyle::Blink, 5000, [4000, 2500, 0]),  // orange, so as not to be forgotten about
+    Indicator::new(IndicatorStyle::Steady, 5000, [4000, 0, 4000]),  // magenta
+    Indicator::new(IndicatorStyle::Blink, 5000, [0, 3000, 3000]),  // cyan
+];
+
+/// The [Indicator] for `indication`, as chosen in the [settings]
+pub fn indicator(indication: Indication) -> Indicator {
+    settings::get().indicators[indication as usize]
+}
+
+pub enum LedCommand {
+    /// Show this on the status LED from now on
+    Status(Pattern),
+    /// Light up the status LED briefly, over whatever it's showing
+    Pulse,
+}
+
+pub static LED_COMMANDS: Channel<RawMutex, LedCommand, 8> = Channel::new();
+
+/// Have the LED task show something, unless it has fallen so far behind that the command has to
+/// be dropped.
+pub fn send(command: LedCommand) {
+    if LED_COMMANDS.try_send(command).is_err() {
+        warn!("LED command dropped");
+    }
+}
+
+/// Scale `duty` by the [LED_BRIGHTNESS_LEVELS] chosen in the [settings], and dimmed further while
+/// the host hasn't granted all the current the board asked for (see [power::led_scale]).
+fn scale_led_duty(duty: u16) -> u16 {
+    let level = settings::get().led_brightness_level as usize;
+    let brightness = LED_BRIGHTNESS_LEVELS.get(level).copied().unwrap_or(LED_BRIGHTNESS_LEVELS[0]);
+    (duty as u32 * brightness / 256 * power::led_scale() / 256) as u16
+}
+
+/// Scan LED duty for typing [ACTIVITY] `activity`, following [ACTIVITY_CURVE].
+fn scan_led_duty(activity: u16) -> u16 {
+    let activity = activity as u32;
+    let level = match ACTIVITY_CURVE {
+        ActivityCurve::Linear => activity,
+        ActivityCurve::Quadratic => activity * activity / u16::MAX as u32,
+        ActivityCurve::SquareRoot => (activity * u16::MAX as u32).isqrt(),
+    };
+    SCAN_LED_IDLE + ((SCAN_LED_ACTIVE - SCAN_LED_IDLE) as u32 * level / u16::MAX as u32) as u16
+}
+
+impl Pattern {
+    /// Light to show at `now`, before scaling by brightness
+    fn light_at(self, now: Instant) -> Light {
+        let millis = now.as_millis();
+        match self {
+            Pattern::Off => Light::OFF,
+            Pattern::Steady(light) => light,
+            Pattern::Flicker(light) => if millis % 1000 < 150 { Light::OFF } else { light },
+            Pattern::Blink(light) => if millis % 1000 < 500 { light } else { Light::OFF },
+            Pattern::BlinkCount(light, count) => {
+                let blink = (millis % COUNT_BLINK_PERIOD_MS) / COUNT_BLINK_MS;
+                if blink < 2 * count as u64 && blink.is_multiple_of(2) { light } else { Light::OFF }
+            },
+            Pattern::Breathe(light) => {
+                // up and back down again in a triangle, which looks smooth enough once eased
+                let half = BREATHE_PERIOD_MS / 2;
+                let into_breath = millis % BREATHE_PERIOD_MS;
+                let rise = if into_breath < half { into_breath } else { BREATHE_PERIOD_MS - into_breath };
+                light.dimmed((rise * u16::MAX as u64 / half) as u16)
+            },
+        }
+    }
+}
+
+/// Drive the scan LED, where there is one (the Pico W's is on its radio, see [crate::ble]), and the
+/// status LED.
+#[embassy_executor::task]
+pub async fn run(mut scan_led: Option<Pwm<'static>>, mut status_led: [Pwm<'static>; STATUS_CHANNELS]) {
+    let mut status = Pattern::Off;
+    let mut status_light = Light::OFF;
+    let mut pulse_until = Instant::MIN;
+
+    let mut ticker = Ticker::every(FRAME_INTERVAL);
+    loop {
+        while let Ok(command) = LED_COMMANDS.try_receive() {
+            match command {
+                LedCommand::Status(pattern) => status = pattern,
+                LedCommand::Pulse => pulse_until = Instant::now() + PULSE_TIME,
+            }
+        }
+        let now = Instant::now();
+        let suspended = power::is_host_suspended();
+
+        if let Some(scan_led) = &mut scan_led {
+            let scan_duty = if suspended { 0 } else { scan_led_duty(ACTIVITY.lock(|activity| activity.get())) };
+            scan_led.set_duty_cycle(scale_led_duty(scan_duty)).expect("pwm");
+        }
+
+        // Eases halfway towards the pattern each frame, to soften changes.
+        let target = if suspended {
+            Light::OFF
+        } else if now < pulse_until {
+            Light::FULL
+        } else {
+            status.light_at(now)
+        };
+        status_light = status_light.ease_towards(target);
+        for (channel, duty) in status_led.iter_mut().zip(status_light.0) {
+            channel.set_duty_cycle(scale_led_duty(duty)).expect("pwm");
+        }
+
+        ticker.next().await;
+    }
+}
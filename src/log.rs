@@ -0,0 +1,62 @@
+//! Logging macros which forward to [defmt] when built with the `debug-log` feature, and otherwise
+//! compile to nothing (while still "using" their arguments, to avoid unused variable warnings).
+//!
+//! The level shown is chosen at build time by the `DEFMT_LOG` environment variable.
+
+#![allow(unused_macros)]
+
+#[cfg(feature = "debug-log")]
+use defmt_rtt as _;
+
+macro_rules! trace {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "debug-log")]
+        ::defmt::trace!($s $(, $x)*);
+        #[cfg(not(feature = "debug-log"))]
+        let _ = ($( & $x ),*);
+    }};
+}
+
+macro_rules! debug {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "debug-log")]
+        ::defmt::debug!($s $(, $x)*);
+        #[cfg(not(feature = "debug-log"))]
+        let _ = ($( & $x ),*);
+    }};
+}
+
+macro_rules! info {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "debug-log")]
+        ::defmt::info!($s $(, $x)*);
+        #[cfg(not(feature = "debug-log"))]
+        let _ = ($( & $x ),*);
+    }};
+}
+
+macro_rules! warn {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "debug-log")]
+        ::defmt::warn!($s $(, $x)*);
+        #[cfg(not(feature = "debug-log"))]
+        let _ = ($( & $x ),*);
+    }};
+}
+
+macro_rules! error {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "debug-log")]
+        ::defmt::error!($s $(, $x)*);
+        #[cfg(not(feature = "debug-log"))]
+        let _ = ($( & $x ),*);
+    }};
+}
+
+/// Log the panic message, then reset, as `panic_reset` would have done.
+#[cfg(feature = "debug-log")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    defmt::error!("{}", defmt::Display2Format(info));
+    cortex_m::peripheral::SCB::sys_reset();
+}
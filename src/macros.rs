@@ -0,0 +1,333 @@
+//! Macros typed by [crate::keymap::Thing::Sequence], kept as a compact bytecode of [Step]s (made
+//! by [bytecode!]) and played back by [play] one report at a time, so that keys can be held across
+//! other keys (as for Alt-Tab), typed with pauses between, or repeated.
+//!
+//! Each step is an opcode, then its operands:
+//! - [TAP] keycode, modifiers: press the key with its modifiers, and release it
+//! - [PRESS] keycode, modifiers: hold them down until released (the keycode 0 for only modifiers)
+//! - [RELEASE] keycode, modifiers: let go of them
+//! - [DELAY] milliseconds (little-endian `u16`): wait
+//! - [REPEAT] count, then the steps to repeat, then [END_REPEAT]
+//!
+//! Whatever is still held at the end is released, so a macro can never leave a key stuck down.
+
+use crate::hid::OutgoingReport;
+use crate::keymap::{HidKey, HidKeyCode};
+use crate::{settings, REPORTS_CHANNEL};
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use usbd_hid::descriptor::KeyboardReport;
+
+pub const TAP: u8 = 0x01;
+pub const PRESS: u8 = 0x02;
+pub const RELEASE: u8 = 0x03;
+pub const DELAY: u8 = 0x04;
+pub const REPEAT: u8 = 0x05;
+pub const END_REPEAT: u8 = 0x06;
+
+/// Most [Step::Repeat]s one can be inside at once
+const MAX_REPEAT_DEPTH: usize = 4;
+
+/// One step of a macro, as written in the keymap, before it's encoded
+#[derive(Clone, Copy)]
+#[allow(dead_code)]  // only used by keymaps with macros, which the built-in one has none of
+pub enum Step {
+    Tap(HidKey),
+    Press(HidKey),
+    Release(HidKey),
+    Delay(Duration),
+    /// Play the steps up to the matching [Step::EndRepeat] this many times
+    Repeat(u8),
+    EndRepeat,
+}
+
+#[allow(dead_code)]  // as for [Step]
+impl Step {
+    /// How many bytes this step takes in bytecode
+    const fn len(&self) -> usize {
+        match self {
+            Step::Tap(_) | Step::Press(_) | Step::Release(_) | Step::Delay(_) => 3,
+            Step::Repeat(_) => 2,
+            Step::EndRepeat => 1,
+        }
+    }
+}
+
+/// How many bytes `steps` take in bytecode
+#[allow(dead_code)]
+pub const fn encoded_len(steps: &[Step]) -> usize {
+    let (mut len, mut idx) = (0, 0);
+    while idx < steps.len() {
+        len += steps[idx].len();
+        idx += 1;
+    }
+    len
+}
+
+/// Encode `steps` as bytecode of [encoded_len] bytes, failing to compile if their repeats don't
+/// match up or nest too deeply.
+#[allow(dead_code)]
+pub const fn encode<const LEN: usize>(steps: &[Step]) -> [u8; LEN] {
+    let mut code = [0; LEN];
+    let (mut pos, mut idx, mut depth) = (0, 0, 0);
+    while idx < steps.len() {
+        match steps[idx] {
+            Step::Tap((keycode, mods)) => code = put(code, pos, [TAP, keycode, mods]),
+            Step::Press((keycode, mods)) => code = put(code, pos, [PRESS, keycode, mods]),
+            Step::Release((keycode, mods)) => code = put(code, pos, [RELEASE, keycode, mods]),
+            Step::Delay(time) => {
+                let millis = time.as_millis();
+                assert!(millis <= u16::MAX as u64, "a macro delay must be under a minute");
+                let [low, high] = (millis as u16).to_le_bytes();
+                code = put(code, pos, [DELAY, low, high]);
+            },
+            Step::Repeat(count) => {
+                depth += 1;
+                assert!(depth <= MAX_REPEAT_DEPTH, "macro repeats nested too deeply");
+                code[pos] = REPEAT;
+                code[pos + 1] = count;
+            },
+            Step::EndRepeat => {
+                assert!(depth > 0, "macro repeat ended without starting");
+                depth -= 1;
+                code[pos] = END_REPEAT;
+            },
+        }
+        pos += steps[idx].len();
+        idx += 1;
+    }
+    assert!(depth == 0, "macro repeat started without ending");
+    code
+}
+
+#[allow(dead_code)]
+const fn put<const LEN: usize>(mut code: [u8; LEN], pos: usize, bytes: [u8; 3]) -> [u8; LEN] {
+    code[pos] = bytes[0];
+    code[pos + 1] = bytes[1];
+    code[pos + 2] = bytes[2];
+    code
+}
+
+/// Encode a macro's [Step]s as bytecode, in a `&'static [u8]`, at compile time.
+#[allow(unused_macros)]
+macro_rules! bytecode {
+    ($($step:expr),* $(,)?) => {{
+        const STEPS: &[$crate::macros::Step] = &[$($step),*];
+        const CODE: [u8; $crate::macros::encoded_len(STEPS)] = $crate::macros::encode(STEPS);
+        &CODE
+    }};
+}
+#[allow(unused_imports)]
+pub(crate) use bytecode;
+
+/// Keys held by a macro while it plays, as a report
+struct Held(KeyboardReport);
+
+impl Held {
+    fn press(&mut self, (keycode, mods): HidKey) {
+        self.0.modifier |= mods;
+        if keycode != 0 && !self.0.keycodes.contains(&keycode) {
+            match self.0.keycodes.iter_mut().find(|slot| **slot == 0) {
+                Some(slot) => *slot = keycode,
+                None => warn!("Macro holding too many keys, not pressing {}", keycode),
+            }
+        }
+    }
+
+    fn release(&mut self, (keycode, mods): HidKey) {
+        self.0.modifier &= !mods;
+        for slot in self.0.keycodes.iter_mut().filter(|slot| **slot == keycode) {
+            *slot = 0;
+        }
+    }
+
+    async fn send(&self) {
+        REPORTS_CHANNEL.send(OutgoingReport::Keyboard(self.0)).await;
+    }
+}
+
+/// Type the macro in `code`, as encoded by [encode], with each key as on the current
+/// [crate::os::Os].
+pub async fn play(code: &[u8]) {
+    let os = settings::get().os;
+    let mut held = Held(KeyboardReport::default());
+    // for each repeat being played: where its steps start, and how many more times to play them
+    let mut repeats = Vec::<(usize, u8), MAX_REPEAT_DEPTH>::new();
+    let mut pos = 0;
+    while let Some(&opcode) = code.get(pos) {
+        let key = |pos: usize| -> Option<HidKey> {
+            Some(os.translate((*code.get(pos + 1)? as HidKeyCode, *code.get(pos + 2)?)))
+        };
+        match opcode {
+            TAP | PRESS | RELEASE => {
+                let Some(key) = key(pos) else { break };
+                match opcode {
+                    TAP => {
+                        held.press(key);
+                        held.send().await;
+                        held.release(key);
+                    },
+                    PRESS => held.press(key),
+                    _ => held.release(key),
+                }
+                held.send().await;
+                pos += 3;
+            },
+            DELAY => {
+                let Some(&[low, high]) = code.get(pos + 1..pos + 3) else { break };
+                Timer::after_millis(u16::from_le_bytes([low, high]).into()).await;
+                pos += 3;
+            },
+            REPEAT => {
+                let Some(&count) = code.get(pos + 1) else { break };
+                pos += 2;
+                if count == 0 {
+                    pos = skip_repeat(code, pos);
+                } else if repeats.push((pos, count - 1)).is_err() {
+                    break;
+                }
+            },
+            END_REPEAT => match repeats.last_mut() {
+                Some((start, left)) if *left > 0 => {
+                    *left -= 1;
+                    pos = *start;
+                },
+                Some(_) => {
+                    repeats.pop();
+                    pos += 1;
+                },
+                None => break,
+            },
+            _ => break,
+        }
+    }
+    if pos < code.len() {
+        warn!("Bad macro step at byte {}, stopped", pos);
+    }
+    if held.0 != KeyboardReport::default() {
+        Held(KeyboardReport::default()).send().await;
+    }
+}
+
+/// Where the steps after the repeat whose steps start at `pos` carry on, skipping over them.
+fn skip_repeat(code: &[u8], mut pos: usize) -> usize {
+    let mut depth = 1;
+    while let Some(&opcode) = code.get(pos) {
+        pos += match opcode {
+            TAP | PRESS | RELEASE | DELAY => 3,
+            REPEAT => {
+                depth += 1;
+                2
+            },
+            END_REPEAT => {
+                depth -= 1;
+                if depth == 0 {
+                    return pos + 1;
+                }
+                1
+            },
+            _ => return code.len(),
+        };
+    }
+    pos
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::{block_on, select::select};
+    use std::sync::Mutex as StdMutex;
+
+    /// Held while a test plays a macro, as they share [REPORTS_CHANNEL]
+    static CHANNEL: StdMutex<()> = StdMutex::new(());
+
+    const LEFT_SHIFT: u8 = 0x02;
+    const A: u8 = 0x04;
+    const B: u8 = 0x05;
+    const C: u8 = 0x06;
+    const D: u8 = 0x07;
+
+    /// Every report sent playing `code`, as the keycodes held and modifiers. None of the keys have
+    /// Alt or Gui, so they're the same on every [crate::os::Os].
+    fn played(code: &[u8]) -> std::vec::Vec<(u8, std::vec::Vec<u8>)> {
+        let _channel = CHANNEL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut reports = std::vec::Vec::new();
+        block_on(select(play(code), async {
+            loop {
+                reports.push(REPORTS_CHANNEL.receive().await);
+            }
+        }));
+        // the last report sent waits in the channel, as it's never received before play returns
+        while let Ok(report) = REPORTS_CHANNEL.try_receive() {
+            reports.push(report);
+        }
+        reports.into_iter().map(|report| match report {
+            OutgoingReport::Keyboard(keyboard) => (keyboard.modifier, keyboard.keycodes.into_iter().filter(|&keycode| keycode != 0).collect()),
+            _ => panic!("macros only send keyboard reports"),
+        }).collect()
+    }
+
+    /// The reports sent tapping each of `keys` on its own
+    fn taps(keys: &[u8]) -> std::vec::Vec<(u8, std::vec::Vec<u8>)> {
+        keys.iter().flat_map(|&key| [(0, vec![key]), (0, vec![])]).collect()
+    }
+
+    #[test]
+    fn nested_repeats_play_their_steps_each_time_round() {
+        let code = bytecode!(
+            Step::Repeat(2),
+                Step::Tap((A, 0)),
+                Step::Repeat(3),
+                    Step::Tap((B, 0)),
+                Step::EndRepeat,
+            Step::EndRepeat,
+            Step::Tap((C, 0)),
+        );
+        assert_eq!(played(code), taps(&[A, B, B, B, A, B, B, B, C]));
+    }
+
+    #[test]
+    fn repeat_of_zero_is_skipped_with_the_repeats_inside_it() {
+        let code = bytecode!(
+            Step::Tap((A, 0)),
+            Step::Repeat(0),
+                Step::Tap((B, 0)),
+                Step::Repeat(2),
+                    Step::Tap((C, 0)),
+                Step::EndRepeat,
+            Step::EndRepeat,
+            Step::Tap((D, 0)),
+        );
+        assert_eq!(played(code), taps(&[A, D]));
+    }
+
+    #[test]
+    fn keys_still_held_at_the_end_are_released() {
+        let code = bytecode!(Step::Press((0, LEFT_SHIFT)), Step::Press((A, 0)));
+        assert_eq!(played(code), [(LEFT_SHIFT, vec![]), (LEFT_SHIFT, vec![A]), (0, vec![])]);
+    }
+
+    #[test]
+    fn nothing_more_is_sent_at_the_end_if_nothing_is_held() {
+        let code = bytecode!(Step::Press((A, 0)), Step::Release((A, 0)));
+        assert_eq!(played(code), taps(&[A]));
+    }
+
+    #[test]
+    fn bad_bytecode_stops_the_macro_with_its_keys_released() {
+        let pressed_then_released = [(0, vec![A]), (0, vec![])];
+        // cut short in the middle of a step
+        assert_eq!(played(&[PRESS, A, 0, TAP, B]), pressed_then_released);
+        assert_eq!(played(&[PRESS, A, 0, DELAY, 1]), pressed_then_released);
+        assert_eq!(played(&[PRESS, A, 0, REPEAT]), pressed_then_released);
+        // not an opcode
+        assert_eq!(played(&[PRESS, A, 0, 0xff, B, 0]), pressed_then_released);
+        // a repeat ended without starting
+        assert_eq!(played(&[PRESS, A, 0, END_REPEAT, TAP, B, 0]), pressed_then_released);
+        // repeats nested too deeply
+        let mut code = vec![PRESS, A, 0];
+        code.extend([REPEAT, 1].repeat(MAX_REPEAT_DEPTH + 1));
+        code.extend([TAP, B, 0]);
+        assert_eq!(played(&code), pressed_then_released);
+    }
+}
@@ -1,75 +1,370 @@
 //! Firmware for a custom USB keyboard based on the Raspberry Pi Pico, using the [embassy_rp]
 //! framework.
 
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
+#[macro_use]
+mod log;
+#[macro_use]
+mod boards;
 mod scan;
 mod keymap;
 mod usb;
+mod hid;
 mod steno;
+mod console;
+mod drill;
+mod health;
+mod jiggler;
+mod latency;
+mod macros;
+mod led;
+mod midi;
+mod os;
+mod power;
+mod settings;
+mod selftest;
+mod stats;
+mod vial;
+mod voltage;
+#[cfg(feature = "backlight")]
+mod backlight;
+#[cfg(feature = "display")]
+mod display;
+#[cfg(feature = "buzzer")]
+mod buzzer;
+#[cfg(feature = "usb-host")]
+mod usb_host;
+#[cfg(feature = "split")]
+mod split;
+#[cfg(feature = "ble")]
+mod ble;
+
+#[cfg(all(feature = "buzzer", feature = "rgb-led"))]
+compile_error!("the buzzer and the RGB LED's blue channel are both on GP28");
+#[cfg(all(feature = "split", feature = "display"))]
+compile_error!("the split link and the display's SCL are both on GP1");
+#[cfg(all(feature = "ble", feature = "split"))]
+compile_error!("the split link and the Pico W's radio both use PIO0 and GP24");
+const _: () = assert!(
+    boards::STRAPS == 0 || !cfg!(any(feature = "rgb-led", feature = "buzzer")),
+    "the revision straps are on GP27 and GP28, which the RGB LED and the buzzer drive",
+);
 
 /// Useful constants (such as keycodes) extracted from the otherwise-unrelated [rmk](https://github.com/HaoboGu/rmk/) project.
 mod rmk;
 
 use embassy_executor::Spawner;
 use embassy_rp::{
-    gpio::{Input, OutputOpenDrain, Level, Pull},
+    gpio::{Flex, Input, Output, Level, Pull},
     pwm::Pwm,
 };
-use embassy_sync::channel::Channel;
+use embassy_sync::{channel::Channel, signal::Signal};
+use embassy_time::{Ticker, Timer};
+use heapless::Deque;
+use usbd_hid::descriptor::{KeyboardReport, MediaKeyboardReport};
 
+#[cfg(not(any(feature = "debug-log", test)))]
 use panic_reset as _;
 
 macro_rules! row_pins {
-    ($dev:ident; $($pin:ident),*) => {[ $(OutputOpenDrain::new($dev.$pin, Level::High)),* ]}
+    ($dev:ident; $($pin:ident),*) => {[ $(Flex::new($dev.$pin)),* ]}
 }
 macro_rules! column_pins {
-    ($dev:ident; $($pin:ident),*) => {[ $(Input::new($dev.$pin, Pull::Up)),* ]}
+    ($dev:ident; $($pin:ident),*) => {[ $(Flex::new($dev.$pin)),* ]}
 }
 
-/// Channel for [scan] to send keyboard updates to [usb], and ultimately to the host.
-pub(crate) static UPDATES_CHANNEL: Channel<RawMutex, Update, 1> = Channel::new();
+/// Channel for anything but [scan] to send HID reports to [usb], and ultimately to the host. Every
+/// report is sent, in order, so a slow host holds up whoever is sending.
+pub(crate) static REPORTS_CHANNEL: Channel<RawMutex, hid::OutgoingReport, 1> = Channel::new();
+/// The latest keyboard report from [scan], for [usb] to send. Never held up by a slow host, so as
+/// not to upset the timing of scans: if a report hasn't been taken by the time the next is
+/// signalled, it's replaced, so the host only sees the keys as they are now.
+pub(crate) static KEYBOARD_REPORT: Signal<RawMutex, KeyboardReport> = Signal::new();
+/// The latest consumer report from [scan], replaced like [KEYBOARD_REPORT]
+pub(crate) static CONSUMER_REPORT: Signal<RawMutex, MediaKeyboardReport> = Signal::new();
+/// Channel for [scan] to send steno strokes to [usb], apart from the reports so that a slow serial
+/// port can't hold up typing. Strokes are never dropped: [run_matrix] keeps those which don't fit
+/// until they do.
+pub(crate) static STROKES_CHANNEL: Channel<RawMutex, steno::GeminiPacket, 8> = Channel::new();
+#[cfg(not(test))]
 type RawMutex = embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-type Update = (usbd_hid::descriptor::KeyboardReport, steno::Packet);
+/// The tests run on the host, on threads of their own rather than in thread mode
+#[cfg(test)]
+type RawMutex = embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+/// How long to let the strap pins' pull-ups settle before reading them
+const STRAP_SETTLE_TIME: embassy_time::Duration = embassy_time::Duration::from_micros(10);
 
-#[embassy_executor::main]
+#[cfg_attr(not(test), embassy_executor::main)]
 async fn main(spawner: Spawner) {
-    let p = embassy_rp::init(Default::default());
+    #[cfg_attr(feature = "macropad", allow(unused_mut))] // only borrowed mutably for strap pins
+    let mut p = embassy_rp::init(Default::default());
+    info!("Starting up");
 
-    let led_pin_onboard = Pwm::new_output_b(p.PWM_SLICE4, p.PIN_25, Default::default());
-    let led_pin_front = Pwm::new_output_a(p.PWM_SLICE3, p.PIN_22, Default::default());
+    // read before anything else takes the strap pins
+    let revision = {
+        let straps: [Input; boards::STRAPS] = strap_pins!(p);
+        embassy_time::block_for(STRAP_SETTLE_TIME);
+        boards::revision(straps.each_ref().map(|strap| strap.is_low()))
+    };
+    info!("Board revision: {}", revision.name);
 
-    let pedal_pin = Input::new(p.PIN_2, Pull::Up);
+    #[cfg(not(feature = "ble"))]
+    let led_pin_onboard = Some(Pwm::new_output_b(p.PWM_SLICE4, p.PIN_25, Default::default()));
+    // on a Pico W, GP25 selects the radio instead, whose own LED shows the Bluetooth connection
+    #[cfg(feature = "ble")]
+    let led_pin_onboard = None;
+    #[cfg(not(feature = "rgb-led"))]
+    let led_pins_front = [Pwm::new_output_a(p.PWM_SLICE3, p.PIN_22, Default::default())];
+    // common anode, so each colour is lit while its pin is low
+    #[cfg(feature = "rgb-led")]
+    let led_pins_front = {
+        let (mut inverted_a, mut inverted_b) = (embassy_rp::pwm::Config::default(), embassy_rp::pwm::Config::default());
+        inverted_a.invert_a = true;
+        inverted_b.invert_b = true;
+        [
+            Pwm::new_output_a(p.PWM_SLICE3, p.PIN_22, inverted_a.clone()),
+            Pwm::new_output_b(p.PWM_SLICE5, p.PIN_27, inverted_b),
+            Pwm::new_output_a(p.PWM_SLICE6, p.PIN_28, inverted_a),
+        ]
+    };
 
-    let row_pins: [OutputOpenDrain; keymap::ROWS] = row_pins!(p;
-        PIN_10, PIN_11, PIN_12, PIN_13, PIN_21, PIN_20, PIN_19, PIN_18
-    );
-    let mut column_pins: [Input; keymap::COLUMNS] = column_pins!(p;
-        PIN_17, PIN_8, PIN_16, PIN_15, PIN_14, PIN_9
-    );
-    for pin in &mut column_pins {
-        pin.set_schmitt(true);
+    let pedal_pins = [Input::new(p.PIN_2, Pull::Up), Input::new(p.PIN_26, Pull::Up)];
+    let latency_probe_pin = Output::new(p.PIN_3, Level::Low);
+
+    let (row_pins, mut column_pins): ([Flex; keymap::ROWS], [Flex; keymap::COLUMNS]) = matrix_pins!(p);
+    let row_pins = {
+        let mut row_pins = row_pins.map(Some);
+        revision.row_order.map(|idx| row_pins[idx].take().expect("each row pin is used once"))
+    };
+    if revision.columns_reversed {
+        column_pins.reverse();
     }
+    let (mut strobe_pins, sense_pins) = scan::matrix_lines(row_pins, column_pins);
 
-    let matrix = scan::Matrix::new(scan::Pins {
-        scan_led: led_pin_onboard,
-        status_led: led_pin_front,
-        rows: row_pins,
-        columns: column_pins,
-        pedal: pedal_pin,
-    });
+    spawner.spawn(led::run(led_pin_onboard, led_pins_front)).expect("spawn led");
+
+    let mut flash = embassy_rp::flash::Flash::new_blocking(p.FLASH);
+    let settings_intact = settings::load(&mut flash);
+    let keymap_intact = settings::load_keymap(&mut flash);
+    let mut unique_id = [0; 8];
+    if let Err(e) = flash.blocking_unique_id(&mut unique_id) {
+        warn!("Failed to read flash unique ID: {:?}", e);
+    }
+    #[cfg(feature = "ble")]
+    let ble_address = ble::address(unique_id);
+    spawner.spawn(settings::run(flash)).expect("spawn settings");
+
+    // shown before anything else starts, so that the LED isn't wanted for anything else yet
+    let matrix_failure = selftest::check_matrix(&mut strobe_pins, &sense_pins);
+    let settings_failure = (!settings_intact).then_some(selftest::Failure::SettingsCorrupt);
+    let keymap_failure = (!keymap_intact).then_some(selftest::Failure::KeymapCorrupt);
+    selftest::report(matrix_failure.into_iter().chain(settings_failure).chain(keymap_failure)).await;
+
+    #[cfg(feature = "backlight")]
+    let backlight = backlight::Backlight::new(
+        embassy_rp::spi::Spi::new_blocking_txonly(p.SPI0, p.PIN_6, p.PIN_7, Default::default()),
+        Output::new(p.PIN_5, Level::Low),
+        Pwm::new_output_a(p.PWM_SLICE2, p.PIN_4, {
+            let mut config = embassy_rp::pwm::Config::default();
+            config.invert_a = true;
+            config
+        }),
+    );
+
+    let pins = scan::Pins {
+        strobes: strobe_pins,
+        senses: sense_pins,
+        pedals: pedal_pins,
+        latency_probe: latency_probe_pin,
+        #[cfg(feature = "backlight")]
+        backlight,
+    };
+    #[cfg(not(feature = "split"))]
+    let matrix = scan::Matrix::new(scan::WithSimulated(pins));
+    #[cfg(feature = "split")]
+    let matrix = {
+        let link = split::Link::new(p.PIO0, p.PIN_1);
+        if !split::is_primary(Input::new(p.PIN_24, Pull::Down)) {
+            info!("No USB, so the split secondary");
+            spawner.spawn(split::scan_secondary(pins)).expect("spawn secondary scan");
+            spawner.spawn(split::run_secondary(link)).expect("spawn split");
+            return;
+        }
+        spawner.spawn(split::run_primary(link)).expect("spawn split");
+        scan::Matrix::new(scan::WithSimulated(split::WithSecondary(pins)))
+    };
     spawner.spawn(run_matrix(matrix)).expect("spawn matrix");
 
+    let mut watchdog = embassy_rp::watchdog::Watchdog::new(p.WATCHDOG);
+    let max_power = power::max_power(&mut watchdog);
     let usb_driver = embassy_rp::usb::Driver::new(p.USB, usb::Irqs);
-    let (usb_device, hid, cdc) = usb::get_device(usb_driver);
-    spawner.spawn(usb::run(usb_device, hid, cdc)).expect("spawn usb");
+    let (usb_device, hid, cdc, console, raw_hid, midi) = usb::get_device(usb_driver, max_power, unique_id);
+    spawner.spawn(power::run(watchdog)).expect("spawn power");
+    spawner.spawn(usb::run(usb_device, hid, cdc, console)).expect("spawn usb");
+    spawner.spawn(vial::run(raw_hid)).expect("spawn vial");
+    spawner.spawn(midi::run(midi)).expect("spawn midi");
+    spawner.spawn(jiggler::run()).expect("spawn jiggler");
+    // GP29 is the Pico W radio's clock rather than VSYS
+    #[cfg(not(feature = "ble"))]
+    spawner.spawn(voltage::run(
+        embassy_rp::adc::Adc::new(p.ADC, voltage::Irqs, Default::default()),
+        embassy_rp::adc::Channel::new_pin(p.PIN_29, Pull::None),
+    )).expect("spawn voltage");
+
+    #[cfg(feature = "ble")]
+    {
+        let mut pio = embassy_rp::pio::Pio::new(p.PIO0, ble::Irqs);
+        let spi = cyw43_pio::PioSpi::new(
+            &mut pio.common, pio.sm0, cyw43_pio::DEFAULT_CLOCK_DIVIDER, pio.irq0,
+            Output::new(p.PIN_25, Level::High), p.PIN_24, p.PIN_29, p.DMA_CH0,
+        );
+        spawner.spawn(ble::run(Output::new(p.PIN_23, Level::Low), spi, ble_address)).expect("spawn ble");
+    }
+
+    #[cfg(feature = "display")]
+    spawner.spawn(display::run(embassy_rp::i2c::I2c::new_async(p.I2C0, p.PIN_1, p.PIN_0, display::Irqs, {
+        let mut config = embassy_rp::i2c::Config::default();
+        config.frequency = 400_000;
+        config
+    }))).expect("spawn display");
+
+    #[cfg(feature = "buzzer")]
+    spawner.spawn(buzzer::run(Pwm::new_output_a(p.PWM_SLICE6, p.PIN_28, Default::default()))).expect("spawn buzzer");
 }
 
+/// How many steno strokes [run_matrix] keeps while [STROKES_CHANNEL] is full, before it has to wait
+/// (holding up scanning) rather than drop any
+const BACKED_UP_STROKES_LIMIT: usize = 32;
+
+/// Steno strokes which didn't fit in [STROKES_CHANNEL] when scanned, oldest first
+type StrokeBacklog = Deque<steno::GeminiPacket, BACKED_UP_STROKES_LIMIT>;
+
+/// Where [run_matrix] reads the switches from
+#[cfg(not(feature = "split"))]
+type MatrixInput = scan::WithSimulated<scan::Pins<'static>>;
+#[cfg(feature = "split")]
+type MatrixInput = scan::WithSimulated<split::WithSecondary<scan::Pins<'static>>>;
+
 #[embassy_executor::task]
-async fn run_matrix(mut matrix: scan::Matrix<'static>) {
+async fn run_matrix(mut matrix: scan::Matrix<MatrixInput>) {
+    let mut ticker = Ticker::every(scan::SCAN_INTERVAL);
+    let mut last_reports = (KeyboardReport::default(), MediaKeyboardReport { usage_id: 0 });
+    let mut backed_up_strokes = StrokeBacklog::new();
     loop {
-        let (hid_report, steno_packet, _state) = matrix.scan();
-        UPDATES_CHANNEL.send((hid_report, steno_packet)).await;
+        ticker.next().await;
+        if power::is_host_suspended() {
+            Timer::after(scan::SUSPENDED_SCAN_INTERVAL).await;
+            ticker.reset();  // rather than catching up on the scans missed
+        }
+        #[cfg_attr(not(feature = "usb-host"), allow(unused_mut))]
+        let (mut keyboard_report, consumer_report, steno_packet, _state) = matrix.scan();
+        #[cfg(feature = "usb-host")]
+        usb_host::merge(&mut keyboard_report);
+        hand_over_reports(&mut last_reports, keyboard_report, consumer_report);
+        let stroke = (!steno_packet.is_empty() && !drill::take_stroke(steno_packet)).then_some(steno_packet);
+        hand_over_strokes(&mut backed_up_strokes, stroke).await;
+    }
+}
+
+/// Signal a scan's reports to [usb] through [KEYBOARD_REPORT] and [CONSUMER_REPORT], for those
+/// which have changed since the `last` signalled.
+fn hand_over_reports(last: &mut (KeyboardReport, MediaKeyboardReport), keyboard_report: KeyboardReport, consumer_report: MediaKeyboardReport) {
+    let (last_keyboard_report, last_consumer_report) = last;
+    if hid::is_handed_over(last_keyboard_report, &keyboard_report, KEYBOARD_REPORT.signaled()) {
+        KEYBOARD_REPORT.signal(keyboard_report);
+        *last_keyboard_report = keyboard_report;
+    }
+    if consumer_report != *last_consumer_report {
+        CONSUMER_REPORT.signal(consumer_report);
+        *last_consumer_report = consumer_report;
+    }
+}
+
+/// Send a scan's steno stroke (if any) to [usb] through [STROKES_CHANNEL], behind those in
+/// `backlog`, keeping any which don't fit in `backlog` for the next scan. Only once `backlog` is
+/// full does this wait for room in the channel, holding up scanning.
+async fn hand_over_strokes(backlog: &mut StrokeBacklog, stroke: Option<steno::GeminiPacket>) {
+    if let Some(stroke) = stroke {
+        if backlog.is_full() {
+            warn!("Steno strokes backed up, waiting for room");
+            health::count(&health::SCANS_HELD_UP);
+            let oldest = backlog.pop_front().expect("is full");
+            STROKES_CHANNEL.send(oldest).await;
+        }
+        backlog.push_back(stroke).ok();
+        health::note_peak(&health::STROKE_BACKLOG_PEAK, backlog.len());
+    }
+    while let Some(&oldest) = backlog.front() {
+        if STROKES_CHANNEL.try_send(oldest).is_err() {
+            break;
+        }
+        backlog.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::{block_on, join::join};
+
+    /// A keyboard report with `key` held
+    fn holding(key: u8) -> KeyboardReport {
+        KeyboardReport { modifier: 0, reserved: 0, leds: 0, keycodes: [key, 0, 0, 0, 0, 0] }
+    }
+
+    /// A stroke of its own for each `number`, of the keys of [steno::KeyCode::ALL] whose bits are
+    /// set in it
+    fn stroke(number: u32) -> steno::GeminiPacket {
+        let mut packet = steno::GeminiPacket::default();
+        for (idx, &code) in steno::KeyCode::ALL.iter().enumerate() {
+            if number & 1 << idx != 0 {
+                packet.press(code);
+            }
+        }
+        packet
+    }
+
+    #[test]
+    fn reports_not_yet_taken_are_replaced_by_the_latest() {
+        let mut last = (KeyboardReport::default(), MediaKeyboardReport { usage_id: 0 });
+        for (key, usage_id) in [(0x04, 0xe9), (0x05, 0xea), (0x06, 0xe2)] {
+            hand_over_reports(&mut last, holding(key), MediaKeyboardReport { usage_id });
+        }
+        assert!(block_on(usb::any_report()) == hid::OutgoingReport::Keyboard(holding(0x06)));
+        assert!(block_on(usb::any_report()) == hid::OutgoingReport::Consumer(MediaKeyboardReport { usage_id: 0xe2 }));
+        assert!(!KEYBOARD_REPORT.signaled() && !CONSUMER_REPORT.signaled(), "earlier reports left to send");
+
+        // nor is a report which hasn't changed signalled again
+        hand_over_reports(&mut last, holding(0x06), MediaKeyboardReport { usage_id: 0xe2 });
+        assert!(!KEYBOARD_REPORT.signaled() && !CONSUMER_REPORT.signaled());
+    }
+
+    #[test]
+    fn strokes_held_up_are_all_sent_in_order() {
+        let mut backlog = StrokeBacklog::new();
+        let held_up = STROKES_CHANNEL.capacity() + BACKED_UP_STROKES_LIMIT;
+        // while nothing is taken, strokes fill the channel then the backlog, without waiting
+        for number in 1..=held_up as u32 {
+            block_on(hand_over_strokes(&mut backlog, Some(stroke(number))));
+        }
+        assert!(STROKES_CHANNEL.is_full() && backlog.is_full());
+
+        // one more waits for room for the oldest
+        let mut sent = Vec::new();
+        block_on(join(hand_over_strokes(&mut backlog, Some(stroke(held_up as u32 + 1))), async {
+            sent.push(STROKES_CHANNEL.receive().await);
+        }));
+        // then the backlog drains over the next scans, as the strokes are taken
+        while !backlog.is_empty() || !STROKES_CHANNEL.is_empty() {
+            while let Ok(packet) = STROKES_CHANNEL.try_receive() {
+                sent.push(packet);
+            }
+            block_on(hand_over_strokes(&mut backlog, None));
+        }
+        let expected: Vec<_> = (1..=held_up as u32 + 1).map(stroke).collect();
+        assert!(sent == expected, "strokes dropped or out of order");
     }
 }
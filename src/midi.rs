@@ -0,0 +1,66 @@
+//! Plays a note over USB MIDI for each steno key held, instead of writing strokes, while switched on
+//! (from the function layer).
+
+use crate::steno::{GeminiPacket, KeyCode as StenoKeyCode};
+use crate::{usb, RawMutex};
+use core::cell::Cell;
+use embassy_sync::{blocking_mutex::Mutex, channel::Channel};
+
+/// Whether steno keys play notes rather than making strokes, toggled by [crate::scan].
+pub static ENABLED: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Note played by the first steno key, with the rest going up a semitone at a time in steno order
+/// (as in a Gemini PR packet), roughly from left to right.
+const LOWEST_NOTE: u8 = 36;
+const VELOCITY: u8 = 100;
+/// MIDI channel to play on, counting from 0
+const CHANNEL: u8 = 0;
+
+#[derive(Clone, Copy)]
+enum NoteEvent {
+    On(u8),
+    Off(u8),
+}
+
+impl NoteEvent {
+    /// The USB MIDI event packet for this event, on cable 0.
+    const fn to_usb_packet(self) -> [u8; 4] {
+        match self {
+            NoteEvent::On(note) => [0x09, 0x90 | CHANNEL, note, VELOCITY],
+            NoteEvent::Off(note) => [0x08, 0x80 | CHANNEL, note, 0],
+        }
+    }
+}
+
+/// Notes to be sent to the host by [run]
+static EVENTS: Channel<RawMutex, NoteEvent, 32> = Channel::new();
+
+const fn note_for(code: StenoKeyCode) -> u8 {
+    LOWEST_NOTE + code.to_packet_index()
+}
+
+/// Start the notes of steno keys held in `after` but not `before`, and stop the others which were.
+pub fn play_changes(before: &GeminiPacket, after: &GeminiPacket) {
+    for code in StenoKeyCode::ALL {
+        let event = match (before.contains(code), after.contains(code)) {
+            (false, true) => NoteEvent::On(note_for(code)),
+            (true, false) => NoteEvent::Off(note_for(code)),
+            _ => continue,
+        };
+        if EVENTS.try_send(event).is_err() {
+            warn!("Too many MIDI notes waiting, dropped one");
+        }
+    }
+}
+
+/// Send notes to the host as they're played.
+#[embassy_executor::task]
+pub async fn run(midi: usb::MyMidiClass) {
+    let (mut sender, _receiver) = midi.split();
+    loop {
+        let event = EVENTS.receive().await;
+        if sender.write_packet(&event.to_usb_packet()).await.is_err() {
+            sender.wait_connection().await;
+        }
+    }
+}
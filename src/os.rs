@@ -0,0 +1,68 @@
+//! Differences between the operating systems the keyboard gets plugged into, chosen from the
+//! function layer and kept in [crate::settings].
+
+use crate::keymap::{HidKey, HidKeyCode, HidModifiers};
+use crate::rmk::keycode::KeyCode;
+
+const MODIFIER_LALT: HidModifiers = 0x04;
+const MODIFIER_LGUI: HidModifiers = 0x08;
+
+const MIC_MUTE_KEY: HidKeyCode = 198;  // bodged in here as footswitch function
+    // F20 => Xf86AudioMicMute apparently? in theory...
+    // ...not that HID code 198 actually results in anything mapping to F20 or to Xf86AudioMicMute.
+    // however, 198 does map to keycode 248 in wayland (for whatever reason).
+    // so now i'm just using bindcode instead of bindsym in sway, which i guess is fine.
+
+#[derive(Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "debug-log", derive(defmt::Format))]
+pub enum Os {
+    #[default]
+    Linux,
+    Mac,
+    Windows,
+}
+
+impl Os {
+    pub const ALL: [Os; 3] = [Os::Linux, Os::Mac, Os::Windows];
+
+    pub const fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+
+    /// The Os saved as `byte` by [Self::to_byte], or `None` if it isn't one.
+    pub const fn from_byte(byte: u8) -> Option<Self> {
+        if (byte as usize) < Self::ALL.len() {
+            Some(Self::ALL[byte as usize])
+        } else {
+            None
+        }
+    }
+
+    pub const fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    /// Adjust a key from the keymap for this Os: on macOS, LAlt and LGui swap places, so that
+    /// Command sits where Alt does on other computers.
+    pub const fn translate(self, (keycode, modifiers): HidKey) -> HidKey {
+        match self {
+            Os::Mac => {
+                let swapped = modifiers & !(MODIFIER_LALT | MODIFIER_LGUI)
+                    | if modifiers & MODIFIER_LALT != 0 { MODIFIER_LGUI } else { 0 }
+                    | if modifiers & MODIFIER_LGUI != 0 { MODIFIER_LALT } else { 0 };
+                (keycode, swapped)
+            },
+            Os::Linux | Os::Windows => (keycode, modifiers),
+        }
+    }
+
+    /// Key which toggles the microphone's mute, as no standard one is recognised everywhere.
+    /// Not passed through [Self::translate].
+    pub const fn mic_mute(self) -> HidKey {
+        match self {
+            Os::Linux => (MIC_MUTE_KEY, 0),
+            Os::Mac => (KeyCode::F20 as u16 as u8, 0),  // to be bound in System Settings
+            Os::Windows => (KeyCode::K as u16 as u8, MODIFIER_LGUI | MODIFIER_LALT),  // Win+Alt+K
+        }
+    }
+}
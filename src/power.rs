@@ -0,0 +1,134 @@
+//! Saves power when plugged into something which only gives power (such as a power bank) rather
+//! than a host which enumerates the keyboard: once no host has configured it for
+//! [UNCONFIGURED_TIMEOUT], [crate::scan] puts the RP2040 into its dormant state, with every clock
+//! stopped, until a key or pedal is pressed.
+//!
+//! While the host is suspended, every LED is kept off and the matrix is only scanned slowly, for a
+//! key press to wake the host with.
+//!
+//! Boards which ask for more current than [LOW_POWER_MA] (see [crate::boards]) dim their LEDs
+//! whenever they haven't been granted it. A host (or bus-powered hub) without the current to spare
+//! leaves the device unconfigured, so after [POWER_REFUSED_TIMEOUT] of that, [run] resets the board
+//! to ask for only [LOW_POWER_MA] instead, which it keeps asking for until it's unplugged.
+//!
+//! [crate::usb] tells this module whenever the host configures (or stops configuring) the device,
+//! and whenever it suspends or resumes.
+
+use crate::{boards, voltage, RawMutex};
+use core::cell::Cell;
+use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Ticker};
+
+/// How long to wait for a host to configure the device before going dormant, long enough for a
+/// slow host to get round to it after boot or after a key wakes the board.
+const UNCONFIGURED_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Since when no host has had the device configured, if none has now. Starts from boot.
+static UNCONFIGURED_SINCE: Mutex<RawMutex, Cell<Option<Instant>>> = Mutex::new(Cell::new(Some(Instant::from_ticks(0))));
+
+/// Current (in mA) which any USB device may draw, before it's configured or from any host
+pub const LOW_POWER_MA: u16 = 100;
+
+/// How long a host may leave the device addressed but not configured, while it asks for more than
+/// [LOW_POWER_MA], before it's taken that the host hasn't the current to spare
+const POWER_REFUSED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Watchdog scratch register which holds [LOW_POWER_MAGIC] across the reset by [run], as the
+/// watchdog's scratch registers are only cleared by power-on
+const LOW_POWER_SCRATCH: usize = 0;
+const LOW_POWER_MAGIC: u32 = 0x4c4f_5750;  // "LOWP"
+
+/// Current (in mA) the device asks the host for, as chosen by [max_power] at boot
+static MAX_POWER: Mutex<RawMutex, Cell<u16>> = Mutex::new(Cell::new(LOW_POWER_MA));
+
+/// Since when the host has had the device addressed without configuring it, if it has now
+static ADDRESSED_SINCE: Mutex<RawMutex, Cell<Option<Instant>>> = Mutex::new(Cell::new(None));
+
+/// Whether the host has suspended the bus, as when it's asleep
+static HOST_SUSPENDED: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Current (in mA) to ask the host for: the board's [boards::USB_MAX_POWER_MA], unless a host
+/// refused that since the board was plugged in.
+pub fn max_power(watchdog: &mut Watchdog) -> u16 {
+    let refused = watchdog.get_scratch(LOW_POWER_SCRATCH) == LOW_POWER_MAGIC;
+    let max_power = if refused { LOW_POWER_MA } else { boards::USB_MAX_POWER_MA };
+    if refused {
+        warn!("Host refused {}mA before reset, asking for {}mA", boards::USB_MAX_POWER_MA, max_power);
+    }
+    MAX_POWER.lock(|power| power.set(max_power));
+    max_power
+}
+
+/// Note that the host has given the device an address, as of `now`, after which it should soon
+/// configure it if it has the current to spare.
+pub fn set_addressed(now: Instant) {
+    ADDRESSED_SINCE.lock(|since| since.set(Some(now)));
+}
+
+/// Current (in mA) the board may draw now: as much as it asked for once configured, or else only
+/// [LOW_POWER_MA].
+pub fn granted_power() -> u16 {
+    let configured = UNCONFIGURED_SINCE.lock(|since| since.get()).is_none();
+    if configured { MAX_POWER.lock(|power| power.get()) } else { LOW_POWER_MA }
+}
+
+/// How much (out of 256) LEDs are dimmed by, in proportion to how much of the board's
+/// [boards::USB_MAX_POWER_MA] has been granted, so as to stay within it, and further while the
+/// supply sags (see [voltage::led_scale])
+pub fn led_scale() -> u32 {
+    (granted_power() as u32 * 256 / boards::USB_MAX_POWER_MA as u32).min(256) * voltage::led_scale() / 256
+}
+
+/// Note whether the host has the device configured, as of `now`.
+pub fn set_configured(configured: bool, now: Instant) {
+    ADDRESSED_SINCE.lock(|since| since.set(None));
+    UNCONFIGURED_SINCE.lock(|since| match (configured, since.get()) {
+        (true, _) => since.set(None),
+        (false, None) => since.set(Some(now)),
+        (false, Some(_)) => {},
+    });
+}
+
+/// Whether it's time to go dormant, as of `now`.
+pub fn should_sleep(now: Instant) -> bool {
+    UNCONFIGURED_SINCE.lock(|since| since.get()).is_some_and(|since| now - since >= UNCONFIGURED_TIMEOUT)
+}
+
+/// Note that the board has just woken up at `now`, giving the host another [UNCONFIGURED_TIMEOUT]
+/// to configure it. The clocks having stopped, no time passed while dormant.
+pub fn woken(now: Instant) {
+    info!("Woken from dormant");
+    UNCONFIGURED_SINCE.lock(|since| if since.get().is_some() {
+        since.set(Some(now));
+    });
+}
+
+/// Note whether the host has suspended the bus.
+pub fn set_host_suspended(suspended: bool) {
+    HOST_SUSPENDED.lock(|host_suspended| host_suspended.set(suspended));
+}
+
+/// Whether the host has suspended the bus, so that the LEDs should be off.
+pub fn is_host_suspended() -> bool {
+    HOST_SUSPENDED.lock(|host_suspended| host_suspended.get())
+}
+
+/// Reset the board to ask for only [LOW_POWER_MA] if the host doesn't configure it while it asks
+/// for more, as the host hasn't the current to spare.
+#[embassy_executor::task]
+pub async fn run(mut watchdog: Watchdog) {
+    if MAX_POWER.lock(|power| power.get()) <= LOW_POWER_MA {
+        return;
+    }
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    loop {
+        ticker.next().await;
+        let refused = ADDRESSED_SINCE.lock(|since| since.get()).is_some_and(|since| Instant::now() - since >= POWER_REFUSED_TIMEOUT);
+        if refused {
+            warn!("Not configured {}s after being addressed, resetting to ask for {}mA", POWER_REFUSED_TIMEOUT.as_secs(), LOW_POWER_MA);
+            watchdog.set_scratch(LOW_POWER_SCRATCH, LOW_POWER_MAGIC);
+            watchdog.trigger_reset();
+        }
+    }
+}
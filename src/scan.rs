@@ -2,18 +2,23 @@
 //! related to typing. Uses definitions from [crate::keymap], and directly produces packets to be
 //! sent out by [crate::usb].
 
+use crate::boards::{self, ActiveLevel, DiodeDirection, SENSES, STROBES};
+use crate::console::{self, ConsoleLine};
 use crate::keymap::*;
-use crate::steno::Packet as StenoPacket;
+use crate::led::{self, Indication, LedCommand, Light, Pattern};
+use crate::steno::{self, GeminiPacket};
+use crate::{health, hid, jiggler, latency, midi, os::Os, power, settings, stats, usb, vial, RawMutex};
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
 use core::mem::take;
-use embassy_rp::{
-    gpio::{Input, OutputOpenDrain},
-    pwm::{Pwm, SetDutyCycle},
-};
+use embassy_rp::gpio::{DormantWake, DormantWakeConfig, Flex, Input, Level, Output, Pull};
+use embassy_sync::{blocking_mutex::Mutex, pubsub::PubSubChannel};
 use embassy_time::{
     block_for,
     Duration,
+    Instant,
 };
-use usbd_hid::descriptor::KeyboardReport;
+use usbd_hid::descriptor::{KeyboardReport, MediaKeyboardReport};
 
 #[derive(Clone, Copy, Default)]
 pub struct MatrixState {
@@ -21,226 +26,1727 @@ pub struct MatrixState {
     right_symbol_key: bool,
     nav_key: bool,
     function_key: bool,
-    emulating_dvorak: bool,
+    /// Layer and modifiers of the [Thing::ModifiedLayer] key held, if any
+    modified_layer: Option<(LayerRef, HidModifiers)>,
+    /// Layer of the [Thing::LayerTap] key held, if any
+    layer_tap: Option<LayerRef>,
+    layout: Layout,
+    /// Layout of the [Thing::MomentaryLayout] key held, if any, typed in instead of [Self::layout]
+    momentary_layout: Option<Layout>,
     stenotype: bool,
+    /// Whether any pedal is toggled on, in [PedalMode::Toggle]
+    pedal_toggled: bool,
+    /// Whether any key held is waiting to be released before it can act again (a toggle, or a steno
+    /// chord), or everything is, after [Interpreter::clear_stuck_keys]
     awaiting_clear: bool,
+    /// Layer key whose layer stays selected after being double-tapped, until it is pressed again
+    locked_layer_key: Option<LockableLayerKey>,
+}
+
+/// Layer keys which can be locked on by double-tapping them
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "debug-log", derive(defmt::Format))]
+enum LockableLayerKey {
+    LeftSymbol,
+    Nav,
+    Function,
+}
+
+impl LockableLayerKey {
+    const ALL: [LockableLayerKey; 3] = [LockableLayerKey::LeftSymbol, LockableLayerKey::Nav, LockableLayerKey::Function];
+}
+
+impl MatrixState {
+    fn is_held(&self, layer_key: LockableLayerKey) -> bool {
+        match layer_key {
+            LockableLayerKey::LeftSymbol => self.left_symbol_key,
+            LockableLayerKey::Nav => self.nav_key,
+            LockableLayerKey::Function => self.function_key,
+        }
+    }
+
+    /// Which symbol keys are held, if any.
+    fn symbol_keys(&self) -> Option<SymbolKeys> {
+        match (self.left_symbol_key, self.right_symbol_key) {
+            (true, true) => Some(SymbolKeys::Both),
+            (true, false) => Some(SymbolKeys::Left),
+            (false, true) => Some(SymbolKeys::Right),
+            (false, false) => None,
+        }
+    }
+
+    /// The layout being typed in, taking any [Thing::MomentaryLayout] key held into account.
+    fn typing_layout(&self) -> Layout {
+        self.momentary_layout.unwrap_or(self.layout)
+    }
+
+    /// The state with any locked layer key treated as if it were held.
+    fn with_lock(&self) -> MatrixState {
+        let mut state = *self;
+        match self.locked_layer_key {
+            Some(LockableLayerKey::LeftSymbol) => state.left_symbol_key = true,
+            Some(LockableLayerKey::Nav) => state.nav_key = true,
+            Some(LockableLayerKey::Function) => state.function_key = true,
+            None => {},
+        }
+        state
+    }
+}
+
+/// Whether the key tester is on, in which each switch pressed is described on the [console]
+/// instead of typing anything. Switched by [KEY_TEST_CHORD], or from the console.
+pub static KEY_TEST: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Whether every key is being ignored, since [Thing::KeyboardLock] was pressed, until
+/// [UNLOCK_CHORD] is held.
+pub static LOCKED: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// How the status LED breathes while [LOCKED]: dimly, at under a tenth of full brightness, so as
+/// not to distract while nothing is typed, and on an RGB LED in a dim white, unlike the full white
+/// shown while remapping.
+const LOCKED_LIGHT: Light = Light::new(6000, [3000, 3000, 3000]);
+
+/// Whether tapping a layer key (pressing and releasing it quickly, with no other key in between)
+/// writes out what each key does on its layer to the [console], for relearning it. Switched from
+/// the console.
+pub static LAYER_PREVIEW: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Longest a layer key can be held for its release to count as a tap, for [LAYER_PREVIEW]
+const LAYER_PREVIEW_TAP: Duration = Duration::from_millis(250);
+
+/// How long without a key being pressed before steno mode and any emulated layout are left, in case
+/// they've been forgotten about. `None` to never leave them. Adjustable from the console.
+pub static MODE_IDLE_TIMEOUT: Mutex<RawMutex, Cell<Option<Duration>>> = Mutex::new(Cell::new(Some(Duration::from_secs(30 * 60))));
+
+/// Whether [REPEATING_KEYS] are repeated by the keyboard while held, rather than leaving it to the
+/// host. Switched from the console.
+pub static TYPEMATIC: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// How a pedal acts on being pressed
+#[derive(Clone, Copy, PartialEq)]
+pub enum PedalMode {
+    /// Does what [LAYER_PEDALS] says while held down
+    Momentary,
+    /// Each press starts or stops it doing that, as if held down in between, e.g. to keep talking
+    /// with a push-to-talk key without keeping a foot on the pedal
+    Toggle,
+}
+
+/// How each of the [PEDALS] acts. Switched from the console.
+pub static PEDAL_MODES: Mutex<RawMutex, Cell<[PedalMode; PEDALS.len()]>> = Mutex::new(Cell::new([PedalMode::Momentary; PEDALS.len()]));
+
+/// A switch found newly closed or opened by comparing a scan with the one before, so before any
+/// debouncing or lookup in the keymap.
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyEvent {
+    Pressed(ScanCode),
+    Released(ScanCode),
+}
+
+/// Every [KeyEvent], with when its scan was made, for anything else (such as the [console]) to
+/// follow the keys by. A subscriber which falls behind misses the oldest.
+pub static KEY_EVENTS: PubSubChannel<RawMutex, (KeyEvent, Instant), 32, 4, 0> = PubSubChannel::new();
+
+/// Steps of remapping a key, started by [REMAP_CHORD]
+#[derive(Clone, Copy, PartialEq)]
+enum Remap {
+    Off,
+    /// Waiting for the key to be changed to be pressed
+    AwaitingTarget,
+    /// Waiting for the key whose mapping the one at this [ScanCode] is to copy to be pressed
+    AwaitingSource(ScanCode),
 }
 
 /// Used to uniquely identify each physical key which can be pressed.
-type ScanCode = (u8, u8);
+pub type ScanCode = (u8, u8);
+
+const HELD_KEYS_LIMIT: usize = 32;
+
+/// What happens to a key pressed while [HELD_KEYS_LIMIT] keys are already held
+#[derive(Clone, Copy, PartialEq)]
+pub enum HeldKeysFull {
+    /// The new key is ignored until it's released
+    RejectNew,
+    /// The key held longest is let go of (and ignored until it's released) to make room for the new
+    /// one, unless it's a modifier, a layer key or a pedal, as those are held on purpose while
+    /// typing others
+    EvictOldest,
+}
+
+/// What happens to a key pressed while too many are held. Switched from the console.
+pub static HELD_KEYS_FULL: Mutex<RawMutex, Cell<HeldKeysFull>> = Mutex::new(Cell::new(HeldKeysFull::RejectNew));
+
+/// How many times a key has been pressed while too many were held, since starting up or being reset
+/// from the console
+pub static HELD_KEYS_OVERFLOWS: Mutex<RawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// How often the matrix is scanned, so that the timings below, which are counted in scans, are kept
+/// in real time whatever else is going on.
+pub const SCAN_INTERVAL: Duration = Duration::from_millis(2);
+/// How often to scan the matrix instead while the host is suspended, only to spot a key press which
+/// should wake it
+pub const SUSPENDED_SCAN_INTERVAL: Duration = Duration::from_millis(50);
+/// How long to let the lines settle after strobing each line, and again after releasing it
+pub const STROBE_SETTLE_TIME: Duration = Duration::from_micros(100);
+const _: () = assert!(STROBES as u64 * 2 * STROBE_SETTLE_TIME.as_ticks() < SCAN_INTERVAL.as_ticks(), "reading the matrix must fit in a scan");
+
+/// Level which strobed lines are driven to, as [boards::ACTIVE_LEVEL]
+const ACTIVE: Level = match boards::ACTIVE_LEVEL {
+    ActiveLevel::Low => Level::Low,
+    ActiveLevel::High => Level::High,
+};
+
+/// How many whole scans there are in `time`
+const fn scans(time: Duration) -> u8 {
+    (time.as_ticks() / SCAN_INTERVAL.as_ticks()) as u8
+}
+
+/// Consecutive scans a switch must be seen closed before it counts as pressed. 1 means presses
+/// register on the first sample, adding no latency.
+const PRESS_DEBOUNCE_COUNT: u8 = scans(SCAN_INTERVAL);
+/// Scans a switch must be seen open before it counts as released.
+const RELEASE_DEBOUNCE_COUNT: u8 = scans(Duration::from_millis(10));
+const _: () = assert!(PRESS_DEBOUNCE_COUNT > 0 && RELEASE_DEBOUNCE_COUNT > 0);
+
+/// With the `integrator-debounce` feature, each switch instead has an integrator, counted up every
+/// scan it's seen closed and down every scan it's seen open: it's pressed once the integrator
+/// reaches this, and released once it's back to 0. A marginal switch which only opens now and then
+/// while held (or closes while released) is then smoothed over, rather than having to be seen
+/// closed or open so many scans in a row.
+#[cfg(feature = "integrator-debounce")]
+const INTEGRATOR_MAX: u8 = scans(Duration::from_millis(6));
+#[cfg(feature = "integrator-debounce")]
+const _: () = assert!(INTEGRATOR_MAX > 0 && LAYER_PRESS_DELAY < INTEGRATOR_MAX, "pending keys must resolve before being released");
+
+/// Scans to wait before deciding what a newly pressed key maps to, so that a layer key pressed
+/// just after it (as in a fast roll) still applies. Adds latency to every non-layer key, so kept to
+/// a couple of scans, which is as far apart as the two keys of a roll usually go down (and must
+/// stay under the release debounce, below).
+const LAYER_PRESS_DELAY: u8 = scans(Duration::from_millis(4));
+/// Scans after a layer key is released during which new presses still use its layer, for rolls
+/// where the thumb comes up slightly before the next key goes down. Costs no latency, only a short
+/// while after letting go of the layer before it's really gone.
+const LAYER_RELEASE_DELAY: u8 = scans(Duration::from_millis(20));
+const _: () = assert!(LAYER_PRESS_DELAY < RELEASE_DEBOUNCE_COUNT, "pending keys must resolve before being released");
+
+/// Scancodes of switches found closed during one scan, in the order they were read.
+pub type PressedCodes = heapless::Vec<ScanCode, { ROWS * COLUMNS + PEDALS.len() }>;
 
-const HELD_KEYS_LIMIT: usize = 16;
-const DEFAULT_DEBOUNCE_COUNT: u8 = 5;
+/// Every switch found closed by one scan, one bit each, numbered `row * COLUMNS + column` (and so
+/// the pedals after the keys), for telling cheaply whether anything has changed since the last
+type Snapshot = u64;
+const _: () = assert!((ROWS * COLUMNS + PEDALS.len()) as u32 <= Snapshot::BITS, "every switch must fit in a snapshot");
 
-const PEDAL_FAKE_SCANCODE: ScanCode = (ROWS as u8, 0);
-const MIC_MUTE_KEY: HidKeyCode = 198;  // bodged in here as footswitch function
-    // F20 => Xf86AudioMicMute apparently? in theory...
-    // ...not that HID code 198 actually results in anything mapping to F20 or to Xf86AudioMicMute.
-    // however, 198 does map to keycode 248 in wayland (for whatever reason).
-    // so now i'm just using bindcode instead of bindsym in sway, which i guess is fine.
+/// The [Snapshot] of a scan finding the switches in `pressed` closed
+fn snapshot(pressed: &PressedCodes) -> Snapshot {
+    pressed.iter().fold(0, |snapshot, &(row, column)| snapshot | 1 << (row as usize * COLUMNS + column as usize))
+}
+
+/// [KeyEvent]s found by one scan: presses in the order they were read, then releases.
+type KeyEvents = heapless::Vec<KeyEvent, { 2 * (ROWS * COLUMNS + PEDALS.len()) }>;
+
+/// The [KeyEvent]s between a scan finding the switches in `before` closed and the next finding
+/// those in `after`.
+fn diff_scans(before: &PressedCodes, after: &PressedCodes) -> KeyEvents {
+    let pressed = after.iter().filter(|code| !before.contains(code)).map(|&code| KeyEvent::Pressed(code));
+    let released = before.iter().filter(|code| !after.contains(code)).map(|&code| KeyEvent::Released(code));
+    pressed.chain(released).collect()
+}
+
+/// Switches newly closed among `events`
+fn presses(events: &KeyEvents) -> impl Iterator<Item = ScanCode> + '_ {
+    events.iter().filter_map(|event| match *event {
+        KeyEvent::Pressed(code) => Some(code),
+        KeyEvent::Released(_) => None,
+    })
+}
+
+/// Pedals are scanned as if they were an extra row, numbered [ROWS], with a column per pedal.
+const fn pedal_fake_scancode(pedal_idx: usize) -> ScanCode {
+    (ROWS as u8, pedal_idx as u8)
+}
+
+const fn is_pedal(code: ScanCode) -> bool {
+    code.0 == ROWS as u8
+}
+
+const _: () = assert!(Layout::ALL.len() <= led::MAX_BLINK_COUNT, "layouts must be told apart by blinks");
+
+/// Longest time between two presses of a layer key for them to count as a double-tap, locking its
+/// layer on.
+const LAYER_LOCK_DOUBLE_TAP: Duration = Duration::from_millis(300);
 
-pub struct Matrix<'a> {
+/// Longest a [Thing::LayerTap] can be held for its release to type its key
+const LAYER_TAP_TERM: Duration = Duration::from_millis(200);
+
+/// How long a pedal in [PedalMode::Toggle] must be seen open before being pressed again toggles it
+/// again, rather than being taken as it bouncing
+const PEDAL_TOGGLE_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// Fewest steno keys a stroke must contain to be sent. Single-key strokes are common briefs (-T,
+/// -F, S...), so only raise this if none are used.
+const STENO_MIN_KEYS: u32 = 1;
+/// Shortest time from the first steno key going down to the last coming up for a stroke to be
+/// sent, so that brushing a key in passing doesn't write anything.
+const STENO_MIN_STROKE_TIME: Duration = Duration::from_millis(30);
+
+/// How long one of [REPEATING_KEYS] must be held before [TYPEMATIC] starts repeating it
+const TYPEMATIC_DELAY: Duration = Duration::from_millis(500);
+/// Time between each repeat by [TYPEMATIC], which releases the key for the first half of it
+const TYPEMATIC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long typing [led::ACTIVITY] takes to die down to about a third once typing stops
+const ACTIVITY_TIME_CONSTANT: Duration = Duration::from_secs(1);
+/// Key presses per second which count as fully active
+const FULL_ACTIVITY_RATE: u32 = 8;
+/// What each key press adds to [Matrix::activity], which is fixed-point to decay smoothly
+const ACTIVITY_PER_PRESS: u32 = 1 << 16;
+/// [Matrix::activity] while pressing keys at [FULL_ACTIVITY_RATE], once it has settled
+const FULL_ACTIVITY: u32 = ACTIVITY_PER_PRESS * FULL_ACTIVITY_RATE * ACTIVITY_TIME_CONSTANT.as_millis() as u32 / 1000;
+
+/// How long the same keys can be held before any held on the matrix itself are assumed stuck, and
+/// let go of until their switches are released. Pedals (which may well be toggled on for longer)
+/// and layer keys are left held.
+const STUCK_KEY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Decides what is being typed from which switches are closed in each scan, keeping track of held
+/// keys and layers. Touches no hardware, so it can be fed scans from [Matrix] or from anywhere else.
+pub struct Interpreter {
     held_keys: HeldKeys,
-    steno_packet: StenoPacket,
+    steno_packet: GeminiPacket,
+    /// When the first key of the steno stroke being built up in [Self::steno_packet] was pressed
+    steno_stroke_started: Option<Instant>,
+    /// Keys of the stroke in [Self::steno_packet] which have come up since joining it. Pressed again
+    /// before the stroke ends, they're taken to be bouncing as they're let go, not joining it again.
+    steno_released: GeminiPacket,
+    /// Keys pressed again before their stroke ended, which do nothing until they're released, so
+    /// as not to carry over into the next stroke
+    steno_spent: GeminiPacket,
+    /// Whether the number key has been tapped on its own, to number the next stroke, with
+    /// [steno::NumberKey::Latched]
+    number_latched: bool,
+    /// Whether everything has just been released by [Self::clear_stuck_keys], so that nothing acts
+    /// until every key is up
+    clearing: bool,
+    /// Steno keys held while playing [midi] notes, whose notes are playing
+    midi_notes: GeminiPacket,
+    /// For [PedalMode::Toggle], whether each pedal is toggled on, and when it was last seen closed
+    pedal_toggles: [(bool, Instant); PEDALS.len()],
+    /// Whether a pedal was held as of the previous scan, to have its release confirmed
+    pedal_held: bool,
     state: MatrixState,
-    pins: Pins<'a>,
+    /// Layer chosen at the end of the previous scan
+    layer: &'static Layer,
+    /// Layer which new presses keep resolving against for a few scans after a layer key is released
+    lingering_layer: Option<(&'static Layer, u8)>,
+    /// Lockable layer key pressed last, and when, to spot double-taps
+    last_layer_key_press: Option<(LockableLayerKey, Instant)>,
+    /// When a layer key was last pressed, unless another key has been pressed since, to spot taps
+    /// for [LAYER_PREVIEW]
+    layer_key_tap_started: Option<Instant>,
+    /// When [STENO_CHORD] was pressed, if it's held, and whether it has switched steno mode yet
+    steno_chord: Option<(Instant, bool)>,
+    /// Last keyboard report produced, and when it last changed, to spot stuck keys
+    last_report: (KeyboardReport, Instant),
+    /// When a switch was last newly closed, for [MODE_IDLE_TIMEOUT] and [TapHold::flow_tap_within]
+    last_activity: Instant,
+}
+
+/// Everything produced by one scan, to be sent on by [crate::usb]
+pub type ScanOutput = (KeyboardReport, MediaKeyboardReport, GeminiPacket, MatrixState);
+
+pub struct Matrix<S: InputSource> {
+    interpreter: Interpreter,
+    input: S,
+    /// Switches found closed by the previous scan, to find [KeyEvent]s by
+    last_pressed: PressedCodes,
+    /// [Snapshot] of [Self::last_pressed]
+    last_snapshot: Snapshot,
+    /// Last shown on the status LED, so as only to tell the [led] task about changes
+    status_pattern: Pattern,
+    /// Whether the previous scan was in [KEY_TEST] mode
+    key_testing: bool,
+    /// Whether the previous scan was [LOCKED], and if so, since when [UNLOCK_CHORD] has been held
+    locked: Option<Option<Instant>>,
+    /// How far through remapping a key, started by [REMAP_CHORD]
+    remap: Remap,
+    /// Key presses, each decaying exponentially over [ACTIVITY_TIME_CONSTANT], for [led::ACTIVITY]
+    activity: u32,
+    /// Last shown on the display, so as only to tell it about changes
+    #[cfg(feature = "display")]
+    display_status: crate::display::Status,
+    /// Steno mode and layout last sounded on the buzzer, so as only to sound changes
+    #[cfg(feature = "buzzer")]
+    sounded_modes: (bool, Layout),
+}
+
+/// Sort the matrix's row and column pins into the lines to strobe and the lines to read, following
+/// [boards::DIODE_DIRECTION], and set them up to be driven at [boards::ACTIVE_LEVEL]: strobed lines
+/// like open-drain outputs, left floating until strobed, and read lines pulled the other way.
+pub fn matrix_lines<'a>(rows: [Flex<'a>; ROWS], columns: [Flex<'a>; COLUMNS]) -> ([Flex<'a>; STROBES], [Flex<'a>; SENSES]) {
+    let mut lines: heapless::Vec<Flex<'a>, { ROWS + COLUMNS }> = rows.into_iter().chain(columns).collect();
+    if boards::DIODE_DIRECTION == DiodeDirection::RowToColumn {
+        lines.rotate_left(ROWS);
+    }
+    let mut lines = lines.into_iter();
+    let strobes = core::array::from_fn(|_| {
+        let mut line = lines.next().expect("as many lines as rows and columns");
+        line.set_level(ACTIVE);
+        line.set_as_input();
+        line
+    });
+    let senses = core::array::from_fn(|_| {
+        let mut line = lines.next().expect("as many lines as rows and columns");
+        line.set_pull(if ACTIVE == Level::Low { Pull::Up } else { Pull::Down });
+        line.set_schmitt(true);
+        line.set_as_input();
+        line
+    });
+    (strobes, senses)
+}
+
+/// Drive `line` to [ACTIVE], or leave it floating again.
+pub fn strobe(line: &mut Flex<'_>, strobed: bool) {
+    if strobed {
+        line.set_as_output();
+    } else {
+        line.set_as_input();
+    }
+}
+
+/// Whether a read line is being pulled to [ACTIVE] through a closed switch.
+pub fn is_active(line: &Flex<'_>) -> bool {
+    line.get_level() == ACTIVE
+}
+
+/// Where a [Matrix] finds which switches are closed, along with the outputs which go with reading
+/// them. On the board, the [Pins]; anything else standing in for them (a recording, or presses made
+/// up for testing) can drive a Matrix just the same.
+pub trait InputSource {
+    /// Read which switches are closed, in scan order.
+    fn read_switches(&mut self) -> PressedCodes;
+
+    /// Wait, as cheaply as can be, until a key or pedal is pressed.
+    fn sleep_until_pressed(&mut self);
+
+    /// Mark a switch as newly closed, while measuring [latency].
+    fn mark_closed(&mut self) {}
+
+    /// Show that `layer` is selected, on the backlight if there is one.
+    fn show_layer(&mut self, _layer: &'static Layer) {}
+
+    /// Whether any switches may have been let go of without being seen since this was last asked,
+    /// so that whatever they were doing should be dropped rather than finished.
+    fn take_switches_lost(&mut self) -> bool {
+        false
+    }
 }
 
 pub struct Pins<'a> {
-    pub scan_led: Pwm<'a>,
-    pub status_led: Pwm<'a>,
-    pub rows: [OutputOpenDrain<'a>; ROWS],
-    pub columns: [Input<'a>; COLUMNS],
-    pub pedal: Input<'a>,
+    /// Lines of the matrix strobed one at a time, as sorted by [matrix_lines]
+    pub strobes: [Flex<'a>; STROBES],
+    /// Lines of the matrix read while each is strobed
+    pub senses: [Flex<'a>; SENSES],
+    pub pedals: [Input<'a>; PEDALS.len()],
+    /// Toggled whenever a switch is newly closed, while measuring [latency]
+    pub latency_probe: Output<'a>,
+    #[cfg(feature = "backlight")]
+    pub backlight: crate::backlight::Backlight<'a>,
 }
 
-trait ConvenientPwm {
-    fn on(&mut self);
-    fn off(&mut self);
-    fn pwm_duty_u16(&mut self, duty: u16);  // TODO is it actually out of a u16?
+impl InputSource for Pins<'_> {
+    /// Strobe each line and read which switches are closed, in scan order.
+    fn read_switches(&mut self) -> PressedCodes {
+        let mut pressed = PressedCodes::new();
+
+        for (strobe_idx, line) in self.strobes.iter_mut().enumerate() {
+            strobe(line, true);
+            block_for(STROBE_SETTLE_TIME);
+            for (sense_idx, sense) in self.senses.iter().enumerate() {
+                if is_active(sense) {
+                    let (row, column) = boards::matrix_position(strobe_idx, sense_idx);
+                    pressed.push((row as u8, column as u8)).expect("fits every key");
+                }
+            }
+            strobe(line, false);
+            block_for(STROBE_SETTLE_TIME);
+        }
+
+        for (pedal_idx, pedal) in self.pedals.iter().enumerate() {
+            if pedal.is_low() {
+                pressed.push(pedal_fake_scancode(pedal_idx)).expect("fits every key");
+            }
+        }
+        pressed
+    }
+
+    /// Put the RP2040 into its dormant state until a key or pedal is pressed: with every line
+    /// strobed at once, so that any key pressed brings the line it's read by to [ACTIVE] too.
+    fn sleep_until_pressed(&mut self) {
+        for line in &mut self.strobes {
+            strobe(line, true);
+        }
+        block_for(STROBE_SETTLE_TIME);
+        let closed = match ACTIVE {
+            Level::Low => DormantWakeConfig { level_low: true, ..Default::default() },
+            Level::High => DormantWakeConfig { level_high: true, ..Default::default() },
+        };
+        let pedal_pressed = DormantWakeConfig { level_low: true, ..Default::default() };
+        let mut wakes: heapless::Vec<DormantWake, { SENSES + PEDALS.len() }> = self.senses.iter_mut().map(|line|
+            line.dormant_wake(closed)
+        ).collect();
+        wakes.extend(self.pedals.iter_mut().map(|pedal| pedal.dormant_wake(pedal_pressed)));
+        #[cfg(target_os = "none")]  // not when built for the tests, which never sleep
+        embassy_rp::clocks::dormant_sleep();
+        drop(wakes);
+        for line in &mut self.strobes {
+            strobe(line, false);
+        }
+        block_for(STROBE_SETTLE_TIME);
+    }
+
+    fn mark_closed(&mut self) {
+        self.latency_probe.toggle();
+    }
+
+    #[cfg_attr(not(feature = "backlight"), allow(unused_variables))]
+    fn show_layer(&mut self, layer: &'static Layer) {
+        #[cfg(feature = "backlight")]
+        self.backlight.show_layer(layer);
+    }
 }
-impl ConvenientPwm for Pwm<'_> {
-    fn on(&mut self) { self.set_duty_cycle_fully_on().expect("pwm"); }
-    fn off(&mut self) { self.set_duty_cycle_fully_off().expect("pwm"); }
-    fn pwm_duty_u16(&mut self, duty: u16) { self.set_duty_cycle(duty).expect("pwm"); }
+
+/// Most switches which can be closed by [simulate_press] at once
+const SIMULATED_LIMIT: usize = 8;
+
+/// Switches closed by [simulate_press], each with when it opens again if it's only tapped
+type Simulated = heapless::Vec<(ScanCode, Option<Instant>), SIMULATED_LIMIT>;
+
+/// Switches closed from the [console] rather than by a finger, for testing on the board from the
+/// host
+static SIMULATED: Mutex<RawMutex, RefCell<Simulated>> = Mutex::new(RefCell::new(Simulated::new()));
+
+/// Whether `code` is a key of the matrix or one of the pedals.
+pub const fn is_switch(code: ScanCode) -> bool {
+    ((code.0 as usize) < ROWS && (code.1 as usize) < COLUMNS) || (is_pedal(code) && (code.1 as usize) < PEDALS.len())
+}
+
+/// Close the switch at `code` as if it were pressed, until [simulate_release], or for `for_time`,
+/// returning whether there was room to.
+pub fn simulate_press(code: ScanCode, for_time: Option<Duration>) -> bool {
+    let until = for_time.map(|for_time| Instant::now() + for_time);
+    SIMULATED.lock(|simulated| {
+        let mut simulated = simulated.borrow_mut();
+        simulated.retain(|(closed, _)| *closed != code);
+        simulated.push((code, until)).is_ok()
+    })
+}
+
+/// Open the switch at `code` again, if it was closed by [simulate_press], or every one if `None`.
+pub fn simulate_release(code: Option<ScanCode>) {
+    SIMULATED.lock(|simulated| simulated.borrow_mut().retain(|(closed, _)| code.is_some_and(|code| *closed != code)));
+}
+
+/// An [InputSource] which finds the switches closed by [simulate_press] closed, as well as those
+/// its own source does.
+pub struct WithSimulated<S: InputSource>(pub S);
+
+impl<S: InputSource> InputSource for WithSimulated<S> {
+    fn read_switches(&mut self) -> PressedCodes {
+        let mut pressed = self.0.read_switches();
+        let now = Instant::now();
+        SIMULATED.lock(|simulated| {
+            let mut simulated = simulated.borrow_mut();
+            simulated.retain(|(_, until)| until.is_none_or(|until| now < until));
+            for &(code, _) in simulated.iter() {
+                if !pressed.contains(&code) {
+                    pressed.push(code).expect("fits every key");
+                }
+            }
+        });
+        pressed
+    }
+
+    fn sleep_until_pressed(&mut self) {
+        self.0.sleep_until_pressed();
+    }
+
+    fn mark_closed(&mut self) {
+        self.0.mark_closed();
+    }
+
+    fn show_layer(&mut self, layer: &'static Layer) {
+        self.0.show_layer(layer);
+    }
+
+    fn take_switches_lost(&mut self) -> bool {
+        self.0.take_switches_lost()
+    }
 }
 
-impl<'a> Matrix<'a> {
-    pub fn new(pins: Pins<'a>) -> Self {
+impl<S: InputSource> Matrix<S> {
+    pub fn new(input: S) -> Self {
         Matrix {
-            held_keys: Default::default(),
-            steno_packet: Default::default(),
-            state: Default::default(),
-            pins,
+            interpreter: Interpreter::new(),
+            input,
+            last_pressed: PressedCodes::new(),
+            last_snapshot: 0,
+            status_pattern: Pattern::Off,
+            key_testing: false,
+            locked: None,
+            remap: Remap::Off,
+            activity: 0,
+            #[cfg(feature = "display")]
+            display_status: Default::default(),
+            #[cfg(feature = "buzzer")]
+            sounded_modes: (false, Layout::Normal),
         }
     }
 
-    fn choose_layer_for_state(&mut self) -> &'static Layer {
-        let led = &mut self.pins.status_led;
+    /// Show the state of the [Interpreter] on the status LED (and backlight).
+    fn show_state(&mut self) {
+        let state = &self.interpreter.state.with_lock();
+        let locked = state.locked_layer_key.is_some();
+        let show = |indication, count| led::indicator(indication).pattern(count, locked);
 
-        if self.state.awaiting_clear {
-            led.on()
-        } else if self.state.function_key {
-            led.pwm_duty_u16(3400)
-        } else if self.state.nav_key || (self.state.left_symbol_key && self.state.right_symbol_key) {
-            led.pwm_duty_u16(1400)
-        } else if self.state.left_symbol_key || self.state.right_symbol_key {
-            led.pwm_duty_u16(300)
-        } else if self.state.stenotype || self.state.emulating_dvorak {
-            led.pwm_duty_u16(5000)
+        // Each layer or mode has its own brightness, or on an RGB LED, its own colour
+        let pattern = if self.locked.is_some() {
+            Pattern::Breathe(LOCKED_LIGHT)
+        } else if self.remap != Remap::Off {
+            Pattern::Blink(Light::FULL)
+        } else if state.awaiting_clear {
+            Pattern::Steady(Light::FULL)
+        } else if state.function_key {
+            show(Indication::Function, 0)
+        } else if state.nav_key || state.symbol_keys().is_some_and(|held| core::ptr::eq(state.typing_layout().symbols(held), &LAYER_NAVIGATION)) {
+            show(Indication::Navigation, 0)
+        } else if state.symbol_keys().is_some() {
+            show(Indication::Symbols, 0)
+        } else if state.pedal_toggled {
+            led::indicator(Indication::PedalToggled).pattern(0, false)
+        } else if jiggler::ENABLED.lock(|enabled| enabled.get()) {
+            led::indicator(Indication::Jiggler).pattern(0, false)
+        } else if state.stenotype {
+            led::indicator(Indication::Steno).pattern(0, false)
+        } else if state.typing_layout() != Layout::Normal {
+            led::indicator(Indication::Layout).pattern(state.typing_layout().index() as u8, false)
         } else {
-            led.off()
+            Pattern::Off
+        };
+        if pattern != self.status_pattern {
+            led::send(LedCommand::Status(pattern));
+            self.status_pattern = pattern;
+        }
+
+        self.input.show_layer(self.interpreter.layer);
+
+        #[cfg(feature = "buzzer")]
+        {
+            let modes = (state.stenotype, state.typing_layout());
+            if modes != self.sounded_modes {
+                // turning either on sounds the rising tune, and turning it back off the falling one
+                let on = if modes.0 != self.sounded_modes.0 { modes.0 } else { modes.1 != Layout::Normal };
+                crate::buzzer::play(if on { crate::buzzer::MODE_ON } else { crate::buzzer::MODE_OFF });
+                self.sounded_modes = modes;
+            }
         }
 
-        if self.state.function_key {
+        #[cfg(feature = "display")]
+        {
+            let status = crate::display::Status {
+                layer: layer_index(self.interpreter.layer),
+                layout: state.typing_layout(),
+                stenotype: state.stenotype,
+                words_per_minute: if state.stenotype {
+                    stats::strokes_per_minute(Instant::now()) as u16
+                } else {
+                    self.words_per_minute()
+                },
+            };
+            if status != self.display_status {
+                crate::display::STATUS.signal(status);
+                self.display_status = status;
+            }
+        }
+    }
+
+    /// Typing speed going by [Self::activity], taking a word to be five key presses
+    #[cfg(feature = "display")]
+    fn words_per_minute(&self) -> u16 {
+        let presses_per_minute = self.activity as u64 * 60_000 / (ACTIVITY_PER_PRESS as u64 * ACTIVITY_TIME_CONSTANT.as_millis());
+        (presses_per_minute / 5) as u16
+    }
+
+    /// Count switches newly closed among `events` into [Self::activity], after letting it decay
+    /// for a scan, and show it on the scan LED.
+    fn update_activity(&mut self, events: &KeyEvents) {
+        const DECAY_SCANS: u32 = (ACTIVITY_TIME_CONSTANT.as_ticks() / SCAN_INTERVAL.as_ticks()) as u32;
+        let new_presses = presses(events).count() as u32;
+        self.activity = self.activity - self.activity / DECAY_SCANS + new_presses * ACTIVITY_PER_PRESS;
+        let level = (self.activity.min(FULL_ACTIVITY) as u64 * u16::MAX as u64 / FULL_ACTIVITY as u64) as u16;
+        led::ACTIVITY.lock(|activity| activity.set(level));
+    }
+
+    /// Take newly pressed switches as the steps of remapping a key, rather than typing anything.
+    /// The key is remapped on whichever layer is in use, as [vial] remaps keys.
+    fn remap_keys(&mut self, events: &KeyEvents) -> ScanOutput {
+        for code in presses(events).filter(|&code| !is_pedal(code)) {
+            match self.remap {
+                Remap::AwaitingTarget => {
+                    self.remap = Remap::AwaitingSource(code);
+                    info!("Remapping: press the key to copy");
+                },
+                Remap::AwaitingSource(target) => {
+                    let layer = self.interpreter.layer;
+                    vial::remap(layer, target.0 as usize, target.1 as usize, thing_at(layer, code));
+                    info!("Remapped row {} column {} on layer {}", target.0, target.1, layer_index(layer));
+                    self.remap = Remap::Off;
+                    // so that none of the keys still held type anything until released
+                    self.interpreter.clear_stuck_keys();
+                    break;
+                },
+                Remap::Off => break,
+            }
+        }
+        (KeyboardReport::default(), MediaKeyboardReport { usage_id: 0 }, Default::default(), self.interpreter.state)
+    }
+
+    /// Describe each newly pressed switch on the [console], rather than typing anything.
+    fn test_keys(&mut self, events: &KeyEvents) -> ScanOutput {
+        for code in presses(events) {
+            let mut line = ConsoleLine::new();
+            let thing = thing_at(self.interpreter.layer, code);
+            write!(line, "row {} column {}: {:?}\r\n", code.0, code.1, thing).ok();
+            console::print(line);
+        }
+        (KeyboardReport::default(), MediaKeyboardReport { usage_id: 0 }, Default::default(), self.interpreter.state)
+    }
+
+    /// While [LOCKED], send nothing for the switches `pressed` at `now` but watch for [UNLOCK_CHORD],
+    /// returning what to send instead. `None` once unlocked.
+    fn stay_locked(&mut self, pressed: &PressedCodes, now: Instant) -> Option<ScanOutput> {
+        let locked = LOCKED.lock(|locked| locked.get());
+        if locked != self.locked.is_some() {
+            // so nothing typed before is left held, nor anything pressed now typed afterwards
+            self.interpreter.clear_stuck_keys();
+            self.locked = locked.then_some(None);
+            info!("Keyboard locked: {}", locked);
+        }
+        let unlock_chord_since = self.locked.as_mut()?;
+
+        let is_chorded = UNLOCK_CHORD.iter().all(|chord_thing|
+            pressed.iter().any(|&code| thing_at(&LAYER_NORMAL, code) == *chord_thing)
+        );
+        *unlock_chord_since = if is_chorded { Some(unlock_chord_since.unwrap_or(now)) } else { None };
+        if unlock_chord_since.is_some_and(|since| now - since >= UNLOCK_HOLD) {
+            LOCKED.lock(|locked| locked.set(false));
+        }
+        Some((KeyboardReport::default(), MediaKeyboardReport { usage_id: 0 }, Default::default(), self.interpreter.state))
+    }
+
+    pub fn scan(&mut self) -> ScanOutput {
+        if power::should_sleep(Instant::now()) {
+            info!("No host, going dormant");
+            self.input.sleep_until_pressed();
+            power::woken(Instant::now());
+        }
+
+        let started = Instant::now();
+        let output = self.scan_awake();
+        if started.elapsed() > SCAN_INTERVAL {
+            health::count(&health::SCAN_OVERRUNS);
+        }
+        output
+    }
+
+    /// Read the switches and work out what they type, as [Self::scan] does once awake.
+    fn scan_awake(&mut self) -> ScanOutput {
+        let pressed = self.input.read_switches();
+        if self.input.take_switches_lost() {
+            warn!("Switches lost, dropping held keys");
+            self.interpreter.drop_held_keys();
+        }
+        let now = Instant::now();
+        let snapshot = snapshot(&pressed);
+        let unchanged = snapshot == self.last_snapshot;
+        let events = if unchanged { KeyEvents::new() } else { diff_scans(&self.last_pressed, &pressed) };
+        let publisher = KEY_EVENTS.immediate_publisher();
+        for &event in &events {
+            publisher.publish_immediate((event, now));
+        }
+        if latency::is_enabled() && presses(&events).next().is_some() {
+            self.input.mark_closed();
+            latency::key_closed(now);
+        }
+
+        if power::is_host_suspended() {
+            // nothing is typed while the host sleeps, but a key press wakes it
+            if presses(&events).next().is_some() {
+                usb::WAKE_HOST.signal(());
+            }
+            (self.last_pressed, self.last_snapshot) = (pressed, snapshot);
+            self.show_state();
+            return (KeyboardReport::default(), MediaKeyboardReport { usage_id: 0 }, Default::default(), self.interpreter.state)
+        }
+
+        if let Some(output) = self.stay_locked(&pressed, now) {
+            (self.last_pressed, self.last_snapshot) = (pressed, snapshot);
+            self.show_state();
+            return output;
+        }
+
+        let is_chorded = |codes: &PressedCodes| KEY_TEST_CHORD.iter().all(|chord_thing|
+            codes.iter().any(|&code| thing_at(&LAYER_NORMAL, code) == *chord_thing)
+        );
+        if is_chorded(&pressed) && !is_chorded(&self.last_pressed) {
+            KEY_TEST.lock(|key_test| key_test.set(!key_test.get()));
+        }
+        let key_testing = KEY_TEST.lock(|key_test| key_test.get());
+        if key_testing != self.key_testing {
+            // so nothing typed before is left held, nor anything pressed now typed afterwards
+            self.interpreter.clear_stuck_keys();
+            self.key_testing = key_testing;
+            info!("Key tester: {}", key_testing);
+        }
+
+        let is_remap_chorded = |codes: &PressedCodes| REMAP_CHORD.iter().all(|chord_thing|
+            codes.iter().any(|&code| thing_at(&LAYER_NORMAL, code) == *chord_thing)
+        );
+        if is_remap_chorded(&pressed) && !is_remap_chorded(&self.last_pressed) && !key_testing {
+            self.interpreter.clear_stuck_keys();
+            self.remap = Remap::AwaitingTarget;
+            info!("Remapping: press the key to change");
+        }
+
+        // Nothing needs deciding while the switches stay as they were, unless something still
+        // counts time (debouncing, a tap-hold, a steno stroke...) after the last change.
+        let output = if unchanged && self.interpreter.is_idle() {
+            self.interpreter.idle(now)
+        } else if key_testing {
+            self.test_keys(&events)
+        } else if self.remap != Remap::Off {
+            self.remap_keys(&events)
+        } else {
+            self.interpreter.process(&pressed, now)
+        };
+        if latency::is_enabled() && !output.2.is_empty() {
+            latency::stroke_taken(now);
+        }
+        self.update_activity(&events);
+        (self.last_pressed, self.last_snapshot) = (pressed, snapshot);
+        self.show_state();
+        output
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter {
+            held_keys: Default::default(),
+            steno_packet: Default::default(),
+            steno_stroke_started: None,
+            steno_released: Default::default(),
+            steno_spent: Default::default(),
+            number_latched: false,
+            clearing: false,
+            midi_notes: Default::default(),
+            pedal_toggles: [(false, Instant::MIN); PEDALS.len()],
+            pedal_held: false,
+            state: Default::default(),
+            layer: &LAYER_NORMAL,
+            lingering_layer: None,
+            last_layer_key_press: None,
+            layer_key_tap_started: None,
+            steno_chord: None,
+            last_report: (KeyboardReport::default(), Instant::MIN),
+            last_activity: Instant::now(),
+        }
+    }
+
+    fn choose_layer_for_state(&self) -> &'static Layer {
+        let state = self.state.with_lock();
+        if state.function_key {
             &LAYER_FUNCTION
-        } else if self.state.nav_key || (self.state.left_symbol_key && self.state.right_symbol_key) {
+        } else if let Some((LayerRef(layer), _)) = state.modified_layer {
+            layer
+        } else if let Some(LayerRef(layer)) = state.layer_tap {
+            layer
+        } else if state.nav_key {
             &LAYER_NAVIGATION
-        } else if self.state.left_symbol_key || self.state.right_symbol_key {
-            if self.state.emulating_dvorak { &LAYER_DVORAK_EMU_SYMBOLS } else { &LAYER_SYMBOLS }
-        } else if self.state.stenotype {
+        } else if let Some(held) = state.symbol_keys() {
+            state.typing_layout().symbols(held)
+        } else if state.stenotype {
             &LAYER_STENO
-        } else if self.state.emulating_dvorak {
-            &LAYER_DVORAK_EMU
         } else {
-            &LAYER_NORMAL
+            state.typing_layout().letters()
         }
     }
 
-    pub fn scan(&mut self) -> (KeyboardReport, StenoPacket, MatrixState) {
-        let layer = self.choose_layer_for_state();
+    /// Set the layer key flags in [MatrixState] from the currently held keys, and lock or unlock
+    /// layers if they were double-tapped, returning whether any of them was released.
+    fn update_layer_keys(&mut self, now: Instant) -> bool {
+        let before = self.state;
 
-        self.held_keys.decrement_holds();
+        self.state.left_symbol_key = false;
+        self.state.right_symbol_key = false;
+        self.state.nav_key = false;
+        self.state.function_key = false;
+        self.state.modified_layer = None;
+        self.state.layer_tap = None;
+        self.state.momentary_layout = None;
+
+        for thing in self.held_keys.iter_pressed_things() {
+            match thing {
+                Thing::LeftSymbolKey => self.state.left_symbol_key = true,
+                Thing::RightSymbolKey => self.state.right_symbol_key = true,
+                Thing::NavKey => self.state.nav_key = true,
+                Thing::FunctionKey => self.state.function_key = true,
+                Thing::ModifiedLayer(layer, mods) => {
+                    self.state.modified_layer.get_or_insert((*layer, *mods));
+                },
+                Thing::LayerTap(layer, _) => {
+                    self.state.layer_tap.get_or_insert(*layer);
+                },
+                Thing::MomentaryLayout(layout) => {
+                    self.state.momentary_layout.get_or_insert(*layout);
+                },
+                _ => {},
+            }
+        }
 
-        self.pins.scan_led.pwm_duty_u16(400);
-        for (row_idx, row) in self.pins.rows.iter_mut().enumerate() {
-            row.set_low();
-            block_for(Duration::from_micros(100));
-            for (column_idx, column) in self.pins.columns.iter_mut().enumerate() {
-                let pressed = column.is_low();
-                if pressed {
-                    self.held_keys.record_pressed((row_idx as u8, column_idx as u8), layer[row_idx][column_idx]);
-                    self.pins.scan_led.pwm_duty_u16(30000);
+        for layer_key in LockableLayerKey::ALL {
+            if !self.state.is_held(layer_key) || before.is_held(layer_key) {
+                continue;
+            }
+            if self.state.locked_layer_key == Some(layer_key) {
+                self.state.locked_layer_key = None;
+                self.last_layer_key_press = None;
+                info!("Layer unlocked: {}", layer_key);
+            } else if matches!(self.last_layer_key_press, Some((last, at)) if last == layer_key && now - at <= LAYER_LOCK_DOUBLE_TAP) {
+                self.state.locked_layer_key = Some(layer_key);
+                self.last_layer_key_press = None;
+                info!("Layer locked: {}", layer_key);
+            } else {
+                self.last_layer_key_press = Some((layer_key, now));
+            }
+        }
+
+        (before.left_symbol_key && !self.state.left_symbol_key)
+            || (before.right_symbol_key && !self.state.right_symbol_key)
+            || (before.nav_key && !self.state.nav_key)
+            || (before.function_key && !self.state.function_key)
+            || (before.modified_layer.is_some() && self.state.modified_layer != before.modified_layer)
+            || (before.layer_tap.is_some() && self.state.layer_tap != before.layer_tap)
+            || (before.momentary_layout.is_some() && self.state.momentary_layout != before.momentary_layout)
+    }
+
+    /// Which of the switches `pressed` at `now` to take as held: all of them, but with pedals in
+    /// [PedalMode::Toggle] held from one press until the next instead.
+    fn apply_pedal_modes(&mut self, pressed: &[ScanCode], now: Instant) -> PressedCodes {
+        let modes = PEDAL_MODES.lock(|modes| modes.get());
+        let mut held: PressedCodes = pressed.iter().copied().filter(|&code| !is_pedal(code)).collect();
+        for (pedal_idx, (mode, (toggled, last_closed))) in modes.iter().zip(&mut self.pedal_toggles).enumerate() {
+            let code = pedal_fake_scancode(pedal_idx);
+            let closed = pressed.contains(&code);
+            if *mode == PedalMode::Momentary {
+                *toggled = false;
+                if closed {
+                    held.push(code).expect("fits every key");
                 }
+                continue;
             }
-            row.set_high();
-            block_for(Duration::from_micros(100));
+            if closed {
+                if now - *last_closed > PEDAL_TOGGLE_DEBOUNCE {
+                    *toggled = !*toggled;
+                    info!("Pedal {} toggled: {}", pedal_idx + 1, *toggled);
+                }
+                *last_closed = now;
+            }
+            if *toggled {
+                held.push(code).expect("fits every key");
+            }
+        }
+        self.state.pedal_toggled = self.pedal_toggles.iter().any(|&(toggled, _)| toggled);
+        held
+    }
+
+    /// Switch steno mode once [STENO_CHORD] has been held for [STENO_CHORD_HOLD], before the layer
+    /// is chosen, so that it works whichever layer is selected.
+    fn update_steno_chord(&mut self, now: Instant) {
+        let chorded = STENO_CHORD.iter().all(|chord_thing| self.held_keys.iter_pressed_things().any(|thing| thing == chord_thing));
+        self.steno_chord = match self.steno_chord {
+            _ if !chorded => None,
+            None => Some((now, false)),
+            Some((since, false)) if now - since >= STENO_CHORD_HOLD => {
+                self.state.stenotype = !self.state.stenotype;
+                info!("Stenotype (by chord): {}", self.state.stenotype);
+                Some((since, true))
+            },
+            held => held,
+        };
+    }
+
+    /// Whether no key is held (even as it's debounced) and nothing else is in progress, so that a scan
+    /// finding every switch as before would change nothing, and [Self::idle] can stand in for
+    /// [Self::process].
+    fn is_idle(&self) -> bool {
+        self.held_keys.is_all_released()
+            && !self.clearing
+            && !self.pedal_held
+            && self.steno_stroke_started.is_none()
+            && !matches!(self.lingering_layer, Some((_, scans_left)) if scans_left > 0)
+            && self.last_report.0 == KeyboardReport::default()
+    }
+
+    /// What [Self::process] would produce at `now` while [Self::is_idle]: nothing, though modes
+    /// can still be left after [MODE_IDLE_TIMEOUT].
+    fn idle(&mut self, now: Instant) -> ScanOutput {
+        self.leave_idle_modes(now);
+        (KeyboardReport::default(), MediaKeyboardReport { usage_id: 0 }, Default::default(), self.state)
+    }
+
+    /// Forget every held key and reset all modes, sending nothing more until every switch has been
+    /// released, and have [crate::usb] send released reports again in case the host missed them.
+    fn clear_stuck_keys(&mut self) {
+        midi::play_changes(&self.midi_notes, &Default::default());
+        *self = Interpreter::new();
+        self.clearing = true;
+        self.state.awaiting_clear = true;
+        usb::RESEND_RELEASED_REPORTS.signal(());
+    }
+
+    /// Forget every held key as [Self::clear_stuck_keys] does, including any steno stroke under
+    /// way, but staying in steno mode and the same layout.
+    fn drop_held_keys(&mut self) {
+        let (stenotype, layout) = (self.state.stenotype, self.state.layout);
+        self.clear_stuck_keys();
+        (self.state.stenotype, self.state.layout) = (stenotype, layout);
+    }
+
+    /// Leave steno mode and any emulated layout if no key has been pressed for [MODE_IDLE_TIMEOUT].
+    fn leave_idle_modes(&mut self, now: Instant) {
+        let Some(timeout) = MODE_IDLE_TIMEOUT.lock(|timeout| timeout.get()) else {
+            return;
+        };
+        if now - self.last_activity < timeout || (!self.state.stenotype && self.state.layout == Layout::Normal) {
+            return;
         }
+        info!("Idle for {}s, leaving stenotype and emulated layouts", timeout.as_secs());
+        self.state.stenotype = false;
+        self.state.layout = Layout::Normal;
+        self.steno_packet = Default::default();
+        self.steno_stroke_started = None;
+        self.steno_released = Default::default();
+        self.number_latched = false;
+    }
 
-        if self.pins.pedal.is_low() {
-            self.pins.scan_led.pwm_duty_u16(30000);
-            self.held_keys.record_pressed(PEDAL_FAKE_SCANCODE, Thing::RealKey((MIC_MUTE_KEY, 0)));
+    /// Add the steno keys `held` as of `now` to the stroke being built up, and take the stroke once
+    /// its own keys are up, whatever else is held. Each key joins the stroke once: a key let go and
+    /// pressed again before the rest of the stroke is up is only bouncing, so stays out of the next
+    /// stroke until it's released.
+    fn update_steno_stroke(&mut self, held: GeminiPacket, now: Instant) -> GeminiPacket {
+        self.steno_spent = self.steno_spent.intersection(held);
+        let held = held.without(self.steno_spent);
+        self.steno_released = self.steno_released.union(self.steno_packet.without(held));
+        let rebounced = held.intersection(self.steno_released);
+        let joining = held.without(rebounced);
+        if !joining.is_empty() {
+            self.steno_stroke_started.get_or_insert(now);
+            self.steno_packet = self.steno_packet.union(joining);
+            return Default::default();
+        }
+        if !rebounced.is_empty() {
+            debug!("Steno keys bounced as the stroke was let go: {} keys", rebounced.key_count());
+        }
+        self.steno_spent = self.steno_spent.union(rebounced);
+        self.steno_released = Default::default();
+        self.take_steno_stroke(now)
+    }
+
+    /// Take the finished steno stroke, unless it looks accidental, in which case it's dropped. With
+    /// [steno::NumberKey::Latched], a stroke of only the number key latches it instead.
+    fn take_steno_stroke(&mut self, now: Instant) -> GeminiPacket {
+        let mut packet = take(&mut self.steno_packet);
+        let Some(started) = self.steno_stroke_started.take() else {
+            return packet;  // no stroke in progress
+        };
+        let number_key = steno::NUMBER_KEY.lock(|number_key| number_key.get());
+        if number_key == steno::NumberKey::Latched && packet.contains(steno::KeyCode::Number) && packet.key_count() == 1 {
+            self.number_latched = !self.number_latched;
+            debug!("Number key latched: {}", self.number_latched);
+            return Default::default();
+        }
+        let keys = packet.key_count();
+        if keys < STENO_MIN_KEYS || now - started < STENO_MIN_STROKE_TIME {
+            debug!("Dropped accidental steno stroke: {} keys in {}ms", keys, (now - started).as_millis());
+            return Default::default();
+        }
+        if take(&mut self.number_latched) {
+            packet.press(steno::KeyCode::Number);
+        }
+        stats::record_stroke(now);
+        packet
+    }
+
+    /// Work out what to send after a scan at `now` found the switches in `pressed` closed, listed
+    /// in the order they were read.
+    pub fn process(&mut self, pressed: &[ScanCode], now: Instant) -> ScanOutput {
+        let pressed = &self.apply_pedal_modes(pressed, now);
+        self.held_keys.decrement_holds();
+        self.held_keys.forget_overflowed(pressed);
+        self.leave_idle_modes(now);
+        if self.last_report.0 != KeyboardReport::default() && now - self.last_report.1 >= STUCK_KEY_TIMEOUT {
+            let released = self.held_keys.release_stuck();
+            if released > 0 {
+                warn!("Same keys held for {}s, assuming {} stuck", STUCK_KEY_TIMEOUT.as_secs(), released);
+            }
+        }
+
+        // Layer keys are recorded before anything else, so that other keys pressed during the same
+        // scan are resolved on the layer they select rather than depending on which row was read
+        // first.
+        let previous_layer = self.layer;
+        let previous_press = self.last_activity;
+        let mut new_codes = PressedCodes::new();
+        let mut new_presses = PressedCodes::new();
+        for &code in pressed {
+            if self.held_keys.refresh(code) {
+                continue;
+            }
+            new_codes.push(code).expect("fits every key");
+            self.last_activity = now;
+            let thing = thing_at(previous_layer, code);
+            if thing.is_layer_key() || is_pedal(code) {
+                self.held_keys.insert(code, Some(thing), now, previous_press, previous_layer);
+            } else {
+                new_presses.push(code).expect("fits every key");
+            }
+            // a layer-tap's tap types its key, rather than being a layer key tapped
+            self.layer_key_tap_started = (thing.is_layer_key() && !matches!(thing, Thing::LayerTap(..))).then_some(now);
+        }
+
+        self.held_keys.resolve_tap_holds(now, &new_codes);
+
+        let released_layer_key = self.update_layer_keys(now);
+        if released_layer_key && self.layer_key_tap_started.take().is_some_and(|at| now - at <= LAYER_PREVIEW_TAP)
+            && LAYER_PREVIEW.lock(|preview| preview.get())
+        {
+            console::print_layer(previous_layer);
+        }
+        self.update_steno_chord(now);
+        self.layer = self.choose_layer_for_state();
+        if !core::ptr::eq(self.layer, previous_layer) {
+            self.lingering_layer = if released_layer_key {
+                Some((previous_layer, LAYER_RELEASE_DELAY))
+            } else {
+                None
+            };
+        }
+        let resolving_layer = match &mut self.lingering_layer {
+            Some((layer, scans_left)) if *scans_left > 0 => {
+                *scans_left -= 1;
+                *layer
+            },
+            _ => self.layer,
+        };
+
+        for &code in &new_presses {
+            self.held_keys.insert(code, None, now, previous_press, resolving_layer);
+        }
+        self.held_keys.resolve_combos(now, core::ptr::eq(resolving_layer, &LAYER_NORMAL));
+        self.held_keys.resolve_pending(|code| thing_at(resolving_layer, code));
+
+        let nothing = MediaKeyboardReport { usage_id: 0 };
+        if CLEAR_CHORD.iter().all(|chord_thing| self.held_keys.iter_pressed_things().any(|thing| thing == chord_thing)) {
+            warn!("Clear chord pressed, releasing everything");
+            self.clear_stuck_keys();
+            return (KeyboardReport::default(), nothing, Default::default(), self.state)
+        }
+        if self.clearing {
+            // keys still held from before clearing do nothing until every key is up
+            self.clearing = !self.held_keys.is_all_released();
+            return (KeyboardReport::default(), nothing, Default::default(), self.state)
         }
 
         let mut report = KeyboardReport::default();
         let mut report_next_keycode_idx = 0;
+        let mut consumer_report = MediaKeyboardReport { usage_id: 0 };
 
-        self.state.left_symbol_key = false;
-        self.state.right_symbol_key = false;
-        self.state.nav_key = false;
-        self.state.function_key = false;
-
-        for thing in self.held_keys.iter_pressed_things() {
+        let os = settings::get().os;
+        let playing_midi = midi::ENABLED.lock(|enabled| enabled.get());
+        let mut midi_notes = GeminiPacket::default();
+        let mut repeating_keycode = None;
+        let mut awaiting_clear = false;
+        let mut steno_held = GeminiPacket::default();
+        let mut pedal_held = false;
+        for key in self.held_keys.iter_pressed_mut() {
+            pedal_held |= is_pedal(key.in_scancode);
+            if now - key.pressed_at < key.mapping.deliberate_hold() {
+                // not acted on until it's clearly not been pressed by mistake
+                continue;
+            }
+            // a tap-hold hasn't acted until it's decided what it is
+            let newly_pressed = !key.acted;
+            key.acted = !matches!(key.mapping, Thing::TapHold(_));
+            let thing = &key.mapping;
             match thing {
-                Thing::RealKey((keycode, mods)) => {
-                    if report_next_keycode_idx < 6 {
-                        report.modifier |= mods;
-                        report.keycodes[report_next_keycode_idx] = *keycode;
-                        report_next_keycode_idx += 1;
+                Thing::RealKey((keycode, _)) if REPEATING_KEYS.contains(thing) => repeating_keycode = Some(*keycode),
+                _ => {},
+            }
+            match thing {
+                Thing::RealKey(key) => add_key(&mut report, &mut report_next_keycode_idx, os.translate(*key)),
+                Thing::MicMute => add_key(&mut report, &mut report_next_keycode_idx, os.mic_mute()),
+                Thing::Turbo(turbo_key, period) => {
+                    // pressed for the first half of each period, counted from when the key went down
+                    let into_period = (now - key.pressed_at).as_ticks() % period.as_ticks();
+                    if into_period < period.as_ticks() / 2 {
+                        add_key(&mut report, &mut report_next_keycode_idx, os.translate(*turbo_key));
+                    }
+                },
+                Thing::ConsumerKey(usage_id) => {
+                    if consumer_report.usage_id == 0 {
+                        consumer_report.usage_id = *usage_id;
+                    }
+                },
+                Thing::StenoKey(code) if playing_midi => {
+                    awaiting_clear = true;
+                    midi_notes.press(*code);
+                },
+                Thing::StenoKey(code) => {
+                    awaiting_clear = true;
+                    steno_held.press(*code);
+                },
+                Thing::LeftSymbolKey | Thing::RightSymbolKey | Thing::NavKey | Thing::FunctionKey | Thing::LayerTap(..) | Thing::MomentaryLayout(_) => {
+                    // already taken into account by update_layer_keys
+                },
+                Thing::ModifiedLayer(_, mods) => {
+                    // the layer is already taken into account by update_layer_keys
+                    report.modifier |= os.translate((0, *mods)).1;
+                },
+                Thing::BacklightBrightness => {
+                    if newly_pressed {
+                        #[cfg(feature = "backlight")]
+                        crate::backlight::next_brightness();
+                    }
+                    awaiting_clear = true;
+                },
+                Thing::BuzzerVolume => {
+                    if newly_pressed {
+                        #[cfg(feature = "buzzer")]
+                        crate::buzzer::next_volume();
                     }
+                    awaiting_clear = true;
                 },
-                Thing::StenoKey((byte_position, flag)) => {
-                    self.state.awaiting_clear = true;
-                    self.steno_packet[*byte_position as usize] |= flag;
+                Thing::LedBrightness => {
+                    if newly_pressed {
+                        settings::update(|settings| {
+                            settings.led_brightness_level = (settings.led_brightness_level + 1) % led::LED_BRIGHTNESS_LEVELS.len() as u8;
+                        });
+                        info!("LED brightness level: {}", settings::get().led_brightness_level);
+                    }
+                    awaiting_clear = true;
+                },
+                Thing::LatencyTestToggle => {
+                    if newly_pressed {
+                        let enabled = latency::ENABLED.lock(|enabled| {
+                            enabled.set(!enabled.get());
+                            enabled.get()
+                        });
+                        info!("Latency test: {}", enabled);
+                    }
+                    awaiting_clear = true;
                 },
-                Thing::LeftSymbolKey => {
-                    self.state.left_symbol_key = true;
+                Thing::DebugMarker => {
+                    if newly_pressed {
+                        console::mark(now);
+                    }
+                    awaiting_clear = true;
+                },
+                Thing::OsCycle => {
+                    if newly_pressed {
+                        settings::update(|settings| settings.os = settings.os.next());
+                        info!("Operating system: {}", settings::get().os);
+                    }
+                    awaiting_clear = true;
+                },
+                Thing::KeyboardLock => {
+                    LOCKED.lock(|locked| locked.set(true));
+                    awaiting_clear = true;
+                },
+                Thing::JigglerToggle => {
+                    if newly_pressed {
+                        let enabled = jiggler::ENABLED.lock(|enabled| {
+                            enabled.set(!enabled.get());
+                            enabled.get()
+                        });
+                        info!("Mouse jiggler: {}", enabled);
+                    }
+                    awaiting_clear = true;
+                },
+                Thing::OutputToggle => {
+                    #[cfg(feature = "ble")]
+                    if newly_pressed {
+                        crate::ble::toggle_output();
+                    }
+                    awaiting_clear = true;
                 },
-                Thing::RightSymbolKey => {
-                    self.state.right_symbol_key = true;
+                Thing::Sequence(keys) => {
+                    if newly_pressed && usb::SEQUENCES.try_send(keys).is_err() {
+                        warn!("Too many sequences waiting, dropped one");
+                    }
+                    awaiting_clear = true;
                 },
-                Thing::NavKey => {
-                    self.state.nav_key = true;
+                Thing::Bootloader => {
+                    usb::REBOOT_TO_BOOTLOADER.signal(());
+                    awaiting_clear = true;
                 },
-                Thing::FunctionKey => {
-                    self.state.function_key = true;
+                Thing::TapHold(_) => {
+                    // not yet known whether it's being tapped or held
                 },
                 Thing::Inactive => {},
-                Thing::DvorakToggle => {
-                    if ! self.state.awaiting_clear {
-                        self.state.emulating_dvorak = !self.state.emulating_dvorak;
+                Thing::LayoutCycle => {
+                    if newly_pressed {
+                        self.state.layout = self.state.layout.next();
+                        info!("Layout: {}", self.state.layout);
                     }
-                    self.state.awaiting_clear = true;
+                    awaiting_clear = true;
                 },
                 Thing::StenoToggle => {
-                    if ! self.state.awaiting_clear {
+                    if newly_pressed {
                         self.state.stenotype = !self.state.stenotype;
+                        info!("Stenotype: {}", self.state.stenotype);
+                    }
+                    awaiting_clear = true;
+                },
+                Thing::StenoProtocolCycle => {
+                    if newly_pressed {
+                        let protocol = steno::PROTOCOL.lock(|protocol| {
+                            protocol.set(protocol.get().next());
+                            protocol.get()
+                        });
+                        info!("Steno protocol: {}", protocol);
+                    }
+                    awaiting_clear = true;
+                },
+                Thing::MidiToggle => {
+                    if newly_pressed {
+                        let enabled = midi::ENABLED.lock(|enabled| {
+                            enabled.set(!enabled.get());
+                            enabled.get()
+                        });
+                        info!("MIDI notes: {}", enabled);
+                    }
+                    awaiting_clear = true;
+                },
+                Thing::PaperTapeToggle => {
+                    if newly_pressed {
+                        let paper_tape = steno::PAPER_TAPE.lock(|paper_tape| {
+                            paper_tape.set(!paper_tape.get());
+                            paper_tape.get()
+                        });
+                        info!("Steno paper tape: {}", paper_tape);
                     }
-                    self.state.awaiting_clear = true;
+                    awaiting_clear = true;
                 },
             }
         }
-        self.pins.scan_led.off();
-        if self.state.awaiting_clear {
-            if self.held_keys.is_all_released() {
-                self.state.awaiting_clear = false;
-                return (KeyboardReport::default(), take(&mut self.steno_packet), self.state)
-            } else {
-                return (KeyboardReport::default(), Default::default(), self.state)
+        if midi_notes != self.midi_notes {
+            midi::play_changes(&self.midi_notes, &midi_notes);
+            self.midi_notes = midi_notes;
+        }
+
+        // shows that the chord has acted, like any other toggle
+        awaiting_clear |= self.steno_chord.is_some_and(|(_, switched)| switched);
+        self.state.awaiting_clear = awaiting_clear;
+
+        // a missed release could leave e.g. push-to-talk on until the pedal is next pressed
+        if self.pedal_held && !pedal_held {
+            usb::CONFIRM_RELEASE.signal(());
+        }
+        self.pedal_held = pedal_held;
+
+        let steno_packet = self.update_steno_stroke(steno_held, now);
+
+        remap_chord(&mut report, os);
+        hid::keep_slots(&self.last_report.0, &mut report);
+        if report != self.last_report.0 {
+            self.last_report = (report, now);
+        }
+
+        let held_for = now - self.last_report.1;
+        if let Some(keycode) = repeating_keycode.filter(|_| held_for >= TYPEMATIC_DELAY && TYPEMATIC.lock(|typematic| typematic.get())) {
+            let into_repeat = (held_for - TYPEMATIC_DELAY).as_ticks() % TYPEMATIC_INTERVAL.as_ticks();
+            if into_repeat < TYPEMATIC_INTERVAL.as_ticks() / 2 {
+                for slot in report.keycodes.iter_mut().filter(|held| **held == keycode) {
+                    *slot = 0;
+                }
             }
         }
-        (report, Default::default(), self.state)
+        (report, consumer_report, steno_packet, self.state)
+    }
+}
+
+/// Hold down `key` in `report`, if there's room left after `next_keycode_idx` keys.
+fn add_key(report: &mut KeyboardReport, next_keycode_idx: &mut usize, (keycode, mods): HidKey) {
+    if *next_keycode_idx < 6 {
+        report.modifier |= mods;
+        report.keycodes[*next_keycode_idx] = keycode;
+        *next_keycode_idx += 1;
+    }
+}
+
+/// Change the shortcut held in `report` into another, if it's one of the [CHORD_REMAPS].
+fn remap_chord(report: &mut KeyboardReport, os: Os) {
+    // so that either Shift, say, makes the same shortcut
+    let either_side = |mods: HidModifiers| (mods | mods >> 4) & 0x0F;
+    for remap in CHORD_REMAPS {
+        let (from, to) = (os.translate(remap.from), os.translate(remap.to));
+        if either_side(report.modifier) != either_side(from.1) {
+            continue;
+        }
+        if let Some(slot) = report.keycodes.iter_mut().find(|held| **held == from.0) {
+            *slot = to.0;
+            report.modifier = to.1;
+            return;
+        }
+    }
+}
+
+/// Look up what the key at `code` does on `layer`, as remapped by [vial] if it has been.
+fn thing_at(layer: &Layer, code: ScanCode) -> Thing {
+    if is_pedal(code) {
+        LAYER_PEDALS[layer_index(layer)][code.1 as usize]
+    } else {
+        vial::thing_at(layer, code.0 as usize, code.1 as usize)
     }
 }
 
+/// Which hand presses the key at `code`, if any (not the pedals).
+fn hand_of(code: ScanCode) -> Option<Hand> {
+    ROW_HANDS.get(code.0 as usize).copied()
+}
+
+/// The "chordal hold" rule, deciding whether an undecided tap-hold key pressed by
+/// `tap_hold_hand` is being held (`Some(true)`) or tapped (`Some(false)`) once a key pressed by
+/// `other_hand` interrupts it.
+///
+/// Pressing a key with the opposite hand means the tap-hold is being held for that hand to use,
+/// whereas pressing one with the same hand is just a fast roll. If either key isn't pressed by a
+/// hand, it's left to be decided by timing alone (`None`).
+fn chordal_hold(tap_hold_hand: Option<Hand>, other_hand: Option<Hand>) -> Option<bool> {
+    Some(tap_hold_hand? != other_hand?)
+}
+
 /// An array for tracking the currently-held keys.
 /// Invariant: Always consists of active [KeyHold]s in order of when they were pressed, followed by
-/// only inactive [KeyHold]s (those whose [KeyHold::debounce_count] has reached 0).
+/// only inactive [KeyHold]s (those whose [KeyHold::debounce_count] has reached 0). Followed by the
+/// switches ignored until they're released, for want of room (see [HELD_KEYS_FULL]).
 #[derive(Default)]
-struct HeldKeys ([KeyHold; HELD_KEYS_LIMIT]);
+struct HeldKeys ([KeyHold; HELD_KEYS_LIMIT], PressedCodes);
 
-#[derive(Default)]
 struct KeyHold {
+    /// Scans left before this is forgotten, unless the switch is seen closed again. With the
+    /// `integrator-debounce` feature, one more than the integrator (see [INTEGRATOR_MAX]).
     debounce_count: u8,
+    /// Consecutive scans the switch has been seen closed, up to [PRESS_DEBOUNCE_COUNT]
+    press_count: u8,
     in_scancode: ScanCode,
     mapping: Thing,
+    /// Scans left before [Self::mapping] is looked up, if it hasn't been yet
+    resolve_delay: Option<u8>,
+    /// Whether the switch was seen closed during the current scan
+    closed: bool,
+    pressed_at: Instant,
+    /// How long after the key pressed before it this one was, for [TapHold::flow_tap_within]
+    after_previous: Duration,
+    /// Whether this is being held back, unresolved, in case it's part of one of the [COMBOS]
+    awaiting_combo: bool,
+    /// Whether [Self::mapping] has been acted on, so that toggles only toggle once per press
+    acted: bool,
+    /// Whether another key has been pressed while this one was held, so that a [Thing::LayerTap]
+    /// is being held for its layer rather than tapped
+    interrupted: bool,
+    /// Whether the key was pressed on one of the [IMMEDIATE_PRESS_LAYERS], so counts as pressed as
+    /// soon as it's seen closed
+    immediate_press: bool,
+}
+
+impl Default for KeyHold {
+    fn default() -> Self {
+        KeyHold {
+            debounce_count: 0,
+            press_count: 0,
+            in_scancode: (0, 0),
+            mapping: Thing::Inactive,
+            resolve_delay: None,
+            closed: false,
+            pressed_at: Instant::MIN,
+            after_previous: Duration::MAX,
+            awaiting_combo: false,
+            acted: false,
+            interrupted: false,
+            immediate_press: false,
+        }
+    }
+}
+
+impl KeyHold {
+    #[cfg(not(feature = "integrator-debounce"))]
+    fn is_debounced(&self) -> bool {
+        self.press_count >= PRESS_DEBOUNCE_COUNT
+    }
+
+    /// Once the integrator is back to 0, the key is released, although it's only forgotten after
+    /// the next scan.
+    #[cfg(feature = "integrator-debounce")]
+    fn is_debounced(&self) -> bool {
+        self.press_count >= PRESS_DEBOUNCE_COUNT && self.debounce_count > 1
+    }
+
+    /// Count the switch as seen closed during this scan. Until a press is confirmed, a single scan
+    /// with the switch open is enough to forget it.
+    #[cfg(not(feature = "integrator-debounce"))]
+    fn see_closed(&mut self) {
+        self.press_count = if self.immediate_press { PRESS_DEBOUNCE_COUNT } else { PRESS_DEBOUNCE_COUNT.min(self.press_count + 1) };
+        self.debounce_count = if self.is_debounced() { RELEASE_DEBOUNCE_COUNT } else { 1 };
+        self.closed = true;
+    }
+
+    /// Count the switch as seen closed during this scan, counting its integrator up: by two, as
+    /// [HeldKeys::decrement_holds] has already counted it down for this scan, or all the way for an
+    /// [Self::immediate_press].
+    #[cfg(feature = "integrator-debounce")]
+    fn see_closed(&mut self) {
+        self.debounce_count = if self.immediate_press { INTEGRATOR_MAX + 1 } else { (self.debounce_count + 2).min(INTEGRATOR_MAX + 1) };
+        if self.debounce_count > INTEGRATOR_MAX {
+            self.press_count = PRESS_DEBOUNCE_COUNT;
+        }
+        self.closed = true;
+    }
 }
 
 impl HeldKeys {
-    fn record_pressed(&mut self, code: ScanCode, mapping: Thing) {
-        for maybe_key in &mut self.0 {
-            if maybe_key.debounce_count > 0 {
-                if maybe_key.in_scancode == code {
-                    maybe_key.debounce_count = DEFAULT_DEBOUNCE_COUNT;
-                    return;
-                }
+    /// Reset the debounce count of `code` if it is already held, returning whether it was.
+    fn refresh(&mut self, code: ScanCode) -> bool {
+        if self.1.contains(&code) {
+            return true;
+        }
+        for key in self.iter_active_mut() {
+            if key.in_scancode == code {
+                key.see_closed();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Start tracking a newly pressed key, with its mapping either already decided or left to
+    /// [Self::resolve_pending] once [LAYER_PRESS_DELAY] has passed. `previous_press` is when the
+    /// key before it was pressed, and `layer` the layer it's pressed on.
+    fn insert(&mut self, code: ScanCode, mapping: Option<Thing>, now: Instant, previous_press: Instant, layer: &Layer) {
+        if let Some(free) = self.make_room(code) {
+            let free = &mut self.0[free];
+            *free = KeyHold {
+                in_scancode: code,
+                mapping: mapping.unwrap_or_default(),
+                press_count: 0,
+                debounce_count: 0,
+                resolve_delay: if mapping.is_some() { None } else { Some(LAYER_PRESS_DELAY) },
+                closed: false,
+                pressed_at: now,
+                after_previous: now - previous_press,
+                awaiting_combo: false,
+                acted: false,
+                interrupted: false,
+                immediate_press: IMMEDIATE_PRESS_LAYERS.iter().any(|&immediate| core::ptr::eq(immediate, layer)),
+            };
+            free.see_closed();
+        }
+    }
+
+    /// Find a free position for newly pressed `code`, if need be letting go of an older key to make
+    /// room for it, according to [HELD_KEYS_FULL]. Whichever key is left out is ignored until its
+    /// switch is released.
+    fn make_room(&mut self, code: ScanCode) -> Option<usize> {
+        if let Some(free) = self.0.iter().position(|key_hold| key_hold.debounce_count == 0) {
+            return Some(free);
+        }
+        HELD_KEYS_OVERFLOWS.lock(|overflows| overflows.set(overflows.get().saturating_add(1)));
+        let evicted = match HELD_KEYS_FULL.lock(|full| full.get()) {
+            HeldKeysFull::RejectNew => None,
+            HeldKeysFull::EvictOldest => self.0.iter().position(|key_hold|
+                !key_hold.mapping.is_modifier() && !key_hold.mapping.is_layer_key() && !is_pedal(key_hold.in_scancode)
+            ),
+        };
+        let Some(evicted) = evicted else {
+            warn!("Too many keys held, ignoring row {} column {}", code.0, code.1);
+            self.1.push(code).expect("fits every key");
+            return None;
+        };
+        let oldest = self.0[evicted].in_scancode;
+        warn!("Too many keys held, letting go of row {} column {}", oldest.0, oldest.1);
+        self.1.push(oldest).expect("fits every key");
+        self.0[evicted..].rotate_left(1);
+        Some(HELD_KEYS_LIMIT - 1)
+    }
+
+    /// Stop ignoring switches left out for want of room once they're no longer among those closed,
+    /// in `pressed`.
+    fn forget_overflowed(&mut self, pressed: &[ScanCode]) {
+        self.1.retain(|code| pressed.contains(code));
+    }
+
+    /// Hold back keys newly pressed at `now` which are in any of the [COMBOS] (only
+    /// `on_normal_layer`), until either every key of one has been held together for its
+    /// [Combo::overlap], or it's clear that none is being pressed: one of the keys held back is
+    /// released, a key in none of them is pressed, or [COMBO_TERM] passes without one being
+    /// completed. A combo is done by the first of its keys, the rest doing nothing; otherwise the
+    /// keys are left to [Self::resolve_pending] to be typed as usual.
+    fn resolve_combos(&mut self, now: Instant, on_normal_layer: bool) {
+        let is_combo_key = |code| COMBOS.iter().any(|combo| combo.keys.contains(&thing_at(&LAYER_NORMAL, code)));
+        let mut interrupted = false;
+        for key in self.iter_active_mut().filter(|key| key.pressed_at == now) {
+            if on_normal_layer && key.resolve_delay.is_some() && is_combo_key(key.in_scancode) {
+                key.awaiting_combo = true;
             } else {
-                *maybe_key = KeyHold {
-                    in_scancode: code,
-                    mapping,
-                    debounce_count: DEFAULT_DEBOUNCE_COUNT,
-                };
-                return;
+                interrupted = true;
             }
         }
+
+        let mut waiting = heapless::Vec::<Thing, HELD_KEYS_LIMIT>::new();
+        let (mut first_pressed, mut last_pressed, mut released) = (Instant::MAX, Instant::MIN, false);
+        for key in self.iter_active_mut().filter(|key| key.awaiting_combo) {
+            waiting.push(thing_at(&LAYER_NORMAL, key.in_scancode)).expect("fits every held key");
+            first_pressed = first_pressed.min(key.pressed_at);
+            last_pressed = last_pressed.max(key.pressed_at);
+            released |= !key.closed;
+        }
+        if waiting.is_empty() {
+            return;
+        }
+
+        let mut possible = COMBOS.iter().filter(|combo| waiting.iter().all(|thing| combo.keys.contains(thing)));
+        let complete = possible.clone().find(|combo| combo.keys.len() == waiting.len());
+        let combo = if released || interrupted {
+            None
+        } else if let Some(combo) = complete.filter(|combo| now - last_pressed >= combo.overlap) {
+            Some(combo)
+        } else if possible.next().is_some() && (complete.is_some() || now - first_pressed < COMBO_TERM) {
+            return;  // still waiting for the rest of the keys, or for them to overlap long enough
+        } else {
+            None
+        };
+
+        if let Some(combo) = combo {
+            info!("Combo of {} keys pressed", combo.keys.len());
+        }
+        let mut first = true;
+        for key in self.iter_active_mut().filter(|key| key.awaiting_combo) {
+            key.awaiting_combo = false;
+            if let Some(combo) = combo {
+                key.mapping = if first { combo.thing } else { Thing::Inactive };
+                key.resolve_delay = None;
+                first = false;
+            }
+        }
+    }
+
+    /// Decide the mappings of keys whose [KeyHold::resolve_delay] has run out.
+    fn resolve_pending(&mut self, lookup: impl Fn(ScanCode) -> Thing) {
+        for key in self.iter_active_mut().filter(|key| key.is_debounced() && !key.awaiting_combo) {
+            match key.resolve_delay {
+                Some(0) => {
+                    key.mapping = lookup(key.in_scancode);
+                    key.resolve_delay = None;
+                },
+                Some(ref mut scans_left) => *scans_left -= 1,
+                None => {},
+            }
+        }
+    }
+
+    /// Decide whether [Thing::TapHold]s are being tapped (released before their time is up) or
+    /// held (still closed once it is). A tap then stays pressed until its release is debounced.
+    ///
+    /// If other keys (`new_codes`) are pressed before then, its [TapHoldFlavour] may decide sooner
+    /// (as by [chordal_hold]). And if the tap-hold itself followed another key quickly enough, it's
+    /// tapped (see [TapHold::flow_tap_within]).
+    ///
+    /// A [Thing::LayerTap] selects its layer from the start, and only becomes its key (pressed until
+    /// its release is debounced) if released within [LAYER_TAP_TERM] with no other key pressed.
+    fn resolve_tap_holds(&mut self, now: Instant, new_codes: &[ScanCode]) {
+        for key in self.iter_active_mut().filter(|key| key.is_debounced()) {
+            if let Thing::LayerTap(_, tap) = key.mapping {
+                key.interrupted |= new_codes.iter().any(|&code| code != key.in_scancode);
+                if !key.closed && !key.interrupted && now - key.pressed_at <= LAYER_TAP_TERM {
+                    key.mapping = Thing::RealKey(tap);
+                }
+            }
+            if let Thing::TapHold(tap_hold) = key.mapping {
+                let interrupted = new_codes.iter().filter(|&&code| code != key.in_scancode).find_map(|&code| match tap_hold.flavour {
+                    TapHoldFlavour::Chordal => chordal_hold(hand_of(key.in_scancode), hand_of(code)),
+                    TapHoldFlavour::HoldPreferred => Some(true),
+                    TapHoldFlavour::TapPreferred => None,
+                });
+                let flowing = tap_hold.flow_tap_within.is_some_and(|within| key.after_previous < within);
+                if !key.closed || flowing {
+                    key.mapping = tap_hold.tap;
+                } else if let Some(held) = interrupted {
+                    key.mapping = if held { tap_hold.hold } else { tap_hold.tap };
+                } else if now - key.pressed_at >= tap_hold.hold_after {
+                    key.mapping = tap_hold.hold;
+                }
+            }
+        }
+    }
+
+    fn iter_active_mut(&mut self) -> impl Iterator<Item = &mut KeyHold> {
+        self.0.iter_mut().take_while(|key_hold| key_hold.debounce_count > 0)
+    }
+
+    fn iter_pressed_mut(&mut self) -> impl Iterator<Item = &mut KeyHold> {
+        self.iter_active_mut().filter(|key_hold| key_hold.is_debounced() && key_hold.resolve_delay.is_none())
     }
 
     fn iter_pressed_things(&self) -> impl Iterator<Item = &Thing> {
         self.0.iter().take_while(|key_hold|
             key_hold.debounce_count > 0
+        ).filter(|key_hold|
+            key_hold.is_debounced() && key_hold.resolve_delay.is_none()
         ).map(|key_hold| {
             &key_hold.mapping
         })
     }
 
+    /// Let go of every key held on the matrix which might be in a report (not pedals or layer
+    /// keys), ignoring each until its switch is released, and return how many there were.
+    fn release_stuck(&mut self) -> usize {
+        let mut released = 0;
+        let mut key_idx = 0;
+        while key_idx < HELD_KEYS_LIMIT && self.0[key_idx].debounce_count > 0 {
+            let key = &self.0[key_idx];
+            if !key.is_debounced() || key.resolve_delay.is_some() || key.mapping.is_layer_key() || is_pedal(key.in_scancode) {
+                key_idx += 1;
+                continue;
+            }
+            self.1.push(key.in_scancode).expect("fits every key");
+            // as in [Self::decrement_holds], moved to the end to preserve the invariant
+            self.0[key_idx..].rotate_left(1);
+            self.0[HELD_KEYS_LIMIT - 1] = KeyHold::default();
+            released += 1;
+        }
+        released
+    }
+
     fn is_all_released(&self) -> bool {
-        self.0[0].debounce_count == 0
+        self.0[0].debounce_count == 0 && self.1.is_empty()
     }
 
     fn decrement_holds(&mut self) {
         'each_position: for key_idx in 0..HELD_KEYS_LIMIT {
             'each_rotation: loop {
                 let key = &mut self.0[key_idx];
+                key.closed = false;
                 if key.debounce_count > 0 {
                     key.debounce_count -= 1;
                     if key.debounce_count == 0 {
@@ -258,3 +1764,6 @@ impl HeldKeys {
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
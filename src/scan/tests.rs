@@ -0,0 +1,240 @@
+//! Tests of the [Interpreter], run on the host: fed scans as [Matrix] would feed it, with what it
+//! sends recorded as [crate::run_matrix] would send it.
+
+use super::*;
+use crate::rmk::keycode::KeyCode;
+use std::sync::{Mutex as StdMutex, MutexGuard};
+
+/// Golden tests, of each layer, from recorded scans, made with the full board's switches and
+/// debounced by counting down
+#[cfg(not(any(feature = "macropad", feature = "integrator-debounce")))]
+mod golden;
+/// Random presses, checking that nothing gets stuck
+mod fuzz;
+/// Keys keeping their report slots through rolls
+mod slots;
+/// Tap-hold keys, remapped onto the home row
+mod tap_hold;
+/// Steno strokes, and the number key
+mod strokes;
+/// Toggle keys pressed among other keys
+mod toggles;
+/// Switches bouncing as they're pressed and released
+mod debounce;
+/// Keys the built-in keymap leaves out, remapped onto it, timed as debounced by counting down
+#[cfg(not(any(feature = "macropad", feature = "integrator-debounce")))]
+mod remapped;
+#[cfg(not(any(feature = "macropad", feature = "integrator-debounce")))]
+mod combos;
+/// Keys held long enough to be taken as stuck
+#[cfg(not(feature = "macropad"))]
+mod stuck;
+
+/// Held while an [Interpreter] is driven, as the settings it reads are global, and the tests run
+/// at the same time
+static GLOBALS: StdMutex<()> = StdMutex::new(());
+
+/// Put every setting the [Interpreter] reads back as it is at boot, so that each test starts the
+/// same whatever ran before.
+fn reset_globals() {
+    PEDAL_MODES.lock(|modes| modes.set([PedalMode::Momentary; PEDALS.len()]));
+    HELD_KEYS_FULL.lock(|full| full.set(HeldKeysFull::RejectNew));
+    TYPEMATIC.lock(|typematic| typematic.set(false));
+    LAYER_PREVIEW.lock(|preview| preview.set(false));
+    LOCKED.lock(|locked| locked.set(false));
+    MODE_IDLE_TIMEOUT.lock(|timeout| timeout.set(Some(Duration::from_secs(30 * 60))));
+    steno::NUMBER_KEY.lock(|number_key| number_key.set(steno::NumberKey::Momentary));
+    steno::PROTOCOL.lock(|protocol| protocol.set(steno::DEFAULT_PROTOCOL));
+    midi::ENABLED.lock(|enabled| enabled.set(false));
+    settings::update(|settings| settings.os = Os::Linux);
+    while usb::SEQUENCES.try_receive().is_ok() {}
+    vial::reset_keymap();
+}
+
+/// Something sent to the host: a report whenever it changes, a steno stroke, or a macro queued
+/// to be typed
+#[derive(Clone, Copy, PartialEq)]
+enum Sent {
+    Keys(KeyboardReport),
+    Consumer(u16),
+    Stroke(GeminiPacket),
+    Sequence(&'static [u8]),
+}
+
+/// Names of the modifier bits of a keyboard report, lowest first
+const MODIFIER_NAMES: [&str; 8] = ["LCtrl", "LShift", "LAlt", "LGui", "RCtrl", "RShift", "RAlt", "RGui"];
+
+impl Sent {
+    /// One line describing this, as the fixtures write what they expect to be sent: `keys`, then
+    /// each modifier and key held (in slot order), `consumer` and the usage in hex, `stroke` and
+    /// the stroke in steno notation, or `sequence` and each byte of its bytecode in hex.
+    fn describe(&self) -> String {
+        match self {
+            Sent::Keys(report) => {
+                let mut line = String::from("keys");
+                for (bit, name) in MODIFIER_NAMES.iter().enumerate() {
+                    if report.modifier & 1 << bit != 0 {
+                        line += " ";
+                        line += name;
+                    }
+                }
+                for &keycode in report.keycodes.iter().filter(|&&keycode| keycode != 0) {
+                    let mut label = console::Label::new();
+                    console::key_label(&mut label, (keycode, 0));
+                    line += " ";
+                    line += &label;
+                }
+                line
+            },
+            Sent::Consumer(usage) => format!("consumer {:x}", usage),
+            Sent::Stroke(packet) => format!("stroke {}", steno::to_notation(packet).trim_end()),
+            Sent::Sequence(code) => code.iter().fold(String::from("sequence"), |line, byte| format!("{} {:02x}", line, byte)),
+        }
+    }
+}
+
+/// The HID usage ID of `key`, as sent in a keyboard report
+const fn usage(key: KeyCode) -> u8 {
+    key as u16 as u8
+}
+
+/// Feeds an [Interpreter] a scan every [SCAN_INTERVAL], as [Matrix] does, finding whichever
+/// switches have been pressed and not yet released closed, and records what it [Sent].
+struct Driver {
+    interpreter: Interpreter,
+    /// Switches closed, in scan order
+    closed: PressedCodes,
+    /// Switches closed as of the last scan
+    last_pressed: PressedCodes,
+    /// When the next scan is
+    now: Instant,
+    keys: KeyboardReport,
+    consumer: u16,
+    /// Everything sent so far, with when its scan was
+    sent: Vec<(Instant, Sent)>,
+    _globals: MutexGuard<'static, ()>,
+}
+
+impl Driver {
+    fn new() -> Self {
+        let globals = GLOBALS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_globals();
+        Driver {
+            interpreter: Interpreter::new(),
+            closed: PressedCodes::new(),
+            last_pressed: PressedCodes::new(),
+            now: Instant::from_ticks(0),
+            keys: KeyboardReport::default(),
+            consumer: 0,
+            sent: Vec::new(),
+            _globals: globals,
+        }
+    }
+
+    /// Close the switch at `code`, from the next scan on.
+    fn press(&mut self, code: ScanCode) {
+        if !self.closed.contains(&code) {
+            self.closed.push(code).expect("fits every key");
+            // rows are strobed in turn, with the pedals read after them
+            self.closed.sort_unstable();
+        }
+    }
+
+    /// Open the switch at `code` again, from the next scan on.
+    fn release(&mut self, code: ScanCode) {
+        self.closed.retain(|&closed| closed != code);
+    }
+
+    /// Scan once, at [Self::now], recording anything sent, and move on to the next scan.
+    fn scan(&mut self) -> ScanOutput {
+        let pressed = self.closed.clone();
+        // as [Matrix::scan_awake] skips the work while nothing can change
+        let output = if pressed == self.last_pressed && self.interpreter.is_idle() {
+            self.interpreter.idle(self.now)
+        } else {
+            self.interpreter.process(&pressed, self.now)
+        };
+        self.last_pressed = pressed;
+
+        let (keys, consumer, stroke, _) = output;
+        // as run_matrix does, with usb taking each report before the next scan
+        if hid::is_handed_over(&self.keys, &keys, false) {
+            self.sent.push((self.now, Sent::Keys(keys)));
+            self.keys = keys;
+        }
+        if consumer.usage_id != self.consumer {
+            self.sent.push((self.now, Sent::Consumer(consumer.usage_id)));
+            self.consumer = consumer.usage_id;
+        }
+        if !stroke.is_empty() {
+            self.sent.push((self.now, Sent::Stroke(stroke)));
+        }
+        while let Ok(code) = usb::SEQUENCES.try_receive() {
+            self.sent.push((self.now, Sent::Sequence(code)));
+        }
+        self.now += SCAN_INTERVAL;
+        output
+    }
+
+    /// Scan for `time`.
+    fn scan_for(&mut self, time: Duration) {
+        let until = self.now + time;
+        while self.now < until {
+            self.scan();
+        }
+    }
+
+    /// Everything sent so far, one per line, each after the time of its scan (in ms).
+    fn transcript(&self) -> Vec<String> {
+        self.sent.iter().map(|(at, sent)| format!("{} {}", at.as_millis(), sent.describe())).collect()
+    }
+}
+
+#[test]
+fn stroke_is_built_from_every_steno_key_held() {
+    let mut driver = Driver::new();
+    driver.interpreter.state.stenotype = true;
+    driver.interpreter.layer = &LAYER_STENO;
+    let mut expected = GeminiPacket::default();
+    for row in 0..=ROWS as u8 {
+        for column in (0..COLUMNS as u8).filter(|&column| is_switch((row, column))) {
+            if let Thing::StenoKey(code) = thing_at(&LAYER_STENO, (row, column)) {
+                driver.press((row, column));
+                expected.press(code);
+            }
+        }
+    }
+    for _ in 0..25 {
+        driver.scan();
+    }
+    driver.closed.clear();
+    for _ in 0..25 {
+        driver.scan();
+    }
+
+    let strokes: Vec<[u8; steno::PACKET_LEN]> = driver.sent.iter().filter_map(|(_, sent)| match sent {
+        Sent::Stroke(packet) => Some(packet.to_bytes()),
+        _ => None,
+    }).collect();
+    assert_eq!(strokes, [expected.to_bytes()]);
+    #[cfg(not(feature = "macropad"))]
+    assert_eq!(expected.to_bytes(), [0xa0, 0x7f, 0x7c, 0x3f, 0x7f, 0x01], "every key is on the steno layer");
+}
+
+#[test]
+fn bare_modifier_tap_is_sent_pressed_then_released() {
+    // LShift on W, in no combo
+    const SHIFT: ScanCode = (0, 3);
+    let mut driver = Driver::new();
+    vial::remap(&LAYER_NORMAL, SHIFT.0 as usize, SHIFT.1 as usize, Thing::RealKey((0, 0x02)));
+    driver.press(SHIFT);
+    for _ in 0..10 {
+        driver.scan();
+    }
+    driver.release(SHIFT);
+    for _ in 0..25 {
+        driver.scan();
+    }
+    let sent: Vec<String> = driver.sent.iter().map(|(_, sent)| sent.describe()).collect();
+    assert_eq!(sent, ["keys LShift", "keys"]);
+}
@@ -0,0 +1,60 @@
+//! The built-in A+S+D combo on the normal layer, against typing the same keys.
+
+use super::*;
+
+/// A, S and D on the normal layer, which together are Ctrl+Alt+T
+const A: ScanCode = (1, 4);
+const S: ScanCode = (1, 3);
+const D: ScanCode = (1, 2);
+
+/// How long the A+S+D combo's keys must be held together, from [COMBOS]
+fn overlap() -> Duration {
+    COMBOS.iter().find(|combo| combo.keys.len() == 3).expect("the A+S+D combo").overlap
+}
+
+/// Press `codes` in turn, each `apart` after the last, then release them in the same order, each
+/// `held` after it was pressed.
+fn roll(driver: &mut Driver, codes: &[ScanCode], apart: Duration, held: Duration) {
+    let start = driver.now;
+    let mut events: Vec<(Instant, bool, ScanCode)> = Vec::new();
+    for (idx, &code) in codes.iter().enumerate() {
+        let pressed_at = start + apart * idx as u32;
+        events.push((pressed_at, true, code));
+        events.push((pressed_at + held, false, code));
+    }
+    events.sort_by_key(|&(at, is_press, _)| (at, is_press));
+    for (at, is_press, code) in events {
+        driver.scan_for(at - driver.now);
+        if is_press { driver.press(code) } else { driver.release(code) }
+    }
+    driver.scan_for(Duration::from_millis(200));
+}
+
+#[test]
+fn roll_faster_than_the_overlap_types_the_keys() {
+    let mut driver = Driver::new();
+    roll(&mut driver, &[A, S, D], Duration::from_millis(10), overlap());
+    // A is let go of 10ms after D is pressed, when all of them are typed at once
+    assert_eq!(driver.transcript(), ["34 keys A S D", "38 keys S D", "48 keys D", "58 keys"]);
+}
+
+#[test]
+fn chord_held_for_the_overlap_is_the_shortcut() {
+    let mut driver = Driver::new();
+    roll(&mut driver, &[A, S, D], Duration::from_millis(5), overlap() * 2);
+    assert_eq!(driver.transcript(), ["40 keys LCtrl LAlt T", "68 keys"], "none of the keys typed");
+}
+
+#[test]
+fn part_of_the_chord_released_early_types_its_keys() {
+    let mut driver = Driver::new();
+    roll(&mut driver, &[A, S], Duration::from_millis(5), overlap() / 2);
+    assert_eq!(driver.transcript(), ["20 keys A S", "24 keys S", "28 keys"]);
+}
+
+#[test]
+fn part_of_the_chord_held_past_the_combo_term_types_its_keys() {
+    let mut driver = Driver::new();
+    roll(&mut driver, &[A, S], Duration::from_millis(5), COMBO_TERM * 2);
+    assert_eq!(driver.transcript(), ["54 keys A S", "108 keys S", "114 keys"]);
+}
@@ -0,0 +1,133 @@
+//! Debouncing, from traces of a single switch bouncing as it's pressed and released: one character
+//! a scan, `#` where the switch was seen closed and `.` where it was seen open. Counting down,
+//! presses are taken at once, and only releases wait for the switch to settle. The traces of
+//! misbehaving switches are played through whichever debouncer is built in, to compare them.
+
+use super::*;
+
+/// W on the normal layer, in no combo, so sent as soon as the layer delay has passed
+const W: ScanCode = (0, 3);
+
+#[cfg(not(feature = "integrator-debounce"))]
+const _: () = assert!(PRESS_DEBOUNCE_COUNT == 1 && RELEASE_DEBOUNCE_COUNT > PRESS_DEBOUNCE_COUNT, "as these tests expect");
+
+/// How long to keep scanning with the switch open after its trace, for any release to be sent
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Play `trace` on switch `code` from the first scan, then leave it open for [SETTLE_TIME],
+/// returning when the key was sent pressed (`true`) or released (`false`), in ms.
+fn play(trace: &str, code: ScanCode) -> Vec<(u64, bool)> {
+    let mut driver = Driver::new();
+    for sample in trace.chars().filter(|&sample| sample != ' ') {
+        match sample {
+            '#' => driver.press(code),
+            '.' => driver.release(code),
+            _ => panic!("traces are made of # and ."),
+        }
+        driver.scan();
+    }
+    driver.release(code);
+    driver.scan_for(SETTLE_TIME);
+    driver.sent.iter().filter_map(|(at, sent)| match sent {
+        Sent::Keys(report) => Some((at.as_millis(), report.keycodes[0] != 0)),
+        _ => None,
+    }).collect()
+}
+
+/// The time of the scan `scans` into a trace, in ms
+fn ms(scans: u8) -> u64 {
+    (SCAN_INTERVAL * scans as u32).as_millis()
+}
+
+/// When a switch seen open from the scan `scans` into a trace onwards is sent released, in ms:
+/// once it's been open for as many scans as the release debounce counts down from the last scan
+/// it was seen closed
+#[cfg(not(feature = "integrator-debounce"))]
+fn released(scans: u8) -> u64 {
+    ms(scans + RELEASE_DEBOUNCE_COUNT - 1)
+}
+
+#[cfg(not(feature = "integrator-debounce"))]
+#[test]
+fn press_is_sent_without_waiting_for_it_to_settle() {
+    // only as late as every key is, in case a layer key is pressed along with it
+    assert_eq!(play("##########", W), [(ms(LAYER_PRESS_DELAY), true), (released(10), false)]);
+}
+
+#[cfg(not(feature = "integrator-debounce"))]
+#[test]
+fn chatter_on_press_is_one_press() {
+    assert_eq!(play("#.#.##.######", W), [(ms(LAYER_PRESS_DELAY), true), (released(13), false)]);
+}
+
+#[cfg(not(feature = "integrator-debounce"))]
+#[test]
+fn chatter_on_release_is_one_release_once_it_settles() {
+    // open for longer and longer, but never for long enough until it's open for good
+    let trace = "########## .#..#...#";
+    assert_eq!(play(trace, W), [(ms(LAYER_PRESS_DELAY), true), (released(19), false)]);
+}
+
+#[cfg(not(feature = "integrator-debounce"))]
+#[test]
+fn release_debounce_is_longer_than_press_debounce() {
+    // open for just long enough to be released, then closed again: pressed again at once
+    let open = RELEASE_DEBOUNCE_COUNT - 1;
+    let trace = format!("##########{}##########", ".".repeat(open as usize));
+    let changes = play(&trace, W);
+    assert_eq!(changes[..3], [(ms(LAYER_PRESS_DELAY), true), (released(10), false), (ms(10 + open + LAYER_PRESS_DELAY), true)]);
+}
+
+
+/// A clean press and release
+const CLEAN: &str = "##########";
+/// Contacts bouncing for a few scans as they close
+const BOUNCY_PRESS: &str = "#.#..##.##########";
+/// Contacts bouncing for a few scans as they open
+const BOUNCY_RELEASE: &str = "########## .#.##..#.#";
+/// A held switch whose worn contacts open every few scans
+const MARGINAL_HOLD: &str = "###.##.#.###.##.#.##.####";
+/// A released switch read closed for a single scan, as by a marginal contact or interference
+const GLITCH: &str = "... #. ...";
+/// A switch let go of whose contacts still touch every few scans, last at scan 28
+const LEAKY_RELEASE: &str = "########## ..#...#...#...#...#";
+
+#[test]
+fn bouncing_and_marginal_switches_are_each_one_press() {
+    for trace in [CLEAN, BOUNCY_PRESS, BOUNCY_RELEASE, MARGINAL_HOLD] {
+        let changes: Vec<bool> = play(trace, W).into_iter().map(|(_, pressed)| pressed).collect();
+        assert_eq!(changes, [true, false], "{}", trace);
+    }
+}
+
+#[test]
+fn integrator_waits_for_a_bouncy_press_to_settle() {
+    let (pressed, _) = play(BOUNCY_PRESS, W)[0];
+    if cfg!(feature = "integrator-debounce") {
+        // after the last bounce, at scan 7
+        assert!(pressed > ms(7), "pressed at {}ms", pressed);
+    } else {
+        assert_eq!(pressed, ms(LAYER_PRESS_DELAY));
+    }
+}
+
+#[test]
+fn glitch_is_a_keystroke_only_when_counting_down() {
+    let changes = play(GLITCH, W);
+    if cfg!(feature = "integrator-debounce") {
+        assert_eq!(changes, []);
+    } else {
+        assert_eq!(changes.len(), 2, "tapped");
+    }
+}
+
+#[test]
+fn integrator_runs_down_through_a_leaky_release() {
+    let (released, _) = play(LEAKY_RELEASE, W)[1];
+    // counting down starts over at each touch, whereas the integrator falls by more than it rises
+    if cfg!(feature = "integrator-debounce") {
+        assert!(released < ms(28), "released at {}ms", released);
+    } else {
+        assert!(released > ms(28), "released at {}ms", released);
+    }
+}
@@ -0,0 +1,136 @@
+//! Property tests: switches pressed, released and bounced at random, on every layer and in every
+//! mode they lead to, checking after each scan that nothing is ever left in a state it shouldn't
+//! be, whatever the order.
+
+use super::*;
+
+/// Runs, each from its own seed
+const RUNS: u64 = 64;
+/// Things done to the switches in each run
+const STEPS: usize = 300;
+/// How long after every switch is released that nothing at all should be held, enough for any
+/// debouncing, combo, tap-hold or turbo key to have finished
+const SETTLE_TIME: Duration = Duration::from_millis(600);
+
+/// Tiny xorshift generator, so that each run can be repeated from its seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // never zero, which xorshift would be stuck at
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Somewhere from 0 up to but not including `n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+/// Every switch on the board, and each pedal
+fn switches() -> Vec<ScanCode> {
+    (0..=ROWS as u8).flat_map(|row| (0..COLUMNS as u8).map(move |column| (row, column))).filter(|&code| is_switch(code)).collect()
+}
+
+/// Panic, saying how to repeat this run and what led up to it, unless `ok`.
+fn check(ok: bool, what: &str, seed: u64, driver: &Driver) {
+    if !ok {
+        let transcript = driver.transcript();
+        let recent = &transcript[transcript.len().saturating_sub(10)..];
+        panic!("{} at {}ms of run {} (last sent:\n{})", what, driver.now.as_millis(), seed, recent.join("\n"));
+    }
+}
+
+/// Whether `report` is one the host can make sense of: no key in it twice, and modifiers only in
+/// its modifier byte
+fn is_well_formed(report: &KeyboardReport) -> bool {
+    let keys = report.keycodes.iter().filter(|&&keycode| keycode != 0);
+    keys.clone().enumerate().all(|(idx, keycode)| !keys.clone().skip(idx + 1).any(|other| other == keycode))
+        && !report.keycodes.iter().any(|keycode| (0xe0..=0xe7).contains(keycode))
+}
+
+/// Whether the [HeldKeys] invariant holds: the active holds first, in the order they were pressed,
+/// then only inactive ones, and no switch both held and ignored, or held twice.
+fn is_in_order(held_keys: &HeldKeys) -> bool {
+    let active = held_keys.0.iter().take_while(|key_hold| key_hold.debounce_count > 0);
+    let active_count = active.clone().count();
+    active.clone().zip(active.clone().skip(1)).all(|(earlier, later)| earlier.pressed_at <= later.pressed_at)
+        && held_keys.0[active_count..].iter().all(|key_hold| key_hold.debounce_count == 0)
+        && active.clone().enumerate().all(|(idx, key_hold)|
+            !active.clone().skip(idx + 1).any(|other| other.in_scancode == key_hold.in_scancode)
+            && !held_keys.1.contains(&key_hold.in_scancode)
+        )
+}
+
+/// Scan once, checking everything which should hold after any scan.
+fn scan_and_check(driver: &mut Driver, seed: u64) {
+    let (keys, _, _, _) = driver.scan();
+    check(is_well_formed(&keys), "malformed report", seed, driver);
+    check(is_in_order(&driver.interpreter.held_keys), "held keys out of order", seed, driver);
+}
+
+/// Let go of every switch, and check that once they've had time to settle, nothing is held.
+fn release_all_and_check(driver: &mut Driver, seed: u64) {
+    driver.closed.clear();
+    let settled = driver.now + SETTLE_TIME;
+    while driver.now < settled {
+        scan_and_check(driver, seed);
+    }
+    check(driver.keys == KeyboardReport::default(), "key left held", seed, driver);
+    check(driver.consumer == 0, "consumer key left held", seed, driver);
+    check(driver.interpreter.held_keys.is_all_released(), "switch left tracked", seed, driver);
+}
+
+#[test]
+fn random_presses_leave_nothing_held() {
+    let switches = switches();
+    for seed in 0..RUNS {
+        let mut rng = Rng::new(seed);
+        let mut driver = Driver::new();
+        driver.interpreter.state.layout = Layout::ALL[rng.below(Layout::ALL.len())];
+        driver.interpreter.state.stenotype = rng.chance(25);
+        driver.interpreter.layer = driver.interpreter.choose_layer_for_state();
+        let full = if rng.chance(50) { HeldKeysFull::RejectNew } else { HeldKeysFull::EvictOldest };
+        HELD_KEYS_FULL.lock(|held_keys_full| held_keys_full.set(full));
+
+        for _ in 0..STEPS {
+            let code = switches[rng.below(switches.len())];
+            let closed = driver.closed.contains(&code);
+            match rng.below(100) {
+                // press or release a switch cleanly
+                0..60 => if closed { driver.release(code) } else { driver.press(code) },
+                // or with it bouncing for a few scans first
+                60..80 => {
+                    for _ in 0..1 + rng.below(4) {
+                        if closed { driver.release(code) } else { driver.press(code) }
+                        scan_and_check(&mut driver, seed);
+                        if closed { driver.press(code) } else { driver.release(code) }
+                        scan_and_check(&mut driver, seed);
+                    }
+                    if closed { driver.release(code) } else { driver.press(code) }
+                },
+                // release whichever closed switch is scanned first, to let go of the rest in turn
+                80..95 => if let Some(&oldest) = driver.closed.first() { driver.release(oldest) },
+                // stop typing for a moment
+                _ => release_all_and_check(&mut driver, seed),
+            }
+            // mostly quick typing, sometimes long enough for tap-holds and double-taps to time out
+            let scans = if rng.chance(10) { rng.below(150) } else { rng.below(25) };
+            for _ in 0..scans {
+                scan_and_check(&mut driver, seed);
+            }
+        }
+        release_all_and_check(&mut driver, seed);
+    }
+}
@@ -0,0 +1,105 @@
+//! Golden tests of typing on each layer, each from a fixture in `fixtures/`: a recording of which
+//! switches were pressed and released when, along with everything which should be sent to the
+//! host as a result.
+//!
+//! Each line of a fixture is one of these, with anything after `//` a comment:
+//! - `layout <layout>`, to type in that [Layout] (as named in the code) from the start
+//! - `steno`, to start in steno mode
+//! - `<ms> press <switch>` or `<ms> release <switch>`, for a switch found closed or open from the
+//!   first scan at or after `<ms>`, with switches written as `<row>,<column>` or as `pedal<n>`
+//! - `<ms> end`, for the last scan to be at or before `<ms>` (otherwise the last line's time)
+//! - `<ms> keys [<modifier>...] [<key>...]`, `<ms> consumer <usage>`, `<ms> stroke <notation>` or
+//!   `<ms> sequence <byte>...`, for something expected to be sent by the scan at `<ms>`, written as
+//!   [Sent::describe] does
+//!
+//! Everything sent must be expected, in order, for the fixture to pass.
+
+use super::*;
+
+/// Whether a fixture line is something done to a switch (`true` for a press)
+type Input = (u64, ScanCode, bool);
+
+/// The switch written as `word` in a fixture
+fn parse_switch(word: &str) -> ScanCode {
+    if let Some(pedal) = word.strip_prefix("pedal") {
+        let pedal: usize = pedal.parse().expect("pedals are numbered");
+        return pedal_fake_scancode(pedal - 1);
+    }
+    let (row, column) = word.split_once(',').expect("switches are <row>,<column>");
+    let code = (row.parse().expect("row is a number"), column.parse().expect("column is a number"));
+    assert!(is_switch(code), "no switch at {}", word);
+    code
+}
+
+/// Run the fixture `text`, failing with everything actually sent if it wasn't what the fixture
+/// expects.
+fn check_fixture(text: &str) {
+    let mut driver = Driver::new();
+    let mut inputs = Vec::<Input>::new();
+    let mut expected = Vec::<String>::new();
+    let mut end = None;
+    for line in text.lines() {
+        let line = line.split("//").next().unwrap_or_default();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {},
+            ["layout", name] => {
+                let layout = Layout::ALL.into_iter().find(|layout| format!("{:?}", layout) == *name);
+                driver.interpreter.state.layout = layout.expect("layout is one of Layout::ALL");
+            },
+            ["steno"] => driver.interpreter.state.stenotype = true,
+            [ms, rest @ ..] => {
+                let ms: u64 = ms.parse().unwrap_or_else(|_| panic!("bad fixture line: {}", line));
+                match rest {
+                    ["press", switch] => inputs.push((ms, parse_switch(switch), true)),
+                    ["release", switch] => inputs.push((ms, parse_switch(switch), false)),
+                    ["end"] => end = Some(ms),
+                    ["keys" | "consumer" | "stroke" | "sequence", ..] => expected.push(words.join(" ")),
+                    _ => panic!("bad fixture line: {}", line),
+                }
+                end = end.max(Some(ms));
+            },
+        }
+    }
+
+    // as if the layout or mode had been chosen by a key, before the first scan
+    driver.interpreter.layer = driver.interpreter.choose_layer_for_state();
+
+    inputs.sort_by_key(|&(ms, _, _)| ms);
+    let mut inputs = inputs.into_iter().peekable();
+    while driver.now <= Instant::from_millis(end.unwrap_or_default()) {
+        while let Some((_, code, pressed)) = inputs.next_if(|&(ms, _, _)| Instant::from_millis(ms) <= driver.now) {
+            if pressed {
+                driver.press(code);
+            } else {
+                driver.release(code);
+            }
+        }
+        driver.scan();
+    }
+
+    let sent = driver.transcript();
+    assert!(sent == expected, "expected:\n{}\n\nbut sent:\n{}\n", expected.join("\n"), sent.join("\n"));
+}
+
+/// A test of each fixture, named after the layer it types on
+macro_rules! fixture_tests {
+    ($($layer:ident),* $(,)?) => {$(
+        #[test]
+        fn $layer() {
+            check_fixture(include_str!(concat!("fixtures/", stringify!($layer), ".scans")));
+        }
+    )*};
+}
+
+fixture_tests!(
+    normal,
+    dvorak_emu,
+    colemak_dh_emu,
+    workman_emu,
+    symbols,
+    dvorak_emu_symbols,
+    navigation,
+    function,
+    steno,
+);
@@ -0,0 +1,79 @@
+//! Keys which the built-in keymap leaves out, remapped onto the normal layer to try them: turbo
+//! keys, macros, and keys which select a layer as well as doing something else.
+
+use super::*;
+use crate::macros::{bytecode, Step};
+use crate::rmk::keycode::KeyCode;
+
+/// W on the normal layer, in no combo, where each key is remapped
+const W: ScanCode = (0, 3);
+/// F on the normal layer, and F4 on the navigation layer
+const F: ScanCode = (1, 1);
+
+/// A driver with [W] remapped to `thing`.
+fn driver_with(thing: Thing) -> Driver {
+    let driver = Driver::new();
+    vial::remap(&LAYER_NORMAL, W.0 as usize, W.1 as usize, thing);
+    driver
+}
+
+#[test]
+fn turbo_key_is_pressed_for_half_of_each_period_while_held() {
+    let mut driver = driver_with(Thing::Turbo((usage(KeyCode::Down), 0), Duration::from_millis(40)));
+    driver.press(W);
+    driver.scan_for(Duration::from_millis(100));
+    driver.release(W);
+    driver.scan_for(Duration::from_millis(100));
+    assert_eq!(driver.transcript(), ["4 keys Down", "20 keys", "40 keys Down", "60 keys", "80 keys Down", "100 keys"]);
+}
+
+#[test]
+fn sequence_is_queued_once_per_press() {
+    let code = bytecode!(Step::Tap((usage(KeyCode::Minus), 0)), Step::Tap((usage(KeyCode::Dot), 0x02)));
+    let mut driver = driver_with(Thing::Sequence(code));
+    for _ in 0..2 {
+        driver.press(W);
+        driver.scan_for(Duration::from_millis(100));
+        driver.release(W);
+        driver.scan_for(Duration::from_millis(100));
+    }
+    assert_eq!(driver.transcript(), ["4 sequence 01 2d 00 01 37 02", "204 sequence 01 2d 00 01 37 02"]);
+}
+
+#[test]
+fn layer_tap_types_its_key_when_tapped() {
+    let mut driver = driver_with(Thing::LayerTap(LayerRef(&LAYER_NAVIGATION), (usage(KeyCode::Escape), 0)));
+    driver.press(W);
+    driver.scan_for(Duration::from_millis(20));
+    driver.release(W);
+    driver.scan_for(Duration::from_millis(100));
+    assert_eq!(driver.transcript(), ["20 keys Esc", "28 keys"]);
+}
+
+#[test]
+fn layer_tap_selects_its_layer_while_held() {
+    let mut driver = driver_with(Thing::LayerTap(LayerRef(&LAYER_NAVIGATION), (usage(KeyCode::Escape), 0)));
+    driver.press(W);
+    driver.scan_for(Duration::from_millis(20));
+    driver.press(F);
+    driver.scan_for(Duration::from_millis(20));
+    driver.release(F);
+    driver.scan_for(Duration::from_millis(20));
+    driver.release(W);
+    driver.scan_for(Duration::from_millis(100));
+    assert_eq!(driver.transcript(), ["24 keys F4", "48 keys"], "no Escape");
+}
+
+#[test]
+fn modified_layer_holds_its_modifiers_on_its_layer() {
+    let mut driver = driver_with(Thing::ModifiedLayer(LayerRef(&LAYER_NAVIGATION), 0x01));
+    driver.press(W);
+    driver.scan_for(Duration::from_millis(20));
+    driver.press(F);
+    driver.scan_for(Duration::from_millis(20));
+    driver.release(F);
+    driver.scan_for(Duration::from_millis(20));
+    driver.release(W);
+    driver.scan_for(Duration::from_millis(100));
+    assert_eq!(driver.transcript(), ["0 keys LCtrl", "24 keys LCtrl F4", "48 keys LCtrl", "68 keys"]);
+}
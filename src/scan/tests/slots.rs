@@ -0,0 +1,70 @@
+//! Fast rolls, checking that every key stays in the same slot of the keyboard report for as long
+//! as it's held, whatever is pressed and released around it.
+
+use super::*;
+use crate::rmk::keycode::KeyCode;
+
+/// Keys along the top row of the normal layer, in no combo, and on every board
+const T: ScanCode = (0, 0);
+const R: ScanCode = (0, 1);
+const E: ScanCode = (0, 2);
+const W: ScanCode = (0, 3);
+
+/// Press and release switches as `steps` say, at their times in ms (`true` for a press), then
+/// return every keyboard report sent.
+fn roll(steps: &[(u64, ScanCode, bool)]) -> Vec<KeyboardReport> {
+    let mut driver = Driver::new();
+    for &(ms, code, pressed) in steps {
+        while driver.now < Instant::from_millis(ms) {
+            driver.scan();
+        }
+        if pressed {
+            driver.press(code);
+        } else {
+            driver.release(code);
+        }
+    }
+    for _ in 0..50 {
+        driver.scan();
+    }
+    driver.sent.iter().filter_map(|(_, sent)| match sent {
+        Sent::Keys(report) => Some(*report),
+        _ => None,
+    }).collect()
+}
+
+/// Check that no key in `reports` moves to another slot from one report to the next.
+fn assert_slots_kept(reports: &[KeyboardReport]) {
+    for pair in reports.windows(2) {
+        for (slot, &keycode) in pair[0].keycodes.iter().enumerate().filter(|&(_, &keycode)| keycode != 0) {
+            if let Some(moved_to) = pair[1].keycodes.iter().position(|&other| other == keycode) {
+                assert_eq!(moved_to, slot, "key {:#x} moved from {:?} to {:?}", keycode, pair[0].keycodes, pair[1].keycodes);
+            }
+        }
+    }
+}
+
+#[test]
+fn keys_keep_their_slots_through_a_fast_roll() {
+    // each key pressed before the one before it is released
+    let reports = roll(&[
+        (0, W, true), (6, E, true), (10, R, true),
+        (24, W, false), (36, T, true), (44, E, false),
+        (50, R, false), (56, T, false),
+    ]);
+    assert_slots_kept(&reports);
+    // T takes the slot W left, with E and R still where they were
+    let w_e_r = reports.iter().find(|report| report.keycodes[..3].iter().all(|&keycode| keycode != 0)).expect("W, E and R held at once");
+    assert!(reports.iter().any(|report| report.keycodes[..3] == [usage(KeyCode::T), w_e_r.keycodes[1], w_e_r.keycodes[2]]), "T in W's slot");
+    assert_eq!(reports.last().map(|report| report.keycodes), Some([0; 6]));
+}
+
+#[test]
+fn key_released_from_the_middle_of_a_roll_leaves_a_gap() {
+    let reports = roll(&[
+        (0, W, true), (6, E, true), (12, R, true),
+        (30, E, false), (50, W, false), (60, R, false),
+    ]);
+    assert_slots_kept(&reports);
+    assert!(reports.iter().any(|report| report.keycodes[..3] == [usage(KeyCode::W), 0, usage(KeyCode::R)]), "W and R either side of E's slot");
+}
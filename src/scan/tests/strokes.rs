@@ -0,0 +1,145 @@
+//! Steno strokes written in steno mode, with the left hand's keys, which every board has: the
+//! number key, and keys bouncing as a stroke is let go.
+
+use super::*;
+
+const S: ScanCode = (0, 4);
+const T: ScanCode = (0, 3);
+const NUMBER: ScanCode = (3, 5);
+
+/// Long enough for a stroke not to be taken as accidental, and for its keys' releases to be
+/// debounced
+const HOLD_TIME: Duration = Duration::from_millis(60);
+
+/// A driver in steno mode.
+fn steno_driver() -> Driver {
+    let mut driver = Driver::new();
+    driver.interpreter.state.stenotype = true;
+    driver.interpreter.layer = driver.interpreter.choose_layer_for_state();
+    driver
+}
+
+/// Write a stroke of `keys`, pressed together and released together.
+fn write(driver: &mut Driver, keys: &[ScanCode]) {
+    for &key in keys {
+        driver.press(key);
+    }
+    driver.scan_for(HOLD_TIME);
+    for &key in keys {
+        driver.release(key);
+    }
+    driver.scan_for(HOLD_TIME);
+}
+
+/// Every stroke sent, in steno notation.
+fn strokes(driver: &Driver) -> Vec<String> {
+    driver.sent.iter().filter_map(|(_, sent)| match sent {
+        Sent::Stroke(packet) => Some(steno::to_notation(packet).trim_end().to_string()),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn momentary_number_key_numbers_its_own_stroke() {
+    let mut driver = steno_driver();
+    write(&mut driver, &[NUMBER, S]);
+    write(&mut driver, &[T]);
+    write(&mut driver, &[NUMBER]);
+    assert_eq!(strokes(&driver), ["#S", "T", "#"]);
+}
+
+#[test]
+fn latched_number_key_numbers_the_next_stroke_only() {
+    let mut driver = steno_driver();
+    steno::NUMBER_KEY.lock(|number_key| number_key.set(steno::NumberKey::Latched));
+    write(&mut driver, &[NUMBER]);
+    write(&mut driver, &[S]);
+    write(&mut driver, &[T]);
+    assert_eq!(strokes(&driver), ["#S", "T"]);
+}
+
+#[test]
+fn latched_number_key_tapped_again_unlatches() {
+    let mut driver = steno_driver();
+    steno::NUMBER_KEY.lock(|number_key| number_key.set(steno::NumberKey::Latched));
+    write(&mut driver, &[NUMBER]);
+    write(&mut driver, &[NUMBER]);
+    write(&mut driver, &[S]);
+    assert_eq!(strokes(&driver), ["S"]);
+}
+
+#[test]
+fn latched_number_key_still_numbers_a_stroke_it_is_pressed_in() {
+    let mut driver = steno_driver();
+    steno::NUMBER_KEY.lock(|number_key| number_key.set(steno::NumberKey::Latched));
+    write(&mut driver, &[NUMBER, S]);
+    write(&mut driver, &[T]);
+    assert_eq!(strokes(&driver), ["#S", "T"]);
+}
+
+const P: ScanCode = (0, 2);
+
+/// Play a trace for each of several switches at once, one character a scan from the first, `#`
+/// where the switch was seen closed and `.` where it was seen open, then let every switch go for
+/// [HOLD_TIME]. Returns when each stroke was sent, in ms, and what it was.
+fn play(driver: &mut Driver, traces: &[(ScanCode, &str)]) -> Vec<(u64, String)> {
+    let scans = traces.iter().map(|(_, trace)| trace.len()).max().unwrap_or_default();
+    let start = driver.sent.len();
+    for scan in 0..scans {
+        for &(code, trace) in traces {
+            match trace.as_bytes().get(scan) {
+                Some(b'#') => driver.press(code),
+                _ => driver.release(code),
+            }
+        }
+        driver.scan();
+    }
+    for &(code, _) in traces {
+        driver.release(code);
+    }
+    driver.scan_for(HOLD_TIME);
+    driver.sent[start..].iter().filter_map(|(at, sent)| match sent {
+        Sent::Stroke(packet) => Some((at.as_millis(), steno::to_notation(packet).trim_end().to_string())),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn key_bouncing_back_as_the_stroke_is_let_go_stays_out_of_the_next() {
+    let mut driver = steno_driver();
+    // T comes up well before S, for longer than the release debounce, then bounces back down
+    // until after S is up
+    let sent = play(&mut driver, &[
+        (S, "########################################"),
+        (T, "####################..........#########################"),
+    ]);
+    // taken once S is up, without waiting for T (up for good at 110ms), and T makes no stroke of
+    // its own
+    assert_eq!(sent.len(), 1, "{:?}", sent);
+    assert_eq!(sent[0].1, "ST");
+    assert!(sent[0].0 < 110, "stroke held up by T until {}ms", sent[0].0);
+
+    // once it's been released, T joins the next stroke as usual
+    let next = play(&mut driver, &[(T, "##############################"), (P, "##############################")]);
+    assert_eq!(next.into_iter().map(|(_, stroke)| stroke).collect::<Vec<_>>(), ["TP"]);
+}
+
+#[test]
+fn key_chattering_on_release_makes_one_stroke() {
+    let mut driver = steno_driver();
+    let sent = play(&mut driver, &[
+        (S, "#########################.#..#.#"),
+        (T, "###########################.##.#..#"),
+    ]);
+    assert_eq!(sent.into_iter().map(|(_, stroke)| stroke).collect::<Vec<_>>(), ["ST"]);
+}
+
+#[test]
+fn key_pressed_late_still_joins_the_stroke() {
+    let mut driver = steno_driver();
+    let sent = play(&mut driver, &[
+        (S, "########################################"),
+        (T, "....................####################"),
+    ]);
+    assert_eq!(sent.into_iter().map(|(_, stroke)| stroke).collect::<Vec<_>>(), ["ST"]);
+}
@@ -0,0 +1,65 @@
+//! Keys held unchanged for [STUCK_KEY_TIMEOUT]: only those on the matrix are let go of, and nothing
+//! else is reset.
+
+use super::*;
+use crate::rmk::keycode::KeyCode;
+
+/// A on Dvorak's letters, and backslash on the symbols layer
+const A_OR_BACKSLASH: ScanCode = (1, 4);
+/// H on Dvorak's letters, and Left on the navigation layer
+const H_OR_LEFT: ScanCode = (5, 1);
+
+#[test]
+fn toggled_pedal_is_never_stuck() {
+    let mut driver = Driver::new();
+    PEDAL_MODES.lock(|modes| modes.set([PedalMode::Toggle; PEDALS.len()]));
+    driver.scan_for(Duration::from_millis(100));
+    // the second pedal toggles the navigation layer on, where the first pages down
+    for pedal in [1, 0] {
+        driver.press(pedal_fake_scancode(pedal));
+        driver.scan_for(Duration::from_millis(20));
+        driver.release(pedal_fake_scancode(pedal));
+        driver.scan_for(Duration::from_millis(100));
+    }
+    assert_eq!(driver.keys.keycodes[0], usage(KeyCode::PageDown));
+
+    driver.scan_for(STUCK_KEY_TIMEOUT * 2);
+    assert_eq!(driver.keys.keycodes[0], usage(KeyCode::PageDown), "toggled pedal let go of");
+    assert_eq!(driver.interpreter.pedal_toggles.map(|(toggled, _)| toggled), [true, true]);
+
+    // a key held alongside it is let go of, but not the pedal
+    driver.press(H_OR_LEFT);
+    driver.scan_for(Duration::from_millis(100));
+    assert_eq!(driver.keys.keycodes[..2], [usage(KeyCode::PageDown), usage(KeyCode::Left)]);
+    driver.scan_for(STUCK_KEY_TIMEOUT);
+    assert_eq!(driver.keys.keycodes[..2], [usage(KeyCode::PageDown), 0], "only the stuck key is let go of");
+    assert!(driver.interpreter.state.pedal_toggled && driver.interpreter.state.nav_key);
+}
+
+#[test]
+fn stuck_key_is_let_go_of_without_resetting_anything_else() {
+    let mut driver = Driver::new();
+    driver.interpreter.state.layout = Layout::DvorakEmu;
+    driver.interpreter.state.locked_layer_key = Some(LockableLayerKey::LeftSymbol);
+    driver.press(A_OR_BACKSLASH);
+    driver.scan_for(Duration::from_millis(100));
+    assert_eq!(driver.keys.keycodes[0], usage(KeyCode::Backslash));
+
+    driver.scan_for(STUCK_KEY_TIMEOUT);
+    assert_eq!(driver.keys, KeyboardReport::default(), "stuck key not let go of");
+    assert_eq!(driver.interpreter.state.layout, Layout::DvorakEmu);
+    assert!(driver.interpreter.state.locked_layer_key == Some(LockableLayerKey::LeftSymbol));
+
+    // other keys type as usual while the stuck one is still closed, and it types again once it's
+    // released and pressed again
+    driver.interpreter.state.locked_layer_key = None;
+    driver.press(H_OR_LEFT);
+    driver.scan_for(Duration::from_millis(100));
+    assert_eq!(driver.keys.keycodes[..2], [usage(KeyCode::H), 0]);
+    driver.release(H_OR_LEFT);
+    driver.release(A_OR_BACKSLASH);
+    driver.scan_for(Duration::from_millis(100));
+    driver.press(A_OR_BACKSLASH);
+    driver.scan_for(Duration::from_millis(100));
+    assert_eq!(driver.keys.keycodes[0], usage(KeyCode::A));
+}
@@ -0,0 +1,131 @@
+//! Tap-hold keys, as home-row modifiers remapped onto the normal layer, typed in among other keys.
+
+use super::*;
+use crate::rmk::keycode::KeyCode;
+
+/// F on the normal layer, remapped to [ctrl_or_f]
+const F: ScanCode = (1, 1);
+/// W on the normal layer, typed before F, or with the same hand while F is held
+const W: ScanCode = (0, 3);
+/// J on the normal layer, typed with the other hand while F is held
+#[cfg(not(feature = "macropad"))]
+const J: ScanCode = (5, 1);
+
+const LEFT_CTRL: HidModifiers = 0x01;
+
+/// A home-row Ctrl on F, held after [HOLD_AFTER], and always tapped within `flow_tap_within` of
+/// the key before it
+const fn ctrl_or_f(flow_tap_within: Option<Duration>, flavour: TapHoldFlavour) -> TapHold {
+    TapHold {
+        tap: Thing::RealKey((usage(KeyCode::F), 0)),
+        hold: Thing::RealKey((0, LEFT_CTRL)),
+        hold_after: HOLD_AFTER,
+        flow_tap_within,
+        flavour,
+    }
+}
+
+const HOLD_AFTER: Duration = Duration::from_millis(200);
+const FLOW_TAP_WITHIN: Duration = Duration::from_millis(150);
+static FLOW_TAPPED: TapHold = ctrl_or_f(Some(FLOW_TAP_WITHIN), TapHoldFlavour::Chordal);
+static NOT_FLOW_TAPPED: TapHold = ctrl_or_f(None, TapHoldFlavour::Chordal);
+static HOLD_PREFERRED: TapHold = ctrl_or_f(None, TapHoldFlavour::HoldPreferred);
+static TAP_PREFERRED: TapHold = ctrl_or_f(None, TapHoldFlavour::TapPreferred);
+
+/// A driver with F remapped to `tap_hold`.
+fn driver_with(tap_hold: &'static TapHold) -> Driver {
+    let driver = Driver::new();
+    vial::remap(&LAYER_NORMAL, F.0 as usize, F.1 as usize, Thing::TapHold(tap_hold));
+    driver
+}
+
+/// Tap W, then press F `after` W was pressed, and hold it for longer than [HOLD_AFTER].
+fn type_w_then_hold_f(driver: &mut Driver, after: Duration) {
+    driver.press(W);
+    driver.scan_for(Duration::from_millis(30));
+    driver.release(W);
+    driver.scan_for(after - Duration::from_millis(30));
+    driver.press(F);
+    driver.scan_for(HOLD_AFTER * 2);
+}
+
+#[test]
+fn held_soon_after_another_key_is_flow_tapped() {
+    let mut driver = driver_with(&FLOW_TAPPED);
+    type_w_then_hold_f(&mut driver, FLOW_TAP_WITHIN / 2);
+    assert_eq!((driver.keys.modifier, driver.keys.keycodes[0]), (0, usage(KeyCode::F)), "typed, not held");
+}
+
+#[test]
+fn held_once_the_flow_has_stopped_is_held() {
+    let mut driver = driver_with(&FLOW_TAPPED);
+    type_w_then_hold_f(&mut driver, FLOW_TAP_WITHIN * 2);
+    assert_eq!((driver.keys.modifier, driver.keys.keycodes[0]), (LEFT_CTRL, 0));
+}
+
+#[test]
+fn held_soon_after_another_key_is_held_without_flow_tap() {
+    let mut driver = driver_with(&NOT_FLOW_TAPPED);
+    type_w_then_hold_f(&mut driver, FLOW_TAP_WITHIN / 2);
+    assert_eq!((driver.keys.modifier, driver.keys.keycodes[0]), (LEFT_CTRL, 0));
+}
+
+#[test]
+fn flow_tap_is_sent_at_once() {
+    let mut driver = driver_with(&FLOW_TAPPED);
+    type_w_then_hold_f(&mut driver, FLOW_TAP_WITHIN / 2);
+    // as soon as F is pressed, without waiting to see whether it's held
+    let (pressed_f, _) = driver.sent.iter().find(|(_, sent)| matches!(sent, Sent::Keys(report) if report.keycodes[0] == usage(KeyCode::F))).expect("F sent");
+    assert!(*pressed_f - Instant::from_millis(FLOW_TAP_WITHIN.as_millis() / 2) < Duration::from_millis(20));
+}
+
+
+/// Press F, tap `other` while it's held, then release F, all well before [HOLD_AFTER], returning
+/// what was sent, without when.
+fn tap_while_held(tap_hold: &'static TapHold, other: ScanCode) -> Vec<String> {
+    let mut driver = driver_with(tap_hold);
+    for (code, pressed) in [(F, true), (other, true), (other, false), (F, false)] {
+        if pressed {
+            driver.press(code);
+        } else {
+            driver.release(code);
+        }
+        driver.scan_for(Duration::from_millis(30));
+    }
+    driver.scan_for(HOLD_AFTER);
+    driver.sent.iter().map(|(_, sent)| sent.describe()).collect()
+}
+
+#[test]
+fn chordal_is_tapped_when_rolled_into_with_the_same_hand() {
+    assert_eq!(tap_while_held(&NOT_FLOW_TAPPED, W), ["keys F", "keys F W", "keys F", "keys"]);
+}
+
+#[cfg(not(feature = "macropad"))]
+#[test]
+fn chordal_is_held_for_the_other_hand() {
+    assert_eq!(tap_while_held(&NOT_FLOW_TAPPED, J), ["keys LCtrl", "keys LCtrl J", "keys LCtrl", "keys"]);
+}
+
+#[test]
+fn hold_preferred_is_held_for_any_other_key() {
+    assert_eq!(tap_while_held(&HOLD_PREFERRED, W), ["keys LCtrl", "keys LCtrl W", "keys LCtrl", "keys"]);
+    #[cfg(not(feature = "macropad"))]
+    assert_eq!(tap_while_held(&HOLD_PREFERRED, J), ["keys LCtrl", "keys LCtrl J", "keys LCtrl", "keys"]);
+}
+
+#[test]
+fn tap_preferred_is_not_decided_by_other_keys() {
+    // typed in the order the keys were released, as F is only known to be tapped once it's up
+    assert_eq!(tap_while_held(&TAP_PREFERRED, W), ["keys W", "keys", "keys F", "keys"]);
+}
+
+#[test]
+fn tap_preferred_is_held_once_its_time_is_up() {
+    let mut driver = driver_with(&TAP_PREFERRED);
+    driver.press(F);
+    driver.scan_for(HOLD_AFTER + Duration::from_millis(30));
+    driver.press(W);
+    driver.scan_for(Duration::from_millis(30));
+    assert_eq!((driver.keys.modifier, driver.keys.keycodes[0]), (LEFT_CTRL, usage(KeyCode::W)));
+}
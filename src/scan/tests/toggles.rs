@@ -0,0 +1,89 @@
+//! Toggle keys pressed among other keys: each toggles once per press, and only waits for its own
+//! release, without holding up keys typed around it.
+
+use super::*;
+use crate::rmk::keycode::KeyCode;
+
+/// V on the normal layer, remapped to cycle the operating system
+const OS_CYCLE: ScanCode = (2, 1);
+const W: ScanCode = (0, 3);
+const E: ScanCode = (0, 2);
+/// The steno toggle on the steno layer, and W on the normal layer
+const STENO_TOGGLE: ScanCode = (3, 4);
+
+/// A driver with [OS_CYCLE] remapped to cycle the operating system.
+fn driver_with_os_cycle() -> Driver {
+    let driver = Driver::new();
+    vial::remap(&LAYER_NORMAL, OS_CYCLE.0 as usize, OS_CYCLE.1 as usize, Thing::OsCycle);
+    driver
+}
+
+/// Press `code`, or release it if `pressed` is false, then scan for 40ms.
+fn step(driver: &mut Driver, code: ScanCode, pressed: bool) {
+    if pressed {
+        driver.press(code);
+    } else {
+        driver.release(code);
+    }
+    driver.scan_for(Duration::from_millis(40));
+}
+
+/// Each keyboard report sent, as its first key.
+fn first_keys(driver: &Driver) -> Vec<u8> {
+    driver.sent.iter().filter_map(|(_, sent)| match sent {
+        Sent::Keys(report) => Some(report.keycodes[0]),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn keys_are_typed_while_a_toggle_is_held() {
+    let mut driver = driver_with_os_cycle();
+    step(&mut driver, OS_CYCLE, true);
+    for _ in 0..2 {
+        step(&mut driver, W, true);
+        step(&mut driver, W, false);
+    }
+    step(&mut driver, OS_CYCLE, false);
+    let w = usage(KeyCode::W);
+    assert_eq!(first_keys(&driver), [w, 0, w, 0]);
+    assert!(settings::get().os == Os::Linux.next(), "cycled once for the one press");
+}
+
+#[test]
+fn toggle_pressed_mid_roll_lets_the_roll_finish() {
+    let mut driver = driver_with_os_cycle();
+    step(&mut driver, W, true);
+    step(&mut driver, OS_CYCLE, true);
+    step(&mut driver, W, false);
+    step(&mut driver, E, true);
+    step(&mut driver, E, false);
+    step(&mut driver, OS_CYCLE, false);
+    assert_eq!(first_keys(&driver), [usage(KeyCode::W), 0, usage(KeyCode::E), 0]);
+}
+
+#[test]
+fn toggle_toggles_again_on_each_press() {
+    let mut driver = driver_with_os_cycle();
+    for _ in 0..2 {
+        step(&mut driver, OS_CYCLE, true);
+        step(&mut driver, OS_CYCLE, false);
+    }
+    assert!(settings::get().os == Os::Linux.next().next());
+    assert!(driver.sent.is_empty());
+}
+
+#[test]
+fn typing_starts_before_the_steno_toggle_is_released() {
+    let mut driver = Driver::new();
+    driver.interpreter.state.stenotype = true;
+    driver.interpreter.layer = driver.interpreter.choose_layer_for_state();
+    driver.press(STENO_TOGGLE);
+    driver.scan_for(Thing::StenoToggle.deliberate_hold() + Duration::from_millis(100));
+    assert!(!driver.interpreter.state.stenotype);
+    step(&mut driver, W, true);
+    step(&mut driver, W, false);
+    step(&mut driver, STENO_TOGGLE, false);
+    assert_eq!(first_keys(&driver), [usage(KeyCode::W), 0]);
+    assert!(!driver.interpreter.state.stenotype, "toggled once");
+}
@@ -0,0 +1,61 @@
+//! Checks made once at power-on, before the matrix starts being scanned, for bringing up new
+//! boards: that no matrix line is shorted, and that the saved [crate::settings] and keymap are
+//! intact. Any failure is shown as a count of blinks on the status LED for a few seconds, then the
+//! keyboard starts up anyway, as it may well still be usable.
+
+use crate::boards::{SENSES, STROBES};
+use crate::led::{self, LedCommand, Light, Pattern};
+use crate::scan::{self, STROBE_SETTLE_TIME};
+use embassy_rp::gpio::Flex;
+use embassy_time::{block_for, Duration, Timer};
+
+/// How long to show each failure's blink code for
+const REPORT_TIME: Duration = Duration::from_secs(4);
+
+/// Something found wrong, shown as its number of blinks
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "debug-log", derive(defmt::Format))]
+pub enum Failure {
+    /// A line read (a column, on most boards) reads as closed with nothing strobed, so is shorted to
+    /// ground (or the supply)
+    SenseStuck = 1,
+    /// A line read reads as closed while a line is strobed, so is shorted to it (or a key was held
+    /// down while plugging in)
+    StrobeShorted = 2,
+    /// The saved settings fail their checksum, so the defaults are being used
+    SettingsCorrupt = 3,
+    /// The saved keymap fails its checksum, so the built-in keymap is being used, without the keys
+    /// remapped from Vial
+    KeymapCorrupt = 4,
+}
+
+const _: () = assert!(Failure::KeymapCorrupt as usize <= led::MAX_BLINK_COUNT, "failures must be told apart by blinks");
+
+/// Look for shorts in the matrix, with no keys pressed, leaving every line released.
+pub fn check_matrix(strobes: &mut [Flex<'_>; STROBES], senses: &[Flex<'_>; SENSES]) -> Option<Failure> {
+    block_for(STROBE_SETTLE_TIME);
+    if senses.iter().any(scan::is_active) {
+        return Some(Failure::SenseStuck);
+    }
+    for line in strobes.iter_mut() {
+        scan::strobe(line, true);
+        block_for(STROBE_SETTLE_TIME);
+        let shorted = senses.iter().any(scan::is_active);
+        scan::strobe(line, false);
+        block_for(STROBE_SETTLE_TIME);
+        if shorted {
+            return Some(Failure::StrobeShorted);
+        }
+    }
+    None
+}
+
+/// Show each of `failures` on the status LED in turn.
+pub async fn report(failures: impl IntoIterator<Item = Failure>) {
+    for failure in failures {
+        warn!("Self test failed: {}", failure);
+        led::send(LedCommand::Status(Pattern::BlinkCount(Light::FULL, failure as u8)));
+        Timer::after(REPORT_TIME).await;
+    }
+    led::send(LedCommand::Status(Pattern::Off));
+}
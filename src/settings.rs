@@ -0,0 +1,269 @@
+//! Settings changed from the keyboard which are kept in the last sector of flash, so that they
+//! survive being unplugged, along with keys remapped from Vial (see [vial]) in the sector before.
+
+use crate::led::{self, Indicator, INDICATOR_BYTES};
+use crate::os::Os;
+use crate::vial::{self, SerializedKeymap};
+use crate::RawMutex;
+use core::cell::Cell;
+use embassy_rp::{
+    flash::{Blocking, Flash, ERASE_SIZE},
+    peripherals::FLASH,
+};
+use embassy_futures::select::select;
+use embassy_sync::{blocking_mutex::Mutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+/// Size of the flash chip on the Pico
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// Offset of the sector kept for settings, left out of the program's FLASH region in memory.x
+const SETTINGS_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+/// Offset of the sector kept for the remapped keys, likewise left out, just before the settings
+const KEYMAP_OFFSET: u32 = SETTINGS_OFFSET - ERASE_SIZE as u32;
+const _: () = assert!(size_of::<SerializedKeymap>() <= ERASE_SIZE, "the keymap must fit in its sector");
+
+/// Marks the sector as holding settings in this layout, rather than being erased or left over
+/// from something else
+const MAGIC: [u8; 4] = *b"OCK1";
+
+/// How long to wait after a change before saving, so that cycling through a few values only wears
+/// the flash once.
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+pub type SettingsFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Settings {
+    /// Index into [crate::led::LED_BRIGHTNESS_LEVELS]
+    pub led_brightness_level: u8,
+    /// Operating system whose quirks to work around
+    pub os: Os,
+    /// What the status LED shows for each of [led::Indication::ALL], in the same order
+    pub indicators: [Indicator; led::Indication::ALL.len()],
+    /// The host last paired with over Bluetooth, to reconnect to without pairing again
+    pub bond: Option<Bond>,
+}
+
+/// What's kept of a Bluetooth pairing (see [crate::ble])
+#[derive(Clone, Copy, PartialEq)]
+pub struct Bond {
+    /// The host's address
+    pub address: [u8; 6],
+    /// Long-term key agreed with the host, which encrypts each connection after the first
+    pub ltk: [u8; 16],
+}
+
+const DEFAULTS: Settings = Settings {
+    led_brightness_level: 0,
+    os: Os::Linux,
+    indicators: led::DEFAULT_INDICATORS,
+    bond: None,
+};
+
+static SETTINGS: Mutex<RawMutex, Cell<Settings>> = Mutex::new(Cell::new(DEFAULTS));
+/// Raised whenever [SETTINGS] change, to have them saved
+static CHANGED: Signal<RawMutex, ()> = Signal::new();
+/// Raised whenever keys are remapped, to have the keymap saved
+static KEYMAP_CHANGED: Signal<RawMutex, ()> = Signal::new();
+
+/// The magic, then each setting, then a [crc8] of the settings, then the indicators, added later,
+/// with a [crc8] of their own, then the bond, added later still, likewise
+type SerializedSettings = [u8; BOND_CHECKSUM_INDEX + 1];
+const CHECKSUM_INDEX: usize = MAGIC.len() + 2;
+const INDICATORS_INDEX: usize = CHECKSUM_INDEX + 1;
+const INDICATORS_CHECKSUM_INDEX: usize = INDICATORS_INDEX + INDICATOR_BYTES * led::Indication::ALL.len();
+/// Where the bond starts: a byte saying whether there is one, then its address and key
+const BOND_INDEX: usize = INDICATORS_CHECKSUM_INDEX + 1;
+const BOND_ADDRESS_INDEX: usize = BOND_INDEX + 1;
+const BOND_LTK_INDEX: usize = BOND_ADDRESS_INDEX + 6;
+const BOND_CHECKSUM_INDEX: usize = BOND_LTK_INDEX + 16;
+/// [BOND_INDEX] of a saved bond
+const BONDED: u8 = 1;
+
+/// Why saved settings (or a saved keymap) weren't loaded
+pub enum LoadError {
+    /// Nothing saved (or not in this layout)
+    Missing,
+    /// Saved, but not as they were written
+    Corrupt,
+}
+
+impl Settings {
+    fn serialize(&self) -> SerializedSettings {
+        let mut bytes = [0; BOND_CHECKSUM_INDEX + 1];
+        bytes[..MAGIC.len()].copy_from_slice(&MAGIC);
+        bytes[MAGIC.len()] = self.led_brightness_level;
+        bytes[MAGIC.len() + 1] = self.os.to_byte();
+        bytes[CHECKSUM_INDEX] = crc8(&bytes[MAGIC.len()..CHECKSUM_INDEX]);
+        for (chunk, indicator) in bytes[INDICATORS_INDEX..INDICATORS_CHECKSUM_INDEX].chunks_exact_mut(INDICATOR_BYTES).zip(self.indicators) {
+            chunk.copy_from_slice(&indicator.to_bytes());
+        }
+        bytes[INDICATORS_CHECKSUM_INDEX] = crc8(&bytes[INDICATORS_INDEX..INDICATORS_CHECKSUM_INDEX]);
+        if let Some(bond) = self.bond {
+            bytes[BOND_INDEX] = BONDED;
+            bytes[BOND_ADDRESS_INDEX..BOND_LTK_INDEX].copy_from_slice(&bond.address);
+            bytes[BOND_LTK_INDEX..BOND_CHECKSUM_INDEX].copy_from_slice(&bond.ltk);
+        }
+        bytes[BOND_CHECKSUM_INDEX] = crc8(&bytes[BOND_INDEX..BOND_CHECKSUM_INDEX]);
+        bytes
+    }
+
+    fn deserialize(bytes: &SerializedSettings) -> Result<Self, LoadError> {
+        if bytes[..MAGIC.len()] != MAGIC {
+            return Err(LoadError::Missing);
+        }
+        // saved before there was a checksum (so left erased) can't be checked
+        let checksum = bytes[CHECKSUM_INDEX];
+        if checksum != ERASED && checksum != crc8(&bytes[MAGIC.len()..CHECKSUM_INDEX]) {
+            return Err(LoadError::Corrupt);
+        }
+        Ok(Settings {
+            led_brightness_level: bytes[MAGIC.len()],
+            // saved before there was a choice (so left erased) means the original default
+            os: Os::from_byte(bytes[MAGIC.len() + 1]).unwrap_or(DEFAULTS.os),
+            indicators: Self::deserialize_indicators(bytes)?,
+            bond: Self::deserialize_bond(bytes)?,
+        })
+    }
+
+    fn deserialize_bond(bytes: &SerializedSettings) -> Result<Option<Bond>, LoadError> {
+        match bytes[BOND_CHECKSUM_INDEX] {
+            // saved before there could be one
+            ERASED => return Ok(None),
+            checksum if checksum != crc8(&bytes[BOND_INDEX..BOND_CHECKSUM_INDEX]) => return Err(LoadError::Corrupt),
+            _ => {},
+        }
+        Ok((bytes[BOND_INDEX] == BONDED).then(|| Bond {
+            address: bytes[BOND_ADDRESS_INDEX..BOND_LTK_INDEX].try_into().expect("6 bytes of address"),
+            ltk: bytes[BOND_LTK_INDEX..BOND_CHECKSUM_INDEX].try_into().expect("16 bytes of key"),
+        }))
+    }
+
+    fn deserialize_indicators(bytes: &SerializedSettings) -> Result<[Indicator; led::Indication::ALL.len()], LoadError> {
+        let saved = &bytes[INDICATORS_INDEX..INDICATORS_CHECKSUM_INDEX];
+        match bytes[INDICATORS_CHECKSUM_INDEX] {
+            // saved before they could be changed
+            ERASED => return Ok(DEFAULTS.indicators),
+            checksum if checksum != crc8(saved) => return Err(LoadError::Corrupt),
+            _ => {},
+        }
+        let mut indicators = DEFAULTS.indicators;
+        for (indicator, chunk) in indicators.iter_mut().zip(saved.chunks_exact(INDICATOR_BYTES)) {
+            *indicator = Indicator::from_bytes(chunk.try_into().expect("chunk of INDICATOR_BYTES")).ok_or(LoadError::Corrupt)?;
+        }
+        Ok(indicators)
+    }
+}
+
+/// Value of a byte of erased flash
+const ERASED: u8 = 0xFF;
+
+/// CRC-8 (polynomial 0x07, as in SMBus) of `bytes`. Never [ERASED], so that a checksum can't be
+/// mistaken for there not being one.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    if crc == ERASED { 0 } else { crc }
+}
+
+/// CRC-16 (CCITT-FALSE, polynomial 0x1021) of `bytes`, for the keymap, which is too long for a
+/// [crc8] to be trusted with.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub fn get() -> Settings {
+    SETTINGS.lock(|settings| settings.get())
+}
+
+/// Change the settings, and save them once [SAVE_DELAY] has passed.
+pub fn update(change: impl FnOnce(&mut Settings)) {
+    SETTINGS.lock(|settings| {
+        let mut new = settings.get();
+        change(&mut new);
+        settings.set(new);
+    });
+    CHANGED.signal(());
+}
+
+/// Read the saved settings, if there are any, to be used from now on. Returns `false` if they
+/// were there but corrupt, leaving the defaults in use.
+pub fn load(flash: &mut SettingsFlash) -> bool {
+    let mut bytes: SerializedSettings = [0; BOND_CHECKSUM_INDEX + 1];
+    match flash.blocking_read(SETTINGS_OFFSET, &mut bytes) {
+        Ok(()) => match Settings::deserialize(&bytes) {
+            Ok(loaded) => SETTINGS.lock(|settings| settings.set(loaded)),
+            Err(LoadError::Missing) => info!("No saved settings, using defaults"),
+            Err(LoadError::Corrupt) => {
+                warn!("Saved settings are corrupt, using defaults");
+                return false;
+            },
+        },
+        Err(e) => warn!("Failed to read settings: {:?}", e),
+    }
+    true
+}
+
+/// Save the keymap once [SAVE_DELAY] has passed, as keys have been remapped.
+pub fn keymap_changed() {
+    KEYMAP_CHANGED.signal(());
+}
+
+/// Read the saved keymap, if there is one, to be used from now on. Returns `false` if it was there
+/// but corrupt, leaving the built-in keymap in use.
+pub fn load_keymap(flash: &mut SettingsFlash) -> bool {
+    let mut bytes: SerializedKeymap = [0; size_of::<SerializedKeymap>()];
+    match flash.blocking_read(KEYMAP_OFFSET, &mut bytes) {
+        Ok(()) => match vial::load_keymap(&bytes) {
+            Ok(()) => info!("Loaded remapped keys"),
+            Err(LoadError::Missing) => {},
+            Err(LoadError::Corrupt) => {
+                warn!("Saved keymap is corrupt, using the built-in one");
+                return false;
+            },
+        },
+        Err(e) => warn!("Failed to read keymap: {:?}", e),
+    }
+    true
+}
+
+/// Save the settings and the keymap whenever they change.
+#[embassy_executor::task]
+pub async fn run(mut flash: SettingsFlash) {
+    loop {
+        select(CHANGED.wait(), KEYMAP_CHANGED.wait()).await;
+        Timer::after(SAVE_DELAY).await;
+        // anything changed in the meantime is about to be saved too
+        CHANGED.reset();
+        KEYMAP_CHANGED.reset();
+
+        save(&mut flash, SETTINGS_OFFSET, &get().serialize(), "settings");
+        save(&mut flash, KEYMAP_OFFSET, &vial::serialize_keymap(), "keymap");
+    }
+}
+
+/// Write `bytes` to the sector at `offset`, unless they're there already.
+fn save<const N: usize>(flash: &mut SettingsFlash, offset: u32, bytes: &[u8; N], what: &str) {
+    let mut saved = [0; N];
+    if flash.blocking_read(offset, &mut saved).is_ok() && saved == *bytes {
+        return;
+    }
+    info!("Saving {}", what);
+    let result = flash.blocking_erase(offset, offset + ERASE_SIZE as u32)
+        .and_then(|()| flash.blocking_write(offset, bytes));
+    if let Err(e) = result {
+        warn!("Failed to save {}: {:?}", what, e);
+    }
+}
@@ -0,0 +1,360 @@
+//! Links the two halves of a split keyboard, each with a Pico of its own, over the one data wire of
+//! a TRRS cable (GP1, pulled up to 3.3V by a few kΩ, as the internal pull-up is too weak for the
+//! speed). Only with the `split` feature.
+//!
+//! Whichever half has USB plugged in, as seen on VBUS, is the primary, and works as the keyboard
+//! always does; the other is the secondary, and only scans its switches for the primary to ask for.
+//! Both use the same [crate::boards] matrix, of which each half has only its own rows wired, so the
+//! switches one finds closed are all its own, and the primary takes in the secondary's alongside.
+//!
+//! The wire is half-duplex: each side sends by driving it low for a 0 and letting go of it for a 1,
+//! so that they can never fight over it, and the primary always speaks first. It polls every
+//! [scan::SCAN_INTERVAL], and the secondary answers with its switches. Every [Frame] starts with
+//! [FRAME_START] and ends with a [settings::crc8] of what's between. The halves first exchange
+//! [PROTOCOL_VERSION]s, and only once they match are any switches taken, so firmware from before a
+//! change in the protocol is never misread. If no good answer comes for [LINK_TIMEOUT], the
+//! secondary's switches are let go of, and whatever they were in the middle of (such as a chord)
+//! dropped rather than finished (see [scan::InputSource::take_switches_lost]), until the link is
+//! back up.
+
+use crate::console::{self, ConsoleLine};
+use crate::keymap::{Layer, COLUMNS, ROWS};
+use crate::scan::{self, InputSource, PressedCodes, ScanCode};
+use crate::{settings, RawMutex};
+use core::cell::Cell;
+use embassy_rp::bind_interrupts;
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::gpio::{Input, Level, Pull};
+use embassy_rp::peripherals::{PIN_1, PIO0};
+use embassy_rp::pio::{self, Common, Config, Direction, FifoJoin, Pio, ShiftDirection, StateMachine};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{with_timeout, Duration, Instant, Ticker};
+use heapless::Vec;
+
+bind_interrupts!(pub struct Irqs {
+    PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
+});
+
+/// Bits per second on the wire
+const BAUD: u32 = 115_200;
+
+/// Bumped whenever [Frame]s change, so that halves with different firmware don't link up
+const PROTOCOL_VERSION: u8 = 1;
+
+/// First byte of every [Frame]
+const FRAME_START: u8 = 0xA5;
+/// The start, the kind, the longest payload ([Frame::Switches]) and the checksum
+const MAX_FRAME_LEN: usize = 1 + 1 + 8 + 1;
+
+/// How long the primary waits for the secondary to answer a poll, from when the poll is sent: long
+/// enough for the longest answer
+const REPLY_TIMEOUT: Duration = Duration::from_micros(1500);
+/// How long a frame may take to come back, as each side hears its own on the shared wire
+const ECHO_TIMEOUT: Duration = Duration::from_millis(2);
+
+/// How long without a good answer before the link counts as lost
+const LINK_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Every key switch, one bit each, numbered `row * COLUMNS + column`, as sent over the link
+type SwitchBits = u64;
+const _: () = assert!((ROWS * COLUMNS) as u32 <= SwitchBits::BITS, "every key must fit in a frame");
+
+/// The secondary's switches: on the secondary, as it last scanned them, and on the primary, as the
+/// secondary last said they were (none while the link is down)
+static SECONDARY_SWITCHES: Mutex<RawMutex, Cell<SwitchBits>> = Mutex::new(Cell::new(0));
+
+/// Whether the link was lost with any of the secondary's switches held, since the primary's matrix
+/// last asked
+static SWITCHES_LOST: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Whether this half is the primary, having USB plugged in, as `vbus` (GP24) says.
+pub fn is_primary(vbus: Input) -> bool {
+    vbus.is_high()
+}
+
+/// The key switches among `pressed` (but not the pedals, which the secondary doesn't have) as
+/// [SwitchBits]
+fn to_bits(pressed: &PressedCodes) -> SwitchBits {
+    pressed.iter()
+        .filter(|&&(row, _)| (row as usize) < ROWS)
+        .fold(0, |bits, &(row, column)| bits | 1 << (row as usize * COLUMNS + column as usize))
+}
+
+/// Each switch closed in `bits`
+fn from_bits(bits: SwitchBits) -> impl Iterator<Item = ScanCode> {
+    (0..ROWS * COLUMNS)
+        .filter(move |idx| bits & 1 << idx != 0)
+        .map(|idx| ((idx / COLUMNS) as u8, (idx % COLUMNS) as u8))
+}
+
+/// A message over the link, all of which start with [FRAME_START], then their kind
+#[derive(Clone, Copy, PartialEq)]
+enum Frame {
+    /// From the primary, until the link is up, and answered in kind by the secondary
+    Hello(u8),
+    /// From the primary, once the link is up, for the secondary's switches
+    Poll,
+    /// From the secondary, answering a [Frame::Poll]
+    Switches(SwitchBits),
+}
+
+impl Frame {
+    const HELLO: u8 = 1;
+    const POLL: u8 = 2;
+    const SWITCHES: u8 = 3;
+
+    /// Length of the payload of frames of `kind`, between it and the checksum, if it's a kind
+    const fn payload_len(kind: u8) -> Option<usize> {
+        match kind {
+            Self::HELLO => Some(1),
+            Self::POLL => Some(0),
+            Self::SWITCHES => Some(size_of::<SwitchBits>()),
+            _ => None,
+        }
+    }
+
+    fn encode(self) -> Vec<u8, MAX_FRAME_LEN> {
+        let mut bytes = Vec::new();
+        bytes.push(FRAME_START).ok();
+        match self {
+            Frame::Hello(version) => bytes.extend_from_slice(&[Self::HELLO, version]).ok(),
+            Frame::Poll => bytes.push(Self::POLL).ok(),
+            Frame::Switches(bits) => {
+                bytes.push(Self::SWITCHES).ok();
+                bytes.extend_from_slice(&bits.to_le_bytes()).ok()
+            },
+        };
+        bytes.push(settings::crc8(&bytes[1..])).ok();
+        bytes
+    }
+
+    /// The frame of `kind` with `payload`, which is already the right length for it.
+    fn decode(kind: u8, payload: &[u8]) -> Option<Frame> {
+        match kind {
+            Self::HELLO => Some(Frame::Hello(payload[0])),
+            Self::POLL => Some(Frame::Poll),
+            Self::SWITCHES => Some(Frame::Switches(SwitchBits::from_le_bytes(payload.try_into().ok()?))),
+            _ => None,
+        }
+    }
+}
+
+/// One end of the link: a UART over PIO whose transmitter drives the wire as an open-drain output,
+/// and whose receiver hears everything on it, including what's sent from this end
+pub struct Link {
+    tx: StateMachine<'static, PIO0, 0>,
+    rx: StateMachine<'static, PIO0, 1>,
+    /// Kept so that the programs stay loaded
+    _common: Common<'static, PIO0>,
+}
+
+impl Link {
+    pub fn new(pio: PIO0, pin: PIN_1) -> Self {
+        let Pio { mut common, sm0: mut tx, sm1: mut rx, .. } = Pio::new(pio, Irqs);
+        let mut pin = common.make_pio_pin(pin);
+        pin.set_pull(Pull::Up);
+        let clock_divider = ((clk_sys_freq() / (8 * BAUD)) as u16).into();
+
+        // 8N1 like any UART, but sending each bit by the pin's direction rather than its level, the
+        // level being kept low: driven low for a 0, let go of for a 1. So bits go in inverted.
+        let tx_program = pio::program::pio_asm!(
+            ".side_set 1 opt pindirs"
+            "    pull       side 0 [7]"  // stop bit, and idle: let go of the wire
+            "    set x, 7   side 1 [7]"  // start bit: drive it low
+            "bitloop:"
+            "    out pindirs, 1"
+            "    jmp x-- bitloop [6]"
+        );
+        let tx_program = common.load_program(&tx_program.program);
+        let mut config = Config::default();
+        config.use_program(&tx_program, &[&pin]);
+        config.set_out_pins(&[&pin]);
+        config.shift_out.auto_fill = false;
+        config.shift_out.direction = ShiftDirection::Right;
+        config.fifo_join = FifoJoin::TxOnly;
+        config.clock_divider = clock_divider;
+        tx.set_config(&config);
+
+        // the usual 8N1 receiver, dropping bytes which aren't framed right
+        let rx_program = pio::program::pio_asm!(
+            "start:"
+            "    wait 0 pin 0"
+            "    set x, 7 [10]"  // to halfway through the first data bit
+            "bitloop:"
+            "    in pins, 1"
+            "    jmp x-- bitloop [6]"
+            "    jmp pin good_stop"
+            "    wait 1 pin 0"  // framing error or break: wait for the wire to go idle
+            "    jmp start"
+            "good_stop:"
+            "    in null, 24"
+            "    push"
+        );
+        let rx_program = common.load_program(&rx_program.program);
+        let mut config = Config::default();
+        config.use_program(&rx_program, &[]);
+        config.set_in_pins(&[&pin]);
+        config.set_jmp_pin(&pin);
+        config.shift_in.auto_fill = false;
+        config.shift_in.direction = ShiftDirection::Right;
+        config.shift_in.threshold = 32;
+        config.fifo_join = FifoJoin::RxOnly;
+        config.clock_divider = clock_divider;
+        rx.set_config(&config);
+
+        tx.set_pin_dirs(Direction::In, &[&pin]);
+        tx.set_pins(Level::Low, &[&pin]);
+        tx.set_enable(true);
+        rx.set_enable(true);
+        Link { tx, rx, _common: common }
+    }
+
+    async fn read_byte(&mut self) -> u8 {
+        self.rx.rx().wait_pull().await as u8
+    }
+
+    /// Send `frame`, returning whether it went out intact, as heard back on the wire: if not, the
+    /// other half was sending at the same time, or the wire is faulty.
+    async fn send(&mut self, frame: Frame) -> bool {
+        let bytes = frame.encode();
+        self.rx.clear_fifos();  // anything heard before now is too late to answer
+        for &byte in &bytes {
+            self.tx.tx().wait_push(!byte as u32).await;
+        }
+        with_timeout(ECHO_TIMEOUT, async {
+            for &byte in &bytes {
+                if self.read_byte().await != byte {
+                    return false;
+                }
+            }
+            true
+        }).await.unwrap_or(false)
+    }
+
+    /// Wait for the next frame, returning it if it arrives intact.
+    async fn receive(&mut self) -> Option<Frame> {
+        while self.read_byte().await != FRAME_START {}
+        let mut body = Vec::<u8, MAX_FRAME_LEN>::new();
+        let kind = self.read_byte().await;
+        body.push(kind).ok();
+        for _ in 0..Frame::payload_len(kind)? {
+            let byte = self.read_byte().await;
+            body.push(byte).ok();
+        }
+        if self.read_byte().await != settings::crc8(&body) {
+            return None;
+        }
+        Frame::decode(kind, &body[1..])
+    }
+}
+
+/// The primary's switches, with the secondary's added
+pub struct WithSecondary<S: InputSource>(pub S);
+
+impl<S: InputSource> InputSource for WithSecondary<S> {
+    fn read_switches(&mut self) -> PressedCodes {
+        let mut pressed = self.0.read_switches();
+        for code in from_bits(SECONDARY_SWITCHES.lock(|switches| switches.get())) {
+            if !pressed.contains(&code) {
+                pressed.push(code).expect("fits every key");
+            }
+        }
+        pressed
+    }
+
+    fn sleep_until_pressed(&mut self) {
+        // only this half's switches wake it, as the link isn't polled meanwhile
+        self.0.sleep_until_pressed();
+    }
+
+    fn mark_closed(&mut self) {
+        self.0.mark_closed();
+    }
+
+    fn show_layer(&mut self, layer: &'static Layer) {
+        self.0.show_layer(layer);
+    }
+
+    fn take_switches_lost(&mut self) -> bool {
+        let lost = SWITCHES_LOST.lock(|lost| lost.replace(false));
+        self.0.take_switches_lost() || lost
+    }
+}
+
+/// Say on the console (and in the log) that the link has come up or gone down.
+fn report_link(up: bool) {
+    if up {
+        info!("Linked to the other half");
+    } else {
+        warn!("Lost the link to the other half");
+    }
+    let mut line = ConsoleLine::new();
+    line.push_str(if up { "split: linked\r\n" } else { "split: link lost\r\n" }).ok();
+    console::print(line);
+}
+
+/// On the primary, poll the secondary for its switches, linking up with it first.
+#[embassy_executor::task]
+pub async fn run_primary(mut link: Link) {
+    let mut ticker = Ticker::every(scan::SCAN_INTERVAL);
+    // when the secondary last answered, while linked
+    let mut linked: Option<Instant> = None;
+    let mut mismatch_reported = false;
+    loop {
+        ticker.next().await;
+        let request = if linked.is_some() { Frame::Poll } else { Frame::Hello(PROTOCOL_VERSION) };
+        let answer = if link.send(request).await {
+            with_timeout(REPLY_TIMEOUT, link.receive()).await.ok().flatten()
+        } else {
+            None
+        };
+        let now = Instant::now();
+        match (linked, answer) {
+            (None, Some(Frame::Hello(PROTOCOL_VERSION))) => {
+                linked = Some(now);
+                mismatch_reported = false;
+                report_link(true);
+            },
+            (None, Some(Frame::Hello(version))) if !mismatch_reported => {
+                warn!("Other half speaks protocol {}, not {}", version, PROTOCOL_VERSION);
+                mismatch_reported = true;
+            },
+            (Some(_), Some(Frame::Switches(bits))) => {
+                linked = Some(now);
+                SECONDARY_SWITCHES.lock(|switches| switches.set(bits));
+            },
+            _ => {},
+        }
+        if linked.is_some_and(|answered| now - answered >= LINK_TIMEOUT) {
+            linked = None;
+            if SECONDARY_SWITCHES.lock(|switches| switches.replace(0)) != 0 {
+                SWITCHES_LOST.lock(|lost| lost.set(true));
+            }
+            report_link(false);
+        }
+    }
+}
+
+/// On the secondary, scan its switches, ready for the primary to ask for.
+#[embassy_executor::task]
+pub async fn scan_secondary(mut pins: scan::Pins<'static>) {
+    let mut ticker = Ticker::every(scan::SCAN_INTERVAL);
+    loop {
+        ticker.next().await;
+        let bits = to_bits(&pins.read_switches());
+        SECONDARY_SWITCHES.lock(|switches| switches.set(bits));
+    }
+}
+
+/// On the secondary, answer the primary.
+#[embassy_executor::task]
+pub async fn run_secondary(mut link: Link) {
+    loop {
+        let answer = match link.receive().await {
+            Some(Frame::Hello(_)) => Frame::Hello(PROTOCOL_VERSION),
+            Some(Frame::Poll) => Frame::Switches(SECONDARY_SWITCHES.lock(|switches| switches.get())),
+            Some(Frame::Switches(_)) | None => continue,
+        };
+        link.send(answer).await;
+    }
+}
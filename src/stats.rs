@@ -0,0 +1,175 @@
+//! Counts steno strokes, for pacing practice: how many have been written since the count was last
+//! reset, and how many in the last minute. Shown on the [crate::console], which can also have the
+//! status LED pulse every so many strokes.
+//!
+//! The counting itself is kept apart from the hardware, in [Stats], so that it only depends on the
+//! times it's given.
+
+use crate::console::ConsoleLine;
+use crate::led::{self, LedCommand};
+use crate::RawMutex;
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+/// How far back strokes count towards the strokes-per-minute rate, in seconds
+const RATE_WINDOW_SECS: usize = 60;
+
+/// Pulse the status LED every this many strokes, if `Some`. Set from the console.
+pub static PULSE_EVERY: Mutex<RawMutex, Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+static STATS: Mutex<RawMutex, RefCell<Stats>> = Mutex::new(RefCell::new(Stats::new()));
+
+/// Strokes written since the count was started
+pub struct Stats {
+    total: u32,
+    /// When the first stroke counted was written
+    started: Option<Instant>,
+    /// Strokes written in each of the last [RATE_WINDOW_SECS] seconds, by second since boot modulo
+    /// the window's length
+    per_second: [u16; RATE_WINDOW_SECS],
+    /// The second which [Self::per_second] was last brought up to
+    last_second: u64,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Stats { total: 0, started: None, per_second: [0; RATE_WINDOW_SECS], last_second: 0 }
+    }
+
+    /// Clear the counts of any seconds which have fallen out of the window by `now`.
+    fn advance(&mut self, now: Instant) {
+        let second = now.as_secs();
+        if second.saturating_sub(self.last_second) >= RATE_WINDOW_SECS as u64 {
+            self.per_second = [0; RATE_WINDOW_SECS];
+        } else {
+            for passed in self.last_second + 1..=second {
+                self.per_second[passed as usize % RATE_WINDOW_SECS] = 0;
+            }
+        }
+        self.last_second = self.last_second.max(second);
+    }
+
+    /// Count a stroke written at `now`.
+    pub fn record(&mut self, now: Instant) {
+        self.advance(now);
+        let count = &mut self.per_second[now.as_secs() as usize % RATE_WINDOW_SECS];
+        *count = count.saturating_add(1);
+        self.total += 1;
+        self.started.get_or_insert(now);
+    }
+
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    /// Strokes written in the minute up to `now`
+    pub fn strokes_per_minute(&mut self, now: Instant) -> u32 {
+        self.advance(now);
+        self.per_second.iter().map(|&count| count as u32).sum()
+    }
+
+    /// How long since the first stroke counted, as of `now`
+    pub fn session_length(&self, now: Instant) -> Duration {
+        self.started.map_or(Duration::from_ticks(0), |started| now - started)
+    }
+}
+
+/// Count a stroke written at `now`, pulsing the LED if it's one of every [PULSE_EVERY].
+pub fn record_stroke(now: Instant) {
+    let total = STATS.lock(|stats| {
+        let mut stats = stats.borrow_mut();
+        stats.record(now);
+        stats.total()
+    });
+    if is_pulse_due(total, PULSE_EVERY.lock(|every| every.get())) {
+        led::send(LedCommand::Pulse);
+    }
+}
+
+/// Whether the stroke bringing the count to `total` is one to pulse the LED on, pulsing `every` so
+/// many strokes, if at all.
+fn is_pulse_due(total: u32, every: Option<u32>) -> bool {
+    every.is_some_and(|every| total.is_multiple_of(every))
+}
+
+/// Strokes written in the minute up to `now`
+#[cfg(feature = "display")]
+pub fn strokes_per_minute(now: Instant) -> u32 {
+    STATS.lock(|stats| stats.borrow_mut().strokes_per_minute(now))
+}
+
+/// Start counting again from nothing.
+pub fn reset() {
+    STATS.lock(|stats| *stats.borrow_mut() = Stats::new());
+}
+
+/// Describe the strokes counted as of `now`, followed by CRLF.
+pub fn describe(now: Instant) -> ConsoleLine {
+    let mut line = ConsoleLine::new();
+    STATS.lock(|stats| {
+        let mut stats = stats.borrow_mut();
+        let rate = stats.strokes_per_minute(now);
+        let minutes = stats.session_length(now).as_secs() / 60;
+        write!(line, "strokes: {} in {} minutes, {} in the last minute\r\n", stats.total(), minutes, rate).ok();
+    });
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stats of a stroke written at each of `seconds` since boot
+    fn written_at(seconds: &[u64]) -> Stats {
+        let mut stats = Stats::new();
+        for &second in seconds {
+            stats.record(Instant::from_secs(second));
+        }
+        stats
+    }
+
+    #[test]
+    fn every_stroke_counts_towards_the_total() {
+        let stats = written_at(&[0, 0, 1, 30, 300, 3600]);
+        assert_eq!(stats.total(), 6);
+        assert_eq!(stats.session_length(Instant::from_secs(3660)), Duration::from_secs(3660));
+    }
+
+    #[test]
+    fn rate_counts_strokes_in_the_last_minute() {
+        let mut stats = written_at(&[100, 100, 101, 130, 159]);
+        assert_eq!(stats.strokes_per_minute(Instant::from_secs(159)), 5);
+        // each second's strokes drop out once a minute has passed since it
+        assert_eq!(stats.strokes_per_minute(Instant::from_secs(160)), 3);
+        assert_eq!(stats.strokes_per_minute(Instant::from_secs(161)), 2);
+        assert_eq!(stats.strokes_per_minute(Instant::from_secs(190)), 1);
+    }
+
+    #[test]
+    fn rate_decays_to_nothing_after_a_minute_idle() {
+        let mut stats = written_at(&[10, 20, 30]);
+        assert_eq!(stats.strokes_per_minute(Instant::from_secs(91)), 0);
+        // and long after, when the window has wrapped round many times over
+        stats.record(Instant::from_secs(10_000));
+        assert_eq!(stats.strokes_per_minute(Instant::from_secs(10_001)), 1);
+        assert_eq!(stats.total(), 4);
+    }
+
+    #[test]
+    fn rate_is_unchanged_by_looking_at_it_out_of_order() {
+        let mut stats = written_at(&[100, 130]);
+        assert_eq!(stats.strokes_per_minute(Instant::from_secs(150)), 2);
+        // a stroke stamped before the last look still counts in its own second
+        stats.record(Instant::from_secs(140));
+        assert_eq!(stats.strokes_per_minute(Instant::from_secs(150)), 3);
+    }
+
+    #[test]
+    fn led_pulses_on_every_nth_stroke() {
+        let pulsed: std::vec::Vec<u32> = (1..=10).filter(|&total| is_pulse_due(total, Some(4))).collect();
+        assert_eq!(pulsed, [4, 8]);
+        assert!(!(1..=10).any(|total| is_pulse_due(total, None)));
+    }
+}
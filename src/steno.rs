@@ -1,58 +1,406 @@
-//! Defines keycodes for stenotype input, linked to [PacketCode]s corresponding to flag bits
-//! according to the [Gemini PR protocol](https://github.com/openstenoproject/plover/blob/main/plover/machine/geminipr.py).
-
-type BytePosition = u8;
-type Flag = u8;
-pub type PacketCode = (BytePosition, Flag);
-pub type Packet = [u8; 6];
-
-#[derive(Clone, Copy, Debug)]
-pub enum KeyCode {
-    ST1, ST2, ST3, ST4,
-    S1, TL, PL, HL,
-    S2, KL, WL, RL,
-    A, O, E, U,
-    FR, PR, LR, TR, DR,
-    RR, BR, GR, SR, ZR,
-    Number,
-}
+//! Defines keycodes for stenotype input, linked to flag bits according to the
+//! [Gemini PR protocol](https://github.com/openstenoproject/plover/blob/main/plover/machine/geminipr.py)
+//! in [protocol], and the modes strokes are sent in.
+//!
+//! Strokes can instead be sent in the TX Bolt protocol, typed as keys for Plover's keyboard input,
+//! or written out as text in steno notation ([PAPER_TAPE]) for practicing without Plover running.
+
+use crate::hid::NkroReport;
+use crate::rmk::keycode::KeyCode as HidKeyCode;
+use crate::RawMutex;
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+
+mod protocol;
+pub use protocol::*;
 
 impl KeyCode {
-    pub const fn to_packet_code(self) -> PacketCode {
+    /// The key (as a HID keyboard usage) which Plover's default keyboard layout maps to this key
+    pub const fn to_plover_keyboard_key(self) -> u8 {
+        let key = match self {
+            KeyCode::S1 => HidKeyCode::Q,
+            KeyCode::S2 => HidKeyCode::A,
+            KeyCode::TL => HidKeyCode::W,
+            KeyCode::KL => HidKeyCode::S,
+            KeyCode::PL => HidKeyCode::E,
+            KeyCode::WL => HidKeyCode::D,
+            KeyCode::HL => HidKeyCode::R,
+            KeyCode::RL => HidKeyCode::F,
+
+            KeyCode::A => HidKeyCode::C,
+            KeyCode::O => HidKeyCode::V,
+            KeyCode::ST1 => HidKeyCode::T,
+            KeyCode::ST2 => HidKeyCode::G,
+            KeyCode::ST3 => HidKeyCode::Y,
+            KeyCode::ST4 => HidKeyCode::H,
+            KeyCode::E => HidKeyCode::N,
+            KeyCode::U => HidKeyCode::M,
+
+            KeyCode::FR => HidKeyCode::U,
+            KeyCode::RR => HidKeyCode::J,
+            KeyCode::PR => HidKeyCode::I,
+            KeyCode::BR => HidKeyCode::K,
+            KeyCode::LR => HidKeyCode::O,
+            KeyCode::GR => HidKeyCode::L,
+            KeyCode::TR => HidKeyCode::P,
+            KeyCode::SR => HidKeyCode::Semicolon,
+            KeyCode::DR => HidKeyCode::LeftBracket,
+            KeyCode::ZR => HidKeyCode::Quote,
+
+            KeyCode::Number => HidKeyCode::Kc1,
+        };
+        key as u16 as u8
+    }
+}
+
+/// Which of Plover's serial machine protocols strokes are encoded in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "debug-log", derive(defmt::Format))]
+pub enum Protocol {
+    /// 6-byte packets, see [KeyCode::to_packet_code]
+    GeminiPr,
+    /// Up to 4 bytes of 6 key bits each, see [KeyCode::to_tx_bolt_code]
+    TxBolt,
+    /// Not over serial at all, but as HID keys in Plover's default qwerty layout for keyboard input,
+    /// see [KeyCode::to_plover_keyboard_key]
+    PloverKeyboard,
+    /// As [Self::PloverKeyboard], but followed by a tap of [PLOVER_ARPEGGIATE_KEY], for Plover's
+    /// keyboard input with "arpeggiate" on, which only ends a stroke on that
+    PloverArpeggiate,
+}
+
+/// Key which ends a stroke for Plover's keyboard input with "arpeggiate" on
+pub const PLOVER_ARPEGGIATE_KEY: u8 = HidKeyCode::Space as u16 as u8;
+
+/// Used when the port is opened at a baud rate not found in [PROTOCOL_BAUD_RATES].
+pub const DEFAULT_PROTOCOL: Protocol = Protocol::GeminiPr;
+
+/// Baud rates which select a particular [Protocol] when the host opens the port at them.
+///
+/// Plover opens the port at 9600 baud for both Gemini PR and TX Bolt unless configured otherwise,
+/// so for them to be told apart, the TX Bolt machine's baud rate must be changed in Plover to match
+/// the one here.
+const PROTOCOL_BAUD_RATES: [(u32, Protocol); 1] = [
+    (19200, Protocol::TxBolt),
+];
+
+impl Protocol {
+    /// Cycle through the protocols, for choosing from the keyboard.
+    pub const fn next(self) -> Self {
         match self {
-            KeyCode::S1 => (1,64),
-            KeyCode::TL => (1,16),
-            KeyCode::PL => (1,4),
-            KeyCode::HL => (1,1),
-
-            KeyCode::S2 => (1,32),
-            KeyCode::KL => (1,8),
-            KeyCode::WL => (1,2),
-            KeyCode::RL => (2,64),
-
-            KeyCode::ST1 => (2,8),
-            KeyCode::ST2 => (2,4),
-            KeyCode::ST3 => (3,32),
-            KeyCode::ST4 => (3,16),
-
-            KeyCode::A => (2,32),
-            KeyCode::O => (2,16),
-            KeyCode::E => (3,8),
-            KeyCode::U => (3,4),
-
-            KeyCode::FR => (3,2),
-            KeyCode::PR => (4,64),
-            KeyCode::LR => (4,16),
-            KeyCode::TR => (4,4),
-            KeyCode::DR => (4,1),
-
-            KeyCode::RR => (3,1),
-            KeyCode::BR => (4,32),
-            KeyCode::GR => (4,8),
-            KeyCode::SR => (4,2),
-            KeyCode::ZR => (5,1),
-
-            KeyCode::Number => (0, 32),  // #1 according to the GeminiPR keymap
+            Protocol::GeminiPr => Protocol::TxBolt,
+            Protocol::TxBolt => Protocol::PloverKeyboard,
+            Protocol::PloverKeyboard => Protocol::PloverArpeggiate,
+            Protocol::PloverArpeggiate => Protocol::GeminiPr,
+        }
+    }
+
+    /// Guess the protocol the host expects from the baud rate it opened the port at.
+    pub fn for_baud_rate(baud_rate: u32) -> Self {
+        PROTOCOL_BAUD_RATES.iter().find(|(rate, _)| *rate == baud_rate).map_or(DEFAULT_PROTOCOL, |(_, protocol)| *protocol)
+    }
+
+    /// The protocol to use once the host has set the port to `baud_rate`, instead of this one:
+    /// guessed from the baud rate, unless strokes are typed as keys instead, which were chosen on
+    /// the keyboard and have nothing to do with the port.
+    pub fn on_baud_rate_set(self, baud_rate: u32) -> Self {
+        match self {
+            Protocol::PloverKeyboard | Protocol::PloverArpeggiate => self,
+            Protocol::GeminiPr | Protocol::TxBolt => Self::for_baud_rate(baud_rate),
+        }
+    }
+}
+
+/// Currently selected [Protocol], detected by [crate::usb] when the host opens the port.
+pub static PROTOCOL: Mutex<RawMutex, Cell<Protocol>> = Mutex::new(Cell::new(DEFAULT_PROTOCOL));
+
+/// Whether to write strokes as human-readable steno notation (see [to_notation]) instead of in the
+/// [PROTOCOL], switched by [crate::scan].
+pub static PAPER_TAPE: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Whether each Gemini PR stroke is followed by a packet with no keys (but the lead bit), to stand
+/// for the keys' release. Some host stacks take it for an empty stroke. Switched from the console.
+pub static GEMINI_RELEASE_PACKET: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(true));
+
+/// Shortest time to leave between strokes written to the serial port, so that strokes written in
+/// quick succession aren't run together by the host's serial buffering. Set from the console.
+pub static STROKE_SPACING: Mutex<RawMutex, Cell<Duration>> = Mutex::new(Cell::new(Duration::from_ticks(0)));
+
+/// What the number key ([KeyCode::Number]) does
+#[derive(Clone, Copy, PartialEq)]
+pub enum NumberKey {
+    /// Numbers the stroke it's pressed in, like the number bar of a steno machine
+    Momentary,
+    /// Also, tapped in a stroke of its own (which isn't sent), latches on to number the next
+    /// stroke only; tapped again before then, unlatches
+    Latched,
+}
+
+/// How the number key behaves, switched from the [crate::console].
+pub static NUMBER_KEY: Mutex<RawMutex, Cell<NumberKey>> = Mutex::new(Cell::new(NumberKey::Momentary));
+
+/// Encode a stroke as HID keys held all at once, for Plover's keyboard input to read when they're
+/// released.
+pub fn to_nkro(packet: &GeminiPacket) -> NkroReport {
+    let mut report = NkroReport::released();
+    for code in KeyCode::ALL {
+        if packet.contains(code) {
+            report.press(code.to_plover_keyboard_key());
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::protocol::PLOVER_GEMINI_KEY_CHART;
+    use crate::hid::{OutgoingReport, MAX_INPUT_REPORT_SIZE};
+    use heapless::Vec;
+
+    /// `STENO_KEY_CHART` as copied from Plover's [txbolt.py](https://github.com/openstenoproject/plover/blob/main/plover/machine/txbolt.py):
+    /// the key of each bit of each of the 4 key sets in turn, from the lowest bit up.
+    const PLOVER_TX_BOLT_KEY_CHART: [&str; 23] = [
+        "S-", "T-", "K-", "P-", "W-", "H-",
+        "R-", "A-", "O-", "*", "-E", "-U",
+        "-F", "-R", "-P", "-B", "-L", "-G",
+        "-T", "-S", "-D", "-Z", "#",
+    ];
+
+    /// The keyboard keys of Plover's default layout for keyboard input, as in its
+    /// [english_stenotype.py](https://github.com/openstenoproject/plover/blob/main/plover/system/english_stenotype.py),
+    /// by the character each types on a qwerty keyboard (of which the number bar has any digit).
+    const PLOVER_KEYBOARD_KEYMAP: [(&str, &str); 23] = [
+        ("#", "1"), ("S-", "qa"), ("T-", "w"), ("K-", "s"), ("P-", "e"), ("W-", "d"), ("H-", "r"),
+        ("R-", "f"), ("A-", "c"), ("O-", "v"), ("*", "tygh"), ("-E", "n"), ("-U", "m"), ("-F", "u"),
+        ("-R", "j"), ("-P", "i"), ("-B", "k"), ("-L", "o"), ("-G", "l"), ("-T", "p"), ("-S", ";"),
+        ("-D", "["), ("-Z", "'"),
+    ];
+
+    /// Plover's steno order, which its strokes are written in
+    const PLOVER_STENO_ORDER: [&str; 23] = [
+        "#", "S-", "T-", "K-", "P-", "W-", "H-", "R-", "A-", "O-", "*", "-E", "-U",
+        "-F", "-R", "-P", "-B", "-L", "-G", "-T", "-S", "-D", "-Z",
+    ];
+
+    /// Keys in the middle, which need no hyphen to show which side the others are on
+    const PLOVER_MIDDLE_KEYS: [&str; 5] = ["A-", "O-", "*", "-E", "-U"];
+
+    /// Name Plover's English system gives `code`, once its machine has read it: as for Gemini PR,
+    /// but with one S and star, and the first number key standing for the whole bar
+    fn plover_key(code: KeyCode) -> &'static str {
+        match code.plover_gemini_name() {
+            "S1-" | "S2-" => "S-",
+            "*1" | "*2" | "*3" | "*4" => "*",
+            "#1" => "#",
+            name => name,
+        }
+    }
+
+    /// Keyboard usage of the key which types `character` on a qwerty keyboard
+    fn usage_of(character: char) -> u8 {
+        match character {
+            'a'..='z' => HidKeyCode::A as u16 as u8 + (character as u8 - b'a'),
+            '1'..='9' => HidKeyCode::Kc1 as u16 as u8 + (character as u8 - b'1'),
+            ';' => HidKeyCode::Semicolon as u16 as u8,
+            '[' => HidKeyCode::LeftBracket as u16 as u8,
+            '\'' => HidKeyCode::Quote as u16 as u8,
+            _ => panic!("no usage for {}", character),
+        }
+    }
+
+    /// The stroke of the keys of [KeyCode::ALL] whose bits are set in `chord`
+    fn stroke(chord: u32) -> GeminiPacket {
+        let mut packet = GeminiPacket::default();
+        for (idx, &code) in KeyCode::ALL.iter().enumerate() {
+            if chord & 1 << idx != 0 {
+                packet.press(code);
+            }
+        }
+        packet
+    }
+
+    /// Every chord of up to two keys, then a spread of larger ones through all of them
+    fn chords() -> impl Iterator<Item = u32> {
+        let keys = KeyCode::ALL.len();
+        let pairs = (0..keys).flat_map(move |first| (first..keys).map(move |second| 1 << first | 1 << second));
+        core::iter::once(0).chain(pairs).chain((0..1 << keys).step_by(9973))
+    }
+
+    /// Keys read from `bytes` as Plover's Gemini PR machine reads a packet
+    fn read_gemini(bytes: [u8; PACKET_LEN]) -> Vec<&'static str, 32> {
+        assert!(bytes[0] & LEAD_BYTE_FLAG != 0 && bytes[1..].iter().all(|byte| byte & LEAD_BYTE_FLAG == 0), "lead byte flag not only on the first byte");
+        let mut keys = Vec::new();
+        for (byte_idx, byte) in bytes.into_iter().enumerate() {
+            for bit in 1..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    keys.push(PLOVER_GEMINI_KEY_CHART[byte_idx][bit - 1]).unwrap();
+                }
+            }
+        }
+        keys
+    }
+
+    /// Keys read from `bytes` as Plover's TX Bolt machine reads a stroke, which must end the stroke
+    fn read_tx_bolt(bytes: &[u8]) -> Vec<&'static str, 32> {
+        let (&end, sets) = bytes.split_last().expect("stroke is never empty");
+        assert_eq!(end, 0, "stroke ends with a null byte");
+        let mut keys = Vec::new();
+        let mut last_set = None;
+        for &byte in sets {
+            let set = byte >> 6;
+            // a key set no later than the last starts a new stroke
+            assert!(last_set < Some(set), "key sets out of order in {:?}", bytes);
+            last_set = Some(set);
+            for bit in 0..6 {
+                if byte & 1 << bit != 0 {
+                    keys.push(PLOVER_TX_BOLT_KEY_CHART[set as usize * 6 + bit]).unwrap();
+                }
+            }
+        }
+        keys
+    }
+
+    /// Keys read from the keyboard report `report`, as Plover's keyboard input reads the keys held
+    fn read_keyboard(report: NkroReport) -> Vec<&'static str, 32> {
+        let mut buf = [0; MAX_INPUT_REPORT_SIZE];
+        let bitmap = &OutgoingReport::Nkro(report).serialize(&mut buf)[1..];
+        let mut keys = Vec::new();
+        for usage in (0..bitmap.len() * 8).filter(|usage| bitmap[usage / 8] & 1 << (usage % 8) != 0) {
+            let (name, _) = PLOVER_KEYBOARD_KEYMAP.iter()
+                .find(|(_, characters)| characters.chars().any(|character| usage_of(character) as usize == usage))
+                .unwrap_or_else(|| panic!("usage {:#x} isn't a steno key to Plover", usage));
+            keys.push(*name).unwrap();
+        }
+        keys
+    }
+
+    /// `keys` in Plover's steno order, each once
+    fn in_steno_order(keys: &[&'static str]) -> Vec<&'static str, 32> {
+        PLOVER_STENO_ORDER.into_iter().filter(|key| keys.contains(key)).collect()
+    }
+
+    /// How Plover writes the stroke of `keys`, were it to leave the number key as `#`
+    fn plover_stroke(keys: &[&'static str]) -> std::string::String {
+        let keys = in_steno_order(keys);
+        let has_middle = keys.iter().any(|key| PLOVER_MIDDLE_KEYS.contains(key));
+        let mut stroke = std::string::String::new();
+        for key in keys {
+            if key.starts_with('-') && !has_middle && !stroke.contains('-') {
+                stroke.push('-');
+            }
+            stroke.push_str(key.trim_matches('-'));
+        }
+        stroke
+    }
+
+    #[test]
+    fn baud_rate_picks_a_serial_protocol_only() {
+        assert_eq!(Protocol::GeminiPr.on_baud_rate_set(19200), Protocol::TxBolt);
+        assert_eq!(Protocol::TxBolt.on_baud_rate_set(9600), Protocol::GeminiPr);
+        assert_eq!(Protocol::GeminiPr.on_baud_rate_set(115200), DEFAULT_PROTOCOL);
+        for protocol in [Protocol::PloverKeyboard, Protocol::PloverArpeggiate] {
+            for baud_rate in [9600, 19200, 115200] {
+                assert_eq!(protocol.on_baud_rate_set(baud_rate), protocol, "{:?} overridden at {} baud", protocol, baud_rate);
+            }
+        }
+    }
+
+    #[test]
+    fn each_key_has_a_bit_and_plover_key_of_its_own() {
+        for (idx, &code) in KeyCode::ALL.iter().enumerate() {
+            let mut packet = GeminiPacket::default();
+            packet.press(code);
+            assert_eq!(packet.key_count(), 1, "{:?} sets one bit", code);
+            assert!(packet.0.iter().all(|byte| byte & LEAD_BYTE_FLAG == 0), "{:?} sets the lead byte flag", code);
+            for &other in &KeyCode::ALL[..idx] {
+                assert!(!packet.contains(other), "{:?} and {:?} share a bit", code, other);
+                assert_ne!(code.to_plover_keyboard_key(), other.to_plover_keyboard_key(), "{:?} and {:?} share a key", code, other);
+            }
+        }
+    }
+
+    #[test]
+    fn packet_holds_exactly_the_keys_pressed() {
+        for chord in chords() {
+            let mut packet = stroke(chord);
+            assert_eq!(packet.key_count(), chord.count_ones(), "chord {:#x}", chord);
+            for (idx, &code) in KeyCode::ALL.iter().enumerate() {
+                assert_eq!(packet.contains(code), chord & 1 << idx != 0, "{:?} in chord {:#x}", code, chord);
+            }
+            assert_eq!(packet.is_empty(), chord == 0);
+            // pressing a key again changes nothing
+            let before = packet;
+            for (idx, &code) in KeyCode::ALL.iter().enumerate() {
+                if chord & 1 << idx != 0 {
+                    packet.press(code);
+                }
+            }
+            assert!(packet == before, "chord {:#x} pressed twice", chord);
+        }
+    }
+
+    #[test]
+    fn packets_combine_as_sets_of_keys() {
+        let other = 0b101_0110_1100_0011_1001_0110_1010;
+        for chord in chords() {
+            let (packet, other_packet) = (stroke(chord), stroke(other));
+            assert!(packet.union(other_packet) == stroke(chord | other), "chord {:#x}", chord);
+            assert!(packet.intersection(other_packet) == stroke(chord & other), "chord {:#x}", chord);
+            assert!(packet.without(other_packet) == stroke(chord & !other), "chord {:#x}", chord);
+        }
+    }
+
+    #[test]
+    fn bytes_have_the_lead_flag_on_the_first_only() {
+        assert_eq!(GeminiPacket::default().to_bytes(), [0x80, 0, 0, 0, 0, 0]);
+        assert_eq!(stroke((1 << KeyCode::ALL.len()) - 1).to_bytes(), [0xa0, 0x7f, 0x7c, 0x3f, 0x7f, 0x01]);
+        let mut packet = GeminiPacket::default();
+        packet.press(KeyCode::ZR);
+        assert_eq!(packet.to_bytes(), [0x80, 0, 0, 0, 0, 0x01]);
+    }
+
+    #[test]
+    fn gemini_pr_round_trips_through_plover() {
+        for chord in chords() {
+            let packet = stroke(chord);
+            let mut expected: Vec<&str, 32> = KeyCode::ALL.into_iter().filter(|&code| packet.contains(code)).map(KeyCode::plover_gemini_name).collect();
+            let mut read = read_gemini(packet.to_bytes());
+            expected.sort_unstable();
+            read.sort_unstable();
+            assert_eq!(read, expected, "chord {:#x}", chord);
+        }
+    }
+
+    #[test]
+    fn tx_bolt_round_trips_through_plover() {
+        for chord in chords() {
+            let packet = stroke(chord);
+            let expected: Vec<&str, 32> = in_steno_order(&KeyCode::ALL.map(|code| if packet.contains(code) { plover_key(code) } else { "" }));
+            assert_eq!(in_steno_order(&read_tx_bolt(&to_tx_bolt(&packet))), expected, "chord {:#x}", chord);
+        }
+    }
+
+    #[test]
+    fn plover_keyboard_round_trips_through_plover() {
+        for chord in chords() {
+            let packet = stroke(chord);
+            let expected: Vec<&str, 32> = in_steno_order(&KeyCode::ALL.map(|code| if packet.contains(code) { plover_key(code) } else { "" }));
+            assert_eq!(in_steno_order(&read_keyboard(to_nkro(&packet))), expected, "chord {:#x}", chord);
+        }
+    }
+
+    #[test]
+    fn notation_is_the_steno_letters_plover_reads() {
+        for chord in chords() {
+            let packet = stroke(chord);
+            let read = read_gemini(packet.to_bytes());
+            let keys: Vec<&str, 32> = KeyCode::ALL.into_iter()
+                .filter(|code| read.contains(&code.plover_gemini_name()))
+                .map(plover_key)
+                .collect();
+            assert_eq!(to_notation(&packet).as_str(), plover_stroke(&keys) + "\r\n", "chord {:#x}", chord);
         }
     }
 }
@@ -0,0 +1,330 @@
+//! The wire formats of steno strokes: [GeminiPacket]s as sent over the steno port in the Gemini PR
+//! and TX Bolt protocols, and as steno notation for the paper tape and the console. Depends on
+//! nothing but `heapless`, so that `host-tools` can include it too, to decode what's sent.
+
+use heapless::{String, Vec};
+
+type BytePosition = u8;
+type Flag = u8;
+type PacketCode = (BytePosition, Flag);
+
+/// Length of a Gemini PR packet
+pub const PACKET_LEN: usize = 6;
+/// Top bit of each byte, set only in the first to mark the start of a packet
+pub const LEAD_BYTE_FLAG: u8 = 0x80;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyCode {
+    ST1, ST2, ST3, ST4,
+    S1, TL, PL, HL,
+    S2, KL, WL, RL,
+    A, O, E, U,
+    FR, PR, LR, TR, DR,
+    RR, BR, GR, SR, ZR,
+    Number,
+}
+
+impl KeyCode {
+    pub const ALL: [KeyCode; 27] = [
+        KeyCode::ST1, KeyCode::ST2, KeyCode::ST3, KeyCode::ST4,
+        KeyCode::S1, KeyCode::TL, KeyCode::PL, KeyCode::HL,
+        KeyCode::S2, KeyCode::KL, KeyCode::WL, KeyCode::RL,
+        KeyCode::A, KeyCode::O, KeyCode::E, KeyCode::U,
+        KeyCode::FR, KeyCode::PR, KeyCode::LR, KeyCode::TR, KeyCode::DR,
+        KeyCode::RR, KeyCode::BR, KeyCode::GR, KeyCode::SR, KeyCode::ZR,
+        KeyCode::Number,
+    ];
+
+    /// Which of the 4 TX Bolt key sets this key is in, and its flag bit within that set.
+    pub const fn to_tx_bolt_code(self) -> (u8, Flag) {
+        match self {
+            KeyCode::S1 | KeyCode::S2 => (0, 1),
+            KeyCode::TL => (0, 2),
+            KeyCode::KL => (0, 4),
+            KeyCode::PL => (0, 8),
+            KeyCode::WL => (0, 16),
+            KeyCode::HL => (0, 32),
+
+            KeyCode::RL => (1, 1),
+            KeyCode::A => (1, 2),
+            KeyCode::O => (1, 4),
+            KeyCode::ST1 | KeyCode::ST2 | KeyCode::ST3 | KeyCode::ST4 => (1, 8),
+            KeyCode::E => (1, 16),
+            KeyCode::U => (1, 32),
+
+            KeyCode::FR => (2, 1),
+            KeyCode::RR => (2, 2),
+            KeyCode::PR => (2, 4),
+            KeyCode::BR => (2, 8),
+            KeyCode::LR => (2, 16),
+            KeyCode::GR => (2, 32),
+
+            KeyCode::TR => (3, 1),
+            KeyCode::SR => (3, 2),
+            KeyCode::DR => (3, 4),
+            KeyCode::ZR => (3, 8),
+            KeyCode::Number => (3, 16),
+        }
+    }
+
+    /// Position of this key's flag among the key bits of a packet, counting from the highest bit of
+    /// the first byte (as QMK numbers its steno keycodes)
+    pub const fn to_packet_index(self) -> u8 {
+        let (byte_position, flag) = self.to_packet_code();
+        byte_position * 7 + 6 - flag.trailing_zeros() as u8
+    }
+
+    /// Which byte of a packet this key's flag is in, and the flag
+    pub const fn to_packet_code(self) -> PacketCode {
+        match self {
+            KeyCode::S1 => (1,64),
+            KeyCode::TL => (1,16),
+            KeyCode::PL => (1,4),
+            KeyCode::HL => (1,1),
+
+            KeyCode::S2 => (1,32),
+            KeyCode::KL => (1,8),
+            KeyCode::WL => (1,2),
+            KeyCode::RL => (2,64),
+
+            KeyCode::ST1 => (2,8),
+            KeyCode::ST2 => (2,4),
+            KeyCode::ST3 => (3,32),
+            KeyCode::ST4 => (3,16),
+
+            KeyCode::A => (2,32),
+            KeyCode::O => (2,16),
+            KeyCode::E => (3,8),
+            KeyCode::U => (3,4),
+
+            KeyCode::FR => (3,2),
+            KeyCode::PR => (4,64),
+            KeyCode::LR => (4,16),
+            KeyCode::TR => (4,4),
+            KeyCode::DR => (4,1),
+
+            KeyCode::RR => (3,1),
+            KeyCode::BR => (4,32),
+            KeyCode::GR => (4,8),
+            KeyCode::SR => (4,2),
+            KeyCode::ZR => (5,1),
+
+            KeyCode::Number => (0, 32),  // #1 according to the GeminiPR keymap
+        }
+    }
+
+    /// Name Plover's Gemini PR machine gives this key, as in [PLOVER_GEMINI_KEY_CHART]
+    pub(super) const fn plover_gemini_name(self) -> &'static str {
+        match self {
+            KeyCode::S1 => "S1-",
+            KeyCode::S2 => "S2-",
+            KeyCode::TL => "T-",
+            KeyCode::KL => "K-",
+            KeyCode::PL => "P-",
+            KeyCode::WL => "W-",
+            KeyCode::HL => "H-",
+            KeyCode::RL => "R-",
+
+            KeyCode::A => "A-",
+            KeyCode::O => "O-",
+            KeyCode::ST1 => "*1",
+            KeyCode::ST2 => "*2",
+            KeyCode::ST3 => "*3",
+            KeyCode::ST4 => "*4",
+            KeyCode::E => "-E",
+            KeyCode::U => "-U",
+
+            KeyCode::FR => "-F",
+            KeyCode::RR => "-R",
+            KeyCode::PR => "-P",
+            KeyCode::BR => "-B",
+            KeyCode::LR => "-L",
+            KeyCode::GR => "-G",
+            KeyCode::TR => "-T",
+            KeyCode::SR => "-S",
+            KeyCode::DR => "-D",
+            KeyCode::ZR => "-Z",
+
+            KeyCode::Number => "#1",
+        }
+    }
+}
+
+/// `STENO_KEY_CHART` as copied from Plover's [geminipr.py](https://github.com/openstenoproject/plover/blob/main/plover/machine/geminipr.py),
+/// for checking [KeyCode::to_packet_code] against: the key of each bit of a packet, byte by byte,
+/// from the highest key bit (below the lead byte flag) down.
+pub(super) const PLOVER_GEMINI_KEY_CHART: [[&str; 7]; PACKET_LEN] = [
+    ["Fn", "#1", "#2", "#3", "#4", "#5", "#6"],
+    ["S1-", "S2-", "T-", "K-", "P-", "W-", "H-"],
+    ["R-", "A-", "O-", "*1", "*2", "res1", "res2"],
+    ["pwr", "*3", "*4", "-E", "-U", "-F", "-R"],
+    ["-P", "-B", "-L", "-G", "-T", "-S", "-D"],
+    ["#7", "#8", "#9", "#A", "#B", "#C", "-Z"],
+];
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut idx = 0;
+    while idx < a.len() {
+        if a[idx] != b[idx] {
+            return false;
+        }
+        idx += 1;
+    }
+    true
+}
+
+// Every key's flag bit must be the one Plover reads as that key.
+const _: () = {
+    let mut i = 0;
+    while i < KeyCode::ALL.len() {
+        let key = KeyCode::ALL[i];
+        let (byte_position, flag) = key.to_packet_code();
+        let chart_name = PLOVER_GEMINI_KEY_CHART[byte_position as usize][6 - flag.trailing_zeros() as usize];
+        assert!(str_eq(chart_name, key.plover_gemini_name()), "flag is a different key to Plover");
+        i += 1;
+    }
+};
+
+/// The keys of one stroke, as sent in a [Gemini PR](https://github.com/openstenoproject/plover/blob/main/plover/machine/geminipr.py)
+/// packet. Only built up from [KeyCode]s, so that no flag can be set which isn't a key's.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct GeminiPacket(pub(super) [u8; PACKET_LEN]);
+
+impl GeminiPacket {
+    /// Add `code` to the keys pressed in this stroke.
+    pub fn press(&mut self, code: KeyCode) {
+        let (byte_position, flag) = code.to_packet_code();
+        self.0[byte_position as usize] |= flag;
+    }
+
+    pub fn contains(&self, code: KeyCode) -> bool {
+        let (byte_position, flag) = code.to_packet_code();
+        self.0[byte_position as usize] & flag != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == [0; PACKET_LEN]
+    }
+
+    /// Keys in this stroke or in `other`
+    pub fn union(self, other: GeminiPacket) -> GeminiPacket {
+        GeminiPacket(core::array::from_fn(|idx| self.0[idx] | other.0[idx]))
+    }
+
+    /// Keys in both this stroke and `other`
+    pub fn intersection(self, other: GeminiPacket) -> GeminiPacket {
+        GeminiPacket(core::array::from_fn(|idx| self.0[idx] & other.0[idx]))
+    }
+
+    /// Keys in this stroke which aren't in `other`
+    pub fn without(self, other: GeminiPacket) -> GeminiPacket {
+        GeminiPacket(core::array::from_fn(|idx| self.0[idx] & !other.0[idx]))
+    }
+
+    /// How many keys are pressed in this stroke
+    pub fn key_count(&self) -> u32 {
+        self.0.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    /// The packet as sent, with its first byte marked as the lead byte.
+    pub fn to_bytes(self) -> [u8; PACKET_LEN] {
+        let mut bytes = self.0;
+        bytes[0] |= LEAD_BYTE_FLAG;
+        bytes
+    }
+}
+
+
+/// Long enough for a byte from each of the 4 key sets, plus the terminating null.
+pub type TxBoltBytes = Vec<u8, 5>;
+
+/// Encode a stroke for the [TX Bolt protocol](https://github.com/openstenoproject/plover/blob/main/plover/machine/txbolt.py),
+/// sending only the key sets with any keys pressed, followed by a null byte to end the stroke.
+pub fn to_tx_bolt(packet: &GeminiPacket) -> TxBoltBytes {
+    let mut sets = [0u8; 4];
+    for code in KeyCode::ALL {
+        if packet.contains(code) {
+            let (set, flag) = code.to_tx_bolt_code();
+            sets[set as usize] |= flag;
+        }
+    }
+
+    let mut bytes = TxBoltBytes::new();
+    for (set, keys) in sets.into_iter().enumerate() {
+        if keys != 0 {
+            bytes.push(((set as u8) << 6) | keys).expect("fits every set");
+        }
+    }
+    bytes.push(0).expect("fits terminator");
+    bytes
+}
+/// Steno order of keys to the left of the vowels, with their notation letters.
+pub const NOTATION_LEFT: [(char, &[KeyCode]); 8] = [
+    ('#', &[KeyCode::Number]),
+    ('S', &[KeyCode::S1, KeyCode::S2]),
+    ('T', &[KeyCode::TL]),
+    ('K', &[KeyCode::KL]),
+    ('P', &[KeyCode::PL]),
+    ('W', &[KeyCode::WL]),
+    ('H', &[KeyCode::HL]),
+    ('R', &[KeyCode::RL]),
+];
+/// Steno order of the vowels and star, which separate the left and right banks.
+pub const NOTATION_MIDDLE: [(char, &[KeyCode]); 5] = [
+    ('A', &[KeyCode::A]),
+    ('O', &[KeyCode::O]),
+    ('*', &[KeyCode::ST1, KeyCode::ST2, KeyCode::ST3, KeyCode::ST4]),
+    ('E', &[KeyCode::E]),
+    ('U', &[KeyCode::U]),
+];
+/// Steno order of keys to the right of the vowels.
+pub const NOTATION_RIGHT: [(char, &[KeyCode]); 10] = [
+    ('F', &[KeyCode::FR]),
+    ('R', &[KeyCode::RR]),
+    ('P', &[KeyCode::PR]),
+    ('B', &[KeyCode::BR]),
+    ('L', &[KeyCode::LR]),
+    ('G', &[KeyCode::GR]),
+    ('T', &[KeyCode::TR]),
+    ('S', &[KeyCode::SR]),
+    ('D', &[KeyCode::DR]),
+    ('Z', &[KeyCode::ZR]),
+];
+
+/// Long enough for every key in steno order, a hyphen and a line ending.
+pub type NotationLine = String<32>;
+
+fn contains(packet: &GeminiPacket, codes: &[KeyCode]) -> bool {
+    codes.iter().any(|&code| packet.contains(code))
+}
+
+/// Describe a stroke in steno notation, as would be printed on a paper tape, followed by CRLF.
+///
+/// A hyphen separates the banks when there are right-hand keys but no vowels or star to show
+/// where the left hand ends, so that e.g. `-R` isn't mistaken for `R`.
+pub fn to_notation(packet: &GeminiPacket) -> NotationLine {
+    let mut line = NotationLine::new();
+    let mut push = |c| line.push(c).expect("notation fits");
+
+    for (letter, codes) in NOTATION_LEFT {
+        if contains(packet, codes) { push(letter) }
+    }
+    let has_middle = NOTATION_MIDDLE.iter().any(|(_, codes)| contains(packet, codes));
+    for (letter, codes) in NOTATION_MIDDLE {
+        if contains(packet, codes) { push(letter) }
+    }
+    let has_right = NOTATION_RIGHT.iter().any(|(_, codes)| contains(packet, codes));
+    if has_right && !has_middle {
+        push('-');
+    }
+    for (letter, codes) in NOTATION_RIGHT {
+        if contains(packet, codes) { push(letter) }
+    }
+    push('\r');
+    push('\n');
+    line
+}
@@ -1,143 +1,645 @@
-//! Implements USB devices and tasks for transporting HID [KeyboardReport]s and CDC [crate::steno::Packet]s.
-//! Mostly lifted from [embassy_usb] examples.
+//! Implements USB devices and tasks for transporting HID [hid::OutgoingReport]s and CDC [steno::GeminiPacket]s,
+//! plus a second CDC port for the [console]. Mostly lifted from [embassy_usb] examples.
 
+use core::cell::Cell;
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::UPDATES_CHANNEL;
+use crate::{boards, console, health, hid, keymap, latency, macros, power, settings, steno, vial, RawMutex, CONSUMER_REPORT, KEYBOARD_REPORT, REPORTS_CHANNEL, STROKES_CHANNEL};
 
-use embassy_futures::join::join;
+use embassy_futures::{
+    join::{join3, join4},
+    select::{select, select3, Either, Either3},
+};
 use embassy_rp::{
     peripherals::USB,
     usb::{Driver, InterruptHandler},
     bind_interrupts,
 };
+use embassy_sync::{blocking_mutex::Mutex, channel::Channel, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
 use embassy_usb::{
-    class::hid::{HidReaderWriter, ReportId, RequestHandler, State as HidState},
-    class::cdc_acm::{CdcAcmClass, State as CdcState},
-    control::OutResponse,
+    class::hid::{HidReaderWriter, HidWriter, ReportId, RequestHandler, State as HidState},
+    class::cdc_acm::{CdcAcmClass, Sender as CdcSender, State as CdcState},
+    class::midi::MidiClass,
+    control::{OutResponse, Recipient, Request, RequestType},
+    driver::EndpointError,
+    msos::{windows_version, PropertyData, RegistryPropertyFeatureDescriptor},
+    types::InterfaceNumber,
     Builder, Handler, UsbDevice,
 };
-use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
 
+use core::fmt::Write;
+use heapless::Deque;
 use static_cell::StaticCell;
+use usbd_hid::descriptor::{KeyboardReport, MediaKeyboardReport};
 
 type MyDriver = Driver<'static, USB>;
 type MyUsbDevice = UsbDevice<'static, MyDriver>;
-type MyHidReaderWriter = HidReaderWriter<'static, MyDriver, 1, 8>;
+type MyHidReaderWriter = HidReaderWriter<'static, MyDriver, { hid::MAX_OUTPUT_REPORT_SIZE }, { hid::MAX_INPUT_REPORT_SIZE }>;
+type MyHidWriter = HidWriter<'static, MyDriver, { hid::MAX_INPUT_REPORT_SIZE }>;
 type MyCdcAcmClass = CdcAcmClass<'static, MyDriver>;
+pub type MyMidiClass = MidiClass<'static, MyDriver>;
+pub type RawHidReaderWriter = HidReaderWriter<'static, MyDriver, { vial::RAW_HID_REPORT_SIZE }, { vial::RAW_HID_REPORT_SIZE }>;
 
 bind_interrupts!(pub(crate) struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
 });
 
-pub fn get_device(driver: MyDriver) -> (UsbDevice<'static, MyDriver>, MyHidReaderWriter, MyCdcAcmClass) {
-    let mut config = embassy_usb::Config::new(0xfeed, 0x3061);
-    config.manufacturer = Some("Tom's");
-    config.product = Some("Mini Orthocurvular Keyboard");
-    config.serial_number = Some("001");
-    config.max_power = 100;
+/// Raised to reboot into the RP2040's USB mass-storage bootloader, ready for a new UF2 to be copied
+/// over, without anyone having to hold down BOOTSEL.
+pub(crate) static REBOOT_TO_BOOTLOADER: Signal<RawMutex, ()> = Signal::new();
+
+/// Raised by [crate::scan] when a key is pressed while the host is suspended, to wake it up (if it
+/// has allowed that).
+pub(crate) static WAKE_HOST: Signal<RawMutex, ()> = Signal::new();
+
+/// Raised by [crate::scan] when a pedal is released, to send the keyboard report
+/// [RELEASE_CONFIRMATIONS] more times, in case the host missed the release.
+pub(crate) static CONFIRM_RELEASE: Signal<RawMutex, ()> = Signal::new();
+const RELEASE_CONFIRMATIONS: u8 = 3;
+/// How long to leave between each of the [RELEASE_CONFIRMATIONS]
+const RELEASE_CONFIRMATION_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Raised to send a released report of every kind, even those already sent, in case the host
+/// missed one and thinks a key is still held.
+pub(crate) static RESEND_RELEASED_REPORTS: Signal<RawMutex, ()> = Signal::new();
+
+/// Raised by [MyDeviceHandler] when the host resumes the bus (or resets it), for reports held back
+/// while it was suspended to be sent
+static HOST_RESUMED: Signal<RawMutex, ()> = Signal::new();
+
+/// How long to wait before trying again to send a report which failed, and how many more times to
+/// try before giving it up, so that a host which has gone away can't hold reports up for long
+const REPORT_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+const REPORT_RETRIES: u8 = 10;
+
+/// Reports which have failed to send since the counts were last reset, shown on the console to
+/// tell whether a hub or cable is flaky
+#[derive(Clone, Copy)]
+pub struct ReportErrors {
+    /// Every failed attempt, including those later retried successfully
+    pub failed: u32,
+    /// Reports given up on after [REPORT_RETRIES], which the host never saw
+    pub dropped: u32,
+}
+
+impl ReportErrors {
+    pub const NONE: ReportErrors = ReportErrors { failed: 0, dropped: 0 };
+}
+
+pub static REPORT_ERRORS: Mutex<RawMutex, Cell<ReportErrors>> = Mutex::new(Cell::new(ReportErrors::NONE));
+
+/// Vendor-specific control request (to the device) which raises [REBOOT_TO_BOOTLOADER].
+const VENDOR_REQUEST_REBOOT_TO_BOOTLOADER: u8 = 0x01;
+
+/// Vendor-specific control request which Windows reads the MS OS 2.0 descriptors with, apart from
+/// [VENDOR_REQUEST_REBOOT_TO_BOOTLOADER]
+const VENDOR_CODE_MS_OS: u8 = 0x02;
+
+/// First interfaces of the steno and console serial ports, as numbered in the order [get_device]
+/// adds them, after the keyboard's HID interface (host tools look for the console by it too)
+const STENO_INTERFACE: u8 = 1;
+const CONSOLE_INTERFACE: u8 = 3;
+
+/// Opening the steno serial port at this baud rate raises [REBOOT_TO_BOOTLOADER], as with
+/// Arduino-style boards, so that a flashing script can do it with just `stty`.
+const BOOTLOADER_TOUCH_BAUD_RATE: u32 = 1200;
+
+/// Whether to send a keyboard report's change of modifiers in a report of its own, apart from its
+/// change of keys (see [hid::modifiers_ahead]), for hosts which otherwise mistake shifted symbols.
+/// Off unless switched on from the console, as it sends more reports, later.
+pub static MODIFIERS_AHEAD: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Raised by [MyDeviceHandler] whenever the host configures the device.
+static CONFIGURED: Signal<RawMutex, ()> = Signal::new();
+
+/// How many steno strokes to keep while the serial port is closed (as while Plover restarts), to
+/// be written once it's opened again. The oldest are dropped first.
+const PENDING_STROKES_LIMIT: usize = 16;
+/// Strokes kept for longer than this aren't written, as they're unlikely to still be wanted.
+const PENDING_STROKE_MAX_AGE: Duration = Duration::from_secs(10);
+/// How often to check whether the serial port has been opened while strokes are kept for it.
+const PENDING_STROKES_POLL: Duration = Duration::from_millis(100);
+
+/// Idle rates set by the host for each kind of report (see [hid::OutgoingReport::kind_index]): how
+/// often to repeat the last report even if it hasn't changed, or `None` to only send changes.
+static IDLE_RATES: Mutex<RawMutex, Cell<[Option<Duration>; hid::REPORT_KINDS]>> = Mutex::new(Cell::new([None; hid::REPORT_KINDS]));
+
+/// [keymap::Thing::Sequence]s waiting to be played by [macros::play]
+pub static SEQUENCES: Channel<RawMutex, &'static [u8], 4> = Channel::new();
+
+/// How long after first being configured to start typing [keymap::STARTUP_MACRO], giving the
+/// host time to bind its keyboard driver.
+const STARTUP_MACRO_DELAY: Duration = Duration::from_secs(1);
+
+/// The USB serial number: [vial::SERIAL_NUMBER_MAGIC] for Vial to find, then the flash chip's
+/// unique ID in hex, to tell apart keyboards plugged in together.
+type SerialNumber = heapless::String<{ vial::SERIAL_NUMBER_MAGIC.len() + 1 + 16 }>;
+
+fn serial_number(unique_id: [u8; 8]) -> SerialNumber {
+    let mut serial_number = SerialNumber::new();
+    write!(serial_number, "{}:{:016x}", vial::SERIAL_NUMBER_MAGIC, u64::from_be_bytes(unique_id)).expect("serial number fits");
+    serial_number
+}
+
+/// Build the USB device and its classes, with the serial number made from `unique_id` (the flash
+/// chip's).
+pub fn get_device(driver: MyDriver, max_power: u16, unique_id: [u8; 8]) -> (UsbDevice<'static, MyDriver>, MyHidReaderWriter, MyCdcAcmClass, MyCdcAcmClass, RawHidReaderWriter, MyMidiClass) {
+    static SERIAL_NUMBER: StaticCell<SerialNumber> = StaticCell::new();
+    let mut config = embassy_usb::Config::new(boards::USB_VENDOR_ID, boards::USB_PRODUCT_ID);
+    config.manufacturer = Some(boards::MANUFACTURER);
+    config.product = Some(boards::PRODUCT);
+    config.serial_number = Some(SERIAL_NUMBER.init(serial_number(unique_id)));
+    config.max_power = max_power;
     config.max_packet_size_0 = 64;
+    config.supports_remote_wakeup = true;
 
     static DEVICE_HANDLER: StaticCell<MyDeviceHandler> = StaticCell::new();
 
     // Create embassy-usb DeviceBuilder using the driver and config.
-    static CONFIG_DESC: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONFIG_DESC: StaticCell<[u8; 512]> = StaticCell::new();
     static BOS_DESC: StaticCell<[u8; 256]> = StaticCell::new();
+    static MSOS_DESC: StaticCell<[u8; 256]> = StaticCell::new();
     static CONTROL_BUF: StaticCell<[u8; 128]> = StaticCell::new();
     let mut builder = Builder::new(
         driver,
         config,
-        &mut CONFIG_DESC.init([0; 256])[..],
+        &mut CONFIG_DESC.init([0; 512])[..],
         &mut BOS_DESC.init([0; 256])[..],
-        &mut [], // no msos descriptors
+        &mut MSOS_DESC.init([0; 256])[..],
         &mut CONTROL_BUF.init([0; 128])[..],
     );
+    builder.msos_descriptor(windows_version::WIN8_1, VENDOR_CODE_MS_OS);
 
     static STATE: StaticCell<HidState> = StaticCell::new();
 
     builder.handler(DEVICE_HANDLER.init(MyDeviceHandler::new()));
 
-    // Create classes on the builder.
+    // Create classes on the builder. The request handler answers control requests, such as for the
+    // health feature report, while [run]'s own answers reports sent on the OUT endpoint.
+    static REQUEST_HANDLER: StaticCell<MyRequestHandler> = StaticCell::new();
     let config = embassy_usb::class::hid::Config {
-        report_descriptor: KeyboardReport::desc(),
-        request_handler: None,
+        report_descriptor: hid::REPORT_DESCRIPTOR,
+        request_handler: Some(REQUEST_HANDLER.init(MyRequestHandler {})),
         poll_ms: 60,
         max_packet_size: 64,
     };
-    let hid = HidReaderWriter::<_, 1, 8>::new(&mut builder, STATE.init(HidState::new()), config);
+    let hid = MyHidReaderWriter::new(&mut builder, STATE.init(HidState::new()), config);
 
     let cdc = {
         static STATE: StaticCell<CdcState> = StaticCell::new();
         let state = STATE.init(CdcState::new());
         CdcAcmClass::new(&mut builder, state, 64)
     };
+    let console = {
+        static STATE: StaticCell<CdcState> = StaticCell::new();
+        let state = STATE.init(CdcState::new());
+        CdcAcmClass::new(&mut builder, state, 64)
+    };
+
+    // So that Windows names the serial ports for what they are, rather than both being just "USB
+    // Serial Device", which makes the steno port easier to pick out in Plover. Other hosts (and
+    // `lsusb -v`) would name them by interface string descriptors instead, but embassy-usb's classes
+    // write every interface without one, and don't let one be given.
+    let msos = builder.msos_writer();
+    msos.configuration(0);
+    for (interface, name) in [(STENO_INTERFACE, "Steno machine (Gemini PR/TX Bolt)"), (CONSOLE_INTERFACE, "Keyboard console")] {
+        msos.function(InterfaceNumber(interface));
+        msos.function_feature(RegistryPropertyFeatureDescriptor::new("FriendlyName", PropertyData::Sz(name)));
+    }
+    msos.end_function();
+
+    let raw_hid = {
+        static STATE: StaticCell<HidState> = StaticCell::new();
+        let config = embassy_usb::class::hid::Config {
+            report_descriptor: vial::RAW_HID_REPORT_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 1,
+            max_packet_size: vial::RAW_HID_REPORT_SIZE as u16,
+        };
+        RawHidReaderWriter::new(&mut builder, STATE.init(HidState::new()), config)
+    };
+
+    let midi = MyMidiClass::new(&mut builder, 1, 1, 64);
 
-    (builder.build(), hid, cdc)
+    (builder.build(), hid, cdc, console, raw_hid, midi)
 }
 
 #[embassy_executor::task]
-pub async fn run(mut usb: MyUsbDevice, hid: MyHidReaderWriter, mut cdc: MyCdcAcmClass)
+pub async fn run(mut usb: MyUsbDevice, hid: MyHidReaderWriter, cdc: MyCdcAcmClass, console: MyCdcAcmClass)
 {
-    // Run the USB device.
-    let usb_fut = usb.run();
+    // Run the USB device, waking the host from suspend if a key is pressed.
+    let usb_fut = async {
+        loop {
+            usb.run_until_suspend().await;
+            WAKE_HOST.reset();
+            if let Either::Second(()) = select(usb.wait_resume(), WAKE_HOST.wait()).await {
+                if let Err(e) = usb.remote_wakeup().await {
+                    warn!("Failed to wake the host: {:?}", e);
+                }
+            }
+        }
+    };
 
     let (reader, mut writer) = hid.split();
+    let (mut cdc, cdc_receiver, cdc_control) = cdc.split_with_control();
+    let (mut console_tx, mut console_rx) = console.split();
 
     // Do stuff with the class!
     let in_fut = async {
-        let mut last_report: KeyboardReport = KeyboardReport::default();
+        let mut last_reports = hid::RELEASED_REPORTS;
+        let mut last_sent = [Instant::MIN; hid::REPORT_KINDS];
+        let keyboard_kind = hid::RELEASED_REPORTS[0].kind_index();
+        let mut confirmations_left = 0;
+        // A report which failed to send, when it did, and how many more times to retry it. Only
+        // kept until a newer report of its kind replaces it.
+        let mut unsent: Option<(hid::OutgoingReport, Instant, u8)> = None;
         loop {
-            let (report, mut steno_packet) = UPDATES_CHANNEL.receive().await;
-            if report != last_report {
-                match writer.write_serialize(&report).await {
-                    Ok(()) => {}
-                    Err(_e) => {} //warn!("Failed to send report: {:?}", e),
-                };
-
-                last_report = report;
+            if CONFIRM_RELEASE.try_take().is_some() {
+                confirmations_left = RELEASE_CONFIRMATIONS;
+            }
+
+            // Movement isn't repeated, as the host would take it as more movement. Nor is the last
+            // report sent of a kind with one unsent, which would take the host back a step.
+            let unsent_kind = unsent.map(|(report, _, _)| report.kind_index());
+            let idle_rates = IDLE_RATES.lock(|idle_rates| idle_rates.get());
+            let next_repeat = (0..hid::REPORT_KINDS).filter(|&idx| !last_reports[idx].is_relative() && Some(idx) != unsent_kind).filter_map(|idx|
+                Some((last_reports[idx], last_sent[idx] + idle_rates[idx]?))
+            ).chain(
+                (confirmations_left > 0 && unsent_kind != Some(keyboard_kind)).then(|| (last_reports[keyboard_kind], last_sent[keyboard_kind] + RELEASE_CONFIRMATION_INTERVAL))
+            ).chain(
+                unsent.map(|(report, failed_at, _)| (report, failed_at + REPORT_RETRY_INTERVAL))
+            ).min_by_key(|&(_, at)| at);
+
+            // a repeat of the unsent report's kind can only be a retry of it
+            let (report, repeat) = match next_repeat {
+                Some((report, at)) => match select(next_report(), Timer::at(at)).await {
+                    Either::First(report) => (report, false),
+                    Either::Second(()) => (report, true),
+                },
+                None => (next_report().await, false),
+            };
+            let retries_left = match unsent {
+                Some((_, _, retries_left)) if unsent_kind == Some(report.kind_index()) => {
+                    unsent = None;  // retried now, or replaced by a newer report
+                    repeat.then_some(retries_left)
+                },
+                _ => None,
+            };
+
+            // Nothing is sent while the host is suspended. Released reports queued on suspending
+            // (or by anything else) go first, ahead of what's held now, and leave any repeat of
+            // what was held before unwanted.
+            while power::is_host_suspended() {
+                HOST_RESUMED.wait().await;
+            }
+            if RESEND_RELEASED_REPORTS.try_take().is_some() {
+                for report in hid::RELEASED_REPORTS {
+                    write_report(&mut writer, &report).await;
+                    last_sent[report.kind_index()] = Instant::now();
+                }
+                last_reports = hid::RELEASED_REPORTS;
+                unsent = None;
+                if repeat {
+                    continue;
+                }
             }
-            if steno_packet.iter().any(|x| x != &0u8) && cdc.dtr() {
-                // TODO possibly handle RTS pauses / disconnections better(?)
-                steno_packet[0] |= 128;  // indicates lead byte of packet
-                cdc.write_packet(&steno_packet).await.expect("cdc write");
-
-                steno_packet = Default::default();
-                steno_packet[0] |= 128;
-                cdc.write_packet(&steno_packet).await.expect("cdc write");
+
+            let last_report = &mut last_reports[report.kind_index()];
+            if report.is_to_be_written(last_report, repeat) {
+                let closed_at = if repeat { None } else { latency::take_closed_at() };
+                let handed_at = Instant::now();
+
+                if let (hid::OutgoingReport::Keyboard(last), hid::OutgoingReport::Keyboard(next)) = (*last_report, report) {
+                    if MODIFIERS_AHEAD.lock(|enabled| enabled.get()) {
+                        for between in hid::modifiers_ahead(&last, &next) {
+                            write_report(&mut writer, &hid::OutgoingReport::Keyboard(between)).await;
+                        }
+                    }
+                }
+                if !write_report(&mut writer, &report).await {
+                    // Movement isn't retried, as it would arrive too late to be wanted.
+                    if !report.is_relative() {
+                        match retries_left.unwrap_or(REPORT_RETRIES) {
+                            0 => {
+                                warn!("Gave up sending report");
+                                REPORT_ERRORS.lock(|errors| errors.set(ReportErrors { dropped: errors.get().dropped + 1, ..errors.get() }));
+                            },
+                            retries_left => unsent = Some((report, Instant::now(), retries_left - 1)),
+                        }
+                    }
+                    continue;
+                }
+
+                *last_report = report;
+                last_sent[report.kind_index()] = Instant::now();
+                if repeat && retries_left.is_none() && report.kind_index() == keyboard_kind {
+                    confirmations_left = confirmations_left.saturating_sub(1);
+                }
+
+                if let Some(closed_at) = closed_at.filter(|_| latency::is_enabled()) {
+                    console::print(latency::describe(closed_at, handed_at, Instant::now()));
+                }
+            }
+        }
+    };
+
+    let steno_fut = async {
+        let mut pending_strokes = Deque::<(steno::GeminiPacket, Instant), PENDING_STROKES_LIMIT>::new();
+        let mut last_written = Instant::MIN;
+        loop {
+            if cdc.dtr() {
+                while let Some((steno_packet, stroked_at)) = pending_strokes.pop_front() {
+                    if stroked_at.elapsed() <= PENDING_STROKE_MAX_AGE {
+                        write_stroke(&mut cdc, &steno_packet, &mut last_written).await;
+                    }
+                }
+            }
+
+            let steno_packet = if pending_strokes.is_empty() {
+                STROKES_CHANNEL.receive().await
+            } else {
+                // look out for the port being opened again meanwhile
+                match select(STROKES_CHANNEL.receive(), Timer::after(PENDING_STROKES_POLL)).await {
+                    Either::First(steno_packet) => steno_packet,
+                    Either::Second(()) => continue,
+                }
+            };
+
+            let taken_at = latency::take_stroke_taken_at();
+            console::mirror_stroke(&steno_packet, Instant::now());
+
+            let protocol = steno::PROTOCOL.lock(|protocol| protocol.get());
+            if matches!(protocol, steno::Protocol::PloverKeyboard | steno::Protocol::PloverArpeggiate) {
+                REPORTS_CHANNEL.send(hid::OutgoingReport::Nkro(steno::to_nkro(&steno_packet))).await;
+                REPORTS_CHANNEL.send(hid::OutgoingReport::Nkro(hid::NkroReport::released())).await;
+                if protocol == steno::Protocol::PloverArpeggiate {
+                    let mut report = hid::NkroReport::released();
+                    report.press(steno::PLOVER_ARPEGGIATE_KEY);
+                    REPORTS_CHANNEL.send(hid::OutgoingReport::Nkro(report)).await;
+                    REPORTS_CHANNEL.send(hid::OutgoingReport::Nkro(hid::NkroReport::released())).await;
+                }
+                continue;
+            }
+            if !cdc.dtr() {
+                // kept for when Plover (re)opens the port
+                if pending_strokes.is_full() {
+                    pending_strokes.pop_front();
+                }
+                pending_strokes.push_back((steno_packet, Instant::now())).ok();
+                continue;
+            }
+            let handed_at = Instant::now();
+            write_stroke(&mut cdc, &steno_packet, &mut last_written).await;
+            if let Some(taken_at) = taken_at.filter(|_| latency::is_enabled()) {
+                console::print(latency::describe_stroke(taken_at, handed_at, Instant::now()));
             }
         }
     };
 
     let out_fut = async {
         static REQUEST_HANDLER: StaticCell<MyRequestHandler> = StaticCell::new();
-        reader.run(false, REQUEST_HANDLER.init(MyRequestHandler {})).await;
+        reader.run(true, REQUEST_HANDLER.init(MyRequestHandler {})).await;
+    };
+
+    let line_coding_fut = async {
+        // the baud rate last set, the only part of the line coding which picks a protocol
+        let mut last_baud_rate = None;
+        loop {
+            cdc_control.control_changed().await;
+            let baud_rate = cdc_receiver.line_coding().data_rate();
+            if baud_rate == BOOTLOADER_TOUCH_BAUD_RATE {
+                REBOOT_TO_BOOTLOADER.signal(());
+            }
+            // only the control lines changed, e.g. as the port is closed, or opened again as before,
+            // so the protocol chosen since stays
+            if last_baud_rate.replace(baud_rate) == Some(baud_rate) {
+                continue;
+            }
+            let protocol = steno::PROTOCOL.lock(|current| {
+                current.set(current.get().on_baud_rate_set(baud_rate));
+                current.get()
+            });
+            info!("Steno port set to {} baud, using {}", baud_rate, protocol);
+        }
+    };
+    let bootloader_fut = async {
+        REBOOT_TO_BOOTLOADER.wait().await;
+        info!("Rebooting to bootloader");
+        Timer::after_millis(100).await;  // let any control transfer in progress complete
+        embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+    };
+    let console_fut = async {
+        let mut editor = console::LineEditor::default();
+        let mut buf = [0; 64];
+        loop {
+            match select(console_rx.read_packet(&mut buf), console::OUTPUT.receive()).await {
+                Either::First(Ok(len)) => {
+                    write_console(&mut console_tx, &buf[..len]).await;  // echo
+                    for &byte in &buf[..len] {
+                        if let Some(command) = editor.push(byte) {
+                            write_console(&mut console_tx, b"\r\n").await;
+                            write_console(&mut console_tx, console::run_command(&command).as_bytes()).await;
+                        }
+                    }
+                },
+                Either::First(Err(_)) => console_rx.wait_connection().await,
+                Either::Second(line) => {
+                    if console_tx.dtr() {
+                        write_console(&mut console_tx, line.as_bytes()).await;
+                    }
+                },
+            }
+        }
+    };
+    let startup_macro_fut = async {
+        if keymap::STARTUP_MACRO.is_empty() {
+            return;
+        }
+        CONFIGURED.wait().await;
+        Timer::after(STARTUP_MACRO_DELAY).await;
+        info!("Typing startup macro");
+        for (idx, thing) in keymap::STARTUP_MACRO.iter().enumerate() {
+            let (pressed, released) = match *thing {
+                keymap::Thing::RealKey(key) => key_reports(key),
+                keymap::Thing::ConsumerKey(usage_id) => (
+                    hid::OutgoingReport::Consumer(MediaKeyboardReport { usage_id }),
+                    hid::OutgoingReport::Consumer(MediaKeyboardReport { usage_id: 0 }),
+                ),
+                _ => {
+                    warn!("Can't type item {} of startup macro, only keys", idx);
+                    continue;
+                },
+            };
+            REPORTS_CHANNEL.send(pressed).await;
+            REPORTS_CHANNEL.send(released).await;
+        }
+    };
+
+    let sequence_fut = async {
+        loop {
+            macros::play(SEQUENCES.receive().await).await;
+        }
     };
 
     // Run everything concurrently.
     // If we had made everything `'static` above instead, we could do this using separate tasks instead.
-    join(usb_fut, join(in_fut, out_fut)).await;
+    join3(usb_fut, join4(in_fut, steno_fut, out_fut, console_fut), join4(line_coding_fut, bootloader_fut, startup_macro_fut, sequence_fut)).await;
+}
+
+/// Reports pressing `key` (as on the current [crate::os::Os]) on its own, then releasing it.
+fn key_reports(key: keymap::HidKey) -> (hid::OutgoingReport, hid::OutgoingReport) {
+    let (keycode, modifier) = settings::get().os.translate(key);
+    (
+        hid::OutgoingReport::Keyboard(KeyboardReport { modifier, reserved: 0, leds: 0, keycodes: [keycode, 0, 0, 0, 0, 0] }),
+        hid::OutgoingReport::Keyboard(KeyboardReport::default()),
+    )
+}
+
+/// Wait for the next report to send over USB.
+#[cfg(not(feature = "ble"))]
+async fn next_report() -> hid::OutgoingReport {
+    any_report().await
+}
+
+/// Wait for the next report to send over USB, while it's the chosen [crate::ble::Output].
+#[cfg(feature = "ble")]
+async fn next_report() -> hid::OutgoingReport {
+    crate::ble::next_report(crate::ble::Output::Usb).await
+}
+
+/// Wait for the next report to send, however it's sent: the latest from [crate::scan] of either
+/// kind, or the next from [REPORTS_CHANNEL].
+pub(crate) async fn any_report() -> hid::OutgoingReport {
+    match select3(KEYBOARD_REPORT.wait(), CONSUMER_REPORT.wait(), REPORTS_CHANNEL.receive()).await {
+        Either3::First(report) => hid::OutgoingReport::Keyboard(report),
+        Either3::Second(report) => hid::OutgoingReport::Consumer(report),
+        Either3::Third(report) => report,
+    }
+}
+
+/// Write a steno stroke to the serial port in the current [steno::PROTOCOL], or as
+/// [steno::PAPER_TAPE], no sooner than [steno::STROKE_SPACING] after the last was written (at
+/// `last_written`).
+async fn write_stroke(cdc: &mut CdcSender<'static, MyDriver>, steno_packet: &steno::GeminiPacket, last_written: &mut Instant) {
+    Timer::at(*last_written + steno::STROKE_SPACING.lock(|spacing| spacing.get())).await;
+    write_stroke_now(cdc, steno_packet).await;
+    *last_written = Instant::now();
+}
+
+/// Write a steno stroke to the serial port straight away, as [write_stroke] says.
+async fn write_stroke_now(cdc: &mut CdcSender<'static, MyDriver>, steno_packet: &steno::GeminiPacket) {
+    let written = if steno::PAPER_TAPE.lock(|paper_tape| paper_tape.get()) {
+        let line = steno::to_notation(steno_packet);
+        cdc.write_packet(line.as_bytes()).await
+    } else {
+        write_stroke_packet(cdc, steno_packet).await
+    };
+    if let Err(e) = written {
+        warn!("Failed to write stroke: {:?}", e);
+        health::count(&health::SERIAL_WRITE_FAILURES);
+    }
+}
+
+/// Write a steno stroke to the serial port in the current [steno::PROTOCOL].
+async fn write_stroke_packet(cdc: &mut CdcSender<'static, MyDriver>, steno_packet: &steno::GeminiPacket) -> Result<(), EndpointError> {
+    match steno::PROTOCOL.lock(|protocol| protocol.get()) {
+        steno::Protocol::GeminiPr if !steno::GEMINI_RELEASE_PACKET.lock(|enabled| enabled.get()) => {
+            cdc.write_packet(&steno_packet.to_bytes()).await
+        },
+        steno::Protocol::GeminiPr => {
+            // both in one packet, so the stroke doesn't wait on a second transfer
+            let mut bytes = [0; 2 * steno::PACKET_LEN];
+            let (stroke, released) = bytes.split_at_mut(steno::PACKET_LEN);
+            stroke.copy_from_slice(&steno_packet.to_bytes());
+            released.copy_from_slice(&steno::GeminiPacket::default().to_bytes());
+            cdc.write_packet(&bytes).await
+        },
+        steno::Protocol::TxBolt => {
+            cdc.write_packet(&steno::to_tx_bolt(steno_packet)).await
+        },
+        steno::Protocol::PloverKeyboard | steno::Protocol::PloverArpeggiate => {
+            // typed as HID reports instead, so only left over from before switching to it
+            Ok(())
+        },
+    }
+}
+
+/// Write `bytes` to the console, split into as many packets as it takes.
+async fn write_console(console_tx: &mut CdcSender<'static, MyDriver>, bytes: &[u8]) {
+    for packet in bytes.chunks(console_tx.max_packet_size() as usize) {
+        if let Err(e) = console_tx.write_packet(packet).await {
+            warn!("Failed to write to console: {:?}", e);
+            return;
+        }
+    }
+}
+
+/// Send `report` to the host, returning whether it went, and counting it in [REPORT_ERRORS] if not.
+async fn write_report(writer: &mut MyHidWriter, report: &hid::OutgoingReport) -> bool {
+    let mut buf = [0; hid::MAX_INPUT_REPORT_SIZE];
+    if let Err(e) = writer.write(report.serialize(&mut buf)).await {
+        warn!("Failed to send report: {:?}", e);
+        REPORT_ERRORS.lock(|errors| errors.set(ReportErrors { failed: errors.get().failed + 1, ..errors.get() }));
+        return false;
+    }
+    true
 }
 
 struct MyRequestHandler;
 
 impl RequestHandler for MyRequestHandler {
-    fn get_report(&mut self, _id: ReportId, _buf: &mut [u8]) -> Option<usize> {
-        //info!("Get report for {:?}", id);
-        None
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        info!("Get report for {:?}", id);
+        match id {
+            ReportId::Feature(id) => hid::health_report(id, &health::counters(), buf),
+            _ => None,
+        }
     }
 
-    fn set_report(&mut self, _id: ReportId, _data: &[u8]) -> OutResponse {
-        //info!("Set report for {:?}: {=[u8]}", id, data);
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        info!("Set report for {:?}: {=[u8]}", id, data);
+        take_output_report(data);
         OutResponse::Accepted
     }
 
-    fn set_idle_ms(&mut self, _id: Option<ReportId>, _dur: u32) {
-        //info!("Set idle rate for {:?} to {:?}", id, dur);
+    fn set_idle_ms(&mut self, id: Option<ReportId>, dur: u32) {
+        info!("Set idle rate for {:?} to {:?}", id, dur);
+        let rate = if dur == 0 { None } else { Some(Duration::from_millis(dur.into())) };
+        IDLE_RATES.lock(|idle_rates| {
+            let mut rates = idle_rates.get();
+            match id {
+                None => rates = [rate; hid::REPORT_KINDS],
+                Some(ReportId::In(id)) => match hid::OutgoingReport::kind_index_for_id(id) {
+                    Some(idx) => rates[idx] = rate,
+                    None => warn!("No input report {} to set idle rate of", id),
+                },
+                Some(_) => warn!("Idle rate can only be set for input reports"),
+            }
+            idle_rates.set(rates);
+        });
+    }
+
+    fn get_idle_ms(&mut self, id: Option<ReportId>) -> Option<u32> {
+        info!("Get idle rate for {:?}", id);
+        let idx = match id {
+            None => 0,  // all the same unless set separately, in which case there's no right answer
+            Some(ReportId::In(id)) => hid::OutgoingReport::kind_index_for_id(id)?,
+            Some(_) => return None,
+        };
+        let rate = IDLE_RATES.lock(|idle_rates| idle_rates.get()[idx]);
+        Some(rate.map_or(0, |rate| rate.as_millis() as u32))
     }
+}
 
-    fn get_idle_ms(&mut self, _id: Option<ReportId>) -> Option<u32> {
-        //info!("Get idle rate for {:?}", id);
-        None
+/// Act on an output report from the host (led by its ID), over USB or [crate::ble].
+pub(crate) fn take_output_report(data: &[u8]) {
+    if let Some(caps_lock) = hid::caps_lock(data) {
+        debug!("Caps Lock: {}", caps_lock);
+        #[cfg(feature = "display")]
+        crate::display::CAPS_LOCK.signal(caps_lock);
+        #[cfg(feature = "buzzer")]
+        crate::buzzer::caps_lock(caps_lock);
     }
 }
 
@@ -156,29 +658,70 @@ impl MyDeviceHandler {
 impl Handler for MyDeviceHandler {
     fn enabled(&mut self, enabled: bool) {
         self.configured.store(false, Ordering::Relaxed);
-        if enabled {
-            //info!("Device enabled");
-        } else {
-            //info!("Device disabled");
-        }
+        info!("Device {}", if enabled { "enabled" } else { "disabled" });
     }
 
     fn reset(&mut self) {
         self.configured.store(false, Ordering::Relaxed);
-        //info!("Bus reset, the Vbus current limit is 100mA");
+        power::set_configured(false, Instant::now());
+        // a reset ends any suspend without a resume
+        power::set_host_suspended(false);
+        HOST_RESUMED.signal(());
+        info!("Bus reset, the Vbus current limit is 100mA");
     }
 
-    fn addressed(&mut self, _addr: u8) {
+    fn addressed(&mut self, addr: u8) {
         self.configured.store(false, Ordering::Relaxed);
-        //info!("USB address set to: {}", addr);
+        power::set_addressed(Instant::now());
+        info!("USB address set to: {}", addr);
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.request)
+            == (RequestType::Vendor, Recipient::Device, VENDOR_REQUEST_REBOOT_TO_BOOTLOADER)
+        {
+            REBOOT_TO_BOOTLOADER.signal(());
+            Some(OutResponse::Accepted)
+        } else {
+            None
+        }
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        power::set_host_suspended(suspended);
+        if suspended {
+            // keys released while the host is suspended may never reach it, so everything is
+            // released on resuming before anything held since is sent
+            HOST_RESUMED.reset();
+            RESEND_RELEASED_REPORTS.signal(());
+        } else {
+            HOST_RESUMED.signal(());
+        }
+        info!("Host {}", if suspended { "suspended" } else { "resumed" });
     }
 
     fn configured(&mut self, configured: bool) {
         self.configured.store(configured, Ordering::Relaxed);
+        power::set_configured(configured, Instant::now());
         if configured {
-            //info!("Device configured, it may now draw up to the configured current limit from Vbus.")
-        } else {
-            //info!("Device is no longer configured, the Vbus current limit is 100mA.");
+            CONFIGURED.signal(());
         }
+        info!("{}", if configured {
+            "Device configured, it may now draw up to the configured current limit from Vbus."
+        } else {
+            "Device is no longer configured, the Vbus current limit is 100mA."
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_number_is_the_vial_magic_then_the_unique_id() {
+        let serial_number = serial_number([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        assert_eq!(serial_number.as_str(), "vial:f64c2b3c:0123456789abcdef");
+        assert!(serial_number.contains(vial::SERIAL_NUMBER_MAGIC));
     }
 }
@@ -0,0 +1,56 @@
+//! Merges the keys held on a small keyboard (such as a numpad) plugged into a second USB port, for
+//! which the RP2040 is the host, into the keyboard's own reports, as if they were part of it.
+//!
+//! Only with the `usb-host` feature. The port itself is still to come: embassy-rp has no USB host
+//! support, over PIO or otherwise, so for now nothing calls [receive_boot_report]. Whatever drives
+//! the port should ask the attached keyboard for its boot protocol, so as to need no parsing of
+//! its report descriptor, pass on each report it sends, and call [disconnected] when it's unplugged.
+
+use crate::RawMutex;
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::Mutex;
+use usbd_hid::descriptor::KeyboardReport;
+
+/// Length of a boot protocol keyboard report: modifiers, a reserved byte, and six keys
+const BOOT_REPORT_LEN: usize = 8;
+
+/// Keycode filling every slot of a boot report while too many keys are held to tell which
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Keys held on the attached keyboard, as of its latest report
+static HELD: Mutex<RawMutex, Cell<KeyboardReport>> = Mutex::new(Cell::new(KeyboardReport::default()));
+
+/// Take in a boot protocol report from the attached keyboard. Reports of too many keys held at once
+/// are ignored, keeping the keys from before, as they don't say which are.
+#[allow(dead_code)]  // not yet called, until there's a port (see above)
+pub fn receive_boot_report(bytes: &[u8]) {
+    let Some(bytes) = bytes.get(..BOOT_REPORT_LEN) else {
+        warn!("Short report from attached keyboard: {} bytes", bytes.len());
+        return;
+    };
+    let keycodes: [u8; 6] = bytes[2..].try_into().expect("six keys");
+    if keycodes.contains(&ERROR_ROLL_OVER) {
+        return;
+    }
+    HELD.lock(|held| held.set(KeyboardReport { modifier: bytes[0], reserved: 0, leds: 0, keycodes }));
+}
+
+/// Release every key of the attached keyboard, as it has been unplugged.
+#[allow(dead_code)]  // not yet called, until there's a port (see above)
+pub fn disconnected() {
+    HELD.lock(|held| held.set(KeyboardReport::default()));
+}
+
+/// Add the keys held on the attached keyboard to `report`, in whichever of its slots are free.
+pub fn merge(report: &mut KeyboardReport) {
+    let held = HELD.lock(|held| held.get());
+    report.modifier |= held.modifier;
+    for keycode in held.keycodes.into_iter().filter(|&keycode| keycode != 0) {
+        if report.keycodes.contains(&keycode) {
+            continue;
+        }
+        if let Some(slot) = report.keycodes.iter_mut().find(|slot| **slot == 0) {
+            *slot = keycode;
+        }
+    }
+}
@@ -0,0 +1,403 @@
+//! Enough of the [Vial](https://get.vial.today/) raw HID protocol (and the VIA protocol it extends)
+//! for the Vial GUI to show the keymap and remap keys, with [Thing]s translated to and from QMK
+//! keycodes.
+//!
+//! Remapped keys are kept in RAM over the top of the layers built into [crate::keymap], and saved
+//! to flash by [crate::settings] a few seconds after each change. A saved keymap which fails its
+//! checksum is ignored, leaving the built-in layers (with their plain qwerty base layer) as they are.
+
+use crate::keymap::{layer_index, HidModifiers, Layer, LayerRef, Thing, COLUMNS, LAYERS, ROWS};
+use crate::rmk::keycode::{ConsumerKey, KeyCode};
+use crate::steno::KeyCode as StenoKeyCode;
+use crate::settings::{self, crc16, LoadError};
+use crate::{boards, usb, RawMutex};
+pub use protocol::RAW_HID_REPORT_SIZE;
+use protocol::*;
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+
+/// Message IDs, kept apart so that the host tools can share them
+mod protocol;
+
+/// The vendor-defined usage page and usage which Vial (like VIA) looks for
+#[rustfmt::skip]
+pub const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF,       // Usage Page (Vendor 0xFF60)
+    0x09, 0x61,             // Usage (0x61)
+    0xA1, 0x01,             // Collection (Application)
+    0x09, 0x62,             //   Usage (0x62)
+    0x15, 0x00, 0x26, 0xFF, 0x00, // Logical Minimum/Maximum (0..255)
+    0x75, 0x08, 0x95, RAW_HID_REPORT_SIZE as u8, // Report Size 8, Count (whole report)
+    0x81, 0x02,             //   Input (Data, Variable, Absolute)
+    0x09, 0x63,             //   Usage (0x63)
+    0x15, 0x00, 0x26, 0xFF, 0x00, // Logical Minimum/Maximum (0..255)
+    0x75, 0x08, 0x95, RAW_HID_REPORT_SIZE as u8, // Report Size 8, Count (whole report)
+    0x91, 0x02,             //   Output (Data, Variable, Absolute)
+    0xC0,                   // End Collection
+];
+
+/// Vial only offers to open devices whose USB serial number contains this
+pub const SERIAL_NUMBER_MAGIC: &str = "vial:f64c2b3c";
+
+// QMK keycodes (as numbered since QMK 0.19, which Vial protocol 6 uses)
+const KC_NO: u16 = 0x0000;
+const KC_LAST_BASIC: u16 = 0x00A4;
+const KC_FIRST_MODIFIER: u16 = 0x00E0;
+const KC_LAST_MODIFIER: u16 = 0x00E7;
+/// Basic keycode with modifiers held, in bits 8-11 (Ctrl, Shift, Alt, Gui), on the right hand if
+/// bit 12 is set
+const QK_MODS: u16 = 0x0100;
+const QK_MODS_MAX: u16 = 0x1FFF;
+const QK_MODS_RIGHT: u16 = 0x1000;
+/// First of the steno keys, numbered as in [StenoKeyCode::to_packet_index]
+const QK_STENO: u16 = 0x74C0;
+/// Layer selected while held along with modifiers, numbered in bits 5-8, with the modifiers in bits
+/// 0-4 as for [QK_MODS]
+const QK_LAYER_MOD: u16 = 0x5000;
+const QK_LAYER_MOD_MAX: u16 = 0x51FF;
+/// Layer selected while held, numbered in bits 8-11, or basic keycode in bits 0-7 typed instead
+/// when tapped
+const QK_LAYER_TAP: u16 = 0x4000;
+const QK_LAYER_TAP_MAX: u16 = 0x4FFF;
+const QK_BOOT: u16 = 0x7C00;
+/// First of the keycodes left for each keyboard to define, named in its Vial definition
+const QK_KB: u16 = 0x7E00;
+
+/// [Thing]s with no QMK keycode, given keyboard-defined ones in this order, which must match the
+/// `customKeycodes` of each board's definition (see `vial/`)
+const CUSTOM_THINGS: [Thing; 15] = [
+    Thing::LeftSymbolKey, Thing::RightSymbolKey, Thing::NavKey, Thing::FunctionKey,
+    Thing::LayoutCycle, Thing::StenoToggle, Thing::PaperTapeToggle, Thing::StenoProtocolCycle,
+    Thing::BacklightBrightness, Thing::LedBrightness, Thing::JigglerToggle, Thing::LatencyTestToggle,
+    Thing::OsCycle, Thing::MicMute, Thing::MidiToggle,
+];
+/// Keyboard-defined keycode (named "as built") shown for [Thing]s which can't be described to Vial
+/// at all, such as [Thing::TapHold]s. Assigning it restores whatever the keymap has built in.
+const AS_BUILT: u16 = QK_KB + CUSTOM_THINGS.len() as u16;
+
+/// Consumer control usages which QMK gives basic keycodes to, by keycode
+const CONSUMER_KEYCODES: [(KeyCode, ConsumerKey); 7] = [
+    (KeyCode::AudioMute, ConsumerKey::Mute),
+    (KeyCode::AudioVolUp, ConsumerKey::VolumeIncrement),
+    (KeyCode::AudioVolDown, ConsumerKey::VolumeDecrement),
+    (KeyCode::MediaNextTrack, ConsumerKey::NextTrack),
+    (KeyCode::MediaPrevTrack, ConsumerKey::PrevTrack),
+    (KeyCode::MediaStop, ConsumerKey::StopPlay),
+    (KeyCode::MediaPlayPause, ConsumerKey::PlayPause),
+];
+
+type Remapped = [[[Option<Thing>; COLUMNS]; ROWS]; LAYERS.len()];
+
+const NOT_REMAPPED: Remapped = [[[None; COLUMNS]; ROWS]; LAYERS.len()];
+
+/// Keys remapped from Vial, replacing what [LAYERS] have built in wherever they are `Some`
+static REMAPPED: Mutex<RawMutex, RefCell<Remapped>> = Mutex::new(RefCell::new(NOT_REMAPPED));
+
+/// Marks the keymap sector as holding remapped keys in this layout, rather than being erased
+const KEYMAP_MAGIC: [u8; 4] = *b"OCV1";
+
+/// The magic, then the number of layers, rows and columns (so that remaps saved for another
+/// keymap aren't put in the wrong places), then the big-endian keycode of every key, ordered as
+/// VIA's keymap buffer is (see [position]) and [AS_BUILT] wherever it isn't remapped, then a
+/// big-endian [crc16] of everything before it. Remapped keys which no keycode does (as only the
+/// remap chord can make) can't be saved, so are saved as [AS_BUILT].
+pub type SerializedKeymap = [u8; KEYMAP_CHECKSUM_INDEX + 2];
+const KEYMAP_SHAPE_INDEX: usize = KEYMAP_MAGIC.len();
+const KEYMAP_KEYCODES_INDEX: usize = KEYMAP_SHAPE_INDEX + 3;
+const KEYMAP_CHECKSUM_INDEX: usize = KEYMAP_KEYCODES_INDEX + 2 * LAYERS.len() * ROWS * COLUMNS;
+const KEYMAP_SHAPE: [u8; 3] = [LAYERS.len() as u8, ROWS as u8, COLUMNS as u8];
+
+/// The remapped keys, as saved to flash.
+pub fn serialize_keymap() -> SerializedKeymap {
+    REMAPPED.lock(|remapped| serialize(&remapped.borrow()))
+}
+
+/// Use the saved remapped keys in `bytes` from now on, unless they don't check out.
+pub fn load_keymap(bytes: &SerializedKeymap) -> Result<(), LoadError> {
+    let loaded = deserialize(bytes)?;
+    REMAPPED.lock(|remapped| *remapped.borrow_mut() = loaded);
+    Ok(())
+}
+
+fn serialize(remapped: &Remapped) -> SerializedKeymap {
+    let mut bytes = [0; KEYMAP_CHECKSUM_INDEX + 2];
+    bytes[..KEYMAP_SHAPE_INDEX].copy_from_slice(&KEYMAP_MAGIC);
+    bytes[KEYMAP_SHAPE_INDEX..KEYMAP_KEYCODES_INDEX].copy_from_slice(&KEYMAP_SHAPE);
+    for (idx, chunk) in bytes[KEYMAP_KEYCODES_INDEX..KEYMAP_CHECKSUM_INDEX].chunks_exact_mut(2).enumerate() {
+        let (layer_idx, row, column) = position(idx).expect("a keycode for each position");
+        let keycode = remapped[layer_idx][row][column].and_then(to_keycode).unwrap_or(AS_BUILT);
+        chunk.copy_from_slice(&keycode.to_be_bytes());
+    }
+    let checksum = crc16(&bytes[..KEYMAP_CHECKSUM_INDEX]);
+    bytes[KEYMAP_CHECKSUM_INDEX..].copy_from_slice(&checksum.to_be_bytes());
+    bytes
+}
+
+fn deserialize(bytes: &SerializedKeymap) -> Result<Remapped, LoadError> {
+    if bytes[..KEYMAP_SHAPE_INDEX] != KEYMAP_MAGIC || bytes[KEYMAP_SHAPE_INDEX..KEYMAP_KEYCODES_INDEX] != KEYMAP_SHAPE {
+        return Err(LoadError::Missing);
+    }
+    if bytes[KEYMAP_CHECKSUM_INDEX..] != crc16(&bytes[..KEYMAP_CHECKSUM_INDEX]).to_be_bytes() {
+        return Err(LoadError::Corrupt);
+    }
+    let mut remapped = NOT_REMAPPED;
+    for (idx, chunk) in bytes[KEYMAP_KEYCODES_INDEX..KEYMAP_CHECKSUM_INDEX].chunks_exact(2).enumerate() {
+        let (layer_idx, row, column) = position(idx).expect("a keycode for each position");
+        let built = LAYERS[layer_idx][row][column];
+        let thing = to_thing(u16::from_be_bytes([chunk[0], chunk[1]]), built);
+        remapped[layer_idx][row][column] = (thing != built).then_some(thing);
+    }
+    Ok(remapped)
+}
+
+/// What the key at `row` and `column` does on `layer`, taking remapping into account.
+pub fn thing_at(layer: &Layer, row: usize, column: usize) -> Thing {
+    let remapped = REMAPPED.lock(|remapped| remapped.borrow()[layer_index(layer)][row][column]);
+    remapped.unwrap_or(layer[row][column])
+}
+
+/// The QMK keycode which does `thing`, if there is one.
+fn to_keycode(thing: Thing) -> Option<u16> {
+    match thing {
+        Thing::Inactive => Some(KC_NO),
+        Thing::RealKey((0, mods)) if mods.count_ones() == 1 => Some(KC_FIRST_MODIFIER + mods.trailing_zeros() as u16),
+        Thing::RealKey((keycode, mods)) => {
+            let keycode = keycode as u16;
+            if keycode > KC_LAST_BASIC && !(KC_FIRST_MODIFIER..=KC_LAST_MODIFIER).contains(&keycode) {
+                return None;
+            }
+            Some(mods_to_qmk(mods)? << 8 | keycode)
+        },
+        Thing::ModifiedLayer(LayerRef(layer), mods) => Some(QK_LAYER_MOD | (layer_index(layer) as u16) << 5 | mods_to_qmk(mods)?),
+        Thing::LayerTap(LayerRef(layer), tap) => to_keycode(Thing::RealKey(tap))
+            .filter(|&keycode| keycode <= 0xFF)
+            .map(|keycode| QK_LAYER_TAP | (layer_index(layer) as u16) << 8 | keycode),
+        Thing::ConsumerKey(usage) => CONSUMER_KEYCODES.iter()
+            .find(|(_, consumer_key)| *consumer_key as u16 == usage)
+            .map(|(keycode, _)| *keycode as u16),
+        Thing::StenoKey(code) => Some(QK_STENO + code.to_packet_index() as u16),
+        Thing::Bootloader => Some(QK_BOOT),
+        _ => CUSTOM_THINGS.iter().position(|&custom| custom == thing).map(|idx| QK_KB + idx as u16),
+    }
+}
+
+/// The [Thing] which does what QMK `keycode` does, given that `built` is what the keymap has there.
+fn to_thing(keycode: u16, built: Thing) -> Thing {
+    match keycode {
+        AS_BUILT => built,
+        KC_FIRST_MODIFIER..=KC_LAST_MODIFIER => Thing::RealKey((0, 1 << (keycode - KC_FIRST_MODIFIER))),
+        0x04..=KC_LAST_BASIC => Thing::RealKey((keycode as u8, 0)),
+        QK_MODS..=QK_MODS_MAX => {
+            let mods = mods_from_qmk(keycode >> 8);
+            match to_thing(keycode & 0xFF, Thing::Inactive) {
+                Thing::RealKey((keycode, key_mods)) => Thing::RealKey((keycode, key_mods | mods)),
+                _ => Thing::RealKey((0, mods)),
+            }
+        },
+        QK_LAYER_MOD..=QK_LAYER_MOD_MAX => match LAYERS.get(((keycode >> 5) & 0x0F) as usize) {
+            Some(&layer) => Thing::ModifiedLayer(LayerRef(layer), mods_from_qmk(keycode)),
+            None => Thing::Inactive,
+        },
+        QK_LAYER_TAP..=QK_LAYER_TAP_MAX => match (LAYERS.get(((keycode >> 8) & 0x0F) as usize), to_thing(keycode & 0xFF, Thing::Inactive)) {
+            (Some(&layer), Thing::RealKey(tap)) => Thing::LayerTap(LayerRef(layer), tap),
+            _ => Thing::Inactive,
+        },
+        QK_BOOT => Thing::Bootloader,
+        QK_KB..AS_BUILT => CUSTOM_THINGS[(keycode - QK_KB) as usize],
+        _ => {
+            if let Some(&(_, consumer_key)) = CONSUMER_KEYCODES.iter().find(|(code, _)| *code as u16 == keycode) {
+                return Thing::ConsumerKey(consumer_key as u16);
+            }
+            if let Some(&code) = StenoKeyCode::ALL.iter().find(|code| QK_STENO + code.to_packet_index() as u16 == keycode) {
+                return Thing::StenoKey(code);
+            }
+            if keycode != KC_NO {
+                warn!("No equivalent for keycode {:#x}, leaving the key inactive", keycode);
+            }
+            Thing::Inactive
+        },
+    }
+}
+
+/// HID modifier bits `mods` as QMK's 5-bit modifiers (Ctrl, Shift, Alt, Gui, and whether they're on
+/// the right hand), if they can be.
+fn mods_to_qmk(mods: HidModifiers) -> Option<u16> {
+    match (mods & 0x0F, mods >> 4) {
+        (0, 0) => Some(0),
+        (left, 0) => Some(left as u16),
+        (0, right) => Some(QK_MODS_RIGHT >> 8 | right as u16),
+        _ => None,  // QMK can't mix left and right modifiers
+    }
+}
+
+/// HID modifier bits for QMK's 5-bit modifiers, in the low bits of `qmk_mods`.
+fn mods_from_qmk(qmk_mods: u16) -> HidModifiers {
+    let mods = (qmk_mods & 0x0F) as u8;
+    if qmk_mods & QK_MODS_RIGHT >> 8 != 0 { mods << 4 } else { mods }
+}
+
+/// The QMK keycode to show Vial for the key at `row` and `column` on layer `layer_idx`.
+fn get_keycode(layer_idx: usize, row: usize, column: usize) -> u16 {
+    to_keycode(thing_at(LAYERS[layer_idx], row, column)).unwrap_or(AS_BUILT)
+}
+
+fn set_keycode(layer_idx: usize, row: usize, column: usize, keycode: u16) {
+    let thing = to_thing(keycode, LAYERS[layer_idx][row][column]);
+    set_thing(layer_idx, row, column, thing);
+}
+
+fn set_thing(layer_idx: usize, row: usize, column: usize, thing: Thing) {
+    let built = LAYERS[layer_idx][row][column];
+    REMAPPED.lock(|remapped| {
+        remapped.borrow_mut()[layer_idx][row][column] = (thing != built).then_some(thing);
+    });
+    settings::keymap_changed();
+}
+
+/// Remap the key at `row` and `column` on `layer` to do `thing`, just as Vial would.
+pub fn remap(layer: &Layer, row: usize, column: usize, thing: Thing) {
+    set_thing(layer_index(layer), row, column, thing);
+}
+
+/// Undo every remap, going back to the keymap as built.
+pub fn reset_keymap() {
+    REMAPPED.lock(|remapped| *remapped.borrow_mut() = NOT_REMAPPED);
+    settings::keymap_changed();
+}
+
+/// Layer, row and column of the keymap position at `idx`, counting along each row of each layer,
+/// as VIA lays out the whole keymap in one buffer.
+fn position(idx: usize) -> Option<(usize, usize, usize)> {
+    let layer_idx = idx / (ROWS * COLUMNS);
+    (layer_idx < LAYERS.len()).then_some((layer_idx, idx / COLUMNS % ROWS, idx % COLUMNS))
+}
+
+/// The position a message addresses by layer, row and column in its bytes 1-3, if it's a key.
+fn addressed_position(msg: &[u8; RAW_HID_REPORT_SIZE]) -> Option<(usize, usize, usize)> {
+    let (layer_idx, row, column) = (msg[1] as usize, msg[2] as usize, msg[3] as usize);
+    (layer_idx < LAYERS.len() && row < ROWS && column < COLUMNS).then_some((layer_idx, row, column))
+}
+
+/// Carry out the VIA or Vial command in `msg`, replacing it with the response.
+fn handle(msg: &mut [u8; RAW_HID_REPORT_SIZE]) {
+    match msg[0] {
+        VIA_GET_PROTOCOL_VERSION => msg[1..3].copy_from_slice(&VIA_PROTOCOL_VERSION.to_be_bytes()),
+        VIA_GET_KEYBOARD_VALUE => match msg[1] {
+            VIA_UPTIME => msg[2..6].copy_from_slice(&(Instant::now().as_millis() as u32).to_be_bytes()),
+            VIA_LAYOUT_OPTIONS | VIA_SWITCH_MATRIX_STATE => msg[2..].fill(0),
+            _ => msg[0] = VIA_UNHANDLED,
+        },
+        VIA_SET_KEYBOARD_VALUE => {},  // there are no layout options to set
+        VIA_GET_KEYCODE => match addressed_position(msg) {
+            Some((layer_idx, row, column)) => msg[4..6].copy_from_slice(&get_keycode(layer_idx, row, column).to_be_bytes()),
+            None => msg[0] = VIA_UNHANDLED,
+        },
+        VIA_SET_KEYCODE => match addressed_position(msg) {
+            Some((layer_idx, row, column)) => set_keycode(layer_idx, row, column, u16::from_be_bytes([msg[4], msg[5]])),
+            None => msg[0] = VIA_UNHANDLED,
+        },
+        VIA_RESET_KEYMAP => {
+            reset_keymap();
+            info!("Keymap reset from Vial");
+        },
+        VIA_GET_MACRO_COUNT => msg[1] = 0,
+        VIA_GET_MACRO_BUFFER_SIZE => msg[1..3].fill(0),
+        VIA_GET_LAYER_COUNT => msg[1] = LAYERS.len() as u8,
+        VIA_GET_KEYMAP_BUFFER | VIA_SET_KEYMAP_BUFFER => {
+            // a byte offset and size of big-endian keycodes, in bytes 1-3, then the keycodes
+            let offset = u16::from_be_bytes([msg[1], msg[2]]) as usize;
+            let size = (msg[3] as usize).min(RAW_HID_REPORT_SIZE - 4);
+            for idx in (0..size / 2).map(|i| i + offset / 2) {
+                let Some((layer_idx, row, column)) = position(idx) else { break };
+                let at = 4 + (idx - offset / 2) * 2;
+                if msg[0] == VIA_GET_KEYMAP_BUFFER {
+                    msg[at..at + 2].copy_from_slice(&get_keycode(layer_idx, row, column).to_be_bytes());
+                } else {
+                    set_keycode(layer_idx, row, column, u16::from_be_bytes([msg[at], msg[at + 1]]));
+                }
+            }
+        },
+        VIAL_PREFIX => handle_vial(msg),
+        _ => msg[0] = VIA_UNHANDLED,
+    }
+}
+
+/// Carry out the Vial-specific command in `msg`, replacing it with the response.
+fn handle_vial(msg: &mut [u8; RAW_HID_REPORT_SIZE]) {
+    let command = msg[1];
+    let page = u16::from_le_bytes([msg[2], msg[3]]) as usize;
+    let dynamic_entry_op = msg[2];
+    msg.fill(0);
+    match command {
+        VIAL_GET_KEYBOARD_ID => {
+            msg[0..4].copy_from_slice(&VIAL_PROTOCOL_VERSION.to_le_bytes());
+            msg[4..12].copy_from_slice(&boards::VIAL_KEYBOARD_ID);
+        },
+        VIAL_GET_DEFINITION_SIZE => msg[0..4].copy_from_slice(&(boards::VIAL_DEFINITION.len() as u32).to_le_bytes()),
+        VIAL_GET_DEFINITION => {
+            let chunk = boards::VIAL_DEFINITION.chunks(RAW_HID_REPORT_SIZE).nth(page).unwrap_or_default();
+            msg[..chunk.len()].copy_from_slice(chunk);
+        },
+        VIAL_GET_UNLOCK_STATUS => {
+            // always unlocked, as remapping is only ever done from a trusted host anyway
+            msg[0] = 1;
+            msg[2..].fill(0xFF);  // no unlock keys
+        },
+        VIAL_UNLOCK_START | VIAL_LOCK => {},
+        VIAL_UNLOCK_POLL => msg[0] = 1,
+        VIAL_QMK_SETTINGS_QUERY => msg.fill(0xFF),  // none
+        VIAL_DYNAMIC_ENTRY_OP if dynamic_entry_op == 0 => {},  // no tap dances, combos or overrides
+        _ => msg[0] = VIA_UNHANDLED,
+    }
+}
+
+/// Answer Vial's messages over the raw HID interface.
+#[embassy_executor::task]
+pub async fn run(raw_hid: usb::RawHidReaderWriter) {
+    let (mut reader, mut writer) = raw_hid.split();
+    loop {
+        let mut msg = [0; RAW_HID_REPORT_SIZE];
+        match reader.read(&mut msg).await {
+            Ok(_) => {
+                handle(&mut msg);
+                if let Err(e) = writer.write(&msg).await {
+                    warn!("Failed to answer Vial: {:?}", e);
+                }
+            },
+            Err(_) => reader.ready().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remapped() -> Remapped {
+        let mut remapped = NOT_REMAPPED;
+        remapped[0][1][2] = Some(Thing::RealKey((0x04, 0)));
+        remapped[LAYERS.len() - 1][ROWS - 1][COLUMNS - 1] = Some(Thing::Inactive);
+        remapped
+    }
+
+    #[test]
+    fn saved_keymap_loads_as_it_was() {
+        assert!(deserialize(&serialize(&remapped())).ok() == Some(remapped()));
+    }
+
+    #[test]
+    fn corrupt_keymap_is_not_loaded() {
+        let mut bytes = serialize(&remapped());
+        bytes[KEYMAP_KEYCODES_INDEX + 5] ^= 0x10;
+        assert!(matches!(deserialize(&bytes), Err(LoadError::Corrupt)));
+    }
+
+    #[test]
+    fn erased_or_other_keymap_is_missing() {
+        assert!(matches!(deserialize(&[0xFF; size_of::<SerializedKeymap>()]), Err(LoadError::Missing)));
+        let mut bytes = serialize(&remapped());
+        bytes[KEYMAP_SHAPE_INDEX] += 1;
+        assert!(matches!(deserialize(&bytes), Err(LoadError::Missing)));
+    }
+}
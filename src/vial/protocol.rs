@@ -0,0 +1,40 @@
+//! IDs of the VIA and Vial messages understood by the `vial` module, in raw HID reports of
+//! [RAW_HID_REPORT_SIZE] bytes. Depends on nothing else, so that `host-tools` can include it too.
+
+/// Size of every raw HID report, in and out
+pub const RAW_HID_REPORT_SIZE: usize = 32;
+
+pub const VIA_PROTOCOL_VERSION: u16 = 9;
+pub const VIAL_PROTOCOL_VERSION: u32 = 6;
+
+// VIA command IDs, in the first byte of each message
+pub const VIA_GET_PROTOCOL_VERSION: u8 = 0x01;
+pub const VIA_GET_KEYBOARD_VALUE: u8 = 0x02;
+pub const VIA_SET_KEYBOARD_VALUE: u8 = 0x03;
+pub const VIA_GET_KEYCODE: u8 = 0x04;
+pub const VIA_SET_KEYCODE: u8 = 0x05;
+pub const VIA_RESET_KEYMAP: u8 = 0x06;
+pub const VIA_GET_MACRO_COUNT: u8 = 0x0C;
+pub const VIA_GET_MACRO_BUFFER_SIZE: u8 = 0x0D;
+pub const VIA_GET_LAYER_COUNT: u8 = 0x11;
+pub const VIA_GET_KEYMAP_BUFFER: u8 = 0x12;
+pub const VIA_SET_KEYMAP_BUFFER: u8 = 0x13;
+pub const VIAL_PREFIX: u8 = 0xFE;
+/// Sent back in place of the command ID for commands which aren't handled
+pub const VIA_UNHANDLED: u8 = 0xFF;
+
+// VIA keyboard value IDs, for VIA_GET_KEYBOARD_VALUE
+pub const VIA_UPTIME: u8 = 0x01;
+pub const VIA_LAYOUT_OPTIONS: u8 = 0x02;
+pub const VIA_SWITCH_MATRIX_STATE: u8 = 0x03;
+
+// Vial command IDs, in the second byte of messages starting with VIAL_PREFIX
+pub const VIAL_GET_KEYBOARD_ID: u8 = 0x00;
+pub const VIAL_GET_DEFINITION_SIZE: u8 = 0x01;
+pub const VIAL_GET_DEFINITION: u8 = 0x02;
+pub const VIAL_GET_UNLOCK_STATUS: u8 = 0x05;
+pub const VIAL_UNLOCK_START: u8 = 0x06;
+pub const VIAL_UNLOCK_POLL: u8 = 0x07;
+pub const VIAL_LOCK: u8 = 0x08;
+pub const VIAL_QMK_SETTINGS_QUERY: u8 = 0x09;
+pub const VIAL_DYNAMIC_ENTRY_OP: u8 = 0x0D;
@@ -0,0 +1,108 @@
+//! Watches the supply voltage, as the Pico measures VSYS through a divider on GP29, so that a weak
+//! USB port (or a long chain of hubs) shows up on the [crate::console] rather than only as odd scan
+//! glitches. While the voltage sags, [AUTO_DIM] can have the LEDs dimmed (see
+//! [crate::power::led_scale]) to draw less from it.
+
+// not measured with the `ble` feature, as a Pico W's radio has GP29
+#![cfg_attr(feature = "ble", allow(dead_code))]
+
+use crate::console::{self, ConsoleLine};
+use crate::RawMutex;
+use core::cell::Cell;
+use core::fmt::Write;
+use embassy_rp::adc::{self, Adc, Async, Channel};
+use embassy_rp::bind_interrupts;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Ticker};
+
+bind_interrupts!(pub struct Irqs {
+    ADC_IRQ_FIFO => adc::InterruptHandler;
+});
+
+/// How often VSYS is measured
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// VSYS (in mV) at the ADC's full scale of 4096 counts: its 3.3V reference, times the Pico's
+/// divide-by-three divider
+const FULL_SCALE_MV: u32 = 3300 * 3;
+
+/// VSYS (in mV) below which the supply counts as sagging. VBUS may be as low as 4.4V at the end
+/// of a bus-powered hub, and VSYS is a Schottky diode's drop below that.
+const SAG_MV: u16 = 4150;
+/// VSYS (in mV) which a sagging supply must get back above to count as recovered, a little over
+/// [SAG_MV] so that noise doesn't flick between the two
+const RECOVERED_MV: u16 = 4250;
+
+/// How much (out of 256) the LEDs are dimmed by while the supply sags, with [AUTO_DIM] on
+const SAG_LED_SCALE: u32 = 64;
+
+/// Whether to dim the LEDs while the supply sags. Switched from the console.
+pub static AUTO_DIM: Mutex<RawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// What's been measured of VSYS, in mV
+#[derive(Clone, Copy)]
+pub struct Readings {
+    /// As last measured, smoothed over the last few samples
+    pub latest: u16,
+    /// Lowest since boot, or since the console reset it
+    pub lowest: u16,
+    /// Whether the supply counts as sagging, below [SAG_MV]
+    pub sagging: bool,
+}
+
+static READINGS: Mutex<RawMutex, Cell<Option<Readings>>> = Mutex::new(Cell::new(None));
+
+/// VSYS as measured so far, or `None` before the first measurement
+pub fn readings() -> Option<Readings> {
+    READINGS.lock(|readings| readings.get())
+}
+
+/// Forget the lowest VSYS measured, starting again from the latest.
+pub fn reset_lowest() {
+    READINGS.lock(|readings| readings.set(readings.get().map(|r| Readings { lowest: r.latest, ..r })));
+}
+
+/// How much (out of 256) the LEDs should be dimmed by for the supply voltage
+pub fn led_scale() -> u32 {
+    let sagging = readings().is_some_and(|r| r.sagging);
+    if sagging && AUTO_DIM.lock(|enabled| enabled.get()) { SAG_LED_SCALE } else { 256 }
+}
+
+/// `mv` as volts, e.g. "4.85V", for the console
+pub fn describe_mv(line: &mut impl Write, mv: u16) -> core::fmt::Result {
+    write!(line, "{}.{:02}V", mv / 1000, mv % 1000 / 10)
+}
+
+#[embassy_executor::task]
+pub async fn run(mut adc: Adc<'static, Async>, mut vsys: Channel<'static>) {
+    let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+    loop {
+        ticker.next().await;
+        let Ok(count) = adc.read(&mut vsys).await else {
+            warn!("Failed to read VSYS");
+            continue;
+        };
+        let mv = (count as u32 * FULL_SCALE_MV / 4096) as u16;
+
+        let last = readings();
+        // averaged over a few samples, so that a single noisy one doesn't warn
+        let latest = last.map_or(mv, |r| ((r.latest as u32 * 3 + mv as u32) / 4) as u16);
+        let was_sagging = last.is_some_and(|r| r.sagging);
+        let sagging = if was_sagging { latest < RECOVERED_MV } else { latest < SAG_MV };
+        let lowest = last.map_or(latest, |r| r.lowest.min(latest));
+        READINGS.lock(|readings| readings.set(Some(Readings { latest, lowest, sagging })));
+
+        if sagging != was_sagging {
+            let mut line = ConsoleLine::new();
+            line.push_str(if sagging { "supply weak: VSYS at " } else { "supply recovered: VSYS at " }).ok();
+            describe_mv(&mut line, latest).ok();
+            line.push_str("\r\n").ok();
+            console::print(line);
+            if sagging {
+                warn!("VSYS sagging to {}mV", latest);
+            } else {
+                info!("VSYS recovered to {}mV", latest);
+            }
+        }
+    }
+}